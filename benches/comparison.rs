@@ -0,0 +1,215 @@
+//! Compares `Qutex` against `futures::lock::Mutex` and `tokio::sync::Mutex`,
+//! and `QrwLock` against `tokio::sync::RwLock`, uncontended, under
+//! contention between tasks, and through a storm of canceled requests.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::task::noop_waker_ref;
+use futures::FutureExt;
+use qutex::{QrwLock, Qutex};
+use std::future::Future;
+use std::sync::Arc;
+use std::task::Context;
+use tokio::runtime::Runtime;
+
+const OPS_PER_TASK: usize = 200;
+
+/// The number of requests queued and then dropped per cancellation storm.
+const STORM_SIZE: usize = 1000;
+
+type FuturesMutex = Arc<futures::lock::Mutex<usize>>;
+type TokioMutex = Arc<tokio::sync::Mutex<usize>>;
+type TokioRwLock = Arc<tokio::sync::RwLock<usize>>;
+
+/// Spawns `task_count` tasks, each running `op` on its own clone of `lock`
+/// `OPS_PER_TASK` times, and waits for all of them.
+fn run_tasks<L, F, Fut>(rt: &Runtime, task_count: usize, lock: &L, op: F)
+where
+    L: Clone + Send + 'static,
+    F: Fn(L, usize) -> Fut + Copy + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    rt.block_on(async {
+        let tasks = (0..task_count)
+            .map(|_| {
+                let lock = lock.clone();
+                tokio::spawn(async move {
+                    for i in 0..OPS_PER_TASK {
+                        op(lock.clone(), i).await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+    })
+}
+
+/// Locks, increments, and unlocks from a single thread, polling each request
+/// just once.
+fn uncontended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("uncontended");
+
+    let qutex = Qutex::new(0usize);
+    group.bench_function("qutex", |b| {
+        b.iter(|| *qutex.lock_ref().now_or_never().unwrap().unwrap() += 1)
+    });
+    let mutex = futures::lock::Mutex::new(0usize);
+    group.bench_function("futures", |b| {
+        b.iter(|| *mutex.lock().now_or_never().unwrap() += 1)
+    });
+    let mutex = tokio::sync::Mutex::new(0usize);
+    group.bench_function("tokio", |b| {
+        b.iter(|| *mutex.lock().now_or_never().unwrap() += 1)
+    });
+
+    group.finish();
+}
+
+/// Many tasks each lock, increment, and unlock, one operation at a time.
+fn contended(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("contended");
+    group.sample_size(20);
+
+    for task_count in [2, 8, 64] {
+        let qutex = Qutex::new(0usize);
+        group.bench_with_input(
+            BenchmarkId::new("qutex", task_count),
+            &task_count,
+            |b, &n| {
+                b.iter(|| {
+                    run_tasks(&rt, n, &qutex, |qutex, _| async move {
+                        *qutex.lock().await.unwrap() += 1
+                    })
+                })
+            },
+        );
+        let mutex = FuturesMutex::default();
+        group.bench_with_input(
+            BenchmarkId::new("futures", task_count),
+            &task_count,
+            |b, &n| {
+                b.iter(|| {
+                    run_tasks(&rt, n, &mutex, |mutex, _| async move {
+                        *mutex.lock().await += 1
+                    })
+                })
+            },
+        );
+        let mutex = TokioMutex::default();
+        group.bench_with_input(
+            BenchmarkId::new("tokio", task_count),
+            &task_count,
+            |b, &n| {
+                b.iter(|| {
+                    run_tasks(&rt, n, &mutex, |mutex, _| async move {
+                        *mutex.lock().await += 1
+                    })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// While the lock is held, `STORM_SIZE` requests are queued and dropped,
+/// after which the lock is released and taken once more.
+fn cancellation_storm(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cancellation_storm");
+    group.sample_size(20);
+
+    group.bench_function("qutex", |b| {
+        let qutex = Qutex::new(0usize);
+        b.iter(|| {
+            let cx = &mut Context::from_waker(noop_waker_ref());
+            let guard = qutex.lock_ref().now_or_never().unwrap().unwrap();
+            for _ in 0..STORM_SIZE {
+                assert!(qutex.lock_ref().poll_unpin(cx).is_pending());
+            }
+            drop(guard);
+            *qutex.lock_ref().now_or_never().unwrap().unwrap() += 1
+        })
+    });
+    group.bench_function("futures", |b| {
+        let mutex = futures::lock::Mutex::new(0usize);
+        b.iter(|| {
+            let cx = &mut Context::from_waker(noop_waker_ref());
+            let guard = mutex.lock().now_or_never().unwrap();
+            for _ in 0..STORM_SIZE {
+                assert!(mutex.lock().poll_unpin(cx).is_pending());
+            }
+            drop(guard);
+            *mutex.lock().now_or_never().unwrap() += 1
+        })
+    });
+    group.bench_function("tokio", |b| {
+        let mutex = tokio::sync::Mutex::new(0usize);
+        b.iter(|| {
+            let cx = &mut Context::from_waker(noop_waker_ref());
+            let guard = mutex.lock().now_or_never().unwrap();
+            for _ in 0..STORM_SIZE {
+                assert!(Box::pin(mutex.lock()).poll_unpin(cx).is_pending());
+            }
+            drop(guard);
+            *mutex.lock().now_or_never().unwrap() += 1
+        })
+    });
+
+    group.finish();
+}
+
+/// Many tasks each read nine times for every write.
+fn read_heavy(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("read_heavy");
+    group.sample_size(20);
+
+    for task_count in [2, 8, 64] {
+        let lock = QrwLock::new(0usize);
+        group.bench_with_input(
+            BenchmarkId::new("qrw_lock", task_count),
+            &task_count,
+            |b, &n| {
+                b.iter(|| {
+                    run_tasks(&rt, n, &lock, |lock, i| async move {
+                        if i % 10 == 0 {
+                            *lock.write().await.unwrap() += 1;
+                        } else {
+                            assert!(*lock.read().await.unwrap() < usize::MAX);
+                        }
+                    })
+                })
+            },
+        );
+        let lock = TokioRwLock::default();
+        group.bench_with_input(
+            BenchmarkId::new("tokio", task_count),
+            &task_count,
+            |b, &n| {
+                b.iter(|| {
+                    run_tasks(&rt, n, &lock, |lock, i| async move {
+                        if i % 10 == 0 {
+                            *lock.write().await += 1;
+                        } else {
+                            assert!(*lock.read().await < usize::MAX);
+                        }
+                    })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    uncontended,
+    contended,
+    cancellation_storm,
+    read_heavy
+);
+criterion_main!(benches);
@@ -0,0 +1,123 @@
+//! Measures the cost of an uncontended lock, and compares per-request guard
+//! handoff, barging, and submitted (combined) operations under contention.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::{executor, future};
+use qutex::{Admission, Qutex, QutexBuilder};
+use std::thread;
+
+const OPS_PER_THREAD: usize = 1000;
+
+/// Each thread locks, increments, and unlocks, one operation at a time.
+fn handoff(thread_count: usize, admission: Admission) {
+    let qutex = QutexBuilder::new().admission(admission).build(0usize);
+
+    let threads = (0..thread_count)
+        .map(|_| {
+            let qutex = qutex.clone();
+            thread::spawn(move || {
+                for _ in 0..OPS_PER_THREAD {
+                    *qutex.clone().lock().wait().unwrap() += 1;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+/// Each thread spins for the lock, then updates several counters within the
+/// data, which share a cache line with the lock state unless padded.
+fn spinning(thread_count: usize) {
+    let qutex = QutexBuilder::new()
+        .admission(Admission::Barging)
+        .spin_limit(32)
+        .build([0usize; 4]);
+
+    let threads = (0..thread_count)
+        .map(|_| {
+            let qutex = qutex.clone();
+            thread::spawn(move || {
+                for _ in 0..OPS_PER_THREAD {
+                    let mut guard = qutex.clone().lock().wait().unwrap();
+                    for counter in guard.iter_mut() {
+                        *counter += 1;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+/// Each thread submits its increments, which are run in batches by whoever
+/// holds the lock.
+fn combined(thread_count: usize) {
+    let qutex = Qutex::new(0usize);
+
+    let threads = (0..thread_count)
+        .map(|_| {
+            let qutex = qutex.clone();
+            thread::spawn(move || {
+                let submits = (0..OPS_PER_THREAD)
+                    .map(|_| qutex.clone().submit(|val| *val += 1))
+                    .collect::<Vec<_>>();
+                executor::block_on(future::try_join_all(submits)).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+/// A single thread locks, increments, and unlocks, measuring the fixed
+/// per-request cost.
+fn uncontended(c: &mut Criterion) {
+    let qutex = Qutex::new(0usize);
+    c.bench_function("uncontended", |b| {
+        b.iter(|| *qutex.clone().lock().wait().unwrap() += 1)
+    });
+    c.bench_function("uncontended_ref", |b| {
+        b.iter(|| *qutex.lock_ref().wait().unwrap() += 1)
+    });
+}
+
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contention");
+    group.sample_size(20);
+
+    for thread_count in [1, 4, 16] {
+        group.bench_with_input(
+            BenchmarkId::new("handoff", thread_count),
+            &thread_count,
+            |b, &n| b.iter(|| handoff(n, Admission::Fair)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("barging", thread_count),
+            &thread_count,
+            |b, &n| b.iter(|| handoff(n, Admission::Barging)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("spinning", thread_count),
+            &thread_count,
+            |b, &n| b.iter(|| spinning(n)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("combined", thread_count),
+            &thread_count,
+            |b, &n| b.iter(|| combined(n)),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, uncontended, contention);
+criterion_main!(benches);
@@ -1,7 +1,7 @@
 extern crate futures;
 extern crate qutex;
 
-use futures::Future;
+use futures::executor;
 use qutex::Qutex;
 use std::thread;
 
@@ -20,16 +20,17 @@ fn main() {
 
         // Add 1 to the protected value. `future_val` is a `FutureGuard` which
         // will resolve to a `Guard` providing mutable access to the protected
-        // value. The guard can be passed between futures combinators and will
-        // unlock the `Qutex` when dropped.
-        let future_add = future_val.map(|mut val| {
+        // value. The guard can be held across `.await` points and will unlock
+        // the `Qutex` when dropped.
+        let future_add = async move {
+            let mut val = future_val.await.unwrap();
             *val += 1;
-        });
+        };
 
         // Spawn a thread which blocks upon completion of the above lock and
         // add operations.
         threads.push(thread::spawn(|| {
-            future_add.wait().unwrap();
+            executor::block_on(future_add);
         }));
     }
 
@@ -0,0 +1,12 @@
+//! Runs arbitrary sequences of lock operations through
+//! `qutex::testing::check_interleaving`:
+//!
+//! ```text
+//! cargo +nightly fuzz run interleavings
+//! ```
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|ops: &[u8]| qutex::testing::check_interleaving(ops));
@@ -0,0 +1,145 @@
+//! Derive macros for [qutex](https://docs.rs/qutex).
+//!
+//! These are re-exported by `qutex` when its `derive` feature is enabled.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+/// The maximum number of fields `lock_all` (via `qutex::lock_all!`) supports.
+const MAX_LOCK_ALL_FIELDS: usize = 8;
+
+/// Generates a `{Name}Fields` wrapper in which each field of a struct with
+/// named fields is held in its own `qutex::Qutex`.
+///
+/// For every field, the wrapper has a method of the same name returning a
+/// handle to that field's `Qutex`, so that fields can be locked
+/// independently (`fields.name().lock()`). `lock_all` acquires every field,
+/// in the same globally consistent order as `qutex::lock_all!`, and
+/// resolves to a tuple of guards in declaration order. The wrapper is
+/// created with `From<Name>`.
+///
+/// ```
+/// use qutex_derive::QutexFields;
+///
+/// #[derive(QutexFields)]
+/// struct Person {
+///     name: String,
+///     age: u32,
+/// }
+///
+/// let person = PersonFields::from(Person { name: "Ada".into(), age: 36 });
+/// *person.age().lock().wait().unwrap() += 1;
+///
+/// let (name, age) = person.lock_all().wait().unwrap();
+/// assert_eq!((name.as_str(), *age), ("Ada", 37));
+/// ```
+#[proc_macro_derive(QutexFields)]
+pub fn derive_qutex_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => fields.named.iter().collect::<Vec<_>>(),
+            _ => {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "QutexFields: Only structs with named fields are supported.",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                Span::call_site(),
+                "QutexFields: Only structs are supported.",
+            ))
+        }
+    };
+
+    let vis = &input.vis;
+    let name = &input.ident;
+    let wrapper = format_ident!("{}Fields", name);
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let idents = fields
+        .iter()
+        .map(|field| field.ident.as_ref().unwrap())
+        .collect::<Vec<_>>();
+    let tys = fields.iter().map(|field| &field.ty).collect::<Vec<_>>();
+
+    let wrapper_doc = format!(
+        "`{}` with each field held in its own `Qutex`. Generated by `#[derive(QutexFields)]`.",
+        name
+    );
+    let accessor_docs = idents
+        .iter()
+        .map(|ident| format!("Returns a handle to the `Qutex` holding `{}`.", ident));
+
+    let lock_all = if fields.is_empty() || fields.len() > MAX_LOCK_ALL_FIELDS {
+        quote!()
+    } else {
+        quote! {
+            /// Returns a future which resolves once every field is locked,
+            /// to a tuple of guards in declaration order.
+            ///
+            /// Fields are acquired in the same globally consistent order as
+            /// `qutex::lock_all!`.
+            #[inline]
+            #vis fn lock_all(&self) -> ::qutex::FutureLockAll<(
+                #(::qutex::LockSlot<#tys>,)*
+            )> {
+                ::qutex::FutureLockAll::new((
+                    #(::qutex::LockSlot::new(self.#idents.clone()),)*
+                ))
+            }
+        }
+    };
+
+    Ok(quote! {
+        #[doc = #wrapper_doc]
+        #vis struct #wrapper #impl_generics #where_clause {
+            #(#idents: ::qutex::Qutex<#tys>,)*
+        }
+
+        impl #impl_generics #wrapper #ty_generics #where_clause {
+            #(
+                #[doc = #accessor_docs]
+                #[inline]
+                #vis fn #idents(&self) -> ::qutex::Qutex<#tys> {
+                    self.#idents.clone()
+                }
+            )*
+
+            #lock_all
+        }
+
+        impl #impl_generics ::std::convert::From<#name #ty_generics>
+            for #wrapper #ty_generics #where_clause
+        {
+            #[inline]
+            fn from(val: #name #ty_generics) -> Self {
+                #wrapper {
+                    #(#idents: ::qutex::Qutex::new(val.#idents),)*
+                }
+            }
+        }
+
+        // Avoids needing the field types to be `Clone`.
+        impl #impl_generics ::std::clone::Clone for #wrapper #ty_generics #where_clause {
+            #[inline]
+            fn clone(&self) -> Self {
+                #wrapper {
+                    #(#idents: self.#idents.clone(),)*
+                }
+            }
+        }
+    })
+}
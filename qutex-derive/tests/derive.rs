@@ -0,0 +1,69 @@
+use qutex_derive::QutexFields;
+use std::thread;
+
+#[derive(QutexFields)]
+struct Account {
+    owner: String,
+    balance: i64,
+    history: Vec<i64>,
+}
+
+#[derive(QutexFields)]
+struct Pair<T: Send> {
+    left: T,
+    right: T,
+}
+
+#[test]
+fn fields_lock_independently() {
+    let account = AccountFields::from(Account {
+        owner: "ada".into(),
+        balance: 0,
+        history: Vec::new(),
+    });
+
+    // Holding one field does not block another:
+    let owner = account.owner().lock().wait().unwrap();
+    *account.balance().lock().wait().unwrap() += 10;
+    assert_eq!(*owner, "ada");
+}
+
+#[test]
+fn lock_all() {
+    let account = AccountFields::from(Account {
+        owner: "ada".into(),
+        balance: 0,
+        history: Vec::new(),
+    });
+
+    let threads = (0..4)
+        .map(|_| {
+            let account = account.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let (_owner, mut balance, mut history) = account.lock_all().wait().unwrap();
+                    *balance += 1;
+                    history.push(*balance);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let (_, balance, history) = account.lock_all().wait().unwrap();
+    assert_eq!(*balance, 200);
+    assert_eq!(*history, (1..=200).collect::<Vec<_>>());
+}
+
+#[test]
+fn generics() {
+    let pair = PairFields::from(Pair {
+        left: 1u8,
+        right: 2u8,
+    });
+    let (left, right) = pair.lock_all().wait().unwrap();
+    assert_eq!(*left + *right, 3);
+}
@@ -0,0 +1,412 @@
+//! An exclusive lock stored wholly inline, with room for a fixed number of
+//! waiters.
+//!
+//
+// The lock's state and its ring of waiters sit behind a spin lock, held
+// only to take, queue, grant or withdraw a request, much as the stand-in
+// `SegQueue` does (see `queue`). Releasing the lock hands it straight to the
+// request at the front of the ring, whose future takes it up once next
+// polled. A request dropped after being granted hands the lock on in turn.
+
+use crate::Error;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::future::Future;
+use core::hint;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use futures::executor;
+
+/// The state of an `ArrayQutex`, behind its spin lock.
+struct Queue<const N: usize> {
+    // Whether the lock is held, or granted and not yet taken up:
+    locked: bool,
+    // The queued request granted the lock, until it is next polled:
+    granted: Option<usize>,
+    next_id: usize,
+    // The queued requests, `len` of them from `head` on, with their ids:
+    waiters: [Option<(usize, Waker)>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Queue<N> {
+    /// Queues a request, returning its id, or `None` if every slot is
+    /// taken.
+    fn push(&mut self, waker: Waker) -> Option<usize> {
+        if self.len == N {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        self.waiters[(self.head + self.len) % N] = Some((id, waker));
+        self.len += 1;
+        Some(id)
+    }
+
+    /// Returns how far from the front of the ring the queued request `id`
+    /// is.
+    fn position(&self, id: usize) -> Option<usize> {
+        (0..self.len).find(|&i| {
+            self.waiters[(self.head + i) % N]
+                .as_ref()
+                .is_some_and(|&(slot_id, _)| slot_id == id)
+        })
+    }
+
+    /// Replaces the waker of the queued request `id`.
+    fn update(&mut self, id: usize, waker: &Waker) {
+        if let Some(pos) = self.position(id) {
+            if let Some((_, ref mut slot_waker)) = self.waiters[(self.head + pos) % N] {
+                slot_waker.clone_from(waker);
+            }
+        }
+    }
+
+    /// Withdraws the queued request `id`, closing up the ring behind it.
+    fn remove(&mut self, id: usize) {
+        let Some(pos) = self.position(id) else {
+            return;
+        };
+        for i in pos..self.len - 1 {
+            self.waiters[(self.head + i) % N] = self.waiters[(self.head + i + 1) % N].take();
+        }
+        self.waiters[(self.head + self.len - 1) % N] = None;
+        self.len -= 1;
+    }
+
+    /// Releases the lock, granting it to the request at the front of the
+    /// ring, if any, whose waker is returned to be woken.
+    fn release(&mut self) -> Option<Waker> {
+        if self.len == 0 {
+            self.locked = false;
+            return None;
+        }
+        let (id, waker) = self.waiters[self.head]
+            .take()
+            .expect("ArrayQutex: Queued request missing.");
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        self.granted = Some(id);
+        Some(waker)
+    }
+}
+
+/// Releases the spin lock once dropped, even should the operation panic.
+struct Spin<'a>(&'a AtomicBool);
+
+impl Drop for Spin<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.store(false, Release);
+    }
+}
+
+/// Allows access to the data contained within an `ArrayQutex`.
+pub struct ArrayGuard<'a, T, const N: usize> {
+    qutex: &'a ArrayQutex<T, N>,
+}
+
+impl<T, const N: usize> Deref for ArrayGuard<'_, T, N> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.qutex.cell.get() }
+    }
+}
+
+impl<T, const N: usize> DerefMut for ArrayGuard<'_, T, N> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.qutex.cell.get() }
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayGuard<'_, T, N> {
+    fn drop(&mut self) {
+        if let Some(waker) = self.qutex.with_queue(Queue::release) {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for ArrayGuard<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ArrayGuard").field(&**self).finish()
+    }
+}
+
+/// A future which resolves to an `ArrayGuard`, or to `Error::QueueFull` if
+/// the lock is held and every waiter slot is taken.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureArrayGuard<'a, T, const N: usize> {
+    qutex: &'a ArrayQutex<T, N>,
+    // Once queued:
+    id: Option<usize>,
+    done: bool,
+}
+
+impl<'a, T, const N: usize> FutureArrayGuard<'a, T, N> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<ArrayGuard<'a, T, N>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<'a, T, const N: usize> Future for FutureArrayGuard<'a, T, N> {
+    type Output = Result<ArrayGuard<'a, T, N>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        assert!(
+            !this.done,
+            "FutureArrayGuard::poll: Task already completed."
+        );
+
+        let id = &mut this.id;
+        let res = this.qutex.with_queue(|queue| match *id {
+            None if !queue.locked => {
+                queue.locked = true;
+                Poll::Ready(Ok(()))
+            }
+            None => match queue.push(cx.waker().clone()) {
+                Some(queued) => {
+                    *id = Some(queued);
+                    Poll::Pending
+                }
+                None => Poll::Ready(Err(Error::QueueFull)),
+            },
+            Some(queued) if queue.granted == Some(queued) => {
+                queue.granted = None;
+                Poll::Ready(Ok(()))
+            }
+            Some(queued) => {
+                queue.update(queued, cx.waker());
+                Poll::Pending
+            }
+        });
+        if res.is_ready() {
+            this.done = true;
+            this.id = None;
+        }
+        let qutex = this.qutex;
+        res.map(|res| res.map(|()| ArrayGuard { qutex }))
+    }
+}
+
+impl<T, const N: usize> Drop for FutureArrayGuard<'_, T, N> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let waker = self.qutex.with_queue(|queue| {
+            if queue.granted == Some(id) {
+                // Passed on, never having been taken up:
+                queue.granted = None;
+                queue.release()
+            } else {
+                queue.remove(id);
+                None
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for FutureArrayGuard<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureArrayGuard")
+            .field("queued", &self.id.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// An exclusive lock whose whole state, from the data to the `N` slots for
+/// waiting requests, is stored inline, so that it can be placed in a
+/// `static` or on the stack and never allocates.
+///
+/// Requests are granted in FIFO order. One made while the lock is held and
+/// all `N` slots are taken fails with `Error::QueueFull`, as does any for a
+/// held lock should `N` be zero.
+///
+/// ```
+/// use qutex::ArrayQutex;
+///
+/// static COUNT: ArrayQutex<u32, 4> = ArrayQutex::new(0);
+///
+/// # #[cfg(feature = "std")] {
+/// *COUNT.lock().wait().unwrap() += 1;
+/// assert_eq!(*COUNT.try_lock().unwrap(), 1);
+/// # }
+/// ```
+pub struct ArrayQutex<T, const N: usize> {
+    spin: AtomicBool,
+    queue: UnsafeCell<Queue<N>>,
+    cell: UnsafeCell<T>,
+}
+
+impl<T, const N: usize> ArrayQutex<T, N> {
+    /// Creates and returns a new `ArrayQutex`.
+    #[inline]
+    pub const fn new(val: T) -> ArrayQutex<T, N> {
+        ArrayQutex {
+            spin: AtomicBool::new(false),
+            queue: UnsafeCell::new(Queue {
+                locked: false,
+                granted: None,
+                next_id: 0,
+                waiters: [const { None }; N],
+                head: 0,
+                len: 0,
+            }),
+            cell: UnsafeCell::new(val),
+        }
+    }
+
+    /// Runs `f` with the spin lock held.
+    fn with_queue<R>(&self, f: impl FnOnce(&mut Queue<N>) -> R) -> R {
+        while self
+            .spin
+            .compare_exchange_weak(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            while self.spin.load(Relaxed) {
+                hint::spin_loop();
+            }
+        }
+        let _spin = Spin(&self.spin);
+        f(unsafe { &mut *self.queue.get() })
+    }
+
+    /// Returns a new `FutureArrayGuard` which will resolve to an
+    /// `ArrayGuard` once the lock is granted.
+    #[inline]
+    pub fn lock(&self) -> FutureArrayGuard<'_, T, N> {
+        FutureArrayGuard {
+            qutex: self,
+            id: None,
+            done: false,
+        }
+    }
+
+    /// Returns an `ArrayGuard` if the lock is free.
+    pub fn try_lock(&self) -> Option<ArrayGuard<'_, T, N>> {
+        let taken = self.with_queue(|queue| !mem::replace(&mut queue.locked, true));
+        taken.then(|| ArrayGuard { qutex: self })
+    }
+
+    /// Returns the number of requests waiting.
+    #[inline]
+    pub fn waiters(&self) -> usize {
+        self.with_queue(|queue| queue.len)
+    }
+
+    /// Returns a mutable reference to the inner value.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.cell.get_mut()
+    }
+
+    /// Consumes this `ArrayQutex`, returning the inner value.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.cell.into_inner()
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Send for ArrayQutex<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for ArrayQutex<T, N> {}
+
+impl<T: Default, const N: usize> Default for ArrayQutex<T, N> {
+    #[inline]
+    fn default() -> ArrayQutex<T, N> {
+        ArrayQutex::new(T::default())
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for ArrayQutex<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ArrayQutex")
+            .field("waiters", &self.waiters())
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::thread;
+
+    #[test]
+    fn queued_in_order() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = ArrayQutex::<_, 2>::new(0);
+        let guard = qutex.try_lock().unwrap();
+        let (mut a, mut b) = (qutex.lock(), qutex.lock());
+        assert!(a.poll_unpin(cx).is_pending() && b.poll_unpin(cx).is_pending());
+        assert_eq!(
+            qutex.lock().poll_unpin(cx).map(|res| res.err()),
+            Poll::Ready(Some(Error::QueueFull))
+        );
+
+        // Withdrawn, freeing its slot:
+        drop(a);
+        let mut c = qutex.lock();
+        assert!(c.poll_unpin(cx).is_pending());
+        assert_eq!(qutex.waiters(), 2);
+
+        drop(guard);
+        assert!(qutex.try_lock().is_none());
+        *b.wait().unwrap() += 1;
+        assert_eq!(*c.wait().unwrap(), 1);
+        assert!(qutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn granted_then_dropped() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = ArrayQutex::<_, 2>::new(());
+        let guard = qutex.try_lock().unwrap();
+        let (mut a, mut b) = (qutex.lock(), qutex.lock());
+        assert!(a.poll_unpin(cx).is_pending() && b.poll_unpin(cx).is_pending());
+
+        // Granted to `a`, which hands it on unused:
+        drop(guard);
+        drop(a);
+        assert!(b.poll_unpin(cx).is_ready());
+        assert_eq!(qutex.waiters(), 0);
+    }
+
+    #[test]
+    fn contended() {
+        static COUNT: ArrayQutex<usize, 8> = ArrayQutex::new(0);
+        let threads = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..500 {
+                        *COUNT.lock().wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*COUNT.try_lock().unwrap(), 2000);
+    }
+}
@@ -0,0 +1,143 @@
+//! A trait abstracting over async exclusive locks.
+//!
+//
+
+use crate::{
+    Error, FutureGuard, FutureIntrusiveGuard, FutureLocalGuard, FutureSpinGuard, Guard,
+    IntrusiveGuard, IntrusiveQutex, LocalGuard, LocalQutex, Qutex, SpinGuard, SpinQutex,
+};
+#[cfg(feature = "std")]
+use crate::{FutureWriteGuard, QrwLock, WriteGuard};
+use core::convert::Infallible;
+use core::future::Future;
+use core::ops::DerefMut;
+#[cfg(feature = "std")]
+use futures::channel::oneshot::Canceled;
+
+/// An async lock granting exclusive access to a `T`.
+///
+/// Allows code to be written once against any async mutex, such as a
+/// `Qutex` or the write side of a `QrwLock`, and tested against another.
+/// `LocalQutex`, `SpinQutex` and `IntrusiveQutex` implement it too, their
+/// requests never failing.
+///
+/// `ArrayQutex` does not: its futures borrow the lock, typically a
+/// `static`, rather than owning a handle to it, which `Future` has no
+/// lifetime to express.
+pub trait AsyncLock<T> {
+    /// The guard granting access to the data.
+    type Guard: DerefMut<Target = T>;
+
+    /// The error a lock request may resolve to.
+    type Error;
+
+    /// The future resolving to a `Guard`.
+    type Future: Future<Output = Result<Self::Guard, Self::Error>>;
+
+    /// Returns a future which will resolve once the lock is held.
+    fn lock(&self) -> Self::Future;
+}
+
+impl<T> AsyncLock<T> for Qutex<T> {
+    type Guard = Guard<T>;
+    type Error = Error;
+    type Future = FutureGuard<T>;
+
+    #[inline]
+    fn lock(&self) -> FutureGuard<T> {
+        self.clone().lock()
+    }
+}
+
+impl<T> AsyncLock<T> for LocalQutex<T> {
+    type Guard = LocalGuard<T>;
+    type Error = Infallible;
+    type Future = FutureLocalGuard<T>;
+
+    #[inline]
+    fn lock(&self) -> FutureLocalGuard<T> {
+        self.clone().lock()
+    }
+}
+
+impl<T> AsyncLock<T> for SpinQutex<T> {
+    type Guard = SpinGuard<T>;
+    type Error = Infallible;
+    type Future = FutureSpinGuard<T>;
+
+    #[inline]
+    fn lock(&self) -> FutureSpinGuard<T> {
+        self.clone().lock()
+    }
+}
+
+impl<T> AsyncLock<T> for IntrusiveQutex<T> {
+    type Guard = IntrusiveGuard<T>;
+    type Error = Infallible;
+    type Future = FutureIntrusiveGuard<T>;
+
+    #[inline]
+    fn lock(&self) -> FutureIntrusiveGuard<T> {
+        self.clone().lock()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> AsyncLock<T> for QrwLock<T> {
+    type Guard = WriteGuard<T>;
+    type Error = Canceled;
+    type Future = FutureWriteGuard<T>;
+
+    #[inline]
+    fn lock(&self) -> FutureWriteGuard<T> {
+        self.clone().write()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use futures::executor;
+
+    async fn bump<L: AsyncLock<Vec<u32>>>(lock: &L, val: u32) -> Result<usize, L::Error> {
+        let mut guard = lock.lock().await?;
+        guard.push(val);
+        Ok(guard.len())
+    }
+
+    #[test]
+    fn qutex() {
+        let qutex = Qutex::new(Vec::new());
+        assert_eq!(executor::block_on(bump(&qutex, 1)), Ok(1));
+        assert_eq!(executor::block_on(bump(&qutex, 2)), Ok(2));
+        assert_eq!(*qutex.lock().wait().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn qrw_lock() {
+        let lock = QrwLock::new(vec![0]);
+        assert_eq!(executor::block_on(bump(&lock, 1)), Ok(2));
+        assert_eq!(*lock.read().wait().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn local_qutex() {
+        let qutex = LocalQutex::new(Vec::new());
+        assert_eq!(executor::block_on(bump(&qutex, 1)), Ok(1));
+        assert_eq!(*qutex.try_lock().unwrap(), [1]);
+    }
+
+    #[test]
+    fn spin_qutex() {
+        let qutex = SpinQutex::new(Vec::new());
+        assert_eq!(executor::block_on(bump(&qutex, 1)), Ok(1));
+        assert_eq!(*qutex.try_lock().unwrap(), [1]);
+    }
+
+    #[test]
+    fn intrusive_qutex() {
+        let qutex = IntrusiveQutex::new(Vec::new());
+        assert_eq!(executor::block_on(bump(&qutex, 1)), Ok(1));
+        assert_eq!(*qutex.try_lock().unwrap(), [1]);
+    }
+}
@@ -0,0 +1,215 @@
+//! A reusable barrier, electing a leader each generation.
+//!
+//
+
+use futures::channel::oneshot::{self, Receiver, Sender};
+use futures::executor;
+use std::convert::Infallible;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+
+/// The tasks arrived so far in the current generation.
+#[derive(Debug, Default)]
+struct Generation {
+    arrived: usize,
+    waiters: Vec<Sender<()>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    parties: usize,
+    generation: Mutex<Generation>,
+}
+
+impl Inner {
+    #[inline]
+    fn generation(&self) -> MutexGuard<'_, Generation> {
+        self.generation
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Counts an arrival, returning `None` if it completes the generation,
+    /// releasing everyone waiting, or the receiver completed once it is.
+    fn arrive(&self) -> Option<Receiver<()>> {
+        let mut generation = self.generation();
+        generation.arrived += 1;
+        if generation.arrived >= self.parties {
+            let released = mem::take(&mut *generation);
+            drop(generation);
+            for tx in released.waiters {
+                tx.send(()).ok();
+            }
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        generation.waiters.push(tx);
+        Some(rx)
+    }
+}
+
+/// Returned by a `FutureBarrier` once every party has arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Returns `true` for exactly one waiter of each generation: the last
+    /// to arrive.
+    #[inline]
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Unarrived,
+    Waiting(Receiver<()>),
+    Done,
+}
+
+/// A future which resolves once every party of the current generation of
+/// the `Barrier` it was created from has arrived.
+///
+/// Arrives when first polled. Once it has, dropping it before resolving
+/// still counts as having arrived. Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureBarrier {
+    inner: Arc<Inner>,
+    state: State,
+}
+
+impl FutureBarrier {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<BarrierWaitResult, Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl Future for FutureBarrier {
+    type Output = Result<BarrierWaitResult, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match mem::replace(&mut this.state, State::Done) {
+                State::Unarrived => match this.inner.arrive() {
+                    Some(rx) => this.state = State::Waiting(rx),
+                    None => return Poll::Ready(Ok(BarrierWaitResult(true))),
+                },
+                State::Waiting(mut rx) => {
+                    return match Pin::new(&mut rx).poll(cx) {
+                        // The sender is only dropped when sent:
+                        Poll::Ready(_) => Poll::Ready(Ok(BarrierWaitResult(false))),
+                        Poll::Pending => {
+                            this.state = State::Waiting(rx);
+                            Poll::Pending
+                        }
+                    };
+                }
+                State::Done => panic!("FutureBarrier::poll: Task already completed."),
+            }
+        }
+    }
+}
+
+/// A barrier which releases its waiters once `parties` of them have
+/// arrived, and then starts over, for tasks working in phases.
+///
+/// Each generation, exactly one waiter is told it is the leader (see
+/// `BarrierWaitResult::is_leader`), to do the work between phases, such as
+/// a reduction over their results, just like `std::sync::Barrier`. A
+/// barrier of zero or one parties releases every waiter alone, leader of
+/// its own generation.
+#[derive(Debug, Clone)]
+pub struct Barrier {
+    inner: Arc<Inner>,
+}
+
+impl Barrier {
+    /// Creates and returns a new `Barrier` releasing its waiters once
+    /// `parties` have arrived.
+    #[inline]
+    pub fn new(parties: usize) -> Barrier {
+        Barrier {
+            inner: Arc::new(Inner {
+                parties,
+                generation: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Returns the number of waiters each generation releases.
+    #[inline]
+    pub fn parties(&self) -> usize {
+        self.inner.parties
+    }
+
+    /// Returns a new `FutureBarrier` which will resolve once every party of
+    /// the generation it arrives in has arrived.
+    #[inline]
+    pub fn wait(&self) -> FutureBarrier {
+        FutureBarrier {
+            inner: self.inner.clone(),
+            state: State::Unarrived,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
+    #[test]
+    fn generations() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let barrier = Barrier::new(2);
+        for _ in 0..3 {
+            let mut first = barrier.wait();
+            assert!(first.poll_unpin(cx).is_pending());
+            let second = barrier.wait().wait().unwrap();
+            assert!(second.is_leader());
+            assert_eq!(first.wait(), Ok(BarrierWaitResult(false)));
+        }
+        assert!(Barrier::new(0).wait().wait().unwrap().is_leader());
+    }
+
+    #[test]
+    fn one_leader_per_phase() {
+        let (parties, phases) = (6, 50);
+        let barrier = Barrier::new(parties);
+        let leaders = Arc::new(AtomicUsize::new(0));
+        let sum = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..parties)
+            .map(|_| {
+                let (barrier, leaders, sum) = (barrier.clone(), leaders.clone(), sum.clone());
+                thread::spawn(move || {
+                    for phase in 0..phases {
+                        sum.fetch_add(1, SeqCst);
+                        if barrier.wait().wait().unwrap().is_leader() {
+                            // Everyone has contributed to this phase alone:
+                            assert_eq!(sum.load(SeqCst), parties * (phase + 1));
+                            leaders.fetch_add(1, SeqCst);
+                        }
+                        barrier.wait().wait().unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(leaders.load(SeqCst), phases);
+    }
+}
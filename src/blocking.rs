@@ -0,0 +1,170 @@
+//! Executor-free blocking acquisition for thread pool workers.
+//!
+//
+
+use crate::{Backoff, Guard, Qutex};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// Unparks the waiting thread.
+struct Unparker(Thread);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl<T> Qutex<T> {
+    /// Blocks the current thread until the lock is acquired, backing off
+    /// as the lock's `Backoff` allows before parking.
+    ///
+    /// Shorthand for `lock_blocking_with_backoff(self.backoff())`.
+    #[inline]
+    pub fn lock_blocking(self) -> Guard<T> {
+        let backoff = self.backoff();
+        self.lock_blocking_with_backoff(backoff)
+    }
+
+    /// Blocks the current thread until the lock is acquired, spinning and
+    /// yielding according to `backoff` before parking.
+    ///
+    /// Unlike `FutureGuard::wait`, no executor is involved, so this is safe
+    /// to call from within thread pools (such as rayon's) which may
+    /// themselves be driven from an executor. Spinning attempts never jump
+    /// the queue under `Admission::Fair`. Should a bounded queue turn the
+    /// request away, the thread backs off again and requests the lock anew.
+    pub fn lock_blocking_with_backoff(self, backoff: Backoff) -> Guard<T> {
+        loop {
+            if let Some(guard) = back_off(backoff, || self.try_lock()) {
+                return guard;
+            }
+            // Without a deadline, only rejected if never queued:
+            match park_on(self.clone().lock()) {
+                Ok(guard) => return guard,
+                Err(_) => thread::yield_now(),
+            }
+        }
+    }
+}
+
+/// Retries `try_lock`, spinning and then yielding in between attempts, as
+/// `backoff` allows.
+pub(crate) fn back_off<G>(backoff: Backoff, mut try_lock: impl FnMut() -> Option<G>) -> Option<G> {
+    let attempts = backoff.spin_count().saturating_add(backoff.yield_count());
+    for attempt in 0..attempts {
+        if let Some(guard) = try_lock() {
+            return Some(guard);
+        }
+        backoff.snooze(attempt);
+    }
+    None
+}
+
+/// Polls `future` to completion on the current thread, parking it in
+/// between polls.
+pub(crate) fn park_on<F: Future + Unpin>(mut future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let cx = &mut Context::from_waker(&waker);
+
+    loop {
+        match Pin::new(&mut future).poll(cx) {
+            Poll::Ready(res) => return res,
+            // Spurious wakeups only cause another poll:
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QutexBuilder;
+
+    #[test]
+    fn contended() {
+        let total = Qutex::new(0usize);
+
+        let threads = (0..8)
+            .map(|i| {
+                let total = total.clone();
+                let backoff = if i % 2 == 0 {
+                    Backoff::new()
+                } else {
+                    Backoff::park()
+                };
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let mut guard = total.clone().lock_blocking_with_backoff(backoff);
+                        *guard += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*total.try_lock().unwrap(), 4000);
+    }
+
+    #[test]
+    fn parks_until_released() {
+        let lock = Qutex::new(Vec::new());
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let lock_c = lock.clone();
+        let thread = thread::spawn(move || {
+            let backoff = Backoff::new().spins(2).yields(1);
+            lock_c.lock_blocking_with_backoff(backoff).push(1);
+        });
+
+        thread::sleep(std::time::Duration::from_millis(20));
+        drop(guard);
+        thread.join().unwrap();
+        assert_eq!(*lock.try_lock().unwrap(), [1]);
+    }
+
+    #[test]
+    fn lock_backoff() {
+        let backoff = Backoff::new().spins(3).yields(0).max_spin_shift(40);
+        assert_eq!(backoff.spin_shift_limit(), 31);
+        let lock = QutexBuilder::new().backoff(backoff).build(Vec::new());
+        assert_eq!(lock.backoff(), backoff);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let lock_c = lock.clone();
+        let thread = thread::spawn(move || {
+            lock_c.lock_blocking().push(1);
+        });
+
+        thread::sleep(std::time::Duration::from_millis(20));
+        drop(guard);
+        thread.join().unwrap();
+        assert_eq!(*lock.try_lock().unwrap(), [1]);
+    }
+
+    #[test]
+    fn requeues_when_full() {
+        let lock = QutexBuilder::new().queue_bound(0).build(0);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let lock_c = lock.clone();
+        let thread = thread::spawn(move || {
+            let backoff = Backoff::new().spins(1).yields(1);
+            *lock_c.lock_blocking_with_backoff(backoff) += 1;
+        });
+
+        thread::sleep(std::time::Duration::from_millis(20));
+        drop(guard);
+        thread.join().unwrap();
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    }
+}
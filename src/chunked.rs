@@ -0,0 +1,405 @@
+//! A slice lock granting exclusive access to disjoint index ranges.
+//!
+//
+
+use futures::channel::oneshot::{self, Canceled, Receiver, Sender};
+use futures::executor;
+use std::future::Future;
+use std::ops::{Deref, DerefMut, Range};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::{fmt, ptr, slice};
+
+/// Returns `true` if two ranges share at least one index.
+#[inline]
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+#[derive(Debug, Default)]
+struct RangeState {
+    // Ranges currently granted:
+    active: Vec<Range<usize>>,
+    // Requests waiting for an overlapping range, in FIFO order:
+    pending: Vec<(Range<usize>, Sender<()>)>,
+}
+
+impl RangeState {
+    /// Returns `true` if `range` may be granted now.
+    ///
+    /// A range must wait not only for overlapping active ranges but also for
+    /// overlapping requests queued before it, otherwise a steady stream of
+    /// small ranges could starve a large one.
+    fn is_available(&self, range: &Range<usize>) -> bool {
+        !self.active.iter().any(|r| overlaps(r, range))
+            && !self.pending.iter().any(|(r, _)| overlaps(r, range))
+    }
+
+    /// Grants every pending request which no longer overlaps an active range
+    /// or an earlier pending request.
+    fn grant(&mut self) {
+        let mut still_pending: Vec<(Range<usize>, Sender<()>)> = Vec::new();
+
+        for (range, tx) in self.pending.drain(..) {
+            let blocked = self.active.iter().any(|r| overlaps(r, &range))
+                || still_pending.iter().any(|(r, _)| overlaps(r, &range));
+
+            if blocked {
+                still_pending.push((range, tx));
+            } else if tx.send(()).is_ok() {
+                self.active.push(range);
+            }
+            // Otherwise the requester has dropped its receiver.
+        }
+
+        self.pending = still_pending;
+    }
+
+    /// Releases a granted range.
+    fn release(&mut self, range: &Range<usize>) {
+        let idx = self
+            .active
+            .iter()
+            .position(|r| r == range)
+            .expect("ChunkedQutex: Released range was not active.");
+        self.active.swap_remove(idx);
+        self.grant();
+    }
+}
+
+struct Inner<T> {
+    // The elements, owned via `Box::into_raw`. Guards may alias disjoint
+    // parts of it, so a reference to the whole is never created:
+    ptr: *mut T,
+    len: usize,
+    state: Mutex<RangeState>,
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                self.ptr, self.len,
+            )))
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// Allows exclusive access to a range of the elements within a
+/// `ChunkedQutex`.
+pub struct RangeGuard<T> {
+    chunked: ChunkedQutex<T>,
+    range: Range<usize>,
+}
+
+impl<T> RangeGuard<T> {
+    /// Returns the range this guard has access to.
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+}
+
+impl<T> Deref for RangeGuard<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe {
+            slice::from_raw_parts(
+                self.chunked.inner.ptr.add(self.range.start),
+                self.range.len(),
+            )
+        }
+    }
+}
+
+impl<T> DerefMut for RangeGuard<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.chunked.inner.ptr.add(self.range.start),
+                self.range.len(),
+            )
+        }
+    }
+}
+
+impl<T> Drop for RangeGuard<T> {
+    fn drop(&mut self) {
+        self.chunked.release(&self.range);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RangeGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RangeGuard")
+            .field("range", &self.range)
+            .field("elements", &&**self)
+            .finish()
+    }
+}
+
+/// A future which resolves to a `RangeGuard`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureRangeGuard<T> {
+    chunked: Option<ChunkedQutex<T>>,
+    range: Range<usize>,
+    // `None` if granted immediately:
+    rx: Option<Receiver<()>>,
+}
+
+impl<T> FutureRangeGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<RangeGuard<T>, Canceled> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureRangeGuard<T> {
+    type Output = Result<RangeGuard<T>, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.chunked.is_none() {
+            panic!("FutureRangeGuard::poll: Task already completed.");
+        }
+
+        if let Some(ref mut rx) = this.rx {
+            futures::ready!(Pin::new(rx).poll(cx))?;
+            this.rx = None;
+        }
+
+        Poll::Ready(Ok(RangeGuard {
+            chunked: this.chunked.take().unwrap(),
+            range: this.range.clone(),
+        }))
+    }
+}
+
+impl<T> Drop for FutureRangeGuard<T> {
+    /// Gracefully releases the range if it has been granted but this future
+    /// has not been polled to completion.
+    fn drop(&mut self) {
+        if let Some(chunked) = self.chunked.take() {
+            match self.rx {
+                Some(ref mut rx) => {
+                    rx.close();
+
+                    if let Ok(Some(())) = rx.try_recv() {
+                        chunked.release(&self.range);
+                    }
+                }
+                None => chunked.release(&self.range),
+            }
+        }
+    }
+}
+
+/// A lock over a fixed-length sequence of elements which grants concurrent
+/// exclusive access to non-overlapping index ranges.
+///
+/// A request only waits for outstanding or earlier-queued ranges which it
+/// overlaps. Overlapping requests are granted in FIFO order. Empty ranges
+/// overlap nothing and are always granted immediately.
+#[derive(Debug)]
+pub struct ChunkedQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> ChunkedQutex<T> {
+    /// Creates and returns a new `ChunkedQutex` containing `elements`.
+    pub fn new<E>(elements: E) -> ChunkedQutex<T>
+    where
+        E: Into<Box<[T]>>,
+    {
+        let elements = elements.into();
+        let len = elements.len();
+
+        ChunkedQutex {
+            inner: Arc::new(Inner {
+                ptr: Box::into_raw(elements) as *mut T,
+                len,
+                state: Mutex::new(RangeState::default()),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureRangeGuard` which will resolve into a
+    /// `RangeGuard` for the elements within `range`.
+    ///
+    /// Panics if `range` is decreasing or out of bounds.
+    pub fn lock_range(self, range: Range<usize>) -> FutureRangeGuard<T> {
+        assert!(
+            range.start <= range.end && range.end <= self.inner.len,
+            "ChunkedQutex::lock_range: Range {:?} is invalid for length {}.",
+            range,
+            self.inner.len
+        );
+
+        let rx = {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if range.is_empty() {
+                None
+            } else if state.is_available(&range) {
+                state.active.push(range.clone());
+                None
+            } else {
+                let (tx, rx) = oneshot::channel();
+                state.pending.push((range.clone(), tx));
+                Some(rx)
+            }
+        };
+
+        FutureRangeGuard {
+            chunked: Some(self),
+            range,
+            rx,
+        }
+    }
+
+    /// Returns a new `FutureRangeGuard` for every element.
+    #[inline]
+    pub fn lock_all(self) -> FutureRangeGuard<T> {
+        let len = self.inner.len;
+        self.lock_range(0..len)
+    }
+
+    /// Returns the number of elements.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len
+    }
+
+    /// Returns `true` if there are no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.len == 0
+    }
+
+    /// Returns a mutable reference to the elements if there are currently no
+    /// other copies of this `ChunkedQutex`.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut [T]> {
+        Arc::get_mut(&mut self.inner)
+            .map(|inner| unsafe { slice::from_raw_parts_mut(inner.ptr, inner.len) })
+    }
+
+    /// Releases a granted range.
+    fn release(&self, range: &Range<usize>) {
+        if !range.is_empty() {
+            self.inner.state.lock().unwrap().release(range);
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for ChunkedQutex<T> {
+    #[inline]
+    fn from(elements: Vec<T>) -> ChunkedQutex<T> {
+        ChunkedQutex::new(elements)
+    }
+}
+
+impl<T> From<Box<[T]>> for ChunkedQutex<T> {
+    #[inline]
+    fn from(elements: Box<[T]>) -> ChunkedQutex<T> {
+        ChunkedQutex::new(elements)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for ChunkedQutex<T> {
+    #[inline]
+    fn clone(&self) -> ChunkedQutex<T> {
+        ChunkedQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Inner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inner")
+            .field("len", &self.len)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::thread;
+
+    #[test]
+    fn disjoint_writers() {
+        let buffer = ChunkedQutex::new(vec![0u32; 64]);
+
+        let threads = (0..4)
+            .map(|tile| {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    let mut guard = buffer
+                        .lock_range(tile * 16..(tile + 1) * 16)
+                        .wait()
+                        .unwrap();
+                    for px in guard.iter_mut() {
+                        *px = tile as u32;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = buffer.lock_all().wait().unwrap();
+        for (idx, px) in guard.iter().enumerate() {
+            assert_eq!(*px, (idx / 16) as u32);
+        }
+    }
+
+    #[test]
+    fn only_overlaps_wait() {
+        let buffer = ChunkedQutex::new(vec![0u8; 10]);
+        let low = buffer.clone().lock_range(0..5).wait().unwrap();
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut overlapping = buffer.clone().lock_range(4..6);
+        assert!(overlapping.poll_unpin(cx).is_pending());
+
+        // Disjoint from `low`, but must queue behind `overlapping`:
+        let mut behind = buffer.clone().lock_range(5..6);
+        assert!(behind.poll_unpin(cx).is_pending());
+
+        // Disjoint from both:
+        let high = buffer.clone().lock_range(6..10).wait().unwrap();
+        assert_eq!(high.range(), 6..10);
+
+        drop(low);
+        let overlapping = overlapping.wait().unwrap();
+        drop(overlapping);
+        behind.wait().unwrap();
+    }
+
+    #[test]
+    fn dropped_requests_release() {
+        let buffer = ChunkedQutex::from(vec![1, 2, 3]);
+        let guard = buffer.clone().lock_all().wait().unwrap();
+        let pending = buffer.clone().lock_range(0..1);
+        drop(guard);
+        // Granted but never polled:
+        drop(pending);
+
+        assert_eq!(*buffer.lock_range(0..2).wait().unwrap(), [1, 2]);
+    }
+}
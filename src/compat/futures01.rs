@@ -0,0 +1,93 @@
+//! `futures` 0.1 compatibility.
+//!
+//! With the `futures01` feature enabled, each lock future also implements
+//! `futures01::Future`, so that code still written against futures 0.1 can
+//! use them (and their combinators) directly.
+//
+
+use crate::qrw_lock::FutureUpgrade;
+use crate::{
+    FutureBackGuard, FutureChanged, FutureEvent, FutureGroupGuard, FutureGuard, FutureIndexGuard,
+    FutureLatch, FutureLockTimeout, FutureManagedGuard, FutureNotified, FutureProjectedGuard,
+    FuturePublish, FutureRangeGuard, FutureReadGuard, FutureReentrantGuard, FutureSeqWriteGuard,
+    FutureSubmit, FutureTimedGuard, FutureWait, FutureWaitTimeout, FutureWatchGuard,
+    FutureWriteGuard,
+};
+use futures::compat::Compat;
+use futures::TryFuture;
+
+/// Implements `futures01::Future` by way of the `std::future::Future` impl.
+macro_rules! impl_future01 {
+    ($($name:ident $(<$($param:ident),+>)?),+ $(,)?) => {$(
+        impl$(<$($param),+>)? futures01::Future for $name$(<$($param),+>)? {
+            type Item = <Self as TryFuture>::Ok;
+            type Error = <Self as TryFuture>::Error;
+
+            #[inline]
+            fn poll(&mut self) -> futures01::Poll<Self::Item, Self::Error> {
+                futures01::Future::poll(&mut Compat::new(self))
+            }
+        }
+    )+};
+}
+
+impl_future01!(
+    FutureBackGuard<T>,
+    FutureChanged<T>,
+    FutureEvent,
+    FutureGroupGuard<T>,
+    FutureGuard<T>,
+    FutureIndexGuard<T>,
+    FutureLatch,
+    FutureLockTimeout<T>,
+    FutureManagedGuard<T>,
+    FutureNotified,
+    FutureProjectedGuard<T, U>,
+    FuturePublish<T>,
+    FutureRangeGuard<T>,
+    FutureReadGuard<T>,
+    FutureReentrantGuard<T>,
+    FutureSeqWriteGuard<T>,
+    FutureSubmit<T, R>,
+    FutureTimedGuard<T>,
+    FutureUpgrade<T>,
+    FutureWait<T>,
+    FutureWaitTimeout<T>,
+    FutureWatchGuard<T>,
+    FutureWriteGuard<T>,
+);
+
+#[cfg(test)]
+mod tests {
+    use crate::{QrwLock, Qutex};
+    use futures01::{future, Future};
+
+    #[test]
+    fn combinators() {
+        let qutex = Qutex::new(0);
+
+        let futures = (0..4)
+            .map(|_| qutex.clone().lock().map(|mut guard| *guard += 1))
+            .collect::<Vec<_>>();
+        future::join_all(futures).wait().unwrap();
+
+        assert_eq!(*Future::wait(qutex.lock()).unwrap(), 4);
+    }
+
+    #[test]
+    fn not_ready_until_unlocked() {
+        let lock = QrwLock::new(0);
+        let write_guard = lock.clone().write().wait().unwrap();
+
+        let mut read = lock.clone().read();
+        future::lazy(|| {
+            assert!(Future::poll(&mut read).unwrap().is_not_ready());
+            future::ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        drop(write_guard);
+        assert_eq!(*Future::wait(read).unwrap(), 0);
+    }
+}
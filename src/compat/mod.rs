@@ -0,0 +1,401 @@
+//! Compatibility with other async lock APIs.
+//!
+//! `Mutex` and `RwLock` mirror the method names and semantics of
+//! `tokio::sync::Mutex` and `tokio::sync::RwLock`, borrowing rather than
+//! consuming the lock, so that either implementation can be swapped in by
+//! changing a single import:
+//!
+//! ```
+//! use qutex::compat::Mutex;
+//! // use tokio::sync::Mutex;
+//!
+//! let mutex = Mutex::new(0);
+//! futures::executor::block_on(async {
+//!     *mutex.lock().await += 1;
+//! });
+//! assert_eq!(*mutex.blocking_lock(), 1);
+//! ```
+//!
+//! With the `futures01` feature enabled, each lock future also implements
+//! `futures01::Future`.
+//
+
+#[cfg(feature = "futures01")]
+mod futures01;
+
+use crate::{FutureReadGuard, FutureWriteGuard, Guard, QrwLock, Qutex, ReadGuard, WriteGuard};
+use futures::channel::oneshot::Canceled;
+use futures::task::noop_waker_ref;
+use futures::FutureExt;
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Requests to an unbounded lock are never rejected.
+const UNBOUNDED: &str = "unbounded lock request canceled";
+
+/// The error returned by the `try_` methods when the lock is held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TryLockError(());
+
+impl fmt::Display for TryLockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("operation would block")
+    }
+}
+
+impl StdError for TryLockError {}
+
+/// Implements `Deref` and `Debug` for a guard wrapping `guard`, and
+/// `DerefMut` if `mut`.
+macro_rules! impl_guard {
+    ($name:ident $(<$lt:lifetime>)?) => {
+        impl<$($lt,)? T> Deref for $name<$($lt,)? T> {
+            type Target = T;
+
+            #[inline]
+            fn deref(&self) -> &T {
+                &self.guard
+            }
+        }
+
+        impl<$($lt,)? T: fmt::Debug> fmt::Debug for $name<$($lt,)? T> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(&**self, f)
+            }
+        }
+    };
+    ($name:ident $(<$lt:lifetime>)?, mut) => {
+        impl_guard!($name $(<$lt>)?);
+
+        impl<$($lt,)? T> DerefMut for $name<$($lt,)? T> {
+            #[inline]
+            fn deref_mut(&mut self) -> &mut T {
+                &mut self.guard
+            }
+        }
+    };
+}
+
+/// A guard borrowed from a `Mutex`, as returned by `Mutex::lock`.
+pub struct MutexGuard<'a, T> {
+    guard: Guard<T>,
+    _mutex: PhantomData<&'a Mutex<T>>,
+}
+
+impl_guard!(MutexGuard<'a>, mut);
+
+/// An owned guard of an `Arc<Mutex>`, as returned by `Mutex::lock_owned`.
+pub struct OwnedMutexGuard<T> {
+    guard: Guard<T>,
+    mutex: Arc<Mutex<T>>,
+}
+
+impl<T> OwnedMutexGuard<T> {
+    /// Returns the `Mutex` this guard was acquired from.
+    #[inline]
+    pub fn mutex(this: &OwnedMutexGuard<T>) -> &Arc<Mutex<T>> {
+        &this.mutex
+    }
+}
+
+impl_guard!(OwnedMutexGuard, mut);
+
+/// A `Qutex` with the API of `tokio::sync::Mutex`.
+pub struct Mutex<T> {
+    qutex: Qutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates and returns a new, unlocked `Mutex`.
+    #[inline]
+    pub fn new(val: T) -> Mutex<T> {
+        Mutex {
+            qutex: Qutex::new(val),
+        }
+    }
+
+    /// Locks this mutex, waiting in FIFO order.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            guard: self.qutex.clone().lock().await.expect(UNBOUNDED),
+            _mutex: PhantomData,
+        }
+    }
+
+    /// Locks this mutex, blocking the current thread.
+    pub fn blocking_lock(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            guard: self.qutex.clone().lock().wait().expect(UNBOUNDED),
+            _mutex: PhantomData,
+        }
+    }
+
+    /// Locks this mutex without waiting, failing if it is held or if any
+    /// requests are queued.
+    pub fn try_lock(&self) -> Result<MutexGuard<'_, T>, TryLockError> {
+        match self.qutex.try_lock() {
+            Some(guard) => Ok(MutexGuard {
+                guard,
+                _mutex: PhantomData,
+            }),
+            None => Err(TryLockError(())),
+        }
+    }
+
+    /// Locks an `Arc`'d mutex, returning a guard which keeps it alive.
+    pub async fn lock_owned(self: Arc<Self>) -> OwnedMutexGuard<T> {
+        OwnedMutexGuard {
+            guard: self.qutex.clone().lock().await.expect(UNBOUNDED),
+            mutex: self,
+        }
+    }
+
+    /// Locks an `Arc`'d mutex without waiting.
+    pub fn try_lock_owned(self: Arc<Self>) -> Result<OwnedMutexGuard<T>, TryLockError> {
+        match self.qutex.try_lock() {
+            Some(guard) => Ok(OwnedMutexGuard { guard, mutex: self }),
+            None => Err(TryLockError(())),
+        }
+    }
+
+    /// Returns a mutable reference to the data. No guards can be alive.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.qutex
+            .get_mut()
+            .expect("Mutex::get_mut: Guard still held.")
+    }
+}
+
+impl<T> From<T> for Mutex<T> {
+    #[inline]
+    fn from(val: T) -> Mutex<T> {
+        Mutex::new(val)
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[inline]
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mutex").finish_non_exhaustive()
+    }
+}
+
+/// A shared guard borrowed from a `RwLock`.
+pub struct RwLockReadGuard<'a, T> {
+    guard: ReadGuard<T>,
+    _lock: PhantomData<&'a RwLock<T>>,
+}
+
+impl_guard!(RwLockReadGuard<'a>);
+
+/// An exclusive guard borrowed from a `RwLock`.
+pub struct RwLockWriteGuard<'a, T> {
+    guard: WriteGuard<T>,
+    _lock: PhantomData<&'a RwLock<T>>,
+}
+
+impl_guard!(RwLockWriteGuard<'a>, mut);
+
+/// An owned shared guard of an `Arc<RwLock>`.
+pub struct OwnedRwLockReadGuard<T> {
+    guard: ReadGuard<T>,
+    _lock: Arc<RwLock<T>>,
+}
+
+impl_guard!(OwnedRwLockReadGuard);
+
+/// An owned exclusive guard of an `Arc<RwLock>`.
+pub struct OwnedRwLockWriteGuard<T> {
+    guard: WriteGuard<T>,
+    _lock: Arc<RwLock<T>>,
+}
+
+impl_guard!(OwnedRwLockWriteGuard, mut);
+
+/// Polls a freshly queued request once, withdrawing it if not granted.
+fn try_now<F, G>(mut future: F) -> Result<G, TryLockError>
+where
+    F: Future<Output = Result<G, Canceled>> + Unpin,
+{
+    let cx = &mut Context::from_waker(noop_waker_ref());
+    match future.poll_unpin(cx) {
+        Poll::Ready(Ok(guard)) => Ok(guard),
+        _ => Err(TryLockError(())),
+    }
+}
+
+/// A `QrwLock` with the API of `tokio::sync::RwLock`.
+pub struct RwLock<T> {
+    lock: QrwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates and returns a new, unlocked `RwLock`.
+    #[inline]
+    pub fn new(val: T) -> RwLock<T> {
+        RwLock {
+            lock: QrwLock::new(val),
+        }
+    }
+
+    #[inline]
+    fn read_request(&self) -> FutureReadGuard<T> {
+        self.lock.clone().read()
+    }
+
+    #[inline]
+    fn write_request(&self) -> FutureWriteGuard<T> {
+        self.lock.clone().write()
+    }
+
+    /// Locks this lock with shared access.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        RwLockReadGuard {
+            guard: self.read_request().await.expect(UNBOUNDED),
+            _lock: PhantomData,
+        }
+    }
+
+    /// Locks this lock with exclusive access.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        RwLockWriteGuard {
+            guard: self.write_request().await.expect(UNBOUNDED),
+            _lock: PhantomData,
+        }
+    }
+
+    /// Locks this lock with shared access, blocking the current thread.
+    pub fn blocking_read(&self) -> RwLockReadGuard<'_, T> {
+        RwLockReadGuard {
+            guard: self.read_request().wait().expect(UNBOUNDED),
+            _lock: PhantomData,
+        }
+    }
+
+    /// Locks this lock with exclusive access, blocking the current thread.
+    pub fn blocking_write(&self) -> RwLockWriteGuard<'_, T> {
+        RwLockWriteGuard {
+            guard: self.write_request().wait().expect(UNBOUNDED),
+            _lock: PhantomData,
+        }
+    }
+
+    /// Locks this lock with shared access without waiting.
+    pub fn try_read(&self) -> Result<RwLockReadGuard<'_, T>, TryLockError> {
+        try_now(self.read_request()).map(|guard| RwLockReadGuard {
+            guard,
+            _lock: PhantomData,
+        })
+    }
+
+    /// Locks this lock with exclusive access without waiting.
+    pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, T>, TryLockError> {
+        try_now(self.write_request()).map(|guard| RwLockWriteGuard {
+            guard,
+            _lock: PhantomData,
+        })
+    }
+
+    /// Locks an `Arc`'d lock with shared access, returning a guard which
+    /// keeps it alive.
+    pub async fn read_owned(self: Arc<Self>) -> OwnedRwLockReadGuard<T> {
+        OwnedRwLockReadGuard {
+            guard: self.read_request().await.expect(UNBOUNDED),
+            _lock: self,
+        }
+    }
+
+    /// Locks an `Arc`'d lock with exclusive access, returning a guard which
+    /// keeps it alive.
+    pub async fn write_owned(self: Arc<Self>) -> OwnedRwLockWriteGuard<T> {
+        OwnedRwLockWriteGuard {
+            guard: self.write_request().await.expect(UNBOUNDED),
+            _lock: self,
+        }
+    }
+
+    /// Returns a mutable reference to the data. No guards can be alive.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.lock
+            .get_mut()
+            .expect("RwLock::get_mut: Guard still held.")
+    }
+}
+
+impl<T> From<T> for RwLock<T> {
+    #[inline]
+    fn from(val: T) -> RwLock<T> {
+        RwLock::new(val)
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    #[inline]
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RwLock").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor;
+    use std::thread;
+
+    #[test]
+    fn mutex() {
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+
+        let guard = mutex.try_lock().unwrap();
+        assert_eq!(mutex.try_lock().unwrap_err(), TryLockError(()));
+        drop(guard);
+
+        let threads = (0..4)
+            .map(|i| {
+                let mutex = mutex.clone();
+                thread::spawn(move || executor::block_on(mutex.lock_owned()).push(i))
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut mutex = Arc::try_unwrap(mutex).unwrap();
+        mutex.get_mut().sort();
+        assert_eq!(*mutex.blocking_lock(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn rw_lock() {
+        let lock = RwLock::new(1);
+
+        executor::block_on(async {
+            let read_0 = lock.read().await;
+            let read_1 = lock.try_read().unwrap();
+            assert!(lock.try_write().is_err());
+            assert_eq!(*read_0 + *read_1, 2);
+        });
+
+        *lock.blocking_write() += 1;
+        assert_eq!(*lock.blocking_read(), 2);
+    }
+}
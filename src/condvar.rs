@@ -0,0 +1,454 @@
+//! An asynchronous condition variable for use with `Qutex`.
+//!
+//
+
+use crate::timer::{self, Sleep};
+use crate::wait_list::WaitList;
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::channel::oneshot::{Canceled, Receiver};
+use futures::executor;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// The state of a `FutureWait`.
+#[derive(Debug)]
+enum Stage<T> {
+    // Waiting for a notification. The lock has already been released.
+    Waiting(Qutex<T>, Receiver<()>),
+    // Notified, waiting to re-acquire the lock.
+    Locking(FutureGuard<T>),
+    Complete,
+}
+
+/// A future which resolves to a re-acquired `Guard` after the `Condvar` it
+/// was waiting on has been notified.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureWait<T> {
+    stage: Stage<T>,
+    condvar: Arc<WaitList>,
+    delay: Option<Sleep>,
+    timed_out: bool,
+}
+
+impl<T> FutureWait<T> {
+    /// Returns a new `FutureWait`.
+    fn new(
+        qutex: Qutex<T>,
+        rx: Receiver<()>,
+        condvar: Arc<WaitList>,
+        delay: Option<Sleep>,
+    ) -> FutureWait<T> {
+        FutureWait {
+            stage: Stage::Waiting(qutex, rx),
+            condvar,
+            delay,
+            timed_out: false,
+        }
+    }
+
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<Guard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureWait<T> {
+    type Output = Result<Guard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match ::std::mem::replace(&mut this.stage, Stage::Complete) {
+                Stage::Waiting(qutex, mut rx) => match Pin::new(&mut rx).poll(cx) {
+                    // A canceled notification means the `Condvar` has been
+                    // dropped and can never notify us. Re-acquire either way.
+                    Poll::Ready(Ok(())) | Poll::Ready(Err(Canceled)) => {
+                        this.stage = Stage::Locking(qutex.lock());
+                    }
+                    Poll::Pending => {
+                        let elapsed = match this.delay {
+                            Some(ref mut delay) => Pin::new(delay).poll(cx).is_ready(),
+                            None => false,
+                        };
+
+                        if elapsed {
+                            // A notification may have arrived in the meantime:
+                            rx.close();
+                            this.timed_out = !matches!(rx.try_recv(), Ok(Some(())));
+                            this.stage = Stage::Locking(qutex.lock());
+                        } else {
+                            this.stage = Stage::Waiting(qutex, rx);
+                            return Poll::Pending;
+                        }
+                    }
+                },
+                Stage::Locking(mut future_guard) => {
+                    let poll = Pin::new(&mut future_guard).poll(cx);
+                    if poll.is_pending() {
+                        this.stage = Stage::Locking(future_guard);
+                    }
+                    return poll;
+                }
+                Stage::Complete => panic!("FutureWait::poll: Task already completed."),
+            }
+        }
+    }
+}
+
+/// Whether or not a timed wait on a `Condvar` has timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait ended because the timeout elapsed rather
+    /// than because of a notification.
+    #[inline]
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A future which resolves to a re-acquired `Guard` after the `Condvar` it
+/// was waiting on has been notified or the timeout has elapsed.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureWaitTimeout<T> {
+    wait: FutureWait<T>,
+}
+
+impl<T> FutureWaitTimeout<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<(Guard<T>, WaitTimeoutResult), Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureWaitTimeout<T> {
+    type Output = Result<(Guard<T>, WaitTimeoutResult), Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let guard = futures::ready!(Pin::new(&mut this.wait).poll(cx))?;
+        Poll::Ready(Ok((guard, WaitTimeoutResult(this.wait.timed_out))))
+    }
+}
+
+/// A future which resolves to a `Guard` once its condition is no longer
+/// met, waiting on a `Condvar` as many times as necessary.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureWaitWhile<T, F> {
+    guard: Option<Guard<T>>,
+    wait: Option<FutureWait<T>>,
+    condvar: Condvar,
+    condition: F,
+}
+
+impl<T, F> FutureWaitWhile<T, F>
+where
+    F: FnMut(&mut T) -> bool,
+{
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<Guard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T, F> Future for FutureWaitWhile<T, F>
+where
+    F: FnMut(&mut T) -> bool,
+{
+    type Output = Result<Guard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if let Some(mut guard) = this.guard.take() {
+                if (this.condition)(&mut guard) {
+                    this.wait = Some(this.condvar.wait(guard));
+                } else {
+                    return Poll::Ready(Ok(guard));
+                }
+            }
+
+            match this.wait {
+                Some(ref mut wait) => {
+                    this.guard = Some(futures::ready!(Pin::new(wait).poll(cx))?);
+                }
+                None => panic!("FutureWaitWhile::poll: Task already completed."),
+            }
+            this.wait = None;
+        }
+    }
+}
+
+// Never pinned structurally.
+impl<T, F> Unpin for FutureWaitWhile<T, F> {}
+
+impl<T, F> ::std::fmt::Debug for FutureWaitWhile<T, F>
+where
+    T: ::std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("FutureWaitWhile")
+            .field("guard", &self.guard)
+            .field("wait", &self.wait)
+            .field("condvar", &self.condvar)
+            .finish()
+    }
+}
+
+impl<T> Drop for FutureWait<T> {
+    /// Passes on a notification which was received but never acted upon so
+    /// that it is not lost.
+    fn drop(&mut self) {
+        if let Stage::Waiting(_, ref mut rx) = self.stage {
+            rx.close();
+
+            if let Ok(Some(())) = rx.try_recv() {
+                self.condvar.notify_one();
+            }
+        }
+    }
+}
+
+/// A condition variable which, rather than blocking the current thread,
+/// releases a `Guard` and resolves once notified and re-locked.
+///
+/// As with any condition variable, waiters may wake spuriously and should
+/// re-check their condition after each wait.
+#[derive(Debug, Clone)]
+pub struct Condvar {
+    inner: Arc<WaitList>,
+}
+
+impl Condvar {
+    /// Creates and returns a new `Condvar`.
+    #[inline]
+    pub fn new() -> Condvar {
+        Condvar {
+            inner: Arc::new(WaitList::new()),
+        }
+    }
+
+    /// Releases `guard` and returns a new `FutureWait` which will resolve
+    /// into a re-acquired `Guard` after this condition variable has been
+    /// notified.
+    ///
+    /// The wait is registered before the lock is released so that a
+    /// notification issued by the next holder can not be missed.
+    pub fn wait<T>(&self, guard: Guard<T>) -> FutureWait<T> {
+        self.enqueue_wait(guard, None)
+    }
+
+    /// Releases `guard` and returns a new `FutureWaitTimeout` which will
+    /// resolve into a re-acquired `Guard` after this condition variable has
+    /// been notified or once `timeout` has elapsed, whichever comes first.
+    ///
+    /// The returned `WaitTimeoutResult` indicates which of the two occurred.
+    /// The timeout only covers the wait for a notification, not the time
+    /// spent re-acquiring the lock.
+    pub fn wait_timeout<T>(&self, guard: Guard<T>, timeout: Duration) -> FutureWaitTimeout<T> {
+        FutureWaitTimeout {
+            wait: self.enqueue_wait(guard, Some(timer::sleep(timeout))),
+        }
+    }
+
+    /// Returns a new `FutureWaitWhile` which will resolve into a `Guard` once
+    /// `condition` returns `false`.
+    ///
+    /// `condition` is checked immediately and again after every wakeup, with
+    /// the lock held, guarding against spurious wakeups.
+    pub fn wait_while<T, F>(&self, guard: Guard<T>, condition: F) -> FutureWaitWhile<T, F>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        FutureWaitWhile {
+            guard: Some(guard),
+            wait: None,
+            condvar: self.clone(),
+            condition,
+        }
+    }
+
+    /// Registers a waiter then releases `guard`.
+    fn enqueue_wait<T>(&self, guard: Guard<T>, delay: Option<Sleep>) -> FutureWait<T> {
+        let rx = self.inner.push();
+        FutureWait::new(Guard::unlock(guard), rx, self.inner.clone(), delay)
+    }
+
+    /// Wakes up one waiting task, if any, returning the number woken: zero
+    /// if nobody was listening.
+    ///
+    /// Waits given up on (by dropping their futures) do not count.
+    #[inline]
+    pub fn notify_one(&self) -> usize {
+        self.inner.notify_one() as usize
+    }
+
+    /// Wakes up all waiting tasks, returning the number woken.
+    #[inline]
+    pub fn notify_all(&self) -> usize {
+        self.inner.notify_all()
+    }
+
+    /// Wakes up to `n` waiting tasks, longest waiting first, returning the
+    /// number woken.
+    #[inline]
+    pub fn notify_at_most(&self, n: usize) -> usize {
+        self.inner.notify_at_most(n)
+    }
+}
+
+impl Default for Condvar {
+    #[inline]
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn notify_one() {
+        let qutex = Qutex::new(false);
+        let condvar = Condvar::new();
+
+        let (qutex_c, condvar_c) = (qutex.clone(), condvar.clone());
+        let thread = thread::spawn(move || {
+            let mut guard = qutex_c.lock().wait().unwrap();
+            *guard = true;
+            condvar_c.notify_one();
+        });
+
+        let mut guard = qutex.clone().lock().wait().unwrap();
+        while !*guard {
+            guard = condvar.wait(guard).wait().unwrap();
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn notify_all() {
+        let thread_count = 8;
+        let qutex = Qutex::new(0usize);
+        let condvar = Condvar::new();
+        let mut threads = Vec::with_capacity(thread_count);
+
+        for _ in 0..thread_count {
+            let (qutex, condvar) = (qutex.clone(), condvar.clone());
+            threads.push(thread::spawn(move || {
+                let mut guard = qutex.lock().wait().unwrap();
+                *guard += 1;
+                while *guard <= thread_count {
+                    guard = condvar.wait(guard).wait().unwrap();
+                }
+            }));
+        }
+
+        // Wait for every thread to have checked in, then release them all.
+        loop {
+            let mut guard = qutex.clone().lock().wait().unwrap();
+            if *guard == thread_count {
+                *guard += 1;
+                condvar.notify_all();
+                break;
+            }
+            drop(guard);
+            thread::yield_now();
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn notify_counts() {
+        let qutex = Qutex::new(());
+        let condvar = Condvar::new();
+        assert_eq!(condvar.notify_one(), 0);
+
+        let waits = (0..4)
+            .map(|_| condvar.wait(qutex.clone().lock().wait().unwrap()))
+            .collect::<Vec<_>>();
+        let mut waits = waits.into_iter();
+        // Given up on, so not counted:
+        drop(waits.next());
+        assert_eq!(condvar.notify_at_most(2), 2);
+        assert_eq!(condvar.notify_all(), 1);
+        assert_eq!(condvar.notify_at_most(2), 0);
+        for wait in waits {
+            drop(wait.wait().unwrap());
+        }
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let qutex = Qutex::new(());
+        let condvar = Condvar::new();
+
+        let guard = qutex.clone().lock().wait().unwrap();
+        let (guard, result) = condvar
+            .wait_timeout(guard, Duration::from_millis(20))
+            .wait()
+            .unwrap();
+        assert!(result.timed_out());
+
+        let future_wait = condvar.wait_timeout(guard, Duration::from_secs(60));
+        condvar.notify_one();
+        let (_guard, result) = future_wait.wait().unwrap();
+        assert!(!result.timed_out());
+    }
+
+    #[test]
+    fn wait_while() {
+        let qutex = Qutex::new(0usize);
+        let condvar = Condvar::new();
+
+        let (qutex_c, condvar_c) = (qutex.clone(), condvar.clone());
+        let thread = thread::spawn(move || {
+            for _ in 0..5 {
+                let mut guard = qutex_c.clone().lock().wait().unwrap();
+                *guard += 1;
+                condvar_c.notify_all();
+            }
+        });
+
+        let guard = qutex.clone().lock().wait().unwrap();
+        let guard = condvar
+            .wait_while(guard, |count| *count < 5)
+            .wait()
+            .unwrap();
+        assert_eq!(*guard, 5);
+        drop(guard);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn dropped_waiter_passes_notification_on() {
+        let qutex = Qutex::new(());
+        let condvar = Condvar::new();
+
+        let guard = qutex.clone().lock().wait().unwrap();
+        let future_wait_0 = condvar.wait(guard);
+        let guard = qutex.clone().lock().wait().unwrap();
+        let future_wait_1 = condvar.wait(guard);
+
+        condvar.notify_one();
+        drop(future_wait_0);
+        future_wait_1.wait().unwrap();
+    }
+}
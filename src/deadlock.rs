@@ -0,0 +1,325 @@
+//! A best-effort deadlock detector, enabled by the `deadlock_detection`
+//! feature.
+//!
+//! Every `Qutex` registers the task holding it and the tasks waiting for it
+//! in a global wait-for graph, tasks being told apart by their wakers. The
+//! graph is searched for cycles by `check_deadlocks`.
+//
+// * Locks taken without a task, through `try_lock` and friends, have no
+//   known holder and so can not be part of a detected cycle.
+// * A task's wakers are assumed to share its data pointer and vtable, which
+//   holds for every common executor but is not guaranteed.
+
+use crate::Tag;
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::Waker;
+use std::thread;
+use std::time::Duration;
+
+/// Identifies a task by its waker.
+type TaskId = (usize, usize);
+
+#[inline]
+fn task_id(waker: &Waker) -> TaskId {
+    (waker.data() as usize, waker.vtable() as *const _ as usize)
+}
+
+/// The task holding a lock.
+#[derive(Debug)]
+struct Holder {
+    task: TaskId,
+    name: Option<String>,
+    backtrace: Arc<Backtrace>,
+}
+
+/// The global wait-for graph.
+#[derive(Debug)]
+struct Registry {
+    // By lock:
+    holders: BTreeMap<usize, Holder>,
+    // By request, the waiting task, the lock it waits for and the request's
+    // tag:
+    waiters: BTreeMap<usize, (TaskId, usize, Option<Tag>)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    holders: BTreeMap::new(),
+    waiters: BTreeMap::new(),
+});
+
+#[inline]
+fn with_registry<R>(f: impl FnOnce(&mut Registry) -> R) -> R {
+    f(&mut REGISTRY.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// Records that the task `waker` wakes holds `lock`.
+pub(crate) fn acquired(lock: usize, name: Option<&str>, waker: &Waker) {
+    let holder = Holder {
+        task: task_id(waker),
+        name: name.map(String::from),
+        backtrace: Arc::new(Backtrace::capture()),
+    };
+    with_registry(|registry| registry.holders.insert(lock, holder));
+}
+
+/// Records that `lock` is no longer held by a known task.
+pub(crate) fn released(lock: usize) {
+    with_registry(|registry| registry.holders.remove(&lock));
+}
+
+/// Records that the task `waker` wakes is waiting for `lock` through
+/// `request`, tagged `tag` if at all.
+pub(crate) fn waiting(request: usize, lock: usize, waker: &Waker, tag: Option<Tag>) {
+    with_registry(|registry| {
+        registry
+            .waiters
+            .insert(request, (task_id(waker), lock, tag))
+    });
+}
+
+/// Records that `request` is no longer waiting.
+pub(crate) fn done_waiting(request: usize) {
+    with_registry(|registry| registry.waiters.remove(&request));
+}
+
+/// A lock within a deadlock cycle.
+#[derive(Debug, Clone)]
+pub struct DeadlockedLock {
+    name: Option<String>,
+    backtrace: Arc<Backtrace>,
+    waiter_tag: Option<Tag>,
+}
+
+impl DeadlockedLock {
+    /// Returns the lock's name, if one was set.
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the backtrace captured as the lock was acquired by its
+    /// holder.
+    ///
+    /// As with `Backtrace::capture`, backtraces are only captured if
+    /// enabled through the `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`
+    /// environment variables.
+    #[inline]
+    pub fn holder_backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+
+    /// Returns the tag of the request within the cycle waiting for the
+    /// lock, if it was made through `Qutex::lock_tagged`.
+    #[inline]
+    pub fn waiter_tag(&self) -> Option<Tag> {
+        self.waiter_tag
+    }
+}
+
+/// A cycle of tasks, each holding a lock the next is waiting for.
+#[derive(Debug, Clone)]
+pub struct Deadlock {
+    locks: Vec<DeadlockedLock>,
+}
+
+impl Deadlock {
+    /// Returns the locks involved, each waited for by the holder of the
+    /// one before it, and the first by the holder of the last.
+    #[inline]
+    pub fn locks(&self) -> &[DeadlockedLock] {
+        &self.locks
+    }
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "deadlock between {} lock holders:", self.locks.len())?;
+        for (i, lock) in self.locks.iter().enumerate() {
+            write!(f, "lock #{} ({})", i, lock.name().unwrap_or("<unnamed>"))?;
+            if let Some(tag) = lock.waiter_tag {
+                write!(f, " waited for by {}", tag)?;
+            }
+            writeln!(f, " held since:\n{}", lock.backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns every deadlock among tasks currently waiting for a `Qutex`.
+///
+/// Cycles are found in a snapshot of the wait-for graph, so a deadlock
+/// persisting across calls is returned by each.
+pub fn check_deadlocks() -> Vec<Deadlock> {
+    with_registry(|registry| {
+        // Each waiting task's edges, by way of the locks it waits for, to
+        // the tasks holding them:
+        let mut edges = BTreeMap::<TaskId, Vec<(usize, TaskId, Option<Tag>)>>::new();
+        for &(task, lock, tag) in registry.waiters.values() {
+            if let Some(holder) = registry.holders.get(&lock) {
+                edges
+                    .entry(task)
+                    .or_default()
+                    .push((lock, holder.task, tag));
+            }
+        }
+
+        let mut deadlocks = Vec::new();
+        let mut visited = BTreeMap::<TaskId, bool>::new();
+        for &start in edges.keys() {
+            if visited.contains_key(&start) {
+                continue;
+            }
+
+            // Depth-first, along the first unexplored edge of the task on
+            // top of the path, marking tasks on the path with `true`. Each
+            // task is kept along with the edge it was reached by:
+            let mut path = vec![(start, 0usize, (0usize, None))];
+            visited.insert(start, true);
+            while let Some(&mut (task, ref mut next, _)) = path.last_mut() {
+                let Some(&(lock, holder, tag)) = edges.get(&task).and_then(|e| e.get(*next)) else {
+                    visited.insert(task, false);
+                    path.pop();
+                    continue;
+                };
+                *next += 1;
+
+                match visited.get(&holder) {
+                    None => {
+                        visited.insert(holder, true);
+                        path.push((holder, 0, (lock, tag)));
+                    }
+                    // Back on the path, so a cycle:
+                    Some(true) => {
+                        let from = path.iter().position(|&(t, _, _)| t == holder).unwrap();
+                        let locks = path[from + 1..]
+                            .iter()
+                            .map(|&(_, _, edge)| edge)
+                            .chain(Some((lock, tag)))
+                            .map(|(lock, waiter_tag)| {
+                                let holder = &registry.holders[&lock];
+                                DeadlockedLock {
+                                    name: holder.name.clone(),
+                                    backtrace: holder.backtrace.clone(),
+                                    waiter_tag,
+                                }
+                            })
+                            .collect();
+                        deadlocks.push(Deadlock { locks });
+                    }
+                    Some(false) => (),
+                }
+            }
+        }
+        deadlocks
+    })
+}
+
+/// Spawns a thread which checks for deadlocks every `interval`, passing
+/// any found to `report`.
+pub fn spawn_deadlock_detector<F>(interval: Duration, mut report: F) -> thread::JoinHandle<()>
+where
+    F: FnMut(Vec<Deadlock>) + Send + 'static,
+{
+    thread::Builder::new()
+        .name("qutex-deadlock-detector".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let deadlocks = check_deadlocks();
+            if !deadlocks.is_empty() {
+                report(deadlocks);
+            }
+        })
+        .expect("spawn_deadlock_detector: Failed to spawn thread.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FutureGuard, Guard, QutexBuilder, Tag};
+    use futures::FutureExt;
+    use std::task::{Context, Poll, Wake};
+
+    struct Task;
+
+    impl Wake for Task {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Polls `future_guard` within the task `waker` wakes.
+    fn poll_in<T>(waker: &Waker, future_guard: &mut FutureGuard<T>) -> Option<Guard<T>> {
+        match future_guard.poll_unpin(&mut Context::from_waker(waker)) {
+            Poll::Ready(res) => Some(res.unwrap()),
+            Poll::Pending => None,
+        }
+    }
+
+    /// Returns the deadlocks involving a lock named `name`, as other tests
+    /// share the registry.
+    fn involving(name: &str) -> Vec<Deadlock> {
+        check_deadlocks()
+            .into_iter()
+            .filter(|deadlock| {
+                deadlock
+                    .locks()
+                    .iter()
+                    .any(|lock| lock.name() == Some(name))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cycle() {
+        let (a, b) = (Waker::from(Arc::new(Task)), Waker::from(Arc::new(Task)));
+        let first = QutexBuilder::new().name("cycle-first").build(0);
+        let second = QutexBuilder::new().name("cycle-second").build(0);
+
+        let _guard_a = poll_in(&a, &mut first.clone().lock()).unwrap();
+        let _guard_b = poll_in(&b, &mut second.clone().lock()).unwrap();
+        let mut waiting_a = second.clone().lock();
+        assert!(poll_in(&a, &mut waiting_a).is_none());
+        assert!(involving("cycle-first").is_empty());
+
+        // Closing the cycle:
+        let mut waiting_b = first.clone().lock_tagged("task-b");
+        assert!(poll_in(&b, &mut waiting_b).is_none());
+        let deadlocks = involving("cycle-first");
+        assert_eq!(deadlocks.len(), 1);
+        let names = deadlocks[0]
+            .locks()
+            .iter()
+            .map(|lock| lock.name().unwrap())
+            .collect::<Vec<_>>();
+        assert!(
+            names == ["cycle-first", "cycle-second"] || names == ["cycle-second", "cycle-first"]
+        );
+        let report = deadlocks[0].to_string();
+        assert!(report.starts_with("deadlock between 2 lock holders:"));
+        assert!(report.contains("(cycle-first) waited for by task-b held since:"));
+        for lock in deadlocks[0].locks() {
+            let tag = (lock.name() == Some("cycle-first")).then_some(Tag::Name("task-b"));
+            assert_eq!(lock.waiter_tag(), tag);
+        }
+
+        // Broken by giving up:
+        drop(waiting_b);
+        assert!(involving("cycle-first").is_empty());
+    }
+
+    #[test]
+    fn recursive() {
+        let task = Waker::from(Arc::new(Task));
+        let lock = QutexBuilder::new().name("recursive").build(0);
+
+        let guard = poll_in(&task, &mut lock.clone().lock()).unwrap();
+        let mut waiting = lock.clone().lock();
+        assert!(poll_in(&task, &mut waiting).is_none());
+        assert_eq!(involving("recursive").len(), 1);
+
+        drop(guard);
+        assert!(poll_in(&task, &mut waiting).is_some());
+        assert!(involving("recursive").is_empty());
+    }
+}
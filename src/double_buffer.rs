@@ -0,0 +1,389 @@
+//! A double-buffered lock with wait-free reads of the published buffer.
+//!
+//
+
+use crate::wait_list::WaitList;
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::channel::oneshot::Receiver;
+use futures::executor;
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+#[derive(Debug)]
+struct Inner<T> {
+    buffers: [UnsafeCell<T>; 2],
+    // The index of the front buffer:
+    front: AtomicUsize,
+    // The number of readers of each buffer:
+    readers: [AtomicUsize; 2],
+    // Publishers waiting for the readers of the previous front to drain:
+    drained: WaitList,
+    // Serializes writers:
+    writer: Qutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Send for Inner<T> {}
+unsafe impl<T: Send + Sync> Sync for Inner<T> {}
+
+/// Waits for every reader of buffer `idx` to finish, registering with
+/// `inner.drained` through `rx`.
+fn poll_drained<T>(
+    inner: &Inner<T>,
+    idx: usize,
+    rx: &mut Option<Receiver<()>>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    loop {
+        if inner.readers[idx].load(SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+
+        match *rx {
+            Some(ref mut waiting) => match Pin::new(waiting).poll(cx) {
+                // Woken (or, should the list be dropped, re-checked):
+                Poll::Ready(_) => *rx = None,
+                Poll::Pending => return Poll::Pending,
+            },
+            // Register, then re-check in case the last reader finished
+            // before we were queued:
+            None => {
+                *rx = Some(inner.drained.push());
+                if inner.readers[idx].load(SeqCst) == 0 {
+                    return Poll::Ready(());
+                }
+            }
+        }
+    }
+}
+
+/// Allows shared access to the front buffer of a `DoubleBuffer`.
+///
+/// Holding a `FrontGuard` across a publish is allowed, but delays the
+/// publisher until it is dropped.
+#[derive(Debug)]
+pub struct FrontGuard<T> {
+    inner: Arc<Inner<T>>,
+    idx: usize,
+}
+
+impl<T> Deref for FrontGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.buffers[self.idx].get() }
+    }
+}
+
+impl<T> Drop for FrontGuard<T> {
+    fn drop(&mut self) {
+        let prev = self.inner.readers[self.idx].fetch_sub(1, SeqCst);
+
+        // The last reader of a buffer which has since become the back:
+        if prev == 1 && self.inner.front.load(SeqCst) != self.idx {
+            self.inner.drained.notify_all();
+        }
+    }
+}
+
+/// Allows exclusive access to the back buffer of a `DoubleBuffer`.
+///
+/// Other writers wait, in FIFO order, until this guard is dropped.
+#[derive(Debug)]
+pub struct BackGuard<T> {
+    inner: Arc<Inner<T>>,
+    _writer: Guard<()>,
+}
+
+impl<T> BackGuard<T> {
+    /// Returns the currently published front buffer.
+    #[inline]
+    pub fn front(guard: &BackGuard<T>) -> &T {
+        let front = guard.inner.front.load(SeqCst);
+        unsafe { &*guard.inner.buffers[front].get() }
+    }
+
+    /// Swaps the buffers, making the contents of this guard visible to
+    /// subsequent readers.
+    ///
+    /// Returns a future which resolves, once every reader of the previous
+    /// front buffer has finished, to a guard over that buffer. The writer
+    /// lock is held throughout.
+    pub fn publish(guard: BackGuard<T>) -> FuturePublish<T> {
+        let prev = guard.inner.front.fetch_xor(1, SeqCst);
+
+        FuturePublish {
+            guard: Some(guard),
+            idx: prev,
+            rx: None,
+        }
+    }
+
+    /// Returns the index of the back buffer.
+    #[inline]
+    fn back(&self) -> usize {
+        self.inner.front.load(SeqCst) ^ 1
+    }
+}
+
+impl<T> Deref for BackGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.buffers[self.back()].get() }
+    }
+}
+
+impl<T> DerefMut for BackGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.inner.buffers[self.back()].get() }
+    }
+}
+
+/// A future which resolves to a `BackGuard` once the writer lock is
+/// granted and every reader of the back buffer has finished.
+///
+/// Readers of the back buffer remain only if a `FuturePublish` was dropped
+/// before resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureBackGuard<T> {
+    inner: Arc<Inner<T>>,
+    writer: FutureGuard<()>,
+    granted: Option<Guard<()>>,
+    rx: Option<Receiver<()>>,
+}
+
+impl<T> FutureBackGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<BackGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureBackGuard<T> {
+    type Output = Result<BackGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.granted.is_none() {
+            this.granted = Some(futures::ready!(Pin::new(&mut this.writer).poll(cx))?);
+        }
+
+        // The back buffer only changes under the writer lock:
+        let back = this.inner.front.load(SeqCst) ^ 1;
+        futures::ready!(poll_drained(&this.inner, back, &mut this.rx, cx));
+        Poll::Ready(Ok(BackGuard {
+            inner: this.inner.clone(),
+            _writer: this.granted.take().unwrap(),
+        }))
+    }
+}
+
+/// A future which resolves to a `BackGuard` once every reader of the
+/// previous front buffer has finished.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FuturePublish<T> {
+    guard: Option<BackGuard<T>>,
+    // The index of the previous front buffer:
+    idx: usize,
+    rx: Option<Receiver<()>>,
+}
+
+impl<T> FuturePublish<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<BackGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FuturePublish<T> {
+    type Output = Result<BackGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let inner = match this.guard {
+            Some(ref guard) => guard.inner.clone(),
+            None => panic!("FuturePublish::poll: Task already completed."),
+        };
+
+        futures::ready!(poll_drained(&inner, this.idx, &mut this.rx, cx));
+        Poll::Ready(Ok(this.guard.take().unwrap()))
+    }
+}
+
+/// A pair of buffers: a front buffer which readers access without locking
+/// and a back buffer which writers modify exclusively, in FIFO order.
+///
+/// `BackGuard::publish` swaps the two. Readers which started before the swap
+/// continue to see the previous front buffer, and the writer is only granted
+/// that buffer once they have all finished.
+///
+/// Note that after a publish the back buffer holds the contents published
+/// before it, not the latest. Writers needing the latest contents should
+/// copy them from `BackGuard::front`.
+#[derive(Debug)]
+pub struct DoubleBuffer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> DoubleBuffer<T> {
+    /// Creates and returns a new `DoubleBuffer` with the given front and
+    /// back buffers.
+    pub fn new(front: T, back: T) -> DoubleBuffer<T> {
+        DoubleBuffer {
+            inner: Arc::new(Inner {
+                buffers: [UnsafeCell::new(front), UnsafeCell::new(back)],
+                front: AtomicUsize::new(0),
+                readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+                drained: WaitList::new(),
+                writer: Qutex::new(()),
+            }),
+        }
+    }
+
+    /// Returns a guard over the front buffer.
+    ///
+    /// Never waits for writers. Retries only if a publish happens to swap
+    /// the buffers concurrently.
+    pub fn read(&self) -> FrontGuard<T> {
+        loop {
+            let idx = self.inner.front.load(SeqCst);
+            self.inner.readers[idx].fetch_add(1, SeqCst);
+
+            if self.inner.front.load(SeqCst) == idx {
+                return FrontGuard {
+                    inner: self.inner.clone(),
+                    idx,
+                };
+            }
+
+            // Swapped in the meantime:
+            drop(FrontGuard {
+                inner: self.inner.clone(),
+                idx,
+            });
+        }
+    }
+
+    /// Returns a new `FutureBackGuard` which will resolve into a guard over
+    /// the back buffer.
+    #[inline]
+    pub fn write(&self) -> FutureBackGuard<T> {
+        FutureBackGuard {
+            inner: self.inner.clone(),
+            writer: self.inner.writer.clone().lock(),
+            granted: None,
+            rx: None,
+        }
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for DoubleBuffer<T> {
+    #[inline]
+    fn clone(&self) -> DoubleBuffer<T> {
+        DoubleBuffer {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::thread;
+
+    #[test]
+    fn publish() {
+        let buffer = DoubleBuffer::new(String::from("v1"), String::new());
+
+        let mut back = buffer.write().wait().unwrap();
+        back.push_str("v2");
+        assert_eq!(*buffer.read(), "v1");
+
+        let back = BackGuard::publish(back).wait().unwrap();
+        assert_eq!(*buffer.read(), "v2");
+        assert_eq!(BackGuard::front(&back), "v2");
+        assert_eq!(*back, "v1");
+    }
+
+    #[test]
+    fn publish_waits_for_readers() {
+        let buffer = DoubleBuffer::new(0u32, 1u32);
+        let reader = buffer.read();
+        let back = buffer.write().wait().unwrap();
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut publish = BackGuard::publish(back);
+        assert!(publish.poll_unpin(cx).is_pending());
+
+        // New readers see the new front immediately:
+        assert_eq!(*buffer.read(), 1);
+
+        let thread = thread::spawn(move || drop(reader));
+        let back = publish.wait().unwrap();
+        assert_eq!(*back, 0);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn publish_dropped_early() {
+        let buffer = DoubleBuffer::new(0u32, 1u32);
+        let reader = buffer.read();
+        let back = buffer.write().wait().unwrap();
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut publish = BackGuard::publish(back);
+        assert!(publish.poll_unpin(cx).is_pending());
+        drop(publish);
+
+        // The next writer waits for the previous front's reader too:
+        let mut write = buffer.write();
+        assert!(write.poll_unpin(cx).is_pending());
+        assert_eq!(*reader, 0);
+        drop(reader);
+        let mut back = write.wait().unwrap();
+        *back = 2;
+        assert_eq!(*buffer.read(), 1);
+    }
+
+    #[test]
+    fn concurrent_readers_and_writers() {
+        let buffer = DoubleBuffer::new(0usize, 0usize);
+
+        let writers = (0..4)
+            .map(|_| {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let mut back = buffer.write().wait().unwrap();
+                        *back = *BackGuard::front(&back) + 1;
+                        drop(BackGuard::publish(back).wait().unwrap());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut last = 0;
+        while last < 200 {
+            let value = *buffer.read();
+            assert!(value >= last);
+            last = value;
+        }
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+}
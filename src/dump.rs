@@ -0,0 +1,234 @@
+//! Snapshots of a lock's state and wait queue, enabled by the `debug_dump`
+//! feature.
+//!
+//! `Qutex::debug_dump` lists the requests waiting for a lock, for finding
+//! out who is waiting on what once something wedges.
+//
+// A `SegQueue` can not be iterated without popping it, so each lock keeps
+// a list of its waiting requests alongside the queues, pruned lazily of
+// those since granted, dropped or abandoned.
+
+use crate::qutex::Holder;
+use crate::{Priority, Tag};
+use std::fmt;
+use std::panic::Location;
+use std::time::{Duration, Instant};
+
+/// A request waiting for a lock, as listed by `LockDump::pending`.
+#[derive(Debug, Clone)]
+pub struct PendingRequest {
+    priority: Priority,
+    since: Instant,
+    waited: Duration,
+    deadline: Option<Instant>,
+    location: Option<&'static Location<'static>>,
+    tag: Option<Tag>,
+}
+
+impl PendingRequest {
+    #[inline]
+    pub(crate) fn new(
+        priority: Priority,
+        since: Instant,
+        deadline: Option<Instant>,
+        location: Option<&'static Location<'static>>,
+        tag: Option<Tag>,
+    ) -> PendingRequest {
+        PendingRequest {
+            priority,
+            since,
+            waited: since.elapsed(),
+            deadline,
+            location,
+            tag,
+        }
+    }
+
+    /// Returns the request's priority.
+    #[inline]
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Returns when the request was queued.
+    #[inline]
+    pub fn queued_at(&self) -> Instant {
+        self.since
+    }
+
+    /// Returns how long the request had been waiting when dumped.
+    #[inline]
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+
+    /// Returns the request's deadline, if it has one.
+    #[inline]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns where the request was made.
+    #[inline]
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.location
+    }
+
+    /// Returns the request's tag, if it was made through `lock_tagged`.
+    #[inline]
+    pub fn tag(&self) -> Option<Tag> {
+        self.tag
+    }
+}
+
+/// The state of a `Qutex` at the time `Qutex::debug_dump` was called.
+#[derive(Debug, Clone)]
+pub struct LockDump {
+    name: Option<String>,
+    is_locked: bool,
+    is_poisoned: bool,
+    holder: Option<Holder>,
+    pending: Vec<PendingRequest>,
+    taken: Instant,
+}
+
+impl LockDump {
+    #[inline]
+    pub(crate) fn new(
+        name: Option<String>,
+        is_locked: bool,
+        is_poisoned: bool,
+        holder: Option<Holder>,
+        pending: Vec<PendingRequest>,
+    ) -> LockDump {
+        LockDump {
+            name,
+            is_locked,
+            is_poisoned,
+            holder,
+            pending,
+            taken: Instant::now(),
+        }
+    }
+
+    /// Returns the lock's name, if one was set.
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns `true` if the lock was held.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+
+    /// Returns `true` if the lock was poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.is_poisoned
+    }
+
+    /// Returns who held the lock, if tracked (see
+    /// `QutexBuilder::track_holder`).
+    #[inline]
+    pub fn holder(&self) -> Option<&Holder> {
+        self.holder.as_ref()
+    }
+
+    /// Returns the waiting requests, in the order they are to be granted.
+    #[inline]
+    pub fn pending(&self) -> &[PendingRequest] {
+        &self.pending
+    }
+}
+
+impl fmt::Display for LockDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lock ({}) ", self.name().unwrap_or("<unnamed>"))?;
+        match (self.is_locked, &self.holder) {
+            (true, Some(holder)) => write!(f, "held by {}", holder)?,
+            (true, None) => write!(f, "held")?,
+            (false, _) => write!(f, "free")?,
+        }
+        if self.is_poisoned {
+            write!(f, ", poisoned")?;
+        }
+        writeln!(f, ", with {} pending requests:", self.pending.len())?;
+        for (i, req) in self.pending.iter().enumerate() {
+            write!(f, "request #{}", i)?;
+            if let Some(tag) = req.tag {
+                write!(f, " {}", tag)?;
+            }
+            write!(f, " ({:?} priority)", req.priority)?;
+            if let Some(location) = req.location {
+                write!(f, " at {}", location)?;
+            }
+            write!(f, ", waiting for {:?}", req.waited)?;
+            if let Some(deadline) = req.deadline {
+                write!(
+                    f,
+                    ", due in {:?}",
+                    deadline.saturating_duration_since(self.taken)
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Priority, QutexBuilder, Tag};
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::task::Context;
+
+    #[test]
+    fn pending() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = QutexBuilder::new().name("dump").track_holder(true).build(0);
+        let dump = lock.debug_dump();
+        assert!(!dump.is_locked() && dump.pending().is_empty());
+
+        let guard = lock.clone().lock().wait().unwrap();
+        let line = line!() + 1;
+        let mut normal = lock.clone().lock();
+        let mut low = lock.clone().lock_with_priority(Priority::Low);
+        let mut high = lock.clone().lock_with_priority(Priority::High);
+        let mut tagged = lock.clone().lock_tagged("flush-task");
+        for req in [&mut normal, &mut low, &mut high, &mut tagged] {
+            assert!(req.poll_unpin(cx).is_pending());
+        }
+        drop(low);
+
+        let dump = lock.debug_dump();
+        assert!(dump.is_locked());
+        assert!(dump.holder().is_some());
+        let priorities = dump
+            .pending()
+            .iter()
+            .map(|req| req.priority())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            priorities,
+            [Priority::High, Priority::Normal, Priority::Normal]
+        );
+        assert_eq!(dump.pending()[1].location().unwrap().line(), line);
+        assert_eq!(dump.pending()[2].tag(), Some(Tag::Name("flush-task")));
+        let text = dump.to_string();
+        assert!(text.starts_with("lock (dump) held by thread"));
+        assert!(text.contains("with 3 pending requests:\nrequest #0 (High priority) at "));
+        assert!(text.contains("request #2 flush-task (Normal priority) at "));
+        drop(tagged);
+
+        // Granted requests are no longer listed:
+        drop(guard);
+        let guard = high.wait().unwrap();
+        assert_eq!(lock.debug_dump().pending().len(), 1);
+        drop(guard);
+        drop(normal.wait().unwrap());
+        assert!(lock.debug_dump().pending().is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+//! Hardware lock elision, running short read-only critical sections as
+//! memory transactions rather than taking the lock.
+//!
+//
+// Uses Intel's RTM. A transaction reads the lock word, so that anyone
+// taking the lock meanwhile aborts it, rolling back whatever it wrote, as
+// does anything else the processor cannot track: a conflicting write to the
+// data, too large a footprint, an interrupt or a system call. Readers
+// running as transactions therefore never write to the lock and do not
+// contend with one another at all.
+//
+// A transaction only copies the data out, as `MaybeUninit` bytes, never
+// forming a reference to it, and the copy is only taken to be a `T` once
+// the transaction has committed, and so was never torn.
+
+use crate::Qutex;
+use std::arch::asm;
+use std::hint;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// The number of transactions attempted before taking the lock instead.
+const ATTEMPTS: u32 = 3;
+
+/// Returned by `begin` once a transaction has started.
+const STARTED: u32 = u32::MAX;
+
+/// Set in an abort status if the transaction may succeed on retry.
+const RETRY: u32 = 1 << 1;
+
+/// Returns `true` if the processor supports RTM.
+#[inline]
+fn supported() -> bool {
+    is_x86_feature_detected!("rtm")
+}
+
+/// Starts a transaction, returning `STARTED`, or the abort status once it is
+/// aborted, resuming from here.
+///
+/// # Safety
+///
+/// RTM must be supported.
+#[inline]
+unsafe fn begin() -> u32 {
+    let status: u32;
+    asm!("mov eax, -1", "xbegin 2f", "2:", out("eax") status, options(nostack));
+    status
+}
+
+/// Commits the current transaction.
+///
+/// # Safety
+///
+/// A transaction must have been started.
+#[inline]
+unsafe fn end() {
+    asm!("xend", options(nostack));
+}
+
+/// Aborts the current transaction.
+///
+/// # Safety
+///
+/// A transaction must have been started.
+#[inline]
+unsafe fn abort() -> ! {
+    asm!("xabort 0xff", options(nostack));
+    // Resumed within `begin`:
+    hint::unreachable_unchecked()
+}
+
+impl<T: Copy> Qutex<T> {
+    /// Returns a copy of the data, read as a hardware transaction without
+    /// taking the lock where the processor supports it, and under the lock
+    /// otherwise.
+    ///
+    /// Suits small, read-mostly data, whose readers then run in parallel
+    /// with one another. Any transaction aborted, whether because the lock
+    /// was or became held, or because the processor could not run it
+    /// transactionally, is rolled back and retried, eventually blocking the
+    /// thread for the lock as `lock_blocking` does.
+    ///
+    /// Uses Intel's RTM on `x86_64` processors which support it, detected
+    /// at run time.
+    ///
+    /// # Safety
+    ///
+    /// The transactional copy may overlap a write by the holder of the
+    /// lock, which is a data race as far as Rust's memory model is
+    /// concerned. RTM aborts the transaction before a torn copy can be
+    /// used, but the language does not know that. By calling this, the
+    /// caller accepts relying on the processor for soundness where the
+    /// language gives no guarantee. `T` must also not be accessed through
+    /// raw pointers, such as `as_mut_ptr`, without holding the lock.
+    pub unsafe fn read_elided(&self) -> T {
+        if supported() {
+            for _ in 0..ATTEMPTS {
+                let status = begin();
+                if status == STARTED {
+                    if self.lock_word().load(Relaxed) {
+                        abort()
+                    }
+                    let copy = ptr::read_volatile(self.as_ptr() as *const MaybeUninit<T>);
+                    end();
+                    // Committed, so never overlapped by a write:
+                    return copy.assume_init();
+                }
+                if status & RETRY == 0 {
+                    break;
+                }
+            }
+        }
+        *self.clone().lock_blocking()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn consistent_reads() {
+        let pair = Qutex::new((0u64, 0u64));
+
+        let writer = thread::spawn({
+            let pair = pair.clone();
+            move || {
+                for _ in 0..2000 {
+                    let mut guard = pair.clone().lock_blocking();
+                    guard.0 += 1;
+                    guard.1 += 1;
+                }
+            }
+        });
+        let readers = (0..4)
+            .map(|_| {
+                let pair = pair.clone();
+                thread::spawn(move || {
+                    for _ in 0..2000 {
+                        let (a, b) = unsafe { pair.read_elided() };
+                        assert_eq!(a, b);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(unsafe { pair.read_elided() }, (2000, 2000));
+    }
+}
@@ -0,0 +1,206 @@
+//! Guards scoped to a single entry of a map within a `Qutex`.
+//!
+//
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Allows access to the entry for one key of the `HashMap` within a
+/// `Qutex`, and to nothing else in it.
+pub struct EntryGuard<K, V, S = RandomState> {
+    guard: Guard<HashMap<K, V, S>>,
+    key: K,
+}
+
+impl<K, V, S> EntryGuard<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Returns the key of the entry.
+    #[inline]
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns `true` if the entry holds a value.
+    #[inline]
+    pub fn is_occupied(&self) -> bool {
+        self.guard.contains_key(&self.key)
+    }
+
+    /// Returns the value of the entry, if any.
+    #[inline]
+    pub fn get(&self) -> Option<&V> {
+        self.guard.get(&self.key)
+    }
+
+    /// Returns the value of the entry, if any, mutably.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut V> {
+        self.guard.get_mut(&self.key)
+    }
+
+    /// Removes and returns the value of the entry, if any.
+    #[inline]
+    pub fn remove(&mut self) -> Option<V> {
+        self.guard.remove(&self.key)
+    }
+}
+
+impl<K, V, S> EntryGuard<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Sets the value of the entry, returning the previous value, if any.
+    #[inline]
+    pub fn insert(&mut self, val: V) -> Option<V> {
+        self.guard.insert(self.key.clone(), val)
+    }
+
+    /// Returns the value of the entry, inserting `default` first if absent.
+    #[inline]
+    pub fn or_insert(&mut self, default: V) -> &mut V {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value of the entry, inserting the result of `default`
+    /// first if absent.
+    ///
+    /// The key is cloned only to be inserted.
+    pub fn or_insert_with<F: FnOnce() -> V>(&mut self, default: F) -> &mut V {
+        if !self.guard.contains_key(&self.key) {
+            self.guard.insert(self.key.clone(), default());
+        }
+        self.guard.get_mut(&self.key).unwrap()
+    }
+
+    /// Returns the value of the entry, inserting the default value first if
+    /// absent.
+    #[inline]
+    pub fn or_default(&mut self) -> &mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<K, V, S> EntryGuard<K, V, S> {
+    /// Releases the lock, returning the key.
+    #[inline]
+    pub fn into_key(self) -> K {
+        self.key
+    }
+}
+
+impl<K, V, S> fmt::Debug for EntryGuard<K, V, S>
+where
+    K: Eq + Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EntryGuard")
+            .field("key", &self.key)
+            .field("value", &self.get())
+            .finish()
+    }
+}
+
+/// A future which resolves to an `EntryGuard` once the map is locked.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureEntryGuard<K, V, S = RandomState> {
+    future_guard: FutureGuard<HashMap<K, V, S>>,
+    key: Option<K>,
+}
+
+impl<K, V, S> FutureEntryGuard<K, V, S> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<EntryGuard<K, V, S>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<K, V, S> Future for FutureEntryGuard<K, V, S> {
+    type Output = Result<EntryGuard<K, V, S>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let guard = futures::ready!(Pin::new(&mut this.future_guard).poll(cx))?;
+        let key = this
+            .key
+            .take()
+            .expect("FutureEntryGuard::poll: Task already completed.");
+        Poll::Ready(Ok(EntryGuard { guard, key }))
+    }
+}
+
+// Never pinned structurally.
+impl<K, V, S> Unpin for FutureEntryGuard<K, V, S> {}
+
+impl<K: fmt::Debug, V, S> fmt::Debug for FutureEntryGuard<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureEntryGuard")
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, V, S> Qutex<HashMap<K, V, S>> {
+    /// Returns a new `FutureEntryGuard` which will resolve, once the map is
+    /// locked, to a guard over the entry for `key` alone.
+    ///
+    /// ```
+    /// use qutex::Qutex;
+    /// use std::collections::HashMap;
+    ///
+    /// let hits = Qutex::new(HashMap::new());
+    /// for _ in 0..2 {
+    ///     *hits.clone().lock_entry("index").wait().unwrap().or_insert(0) += 1;
+    /// }
+    /// assert_eq!(hits.try_lock().unwrap()["index"], 2);
+    /// ```
+    #[inline]
+    pub fn lock_entry(self, key: K) -> FutureEntryGuard<K, V, S> {
+        FutureEntryGuard {
+            future_guard: self.lock(),
+            key: Some(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoped_to_key() {
+        let map = Qutex::new(HashMap::from([("a", 1)]));
+        let mut entry = map.clone().lock_entry("b").wait().unwrap();
+        assert!(!entry.is_occupied());
+        assert!(map.try_lock().is_none());
+
+        *entry.or_default() += 2;
+        assert_eq!(entry.insert(3), Some(2));
+        assert_eq!(entry.key(), &"b");
+        drop(entry);
+
+        let mut entry = map.clone().lock_entry("a").wait().unwrap();
+        assert_eq!(entry.get(), Some(&1));
+        assert_eq!(entry.remove(), Some(1));
+        assert_eq!(entry.into_key(), "a");
+        assert_eq!(*map.try_lock().unwrap(), HashMap::from([("b", 3)]));
+    }
+}
@@ -0,0 +1,59 @@
+//! Errors.
+//!
+//
+
+use core::fmt;
+use futures::channel::oneshot::Canceled;
+
+/// An error resolved by a lock request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The request was given up on before completing, such as by its
+    /// cancellation token.
+    Canceled,
+    /// Granting the request would have completed a cycle of tasks waiting
+    /// on one another, and this request was chosen to be aborted.
+    Deadlocked,
+    /// The request's deadline passed before it was granted.
+    Expired,
+    /// The lock's wait queue was full.
+    QueueFull,
+    /// The guard's lease ran out, and the lock may since have been granted
+    /// to another request.
+    Revoked,
+    /// The request was made on behalf of the very `Owner` already holding
+    /// the lock, so would never have been granted.
+    WouldDeadlock,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Canceled => f.write_str("lock request canceled"),
+            Error::Deadlocked => f.write_str("lock request aborted to resolve a deadlock"),
+            Error::Expired => f.write_str("lock request expired"),
+            Error::QueueFull => f.write_str("lock queue full"),
+            Error::Revoked => f.write_str("lock lease revoked"),
+            Error::WouldDeadlock => f.write_str("lock request made by its holder"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl From<Canceled> for Error {
+    #[inline]
+    fn from(_: Canceled) -> Error {
+        Error::Canceled
+    }
+}
+
+/// Lets `?` be used on lock requests within code still returning
+/// `Canceled`, losing the variant.
+impl From<Error> for Canceled {
+    #[inline]
+    fn from(_: Error) -> Canceled {
+        Canceled
+    }
+}
@@ -0,0 +1,122 @@
+//! Drives the futures finalizing a lock between its release and the next
+//! grant (see `Guard::finalize_with`).
+//!
+//
+// No executor is at hand as a guard is dropped, so each finalizer is first
+// polled inline, on the thread dropping the guard. Its wakeups are passed on
+// to a finalizer thread rather than polling it inline too, as a wakeup may
+// come from anywhere, such as from within another lock's release, where
+// running the finalizer could re-enter that lock.
+
+use futures::future::BoxFuture;
+use futures::task::{self, ArcWake};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Release};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::task::Context;
+use std::thread;
+
+/// Waiting to be woken.
+const IDLE: u8 = 0;
+/// Being polled.
+const POLLING: u8 = 1;
+/// Woken while being polled, so to be polled again.
+const REPOLL: u8 = 2;
+
+struct Task {
+    // Taken out while polled, so that a panicking future is dropped as it
+    // unwinds, releasing the guard it owns:
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    state: AtomicU8,
+}
+
+impl Task {
+    fn run(self: &Arc<Task>) {
+        // Polled by one thread at a time, any others waking it leaving it to
+        // poll again:
+        let mut state = IDLE;
+        while let Err(actual) = self
+            .state
+            .compare_exchange(state, next(state), AcqRel, Acquire)
+        {
+            state = actual;
+        }
+        if state != IDLE {
+            return;
+        }
+
+        let waker = task::waker_ref(self);
+        let cx = &mut Context::from_waker(&waker);
+        loop {
+            let Some(mut future) = self.slot().take() else {
+                return;
+            };
+            if future.as_mut().poll(cx).is_ready() {
+                return;
+            }
+            *self.slot() = Some(future);
+            if self
+                .state
+                .compare_exchange(POLLING, IDLE, AcqRel, Acquire)
+                .is_ok()
+            {
+                return;
+            }
+            // Woken meanwhile:
+            self.state.store(POLLING, Release);
+        }
+    }
+
+    #[inline]
+    fn slot(&self) -> MutexGuard<'_, Option<BoxFuture<'static, ()>>> {
+        self.future.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The state a wakeup moves the task to from `state`.
+#[inline]
+fn next(state: u8) -> u8 {
+    match state {
+        IDLE => POLLING,
+        _ => REPOLL,
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(task: &Arc<Task>) {
+        // The finalizer thread lives for the duration of the process:
+        let _ = driver().send(task.clone());
+    }
+}
+
+/// Returns the channel to the finalizer thread, spawning it if necessary.
+fn driver() -> &'static mpsc::Sender<Arc<Task>> {
+    static DRIVER: OnceLock<mpsc::Sender<Arc<Task>>> = OnceLock::new();
+
+    DRIVER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Arc<Task>>();
+        thread::Builder::new()
+            .name("qutex-finalizer".to_owned())
+            .spawn(move || {
+                for task in rx {
+                    // A panicking finalizer drops its future, and with it the
+                    // guard, as it unwinds. Nobody else is left to tell:
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| task.run()));
+                }
+            })
+            .expect("qutex::finalize: Unable to spawn finalizer thread");
+        tx
+    })
+}
+
+/// Runs `future` to completion, polling it straight away and then on the
+/// finalizer thread once woken.
+pub(crate) fn spawn(future: BoxFuture<'static, ()>) {
+    let task = Arc::new(Task {
+        future: Mutex::new(Some(future)),
+        state: AtomicU8::new(IDLE),
+    });
+    task.run()
+}
@@ -0,0 +1,546 @@
+//! Deadlock-free acquisition of several locks at once.
+//!
+//
+
+use crate::{Backoff, Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A future which resolves to a guard for every lock within a `QutexGroup`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureGroupGuard<T> {
+    group: QutexGroup<T>,
+    // The position (within `group.order`) of the lock being acquired:
+    next: usize,
+    current: Option<FutureGuard<T>>,
+    guards: Vec<Option<Guard<T>>>,
+}
+
+impl<T> FutureGroupGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<Vec<Guard<T>>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureGroupGuard<T> {
+    type Output = Result<Vec<Guard<T>>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if let Some(ref mut future_guard) = this.current {
+                let guard = futures::ready!(Pin::new(future_guard).poll(cx))?;
+                this.guards[this.group.order[this.next]] = Some(guard);
+                this.current = None;
+                this.next += 1;
+            }
+
+            match this.group.order.get(this.next) {
+                Some(&idx) => this.current = Some(this.group.qutexes[idx].clone().lock()),
+                None => {
+                    if this.next > this.group.order.len() {
+                        panic!("FutureGroupGuard::poll: Task already completed.");
+                    }
+                    this.next += 1;
+                    let guards = ::std::mem::take(&mut this.guards);
+                    return Poll::Ready(Ok(guards.into_iter().map(Option::unwrap).collect()));
+                }
+            }
+        }
+    }
+}
+
+/// A set of `Qutex`es which are always locked together, in a globally
+/// consistent order, so that groups sharing locks can never deadlock
+/// against one another regardless of the order their locks were listed in.
+///
+/// Locks are acquired one at a time in order of address. Guards are
+/// returned in the order the locks were originally listed.
+#[derive(Debug)]
+pub struct QutexGroup<T> {
+    qutexes: Vec<Qutex<T>>,
+    // Indices into `qutexes`, in acquisition order:
+    order: Vec<usize>,
+}
+
+impl<T> QutexGroup<T> {
+    /// Creates and returns a new `QutexGroup`.
+    ///
+    /// Panics if the same lock is listed more than once.
+    pub fn new(qutexes: Vec<Qutex<T>>) -> QutexGroup<T> {
+        let mut order: Vec<usize> = (0..qutexes.len()).collect();
+        order.sort_by_key(|&idx| qutexes[idx].as_ptr() as usize);
+
+        if order
+            .windows(2)
+            .any(|w| qutexes[w[0]].as_ptr() == qutexes[w[1]].as_ptr())
+        {
+            panic!("QutexGroup::new: The same lock may not be listed more than once.");
+        }
+
+        QutexGroup { qutexes, order }
+    }
+
+    /// Returns a new `FutureGroupGuard` which will resolve into a guard for
+    /// each of the listed locks.
+    ///
+    /// Shorthand for `QutexGroup::new(qutexes).lock()`.
+    #[inline]
+    pub fn lock_all(qutexes: Vec<Qutex<T>>) -> FutureGroupGuard<T> {
+        QutexGroup::new(qutexes).lock()
+    }
+
+    /// Returns a new `FutureGroupGuard` which will resolve into a guard for
+    /// each lock in this group.
+    pub fn lock(self) -> FutureGroupGuard<T> {
+        let len = self.qutexes.len();
+        FutureGroupGuard {
+            group: self,
+            next: 0,
+            current: None,
+            guards: (0..len).map(|_| None).collect(),
+        }
+    }
+
+    /// Returns a guard for each lock in this group if all of them can be
+    /// acquired without waiting, in the order the locks were listed.
+    ///
+    /// Each lock is tried in turn. If any is taken, those already acquired
+    /// are released again and `None` is returned, so that nothing is held
+    /// on failure.
+    pub fn try_lock_all(&self) -> Option<Vec<Guard<T>>> {
+        let mut guards: Vec<Option<Guard<T>>> = (0..self.qutexes.len()).map(|_| None).collect();
+        for &idx in &self.order {
+            // On failure, dropping `guards` releases those acquired:
+            guards[idx] = Some(self.qutexes[idx].try_lock()?);
+        }
+        Some(guards.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Retries `try_lock_all`, spinning and then yielding in between
+    /// attempts as `backoff` allows, and returns `None` once they run out.
+    ///
+    /// An alternative to `lock`, which never holds one lock while waiting
+    /// for another, often faster when the locks are only briefly held.
+    pub fn try_lock_all_with_backoff(&self, backoff: Backoff) -> Option<Vec<Guard<T>>> {
+        let retries = backoff.spin_count().saturating_add(backoff.yield_count());
+        let mut attempt = 0;
+        loop {
+            if let Some(guards) = self.try_lock_all() {
+                return Some(guards);
+            }
+            if attempt == retries {
+                return None;
+            }
+            backoff.snooze(attempt);
+            attempt += 1;
+        }
+    }
+
+    /// Returns the locks within this group in their originally listed order.
+    #[inline]
+    pub fn qutexes(&self) -> &[Qutex<T>] {
+        &self.qutexes
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for QutexGroup<T> {
+    #[inline]
+    fn clone(&self) -> QutexGroup<T> {
+        QutexGroup {
+            qutexes: self.qutexes.clone(),
+            order: self.order.clone(),
+        }
+    }
+}
+
+/// Acquisition of a single lock within a `lock_all!` invocation.
+#[doc(hidden)]
+pub trait AcquireSlot {
+    /// Returns the address by which this slot's lock is ordered.
+    fn addr(&self) -> usize;
+
+    /// Polls for this slot's guard, first requesting it if necessary.
+    fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>>;
+}
+
+/// A lock and (eventually) its guard within a `lock_all!` invocation.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct LockSlot<T> {
+    qutex: Qutex<T>,
+    future_guard: Option<FutureGuard<T>>,
+    guard: Option<Guard<T>>,
+}
+
+impl<T> LockSlot<T> {
+    #[inline]
+    pub fn new(qutex: Qutex<T>) -> LockSlot<T> {
+        LockSlot {
+            qutex,
+            future_guard: None,
+            guard: None,
+        }
+    }
+}
+
+impl<T> AcquireSlot for LockSlot<T> {
+    #[inline]
+    fn addr(&self) -> usize {
+        self.qutex.as_ptr() as usize
+    }
+
+    fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.guard.is_none() {
+            let qutex = &self.qutex;
+            let future_guard = self
+                .future_guard
+                .get_or_insert_with(|| qutex.clone().lock());
+            self.guard = Some(futures::ready!(Pin::new(future_guard).poll(cx))?);
+            self.future_guard = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A tuple of `LockSlot`s.
+#[doc(hidden)]
+pub trait SlotTuple {
+    type Guards;
+
+    fn count(&self) -> usize;
+
+    fn slot(&mut self, idx: usize) -> &mut dyn AcquireSlot;
+
+    fn addr(&self, idx: usize) -> usize;
+
+    fn take_guards(&mut self) -> Self::Guards;
+}
+
+/// A tuple of `FutureGuard`s, which `join_guards` accepts.
+pub trait GuardTuple {
+    /// The `lock_all!` slots the guards' locks are acquired through.
+    #[doc(hidden)]
+    type Slots: SlotTuple;
+
+    /// Withdraws each request, returning a slot for its lock.
+    #[doc(hidden)]
+    fn into_slots(self) -> Self::Slots;
+}
+
+macro_rules! impl_slot_tuple {
+    ($len:expr; $($T:ident $idx:tt),+) => {
+        impl<$($T),+> GuardTuple for ($(FutureGuard<$T>,)+) {
+            type Slots = ($(LockSlot<$T>,)+);
+
+            #[inline]
+            fn into_slots(self) -> Self::Slots {
+                ($(LockSlot::new(self.$idx.into_qutex()),)+)
+            }
+        }
+
+        impl<$($T),+> SlotTuple for ($(LockSlot<$T>,)+) {
+            type Guards = ($(Guard<$T>,)+);
+
+            #[inline]
+            fn count(&self) -> usize {
+                $len
+            }
+
+            fn slot(&mut self, idx: usize) -> &mut dyn AcquireSlot {
+                match idx {
+                    $($idx => &mut self.$idx,)+
+                    _ => unreachable!(),
+                }
+            }
+
+            fn addr(&self, idx: usize) -> usize {
+                match idx {
+                    $($idx => self.$idx.addr(),)+
+                    _ => unreachable!(),
+                }
+            }
+
+            fn take_guards(&mut self) -> Self::Guards {
+                ($(self.$idx.guard.take().unwrap(),)+)
+            }
+        }
+    };
+}
+
+impl_slot_tuple!(1; A 0);
+impl_slot_tuple!(2; A 0, B 1);
+impl_slot_tuple!(3; A 0, B 1, C 2);
+impl_slot_tuple!(4; A 0, B 1, C 2, D 3);
+impl_slot_tuple!(5; A 0, B 1, C 2, D 3, E 4);
+impl_slot_tuple!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_slot_tuple!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_slot_tuple!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+/// A future which resolves to a tuple of guards, one for each of the
+/// (possibly differently typed) locks passed to `lock_all!`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureLockAll<S> {
+    slots: S,
+    // Indices into `slots`, in acquisition order:
+    order: Vec<usize>,
+    next: usize,
+}
+
+impl<S> FutureLockAll<S>
+where
+    S: SlotTuple,
+{
+    /// Returns a new `FutureLockAll`. Use `lock_all!` instead.
+    ///
+    /// Panics if the same lock is listed more than once.
+    #[doc(hidden)]
+    pub fn new(slots: S) -> FutureLockAll<S> {
+        let mut order: Vec<usize> = (0..slots.count()).collect();
+        order.sort_by_key(|&idx| slots.addr(idx));
+
+        if order
+            .windows(2)
+            .any(|w| slots.addr(w[0]) == slots.addr(w[1]))
+        {
+            panic!("lock_all!: The same lock may not be listed more than once.");
+        }
+
+        FutureLockAll {
+            slots,
+            order,
+            next: 0,
+        }
+    }
+
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<S::Guards, Error> {
+        executor::block_on(self)
+    }
+}
+
+// Never pinned structurally.
+impl<S> Unpin for FutureLockAll<S> {}
+
+impl<S> Future for FutureLockAll<S>
+where
+    S: SlotTuple,
+{
+    type Output = Result<S::Guards, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.next > this.order.len() {
+            panic!("FutureLockAll::poll: Task already completed.");
+        }
+
+        while let Some(&idx) = this.order.get(this.next) {
+            futures::ready!(this.slots.slot(idx).poll_acquire(cx))?;
+            this.next += 1;
+        }
+
+        this.next += 1;
+        Poll::Ready(Ok(this.slots.take_guards()))
+    }
+}
+
+/// Locks several, possibly differently typed, `Qutex`es in a globally
+/// consistent order and resolves to a tuple of their guards.
+///
+/// Evaluates to a `FutureLockAll`. Each argument must be a `Qutex` (by value,
+/// as with `Qutex::lock`). Guards are returned in the order the locks were
+/// listed. Up to eight locks are supported.
+///
+/// The acquisition order is the same one used by `QutexGroup`, so the two can
+/// be freely mixed without risk of deadlocking against one another.
+///
+/// Panics if the same lock is listed more than once.
+///
+/// ```
+/// # #[macro_use] extern crate qutex;
+/// # fn main() {
+/// use qutex::Qutex;
+///
+/// let name = Qutex::new(String::from("balance"));
+/// let balance = Qutex::new(100u32);
+///
+/// let (mut name, mut balance) = lock_all!(name.clone(), balance.clone()).wait().unwrap();
+/// name.push_str(" (updated)");
+/// *balance += 1;
+/// # }
+/// ```
+#[macro_export]
+macro_rules! lock_all {
+    ($($qutex:expr),+ $(,)?) => {
+        $crate::FutureLockAll::new(($($crate::LockSlot::new($qutex),)+))
+    };
+}
+
+/// Returns a future which resolves to a guard for each of `future_guards`,
+/// a tuple of up to eight `FutureGuard`s, once all are held at once.
+///
+/// Awaiting the futures side by side (with `join!`, say) could deadlock
+/// against a task awaiting the same locks in another order. Instead, each
+/// request is withdrawn (releasing its lock if already taken) and the locks
+/// are acquired one at a time in the same global order `lock_all!` and
+/// `QutexGroup` use. Guards are returned in the order listed. Any priority
+/// the original requests were made at is not kept.
+///
+/// Panics if the same lock is listed more than once, or if any of the
+/// futures has already resolved.
+///
+/// ```
+/// use qutex::{join_guards, Qutex};
+///
+/// let (from, to) = (Qutex::new(10u32), Qutex::new(0u32));
+/// let (mut from, mut to) = join_guards((from.clone().lock(), to.clone().lock()))
+///     .wait()
+///     .unwrap();
+/// *from -= 5;
+/// *to += 5;
+/// ```
+pub fn join_guards<G: GuardTuple>(future_guards: G) -> FutureLockAll<G::Slots> {
+    FutureLockAll::new(future_guards.into_slots())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn transfers_do_not_deadlock() {
+        let accounts = [Qutex::new(1000i64), Qutex::new(1000i64)];
+        let mut threads = Vec::new();
+
+        for i in 0..8 {
+            let (from, to) = (accounts[i % 2].clone(), accounts[(i + 1) % 2].clone());
+            threads.push(thread::spawn(move || {
+                for _ in 0..200 {
+                    let mut guards = QutexGroup::lock_all(vec![from.clone(), to.clone()])
+                        .wait()
+                        .unwrap();
+                    *guards[0] -= 1;
+                    *guards[1] += 1;
+                }
+            }));
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guards = QutexGroup::lock_all(accounts.to_vec()).wait().unwrap();
+        assert_eq!(*guards[0] + *guards[1], 2000);
+    }
+
+    #[test]
+    fn preserves_listed_order() {
+        let qutexes: Vec<_> = (0..5).map(Qutex::new).rev().collect();
+        let guards = QutexGroup::new(qutexes).lock().wait().unwrap();
+        let vals: Vec<_> = guards.iter().map(|guard| **guard).collect();
+        assert_eq!(vals, vec![4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn try_lock_all_rolls_back() {
+        let qutexes: Vec<_> = (0..3).map(Qutex::new).collect();
+        let group = QutexGroup::new(qutexes.clone());
+
+        let held = qutexes[1].try_lock().unwrap();
+        assert!(group.try_lock_all().is_none());
+        assert!(group.try_lock_all_with_backoff(Backoff::new()).is_none());
+        // Nothing else was left held:
+        assert!(qutexes[0].try_lock().is_some() && qutexes[2].try_lock().is_some());
+
+        let thread = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(20));
+            drop(held);
+        });
+        let backoff = Backoff::new().yields(u32::MAX);
+        let guards = group.try_lock_all_with_backoff(backoff).unwrap();
+        let vals: Vec<_> = guards.iter().map(|guard| **guard).collect();
+        assert_eq!(vals, vec![0, 1, 2]);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn empty() {
+        assert!(QutexGroup::<()>::lock_all(Vec::new())
+            .wait()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn duplicate() {
+        let qutex = Qutex::new(());
+        QutexGroup::new(vec![qutex.clone(), qutex]);
+    }
+
+    #[test]
+    fn lock_all_macro() {
+        let (a, b, c) = (Qutex::new(1u8), Qutex::new("b"), Qutex::new(vec![3.0f32]));
+
+        // Listed in opposite orders from two threads:
+        let (a_c, b_c, c_c) = (a.clone(), b.clone(), c.clone());
+        let thread = thread::spawn(move || {
+            for _ in 0..200 {
+                let (mut c, _b, mut a) = lock_all!(c_c.clone(), b_c.clone(), a_c.clone())
+                    .wait()
+                    .unwrap();
+                *a = a.wrapping_add(1);
+                c[0] += 1.0;
+            }
+        });
+
+        for _ in 0..200 {
+            let (mut a, b, mut c) = lock_all!(a.clone(), b.clone(), c.clone()).wait().unwrap();
+            assert_eq!(*b, "b");
+            *a = a.wrapping_add(1);
+            c[0] += 1.0;
+        }
+
+        thread.join().unwrap();
+        let (c,) = lock_all!(c).wait().unwrap();
+        assert_eq!(c[0], 403.0);
+    }
+
+    #[test]
+    fn join_guards_reordered() {
+        let (a, b) = (Qutex::new(0u32), Qutex::new(String::new()));
+
+        // Requested in opposite orders from two threads:
+        let (a_c, b_c) = (a.clone(), b.clone());
+        let thread = thread::spawn(move || {
+            for _ in 0..200 {
+                let (mut b, mut a) = join_guards((b_c.clone().lock(), a_c.clone().lock()))
+                    .wait()
+                    .unwrap();
+                *a += 1;
+                b.push('b');
+            }
+        });
+
+        for _ in 0..200 {
+            let (mut a, mut b) = join_guards((a.clone().lock(), b.clone().lock()))
+                .wait()
+                .unwrap();
+            *a += 1;
+            b.push('a');
+        }
+
+        thread.join().unwrap();
+        let (a, b) = lock_all!(a, b).wait().unwrap();
+        assert_eq!((*a, b.len()), (400, 400));
+    }
+}
@@ -0,0 +1,445 @@
+//! Multiple-granularity locking over a tree of resources.
+//!
+//
+
+use futures::channel::oneshot::{self, Receiver, Sender};
+use futures::executor;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// The mode a resource of a `HierarchicalLock` is held in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockMode {
+    /// Held by those locking some descendant `Shared`.
+    IntentShared,
+    /// Held by those locking some descendant `Exclusive`.
+    IntentExclusive,
+    /// Allows reading the resource and all of its descendants.
+    Shared,
+    /// Allows modifying the resource and all of its descendants.
+    Exclusive,
+}
+
+impl LockMode {
+    const ALL: [LockMode; 4] = [
+        LockMode::IntentShared,
+        LockMode::IntentExclusive,
+        LockMode::Shared,
+        LockMode::Exclusive,
+    ];
+
+    /// Returns `true` if a resource may be held in `self` by one holder
+    /// while held in `other` by another.
+    ///
+    /// | | IS | IX | S | X |
+    /// |---|---|---|---|---|
+    /// | **IS** | yes | yes | yes | |
+    /// | **IX** | yes | yes | | |
+    /// | **S** | yes | | yes | |
+    /// | **X** | | | | |
+    pub fn is_compatible(self, other: LockMode) -> bool {
+        use self::LockMode::*;
+
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentShared, _) | (_, IntentShared) => true,
+            (a, b) => a == b,
+        }
+    }
+
+    /// Returns the mode the ancestors of a resource held in `self` are held
+    /// in.
+    #[inline]
+    pub fn intent(self) -> LockMode {
+        match self {
+            LockMode::IntentShared | LockMode::Shared => LockMode::IntentShared,
+            LockMode::IntentExclusive | LockMode::Exclusive => LockMode::IntentExclusive,
+        }
+    }
+
+    /// Returns the mode level `level` of `path` is held in by a request for
+    /// `path` in `self`, the root being level 0.
+    #[inline]
+    fn at<K>(self, path: &[K], level: usize) -> LockMode {
+        if level == path.len() {
+            self
+        } else {
+            self.intent()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Waiter {
+    mode: LockMode,
+    tx: Sender<()>,
+}
+
+/// A resource which is held or waited for.
+#[derive(Debug, Default)]
+struct Node {
+    // The number of holders in each mode, by `LockMode::ALL` index:
+    granted: [usize; 4],
+    // Requests waiting, in request order:
+    waiters: VecDeque<Waiter>,
+}
+
+impl Node {
+    /// Returns `true` if `mode` is compatible with every mode held.
+    fn admits(&self, mode: LockMode) -> bool {
+        LockMode::ALL
+            .iter()
+            .zip(self.granted.iter())
+            .all(|(&held, &count)| count == 0 || mode.is_compatible(held))
+    }
+
+    /// Grants waiters in order, stopping at the first which conflicts with a
+    /// mode held.
+    fn grant(&mut self) {
+        while let Some(waiter) = self.waiters.front() {
+            if !self.admits(waiter.mode) {
+                break;
+            }
+            let waiter = self.waiters.pop_front().unwrap();
+            if waiter.tx.send(()).is_ok() {
+                self.granted[waiter.mode as usize] += 1;
+            }
+        }
+    }
+
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.waiters.is_empty() && self.granted.iter().all(|&count| count == 0)
+    }
+}
+
+/// Every resource which is held or waited for, by path.
+#[derive(Debug)]
+struct Tree<K> {
+    nodes: HashMap<Vec<K>, Node>,
+}
+
+impl<K: Eq + Hash + Clone> Tree<K> {
+    /// Takes `path` in `mode` if no conflicting mode is held and nobody is
+    /// waiting, or queues a request for it, returning its receiver.
+    fn request(&mut self, path: &[K], mode: LockMode) -> Option<Receiver<()>> {
+        let node = self.nodes.entry(path.to_vec()).or_default();
+        if node.waiters.is_empty() && node.admits(mode) {
+            node.granted[mode as usize] += 1;
+            None
+        } else {
+            let (tx, rx) = oneshot::channel();
+            node.waiters.push_back(Waiter { mode, tx });
+            Some(rx)
+        }
+    }
+
+    /// Takes every level of `path` in its mode if all can be taken without
+    /// waiting, and none otherwise.
+    fn try_take(&mut self, path: &[K], mode: LockMode) -> bool {
+        let free = (0..=path.len()).all(|level| {
+            self.nodes
+                .get(&path[..level])
+                .is_none_or(|node| node.waiters.is_empty() && node.admits(mode.at(path, level)))
+        });
+        if free {
+            for level in 0..=path.len() {
+                assert!(self.request(&path[..level], mode.at(path, level)).is_none());
+            }
+        }
+        free
+    }
+
+    /// Releases `path` from `mode`, granting it to whoever it now admits.
+    fn release(&mut self, path: &[K], mode: LockMode) {
+        if let Some(node) = self.nodes.get_mut(path) {
+            node.granted[mode as usize] -= 1;
+            node.grant();
+            if node.is_idle() {
+                self.nodes.remove(path);
+            }
+        }
+    }
+
+    /// Releases the first `levels` levels of `path`, deepest first.
+    fn release_levels(&mut self, path: &[K], mode: LockMode, levels: usize) {
+        for level in (0..levels).rev() {
+            self.release(&path[..level], mode.at(path, level));
+        }
+    }
+
+    /// Drops the requests for `path` whose receivers are gone, letting any
+    /// waiters they held up proceed.
+    fn withdraw(&mut self, path: &[K]) {
+        if let Some(node) = self.nodes.get_mut(path) {
+            node.waiters.retain(|waiter| !waiter.tx.is_canceled());
+            node.grant();
+            if node.is_idle() {
+                self.nodes.remove(path);
+            }
+        }
+    }
+}
+
+/// Holds a resource of a `HierarchicalLock`, and its ancestors in the
+/// corresponding intent mode, releasing them when dropped.
+#[derive(Debug)]
+pub struct HierarchyGuard<K: Eq + Hash + Clone> {
+    tree: Arc<Mutex<Tree<K>>>,
+    path: Vec<K>,
+    mode: LockMode,
+}
+
+impl<K: Eq + Hash + Clone> HierarchyGuard<K> {
+    /// Returns the path of the resource held.
+    #[inline]
+    pub fn path(&self) -> &[K] {
+        &self.path
+    }
+
+    /// Returns the mode the resource is held in.
+    #[inline]
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for HierarchyGuard<K> {
+    fn drop(&mut self) {
+        self.tree
+            .lock()
+            .unwrap()
+            .release_levels(&self.path, self.mode, self.path.len() + 1);
+    }
+}
+
+/// A future which resolves to a `HierarchyGuard`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureHierarchyGuard<K: Eq + Hash + Clone> {
+    tree: Arc<Mutex<Tree<K>>>,
+    // `None` once complete:
+    path: Option<Vec<K>>,
+    mode: LockMode,
+    // The number of levels, from the root down, already held:
+    held: usize,
+    waiting: Option<Receiver<()>>,
+}
+
+impl<K: Eq + Hash + Clone> FutureHierarchyGuard<K> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<HierarchyGuard<K>, Infallible> {
+        executor::block_on(self)
+    }
+}
+
+// Never pinned structurally.
+impl<K: Eq + Hash + Clone> Unpin for FutureHierarchyGuard<K> {}
+
+impl<K: Eq + Hash + Clone> Future for FutureHierarchyGuard<K> {
+    type Output = Result<HierarchyGuard<K>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let path = this
+            .path
+            .as_ref()
+            .expect("FutureHierarchyGuard::poll: Task already completed.");
+
+        loop {
+            if let Some(ref mut rx) = this.waiting {
+                // The sender can not be dropped unsent while we hold a
+                // reference to the tree containing it:
+                let _ = futures::ready!(Pin::new(rx).poll(cx));
+                this.waiting = None;
+                this.held += 1;
+            }
+            if this.held > path.len() {
+                break;
+            }
+
+            let level = this.held;
+            let mut tree = this.tree.lock().unwrap();
+            match tree.request(&path[..level], this.mode.at(path, level)) {
+                Some(rx) => this.waiting = Some(rx),
+                None => this.held += 1,
+            }
+        }
+
+        Poll::Ready(Ok(HierarchyGuard {
+            tree: this.tree.clone(),
+            path: this.path.take().unwrap(),
+            mode: this.mode,
+        }))
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for FutureHierarchyGuard<K> {
+    /// Releases the levels already held, along with the one waited for if
+    /// granted but not yet received.
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let mut tree = self.tree.lock().unwrap();
+            if let Some(mut rx) = self.waiting.take() {
+                rx.close();
+                match rx.try_recv() {
+                    Ok(Some(())) => self.held += 1,
+                    _ => tree.withdraw(&path[..self.held]),
+                }
+            }
+            tree.release_levels(path, self.mode, self.held);
+        }
+    }
+}
+
+/// A lock over a tree of resources, each held in one of the `LockMode`s,
+/// such as the tables of a database and their rows.
+///
+/// Resources are named by their path from the root, the empty path naming
+/// the root itself. Locking a resource first locks each of its ancestors,
+/// from the root down, in the intent mode corresponding to the one
+/// requested (see `LockMode::intent`). Locking a row `Exclusive` thus still
+/// lets other rows of the same table be locked, but not the whole table.
+///
+/// Waiters for each resource are granted in FIFO order, so one waiting for a
+/// conflicting mode holds up those queued behind it.
+#[derive(Debug)]
+pub struct HierarchicalLock<K: Eq + Hash + Clone> {
+    tree: Arc<Mutex<Tree<K>>>,
+}
+
+impl<K: Eq + Hash + Clone> HierarchicalLock<K> {
+    /// Creates and returns a new `HierarchicalLock`, with no resources held.
+    pub fn new() -> HierarchicalLock<K> {
+        HierarchicalLock {
+            tree: Arc::new(Mutex::new(Tree {
+                nodes: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Returns a new `FutureHierarchyGuard` which will resolve into a guard
+    /// holding the resource at `path` in `mode`.
+    pub fn lock(&self, path: &[K], mode: LockMode) -> FutureHierarchyGuard<K> {
+        FutureHierarchyGuard {
+            tree: self.tree.clone(),
+            path: Some(path.to_vec()),
+            mode,
+            held: 0,
+            waiting: None,
+        }
+    }
+
+    /// Returns a `HierarchyGuard` if the resource at `path` and each of its
+    /// ancestors can be held without waiting.
+    pub fn try_lock(&self, path: &[K], mode: LockMode) -> Option<HierarchyGuard<K>> {
+        if self.tree.lock().unwrap().try_take(path, mode) {
+            Some(HierarchyGuard {
+                tree: self.tree.clone(),
+                path: path.to_vec(),
+                mode,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Clone for HierarchicalLock<K> {
+    #[inline]
+    fn clone(&self) -> HierarchicalLock<K> {
+        HierarchicalLock {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for HierarchicalLock<K> {
+    #[inline]
+    fn default() -> HierarchicalLock<K> {
+        HierarchicalLock::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+
+    #[test]
+    fn compatibility() {
+        use self::LockMode::*;
+
+        let expected = [
+            (IntentShared, [true, true, true, false]),
+            (IntentExclusive, [true, true, false, false]),
+            (Shared, [true, false, true, false]),
+            (Exclusive, [false, false, false, false]),
+        ];
+        for (mode, row) in expected {
+            for (other, compatible) in LockMode::ALL.into_iter().zip(row) {
+                assert_eq!(mode.is_compatible(other), compatible);
+                assert_eq!(other.is_compatible(mode), compatible);
+            }
+        }
+    }
+
+    #[test]
+    fn rows_and_tables() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = HierarchicalLock::new();
+        let row_0 = lock.try_lock(&["table", "0"], LockMode::Exclusive).unwrap();
+        let row_1 = lock.try_lock(&["table", "1"], LockMode::Exclusive).unwrap();
+        let _other = lock.try_lock(&["other"], LockMode::Shared).unwrap();
+        assert!(lock.try_lock(&["table", "0"], LockMode::Shared).is_none());
+
+        // Waits for both rows:
+        let mut table = lock.lock(&["table"], LockMode::Shared);
+        assert!(table.poll_unpin(cx).is_pending());
+        drop(row_0);
+        assert!(table.poll_unpin(cx).is_pending());
+        drop(row_1);
+        let table = table.wait().unwrap();
+        assert_eq!(
+            (table.path(), table.mode()),
+            (&["table"][..], LockMode::Shared)
+        );
+
+        // Reading rows is still allowed, but not writing them:
+        assert!(lock.try_lock(&["table", "0"], LockMode::Shared).is_some());
+        assert!(lock
+            .try_lock(&["table", "0"], LockMode::Exclusive)
+            .is_none());
+        drop(table);
+        assert!(lock.try_lock(&[], LockMode::Exclusive).is_none());
+        drop(_other);
+        assert!(lock.try_lock(&[], LockMode::Exclusive).is_some());
+        assert!(lock.tree.lock().unwrap().nodes.is_empty());
+    }
+
+    #[test]
+    fn abandoned() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = HierarchicalLock::new();
+        let reader = lock.try_lock(&[0], LockMode::Shared).unwrap();
+        let mut writer = lock.lock(&[0], LockMode::Exclusive);
+        let mut intent = lock.lock(&[0, 1], LockMode::IntentShared);
+        assert!(writer.poll_unpin(cx).is_pending());
+
+        // Held up behind the writer, until it gives up:
+        assert!(intent.poll_unpin(cx).is_pending());
+        drop(writer);
+        assert!(intent.poll_unpin(cx).is_ready());
+        drop((reader, intent));
+        assert!(lock.tree.lock().unwrap().nodes.is_empty());
+    }
+}
@@ -0,0 +1,377 @@
+//! A slice lock granting exclusive access to individual elements.
+//!
+//
+
+use futures::channel::oneshot::{self, Canceled, Receiver, Sender};
+use futures::executor;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::{fmt, ptr};
+
+// Element states:
+const UNLOCKED: u8 = 0;
+const LOCKED: u8 = 1;
+// Locked, and at least one request may be queued for the element:
+const CONTENDED: u8 = 2;
+
+struct Inner<T> {
+    // The elements, owned via `Box::into_raw`. Guards alias individual
+    // elements, so a reference to the whole is never created:
+    ptr: *mut T,
+    len: usize,
+    states: Box<[AtomicU8]>,
+    // Queued requests, only for elements which have been contended:
+    waiters: Mutex<HashMap<usize, VecDeque<Sender<()>>>>,
+}
+
+impl<T> Inner<T> {
+    /// Locks the element at `idx`, or queues a request for it. Returns
+    /// `None` if locked immediately.
+    fn acquire(&self, idx: usize) -> Option<Receiver<()>> {
+        let state = &self.states[idx];
+
+        if state
+            .compare_exchange(UNLOCKED, LOCKED, SeqCst, SeqCst)
+            .is_ok()
+        {
+            return None;
+        }
+
+        // Slow path. Releases of contended elements also take the waiter
+        // lock, so nobody can slip between our check and our queueing:
+        let mut waiters = self.waiters.lock().unwrap();
+
+        loop {
+            match state.load(SeqCst) {
+                UNLOCKED => {
+                    if state
+                        .compare_exchange(UNLOCKED, LOCKED, SeqCst, SeqCst)
+                        .is_ok()
+                    {
+                        return None;
+                    }
+                }
+                LOCKED => {
+                    if state
+                        .compare_exchange(LOCKED, CONTENDED, SeqCst, SeqCst)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        waiters.entry(idx).or_default().push_back(tx);
+        Some(rx)
+    }
+
+    /// Unlocks the element at `idx`, handing it directly to the next queued
+    /// request if there is one.
+    fn release(&self, idx: usize) {
+        let state = &self.states[idx];
+
+        if state
+            .compare_exchange(LOCKED, UNLOCKED, SeqCst, SeqCst)
+            .is_ok()
+        {
+            return;
+        }
+
+        let mut waiters = self.waiters.lock().unwrap();
+
+        if let Some(queue) = waiters.get_mut(&idx) {
+            while let Some(tx) = queue.pop_front() {
+                // If there is a send error, a requester has dropped its
+                // receiver so just go to the next.
+                if tx.send(()).is_ok() {
+                    if queue.is_empty() {
+                        waiters.remove(&idx);
+                        state.store(LOCKED, SeqCst);
+                    }
+                    return;
+                }
+            }
+            waiters.remove(&idx);
+        }
+
+        state.store(UNLOCKED, SeqCst);
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                self.ptr, self.len,
+            )))
+        }
+    }
+}
+
+impl<T> fmt::Debug for Inner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inner")
+            .field("len", &self.len)
+            .field("states", &self.states)
+            .finish()
+    }
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// Allows exclusive access to a single element within an `IndexedQutex`.
+pub struct IndexGuard<T> {
+    indexed: IndexedQutex<T>,
+    idx: usize,
+}
+
+impl<T> IndexGuard<T> {
+    /// Returns the index of the element this guard has access to.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.idx
+    }
+}
+
+impl<T> Deref for IndexGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.indexed.inner.ptr.add(self.idx) }
+    }
+}
+
+impl<T> DerefMut for IndexGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.indexed.inner.ptr.add(self.idx) }
+    }
+}
+
+impl<T> Drop for IndexGuard<T> {
+    fn drop(&mut self) {
+        self.indexed.inner.release(self.idx);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for IndexGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IndexGuard")
+            .field("idx", &self.idx)
+            .field("element", &**self)
+            .finish()
+    }
+}
+
+/// A future which resolves to an `IndexGuard`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureIndexGuard<T> {
+    indexed: Option<IndexedQutex<T>>,
+    idx: usize,
+    // `None` if locked immediately:
+    rx: Option<Receiver<()>>,
+}
+
+impl<T> FutureIndexGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<IndexGuard<T>, Canceled> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureIndexGuard<T> {
+    type Output = Result<IndexGuard<T>, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.indexed.is_none() {
+            panic!("FutureIndexGuard::poll: Task already completed.");
+        }
+
+        if let Some(ref mut rx) = this.rx {
+            futures::ready!(Pin::new(rx).poll(cx))?;
+            this.rx = None;
+        }
+
+        Poll::Ready(Ok(IndexGuard {
+            indexed: this.indexed.take().unwrap(),
+            idx: this.idx,
+        }))
+    }
+}
+
+impl<T> Drop for FutureIndexGuard<T> {
+    /// Gracefully unlocks the element if it has been locked but this future
+    /// has not been polled to completion.
+    fn drop(&mut self) {
+        if let Some(indexed) = self.indexed.take() {
+            match self.rx {
+                Some(ref mut rx) => {
+                    rx.close();
+
+                    if let Ok(Some(())) = rx.try_recv() {
+                        indexed.inner.release(self.idx);
+                    }
+                }
+                None => indexed.inner.release(self.idx),
+            }
+        }
+    }
+}
+
+/// A lock over a fixed-length sequence of elements, each of which may be
+/// locked independently.
+///
+/// Each element's state occupies a single byte. Requests are only queued,
+/// in FIFO order, for elements which are actually contended.
+#[derive(Debug)]
+pub struct IndexedQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> IndexedQutex<T> {
+    /// Creates and returns a new `IndexedQutex` containing `elements`.
+    pub fn new<E>(elements: E) -> IndexedQutex<T>
+    where
+        E: Into<Box<[T]>>,
+    {
+        let elements = elements.into();
+        let len = elements.len();
+
+        IndexedQutex {
+            inner: Arc::new(Inner {
+                ptr: Box::into_raw(elements) as *mut T,
+                len,
+                states: (0..len).map(|_| AtomicU8::new(UNLOCKED)).collect(),
+                waiters: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureIndexGuard` which will resolve into an
+    /// `IndexGuard` for the element at `idx`.
+    ///
+    /// Panics if `idx` is out of bounds.
+    pub fn lock_index(self, idx: usize) -> FutureIndexGuard<T> {
+        assert!(
+            idx < self.inner.len,
+            "IndexedQutex::lock_index: Index {} is out of bounds for length {}.",
+            idx,
+            self.inner.len
+        );
+
+        let rx = self.inner.acquire(idx);
+
+        FutureIndexGuard {
+            indexed: Some(self),
+            idx,
+            rx,
+        }
+    }
+
+    /// Returns the number of elements.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len
+    }
+
+    /// Returns `true` if there are no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.len == 0
+    }
+
+    /// Returns a mutable reference to the elements if there are currently no
+    /// other copies of this `IndexedQutex`.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut [T]> {
+        Arc::get_mut(&mut self.inner)
+            .map(|inner| unsafe { std::slice::from_raw_parts_mut(inner.ptr, inner.len) })
+    }
+}
+
+impl<T> From<Vec<T>> for IndexedQutex<T> {
+    #[inline]
+    fn from(elements: Vec<T>) -> IndexedQutex<T> {
+        IndexedQutex::new(elements)
+    }
+}
+
+impl<T> From<Box<[T]>> for IndexedQutex<T> {
+    #[inline]
+    fn from(elements: Box<[T]>) -> IndexedQutex<T> {
+        IndexedQutex::new(elements)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for IndexedQutex<T> {
+    #[inline]
+    fn clone(&self) -> IndexedQutex<T> {
+        IndexedQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::thread;
+
+    #[test]
+    fn contended_elements() {
+        let counters = IndexedQutex::new(vec![0usize; 4]);
+
+        let threads = (0..8)
+            .map(|t| {
+                let counters = counters.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        *counters.clone().lock_index((t + i) % 4).wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut counters = counters;
+        assert_eq!(counters.get_mut().unwrap(), &[200; 4]);
+    }
+
+    #[test]
+    fn elements_are_independent() {
+        let elements = IndexedQutex::from(vec!['a', 'b']);
+        let a = elements.clone().lock_index(0).wait().unwrap();
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut queued_a = elements.clone().lock_index(0);
+        assert!(queued_a.poll_unpin(cx).is_pending());
+
+        let b = elements.clone().lock_index(1).wait().unwrap();
+        assert_eq!((*a, *b), ('a', 'b'));
+
+        drop(a);
+        let mut a = queued_a.wait().unwrap();
+        *a = 'z';
+        drop(a);
+
+        assert_eq!(*elements.lock_index(0).wait().unwrap(), 'z');
+    }
+}
@@ -0,0 +1,481 @@
+//! A lock whose waiters queue in an intrusive list threaded through their
+//! own futures, so that no request allocates.
+//!
+//
+// Each `FutureIntrusiveGuard` embeds the node it waits in. A node can only
+// be linked once its future is pinned, so a request joins the list on its
+// first poll rather than when made, and leaves it in O(1) when dropped.
+// The list and the lock's state sit behind one spin lock, held only to
+// link, unlink or hand over a node, with wakers woken once it is released.
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::convert::Infallible;
+use core::fmt;
+use core::future::Future;
+use core::hint;
+use core::marker::PhantomPinned;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use futures::executor;
+
+/// Where a request stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Not yet polled, so not in the list.
+    Unlinked,
+    /// In the list, waiting.
+    Linked,
+    /// Handed the lock, and out of the list.
+    Granted,
+}
+
+/// A waiting request, embedded in its pinned future. Only touched with the
+/// spin lock held.
+struct Node {
+    prev: *mut Node,
+    next: *mut Node,
+    waker: Option<Waker>,
+    stage: Stage,
+}
+
+/// The waiters in order of first poll, and whether the lock is held.
+struct List {
+    locked: bool,
+    head: *mut Node,
+    tail: *mut Node,
+}
+
+impl List {
+    /// Links `node` at the back.
+    unsafe fn push_back(&mut self, node: *mut Node) {
+        (*node).prev = self.tail;
+        (*node).next = ptr::null_mut();
+        match self.tail.is_null() {
+            true => self.head = node,
+            false => (*self.tail).next = node,
+        }
+        self.tail = node;
+        (*node).stage = Stage::Linked;
+    }
+
+    /// Unlinks `node`, wherever in the list it is.
+    unsafe fn unlink(&mut self, node: *mut Node) {
+        let (prev, next) = ((*node).prev, (*node).next);
+        match prev.is_null() {
+            true => self.head = next,
+            false => (*prev).next = next,
+        }
+        match next.is_null() {
+            true => self.tail = prev,
+            false => (*next).prev = prev,
+        }
+    }
+
+    /// Hands the lock to the request at the front, returning its waker, or
+    /// unlocks it if nobody is waiting.
+    unsafe fn hand_over(&mut self) -> Option<Waker> {
+        let node = self.head;
+        if node.is_null() {
+            self.locked = false;
+            return None;
+        }
+        self.unlink(node);
+        (*node).stage = Stage::Granted;
+        (*node).waker.take()
+    }
+}
+
+/// Releases the spin lock once dropped, even should cloning a waker panic.
+struct Locked<'a>(&'a AtomicBool);
+
+impl Drop for Locked<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.store(false, Release);
+    }
+}
+
+struct Inner<T> {
+    spin: AtomicBool,
+    list: UnsafeCell<List>,
+    cell: UnsafeCell<T>,
+}
+
+impl<T> Inner<T> {
+    /// Runs `f` with the spin lock held.
+    fn with<R>(&self, f: impl FnOnce(&mut List) -> R) -> R {
+        while self
+            .spin
+            .compare_exchange_weak(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            while self.spin.load(Relaxed) {
+                hint::spin_loop();
+            }
+        }
+        let _locked = Locked(&self.spin);
+        f(unsafe { &mut *self.list.get() })
+    }
+
+    /// Hands the lock to the next request in line, or unlocks it.
+    fn release(&self) {
+        if let Some(waker) = self.with(|list| unsafe { list.hand_over() }) {
+            waker.wake();
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// Allows access to the data contained within an `IntrusiveQutex`.
+pub struct IntrusiveGuard<T> {
+    qutex: IntrusiveQutex<T>,
+}
+
+impl<T> IntrusiveGuard<T> {
+    /// Releases the lock held by an `IntrusiveGuard` and returns the
+    /// original `IntrusiveQutex`.
+    pub fn unlock(guard: IntrusiveGuard<T>) -> IntrusiveQutex<T> {
+        let qutex = guard.qutex.clone();
+        drop(guard);
+        qutex
+    }
+}
+
+impl<T> Deref for IntrusiveGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.qutex.inner.cell.get() }
+    }
+}
+
+impl<T> DerefMut for IntrusiveGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.qutex.inner.cell.get() }
+    }
+}
+
+impl<T> Drop for IntrusiveGuard<T> {
+    fn drop(&mut self) {
+        self.qutex.inner.release()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for IntrusiveGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("IntrusiveGuard").field(&**self).finish()
+    }
+}
+
+/// A future which resolves to an `IntrusiveGuard`, holding the node its
+/// request waits in.
+///
+/// Being `!Unpin`, it must be pinned to be polled, as by `.await`,
+/// `Box::pin` or `core::pin::pin!`. Its request joins the line on its first
+/// poll, and leaves it as soon as it is dropped. Panics if polled again
+/// after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureIntrusiveGuard<T> {
+    qutex: Option<IntrusiveQutex<T>>,
+    node: UnsafeCell<Node>,
+    _pin: PhantomPinned,
+}
+
+impl<T> FutureIntrusiveGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<IntrusiveGuard<T>, Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureIntrusiveGuard<T> {
+    type Output = Result<IntrusiveGuard<T>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Never moved out of, the node being linked by address:
+        let this = unsafe { self.get_unchecked_mut() };
+        let node = this.node.get();
+        let qutex = this
+            .qutex
+            .as_ref()
+            .expect("FutureIntrusiveGuard::poll: Task already completed.");
+
+        let acquired = qutex.inner.with(|list| unsafe {
+            match (*node).stage {
+                Stage::Granted => true,
+                Stage::Unlinked if !list.locked => {
+                    list.locked = true;
+                    true
+                }
+                Stage::Unlinked => {
+                    (*node).waker = Some(cx.waker().clone());
+                    list.push_back(node);
+                    false
+                }
+                Stage::Linked => {
+                    match (*node).waker {
+                        Some(ref waker) if waker.will_wake(cx.waker()) => (),
+                        ref mut waker => *waker = Some(cx.waker().clone()),
+                    }
+                    false
+                }
+            }
+        });
+        match acquired {
+            true => Poll::Ready(Ok(IntrusiveGuard {
+                qutex: this.qutex.take().unwrap(),
+            })),
+            false => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for FutureIntrusiveGuard<T> {
+    /// Unlinks the request, or passes the lock on should it have been
+    /// granted but not yet polled for.
+    fn drop(&mut self) {
+        let Some(ref qutex) = self.qutex else {
+            return;
+        };
+        let node = self.node.get();
+        let waker = qutex.inner.with(|list| unsafe {
+            match (*node).stage {
+                Stage::Unlinked => None,
+                Stage::Linked => {
+                    list.unlink(node);
+                    None
+                }
+                Stage::Granted => list.hand_over(),
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+// The node is only ever touched with the lock's spin lock held.
+unsafe impl<T: Send> Send for FutureIntrusiveGuard<T> {}
+unsafe impl<T: Send> Sync for FutureIntrusiveGuard<T> {}
+
+impl<T> fmt::Debug for FutureIntrusiveGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureIntrusiveGuard")
+            .field("resolved", &self.qutex.is_none())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A lock with the API of `Qutex`, whose requests wait in nodes stored in
+/// their own futures rather than on the heap.
+///
+/// Locking allocates nothing, whether or not the request waits, and a
+/// request given up on leaves the line at once, in O(1), rather than being
+/// skipped over later. The lock is handed straight to the request at the
+/// front as it is released, so requests are served in FIFO order, by
+/// *first poll* rather than by creation: a future made but not yet polled
+/// holds no place in line. Neither priorities, deadlines nor the other
+/// configuration of a `QutexBuilder` are supported.
+///
+/// Switching between it and `Qutex` takes no more than a type change, as
+/// long as the futures returned by `lock` are polled pinned, as `.await`
+/// does.
+pub struct IntrusiveQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> IntrusiveQutex<T> {
+    /// Creates and returns a new `IntrusiveQutex`.
+    #[inline]
+    pub fn new(val: T) -> IntrusiveQutex<T> {
+        IntrusiveQutex {
+            inner: Arc::new(Inner {
+                spin: AtomicBool::new(false),
+                list: UnsafeCell::new(List {
+                    locked: false,
+                    head: ptr::null_mut(),
+                    tail: ptr::null_mut(),
+                }),
+                cell: UnsafeCell::new(val),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureIntrusiveGuard` which can be used as a future
+    /// and will resolve into an `IntrusiveGuard`.
+    #[inline]
+    pub fn lock(self) -> FutureIntrusiveGuard<T> {
+        FutureIntrusiveGuard {
+            qutex: Some(self),
+            node: UnsafeCell::new(Node {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                waker: None,
+                stage: Stage::Unlinked,
+            }),
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Returns an `IntrusiveGuard` if the lock is free.
+    ///
+    /// A free lock has nobody waiting for it, as it is handed straight over
+    /// to whoever is.
+    pub fn try_lock(&self) -> Option<IntrusiveGuard<T>> {
+        let acquired = self
+            .inner
+            .with(|list| !core::mem::replace(&mut list.locked, true));
+        acquired.then(|| IntrusiveGuard {
+            qutex: self.clone(),
+        })
+    }
+
+    /// Returns `true` if the lock is held.
+    pub fn is_locked(&self) -> bool {
+        self.inner.with(|list| list.locked)
+    }
+
+    /// Returns a mutable reference to the inner value if there are currently
+    /// no other copies of this `IntrusiveQutex`.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(&mut self.inner).map(|inner| inner.cell.get_mut())
+    }
+
+    /// Returns a reference to the inner value.
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        self.inner.cell.get()
+    }
+
+    /// Returns a mutable reference to the inner value.
+    #[inline]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.inner.cell.get()
+    }
+}
+
+impl<T> From<T> for IntrusiveQutex<T> {
+    #[inline]
+    fn from(val: T) -> IntrusiveQutex<T> {
+        IntrusiveQutex::new(val)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for IntrusiveQutex<T> {
+    #[inline]
+    fn clone(&self) -> IntrusiveQutex<T> {
+        IntrusiveQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for IntrusiveQutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IntrusiveQutex")
+            .field("locked", &self.is_locked())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use std::pin::pin;
+    use std::thread;
+
+    #[test]
+    fn contended() {
+        let total = IntrusiveQutex::new(0usize);
+
+        let threads = (0..8)
+            .map(|_| {
+                let total = total.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *total.clone().lock().wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*total.try_lock().unwrap(), 8000);
+    }
+
+    #[test]
+    fn ordered_by_first_poll() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = IntrusiveQutex::new(Vec::new());
+        let guard = qutex.try_lock().unwrap();
+
+        let mut first = pin!(qutex.clone().lock());
+        let mut second = pin!(qutex.clone().lock());
+        let mut third = pin!(qutex.clone().lock());
+        assert!(second.as_mut().poll(cx).is_pending());
+        assert!(first.as_mut().poll(cx).is_pending());
+        assert!(third.as_mut().poll(cx).is_pending());
+
+        // Handed over in the order polled:
+        drop(guard);
+        assert!(first.as_mut().poll(cx).is_pending());
+        let Poll::Ready(Ok(mut guard)) = second.as_mut().poll(cx) else {
+            panic!("second not granted");
+        };
+        guard.push(2);
+        drop(guard);
+        let Poll::Ready(Ok(mut guard)) = first.as_mut().poll(cx) else {
+            panic!("first not granted");
+        };
+        guard.push(1);
+        drop(guard);
+        let Poll::Ready(Ok(mut guard)) = third.as_mut().poll(cx) else {
+            panic!("third not granted");
+        };
+        guard.push(3);
+        drop(guard);
+        assert_eq!(*qutex.try_lock().unwrap(), [2, 1, 3]);
+    }
+
+    #[test]
+    fn dropped_requests() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut qutex = IntrusiveQutex::new(0);
+        let guard = qutex.try_lock().unwrap();
+
+        let mut waiting = Box::pin(qutex.clone().lock());
+        let mut granted = Box::pin(qutex.clone().lock());
+        let mut last = Box::pin(qutex.clone().lock());
+        for future in [&mut waiting, &mut granted, &mut last] {
+            assert!(future.as_mut().poll(cx).is_pending());
+        }
+
+        // Leaving from the front of the line:
+        drop(waiting);
+        drop(guard);
+        // Then granted, but never polled for, so passed on:
+        drop(granted);
+        let Poll::Ready(Ok(mut guard)) = last.as_mut().poll(cx) else {
+            panic!("last not granted");
+        };
+        *guard += 1;
+        drop((guard, last));
+        assert!(!qutex.is_locked());
+        assert_eq!(qutex.get_mut(), Some(&mut 1));
+    }
+}
@@ -0,0 +1,634 @@
+//! Locks shared between processes, through shared memory.
+//!
+//! A `ShmQutex` lives in a named POSIX shared-memory segment, created by
+//! one process and opened by name in any other, each handle mapping the
+//! same lock and value. The value must be plain data (see `Pod`), meaning
+//! nothing in it refers into any one process's memory.
+//!
+//! ```
+//! use qutex::ipc::ShmQutex;
+//!
+//! let name = format!("/qutex-doc-{}", std::process::id());
+//! let created = ShmQutex::create(&name, 0u64).unwrap();
+//! // As done by another process:
+//! let opened = ShmQutex::<u64>::open(&name).unwrap();
+//!
+//! *created.lock().wait().unwrap() += 1;
+//! assert_eq!(*opened.lock().wait().unwrap(), 1);
+//! ShmQutex::<u64>::unlink(&name).unwrap();
+//! ```
+//
+// The segment holds a ticket lock: each request takes the next ticket, and
+// the lock is held by whichever ticket is being served, so that requests
+// are granted in order whatever process they come from. A release serves
+// the next ticket and wakes everyone waiting on the futex of the ticket
+// being served, which is shared by every process mapping it. Tasks waiting
+// within a process are woken by a watcher thread, spawned to wait on the
+// futex for them for as long as any are.
+//
+// A request dropped while waiting cannot leave the line, so it marks its
+// ticket abandoned instead, in a fixed ring of slots. Whichever of the
+// releasing holder and the abandoning request finds the ticket both marked
+// and up skips it, as though it had been served and released. The ring
+// bounds the tickets which may be outstanding at once.
+
+use crate::Error;
+use std::cell::UnsafeCell;
+use std::ffi::CString;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The most tickets outstanding at once, the holder's included.
+const SLOTS: u32 = 64;
+
+/// Stored by the creator once a segment is initialized.
+const MAGIC: u32 = 0x5154_5831;
+
+/// How long `open` waits for a segment to be initialized.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the watcher checks whether anyone is still waiting.
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Plain data, which may be shared between processes.
+///
+/// # Safety
+///
+/// Implementors must hold no pointers, references, or handles into the
+/// memory or resources of any one process, and must be laid out alike by
+/// every process sharing them (`#[repr(C)]` for structs).
+pub unsafe trait Pod: Copy + Send + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// The layout of a segment, zeroed on creation but for the value.
+#[repr(C)]
+struct Shared<T> {
+    init: AtomicU32,
+    // The next ticket to be taken, and the ticket holding the lock:
+    next: AtomicU32,
+    serving: AtomicU32,
+    // Tickets abandoned while waiting, each marked in slot `ticket % SLOTS`:
+    abandoned: [AtomicU64; SLOTS as usize],
+    value: UnsafeCell<T>,
+}
+
+/// Returns the mark of an abandoned `ticket`, never zero.
+#[inline]
+fn mark(ticket: u32) -> u64 {
+    1 << 32 | ticket as u64
+}
+
+impl<T> Shared<T> {
+    #[inline]
+    fn slot(&self, ticket: u32) -> &AtomicU64 {
+        &self.abandoned[(ticket % SLOTS) as usize]
+    }
+
+    /// Takes the next ticket, or returns `Error::QueueFull` if `SLOTS` are
+    /// already outstanding.
+    fn take_ticket(&self) -> Result<u32, Error> {
+        loop {
+            // Loaded first, so never ahead of `next`:
+            let serving = self.serving.load(Acquire);
+            let next = self.next.load(Relaxed);
+            if next.wrapping_sub(serving) >= SLOTS {
+                return Err(Error::QueueFull);
+            }
+            if self
+                .next
+                .compare_exchange_weak(next, next.wrapping_add(1), Relaxed, Relaxed)
+                .is_ok()
+            {
+                return Ok(next);
+            }
+        }
+    }
+
+    /// Takes the ticket being served, if nobody holds it.
+    fn try_take(&self) -> bool {
+        let serving = self.serving.load(Acquire);
+        self.next
+            .compare_exchange(serving, serving.wrapping_add(1), Relaxed, Relaxed)
+            .is_ok()
+    }
+
+    /// Serves the next ticket not abandoned, waking everyone waiting.
+    fn release(&self) {
+        let mut serving = self.serving.fetch_add(1, SeqCst).wrapping_add(1);
+        while self
+            .slot(serving)
+            .compare_exchange(mark(serving), 0, SeqCst, Relaxed)
+            .is_ok()
+        {
+            serving = self.serving.fetch_add(1, SeqCst).wrapping_add(1);
+        }
+        futex_wake(&self.serving);
+    }
+
+    /// Abandons the waiting `ticket`, skipping it should it already be up.
+    fn abandon(&self, ticket: u32) {
+        let slot = self.slot(ticket);
+        slot.store(mark(ticket), SeqCst);
+        if self.serving.load(SeqCst) == ticket
+            && slot
+                .compare_exchange(mark(ticket), 0, SeqCst, Relaxed)
+                .is_ok()
+        {
+            self.release();
+        }
+    }
+}
+
+/// Blocks until `futex` may no longer hold `expected`, or `timeout` passes.
+fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout = timeout
+        .as_ref()
+        .map_or(ptr::null(), |ts| ts as *const libc::timespec);
+    // Spurious returns and interruptions are left to the caller's loop:
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            timeout,
+            ptr::null::<u32>(),
+            0u32,
+        );
+    }
+}
+
+/// Wakes every thread, in any process, waiting on `futex`.
+fn futex_wake(futex: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex.as_ptr(),
+            libc::FUTEX_WAKE,
+            i32::MAX,
+            ptr::null::<libc::timespec>(),
+            ptr::null::<u32>(),
+            0u32,
+        );
+    }
+}
+
+/// Tasks of this process waiting for the ticket being served to change.
+#[derive(Default)]
+struct Watch {
+    wakers: Vec<Waker>,
+    running: bool,
+}
+
+/// A mapping of a segment.
+struct Inner<T> {
+    shared: NonNull<Shared<T>>,
+    name: String,
+    watch: Mutex<Watch>,
+}
+
+unsafe impl<T: Pod> Send for Inner<T> {}
+unsafe impl<T: Pod> Sync for Inner<T> {}
+
+impl<T: Pod> Inner<T> {
+    /// Maps the segment `name`, creating it with `val` if given.
+    fn map(name: &str, create: Option<T>) -> io::Result<Inner<T>> {
+        let c_name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a nul byte"))?;
+        let len = mem::size_of::<Shared<T>>();
+        let oflag = match create {
+            Some(_) => libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+            None => libc::O_RDWR,
+        };
+        let fd = unsafe { libc::shm_open(c_name.as_ptr(), oflag, 0o600) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Closed once mapped:
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mapped = Inner::map_fd(&fd, len, create.is_some());
+        if mapped.is_err() && create.is_some() {
+            unsafe { libc::shm_unlink(c_name.as_ptr()) };
+        }
+        let inner = Inner {
+            shared: mapped?,
+            name: name.to_owned(),
+            watch: Mutex::default(),
+        };
+
+        match create {
+            Some(val) => unsafe {
+                let value = ptr::addr_of!((*inner.shared.as_ptr()).value);
+                ptr::write(UnsafeCell::raw_get(value), val);
+                inner.shared().init.store(MAGIC, Release);
+            },
+            None => {
+                let deadline = Instant::now() + OPEN_TIMEOUT;
+                while inner.shared().init.load(Acquire) != MAGIC {
+                    if Instant::now() >= deadline {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "segment never initialized",
+                        ));
+                    }
+                    thread::yield_now();
+                }
+            }
+        }
+        Ok(inner)
+    }
+
+    /// Sizes (if `create`) or checks the size of the segment open on `fd`,
+    /// then maps it.
+    fn map_fd(fd: &OwnedFd, len: usize, create: bool) -> io::Result<NonNull<Shared<T>>> {
+        if create {
+            if unsafe { libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        } else {
+            let mut stat = unsafe { mem::zeroed::<libc::stat>() };
+            if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if stat.st_size as usize != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "segment is not the size of this lock",
+                ));
+            }
+        }
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NonNull::new(addr.cast()).unwrap())
+    }
+
+    #[inline]
+    fn shared(&self) -> &Shared<T> {
+        unsafe { self.shared.as_ref() }
+    }
+
+    #[inline]
+    fn watch(&self) -> MutexGuard<'_, Watch> {
+        self.watch.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Has `waker` woken once the ticket being served changes, starting a
+    /// watcher if none is running.
+    fn watch_for(self: &Arc<Inner<T>>, waker: &Waker) {
+        let mut watch = self.watch();
+        if !watch.wakers.iter().any(|w| w.will_wake(waker)) {
+            watch.wakers.push(waker.clone());
+        }
+        if !mem::replace(&mut watch.running, true) {
+            // Loaded before the caller checks again, so no change is missed:
+            let seen = self.shared().serving.load(Acquire);
+            let inner = self.clone();
+            thread::Builder::new()
+                .name("qutex-ipc-watcher".into())
+                .spawn(move || inner.run_watcher(seen))
+                .expect("ShmQutex: Unable to spawn watcher thread.");
+        }
+    }
+
+    fn run_watcher(&self, mut seen: u32) {
+        loop {
+            futex_wait(&self.shared().serving, seen, Some(WATCH_INTERVAL));
+            let serving = self.shared().serving.load(Acquire);
+            let mut watch = self.watch();
+            if serving != seen {
+                seen = serving;
+                let wakers = mem::take(&mut watch.wakers);
+                drop(watch);
+                wakers.into_iter().for_each(Waker::wake);
+                watch = self.watch();
+            }
+            if watch.wakers.is_empty() {
+                watch.running = false;
+                return;
+            }
+        }
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.shared.as_ptr().cast(), mem::size_of::<Shared<T>>()) };
+    }
+}
+
+/// Exclusive access to the value of a `ShmQutex`, released on drop.
+pub struct ShmGuard<T: Pod> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Pod> Deref for ShmGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.shared().value.get() }
+    }
+}
+
+impl<T: Pod> DerefMut for ShmGuard<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.inner.shared().value.get() }
+    }
+}
+
+impl<T: Pod> Drop for ShmGuard<T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.inner.shared().release();
+    }
+}
+
+impl<T: Pod + fmt::Debug> fmt::Debug for ShmGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ShmGuard").field(&**self).finish()
+    }
+}
+
+/// A future which resolves to a `ShmGuard` once its ticket is served.
+///
+/// Dropping it while waiting abandons the ticket, to be skipped as it
+/// comes up. Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureShmGuard<T: Pod> {
+    inner: Arc<Inner<T>>,
+    // Taken on resolving:
+    ticket: Option<Result<u32, Error>>,
+}
+
+impl<T: Pod> FutureShmGuard<T> {
+    /// Blocks the current thread until this future resolves, waiting on
+    /// the futex directly rather than through the watcher.
+    pub fn wait(mut self) -> Result<ShmGuard<T>, Error> {
+        let ticket = self
+            .ticket
+            .take()
+            .expect("FutureShmGuard::wait: Task already completed.")?;
+        let shared = self.inner.shared();
+        loop {
+            let serving = shared.serving.load(Acquire);
+            if serving == ticket {
+                break;
+            }
+            futex_wait(&shared.serving, serving, None);
+        }
+        Ok(ShmGuard {
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+impl<T: Pod> Future for FutureShmGuard<T> {
+    type Output = Result<ShmGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let ticket = match this.ticket {
+            Some(Ok(ticket)) => ticket,
+            Some(Err(err)) => {
+                this.ticket = None;
+                return Poll::Ready(Err(err));
+            }
+            None => panic!("FutureShmGuard::poll: Task already completed."),
+        };
+        let serving = &this.inner.shared().serving;
+        if serving.load(Acquire) != ticket {
+            this.inner.watch_for(cx.waker());
+            if serving.load(Acquire) != ticket {
+                return Poll::Pending;
+            }
+        }
+        this.ticket = None;
+        Poll::Ready(Ok(ShmGuard {
+            inner: this.inner.clone(),
+        }))
+    }
+}
+
+impl<T: Pod> Drop for FutureShmGuard<T> {
+    fn drop(&mut self) {
+        if let Some(Ok(ticket)) = self.ticket {
+            self.inner.shared().abandon(ticket);
+        }
+    }
+}
+
+impl<T: Pod> fmt::Debug for FutureShmGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureShmGuard")
+            .field("ticket", &self.ticket)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A lock shared between processes, in a named shared-memory segment.
+///
+/// Requests are granted in order, across every process, and may wait as
+/// futures or block (`FutureShmGuard::wait`). At most 64 may be
+/// outstanding at once, beyond which they resolve to `Error::QueueFull`.
+///
+/// The lock is only as robust as the processes sharing it: one which dies
+/// holding it, or with a request waiting, leaves the lock held for good.
+pub struct ShmQutex<T: Pod> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Pod> ShmQutex<T> {
+    /// Creates the segment `name` (such as `"/my-lock"`), holding a new
+    /// lock of `val`, failing if it already exists.
+    pub fn create(name: &str, val: T) -> io::Result<ShmQutex<T>> {
+        Ok(ShmQutex {
+            inner: Arc::new(Inner::map(name, Some(val))?),
+        })
+    }
+
+    /// Opens the segment `name`, created by some process with `create`.
+    ///
+    /// Fails if the segment is not the size of a lock of `T`, though no
+    /// further check is made that it is one.
+    pub fn open(name: &str) -> io::Result<ShmQutex<T>> {
+        Ok(ShmQutex {
+            inner: Arc::new(Inner::map(name, None)?),
+        })
+    }
+
+    /// Removes the segment `name`, once every process has unmapped it.
+    pub fn unlink(name: &str) -> io::Result<()> {
+        let c_name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a nul byte"))?;
+        if unsafe { libc::shm_unlink(c_name.as_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the segment.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Returns a new `FutureShmGuard` which will resolve to a `ShmGuard`
+    /// once the lock is granted.
+    pub fn lock(&self) -> FutureShmGuard<T> {
+        FutureShmGuard {
+            inner: self.inner.clone(),
+            ticket: Some(self.inner.shared().take_ticket()),
+        }
+    }
+
+    /// Returns a `ShmGuard` if the lock is free and nobody is waiting.
+    pub fn try_lock(&self) -> Option<ShmGuard<T>> {
+        self.inner.shared().try_take().then(|| ShmGuard {
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+impl<T: Pod> Clone for ShmQutex<T> {
+    #[inline]
+    fn clone(&self) -> ShmQutex<T> {
+        ShmQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Pod> fmt::Debug for ShmQutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShmQutex")
+            .field("name", &self.inner.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+
+    /// A segment unlinked once the test is done with it.
+    struct Segment(String);
+
+    impl Segment {
+        fn new(test: &str) -> Segment {
+            Segment(format!("/qutex-test-{}-{}", std::process::id(), test))
+        }
+    }
+
+    impl Drop for Segment {
+        fn drop(&mut self) {
+            ShmQutex::<u8>::unlink(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn shared_between_mappings() {
+        let segment = Segment::new("shared");
+        let created = ShmQutex::create(&segment.0, [0u64; 2]).unwrap();
+        assert!(ShmQutex::create(&segment.0, [0u64; 2]).is_err());
+        assert!(ShmQutex::<u8>::open(&segment.0).is_err());
+
+        let threads = (0..4)
+            .map(|i| {
+                // Every other thread maps the segment afresh:
+                let qutex = match i % 2 {
+                    0 => created.clone(),
+                    _ => ShmQutex::open(&segment.0).unwrap(),
+                };
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let mut guard = match i % 4 {
+                            0 | 1 => qutex.lock().wait().unwrap(),
+                            _ => futures::executor::block_on(qutex.lock()).unwrap(),
+                        };
+                        // Torn should anyone get in between:
+                        guard[0] += 1;
+                        thread::yield_now();
+                        guard[1] += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*created.try_lock().unwrap(), [2000, 2000]);
+    }
+
+    #[test]
+    fn abandoned_skipped() {
+        let segment = Segment::new("abandoned");
+        let qutex = ShmQutex::create(&segment.0, 0u32).unwrap();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let guard = qutex.try_lock().unwrap();
+
+        let mut first = qutex.lock();
+        let mut second = qutex.lock();
+        assert!(first.poll_unpin(cx).is_pending());
+        drop(first);
+        drop(guard);
+        let second = match second.poll_unpin(cx) {
+            Poll::Ready(res) => res.unwrap(),
+            Poll::Pending => panic!("abandoned ticket not skipped"),
+        };
+
+        // Skipped by the abandoning request itself, once already up:
+        let third = qutex.lock();
+        drop(second);
+        drop(third);
+        assert!(qutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn queue_full() {
+        let segment = Segment::new("full");
+        let qutex = ShmQutex::create(&segment.0, 0u8).unwrap();
+        let requests = (0..SLOTS).map(|_| qutex.lock()).collect::<Vec<_>>();
+        assert_eq!(qutex.lock().wait().unwrap_err(), Error::QueueFull);
+        drop(requests);
+        assert!(qutex.try_lock().is_some());
+    }
+}
@@ -0,0 +1,158 @@
+//! A single-use countdown latch.
+//!
+//
+
+use crate::wait_list::WaitList;
+use futures::channel::oneshot::Receiver;
+use futures::executor;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+#[derive(Debug)]
+struct Inner {
+    count: AtomicUsize,
+    waiters: WaitList,
+}
+
+/// A future which resolves once the count of the `Latch` it was created from
+/// reaches zero.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureLatch {
+    inner: Arc<Inner>,
+    rx: Option<Receiver<()>>,
+}
+
+impl FutureLatch {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<(), Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl Future for FutureLatch {
+    type Output = Result<(), Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.inner.count.load(SeqCst) == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        if this.rx.is_none() {
+            this.rx = Some(this.inner.waiters.push());
+
+            // Re-check in case we reached zero before being queued:
+            if this.inner.count.load(SeqCst) == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        match Pin::new(this.rx.as_mut().unwrap()).poll(cx) {
+            Poll::Ready(_) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A countdown latch.
+///
+/// Any number of tasks may wait for the count to reach zero while others
+/// count it down. Unlike a barrier, those counting down never wait. Once
+/// released, a latch stays released.
+#[derive(Debug, Clone)]
+pub struct Latch {
+    inner: Arc<Inner>,
+}
+
+impl Latch {
+    /// Creates and returns a new `Latch` which will be released after `count`
+    /// calls to `count_down`.
+    #[inline]
+    pub fn new(count: usize) -> Latch {
+        Latch {
+            inner: Arc::new(Inner {
+                count: AtomicUsize::new(count),
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    /// Decrements the count, releasing every waiting task if it reaches zero.
+    ///
+    /// Does nothing if the count is already zero.
+    pub fn count_down(&self) {
+        let prev = self
+            .inner
+            .count
+            .fetch_update(SeqCst, SeqCst, |count| count.checked_sub(1));
+
+        if prev == Ok(1) {
+            self.inner.waiters.notify_all();
+        }
+    }
+
+    /// Returns the current count.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.inner.count.load(SeqCst)
+    }
+
+    /// Returns a new `FutureLatch` which will resolve once the count reaches
+    /// zero.
+    #[inline]
+    pub fn wait(&self) -> FutureLatch {
+        FutureLatch {
+            inner: self.inner.clone(),
+            rx: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::{executor, future, FutureExt};
+    use std::thread;
+
+    #[test]
+    fn fan_in() {
+        let thread_count = 8;
+        let latch = Latch::new(thread_count);
+        let waits = (0..4).map(|_| latch.wait()).collect::<Vec<_>>();
+
+        let threads = (0..thread_count)
+            .map(|_| {
+                let latch = latch.clone();
+                thread::spawn(move || latch.count_down())
+            })
+            .collect::<Vec<_>>();
+
+        executor::block_on(future::try_join_all(waits)).unwrap();
+        assert_eq!(latch.count(), 0);
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn saturates_at_zero() {
+        let latch = Latch::new(1);
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert!(latch.wait().poll_unpin(cx).is_pending());
+
+        latch.count_down();
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        latch.wait().wait().unwrap();
+    }
+}
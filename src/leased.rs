@@ -0,0 +1,325 @@
+//! Locks held on a lease, revoked once it runs out.
+//!
+//
+
+use crate::timer::{self, Sleep};
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// The current lease.
+struct Lease {
+    epoch: u64,
+    expires: Instant,
+    // Dropped on revocation, granting the lock onward:
+    _guard: Guard<()>,
+}
+
+struct State<T> {
+    value: T,
+    lease: Option<Lease>,
+    // The epoch of the most recent lease:
+    epoch: u64,
+}
+
+/// What a waiting request finds of the current lease.
+enum Expiry {
+    /// Expired, and now revoked.
+    Revoked,
+    /// Running until the given time.
+    At(Instant),
+    /// Not yet begun, though the lock is granted.
+    Pending,
+}
+
+struct Inner<T> {
+    // Grants leases, in request order:
+    grant: Qutex<()>,
+    duration: Duration,
+    state: Mutex<State<T>>,
+}
+
+impl<T> Inner<T> {
+    #[inline]
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Begins a lease on `guard`.
+    fn begin(self: &Arc<Inner<T>>, guard: Guard<()>) -> LeaseGuard<T> {
+        let mut state = self.state();
+        state.epoch += 1;
+        let epoch = state.epoch;
+        state.lease = Some(Lease {
+            epoch,
+            expires: Instant::now() + self.duration,
+            _guard: guard,
+        });
+        LeaseGuard {
+            inner: self.clone(),
+            epoch,
+        }
+    }
+
+    /// Returns when the current lease expires, revoking it if it already
+    /// has.
+    fn expiry(&self) -> Expiry {
+        let mut state = self.state();
+        let Some(expires) = state.lease.as_ref().map(|lease| lease.expires) else {
+            return Expiry::Pending;
+        };
+        if expires > Instant::now() {
+            return Expiry::At(expires);
+        }
+        let lease = state.lease.take();
+        // Granted onward once unlocked:
+        drop(state);
+        drop(lease);
+        Expiry::Revoked
+    }
+}
+
+/// Access to the value of a `LeasedQutex`, until the lease runs out.
+///
+/// The value is reached only through `with`, each call checking the lease
+/// first, so that access can be revoked between calls. A call which has
+/// begun is never interrupted, and holds up revocation until it returns.
+pub struct LeaseGuard<T> {
+    inner: Arc<Inner<T>>,
+    epoch: u64,
+}
+
+impl<T> LeaseGuard<T> {
+    /// Calls `f` with the value, or returns `Error::Revoked` if the lease
+    /// has run out, revoking it if nobody else yet has.
+    pub fn with<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> Result<R, Error> {
+        let mut state = self.inner.state();
+        match state.lease {
+            Some(ref lease) if lease.epoch == self.epoch => {
+                if lease.expires > Instant::now() {
+                    return Ok(f(&mut state.value));
+                }
+                let lease = state.lease.take();
+                drop(state);
+                drop(lease);
+                Err(Error::Revoked)
+            }
+            _ => Err(Error::Revoked),
+        }
+    }
+
+    /// Returns how long is left of the lease, or `Error::Revoked` if it has
+    /// run out.
+    pub fn check(&self) -> Result<Duration, Error> {
+        let state = self.inner.state();
+        match state.lease {
+            Some(ref lease) if lease.epoch == self.epoch => lease
+                .expires
+                .checked_duration_since(Instant::now())
+                .filter(|left| !left.is_zero())
+                .ok_or(Error::Revoked),
+            _ => Err(Error::Revoked),
+        }
+    }
+}
+
+impl<T> Drop for LeaseGuard<T> {
+    fn drop(&mut self) {
+        let mut state = self.inner.state();
+        if state
+            .lease
+            .as_ref()
+            .is_some_and(|lease| lease.epoch == self.epoch)
+        {
+            let lease = state.lease.take();
+            drop(state);
+            drop(lease);
+        }
+    }
+}
+
+impl<T> fmt::Debug for LeaseGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LeaseGuard")
+            .field("epoch", &self.epoch)
+            .field("revoked", &self.check().is_err())
+            .finish()
+    }
+}
+
+/// A future which resolves to a `LeaseGuard` once the lock is granted.
+///
+/// While waiting, the task is woken as the holder's lease runs out, to
+/// revoke it. Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureLeaseGuard<T> {
+    inner: Arc<Inner<T>>,
+    future_guard: FutureGuard<()>,
+    // Until the holder's lease runs out:
+    sleep: Option<(Instant, Sleep)>,
+}
+
+impl<T> FutureLeaseGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<LeaseGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureLeaseGuard<T> {
+    type Output = Result<LeaseGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if let Poll::Ready(res) = Pin::new(&mut this.future_guard).poll(cx) {
+                this.sleep = None;
+                return Poll::Ready(res.map(|guard| this.inner.begin(guard)));
+            }
+
+            let expires = match this.inner.expiry() {
+                // Granted onward, perhaps to this request:
+                Expiry::Revoked => continue,
+                Expiry::At(expires) => expires,
+                // Due to begin once its holder is polled, so to expire no
+                // sooner than a lease from now:
+                Expiry::Pending => match this.sleep {
+                    Some((at, _)) => at,
+                    None => Instant::now() + this.inner.duration,
+                },
+            };
+            match this.sleep {
+                Some((at, _)) if at == expires => (),
+                _ => this.sleep = Some((expires, timer::sleep_until(expires))),
+            }
+            if let Some((_, ref mut sleep)) = this.sleep {
+                futures::ready!(Pin::new(sleep).poll(cx));
+            }
+            this.sleep = None;
+        }
+    }
+}
+
+impl<T> fmt::Debug for FutureLeaseGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureLeaseGuard")
+            .field("future_guard", &self.future_guard)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An exclusive lock whose every hold is a lease of a fixed duration, so
+/// that a holder which overstays, such as a misbehaving plugin, cannot
+/// starve everyone else.
+///
+/// Once a lease runs out its guard's access is revoked: each later `with`
+/// or `check` on it returns `Error::Revoked`, and the lock is granted to
+/// the next request. Revocation is lazy, made by the first of the holder
+/// (through its guard) or a waiting request to find the lease expired;
+/// waiting requests are woken through the crate's timer (see `set_timer`)
+/// as it runs out. A lease with nobody waiting simply lapses on the
+/// holder's next access.
+pub struct LeasedQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> LeasedQutex<T> {
+    /// Creates and returns a new `LeasedQutex` granting leases of `lease`.
+    pub fn new(val: T, lease: Duration) -> LeasedQutex<T> {
+        LeasedQutex {
+            inner: Arc::new(Inner {
+                grant: Qutex::new(()),
+                duration: lease,
+                state: Mutex::new(State {
+                    value: val,
+                    lease: None,
+                    epoch: 0,
+                }),
+            }),
+        }
+    }
+
+    /// Returns the duration of each lease.
+    #[inline]
+    pub fn lease(&self) -> Duration {
+        self.inner.duration
+    }
+
+    /// Returns a new `FutureLeaseGuard` which will resolve to a
+    /// `LeaseGuard` once a lease is granted.
+    pub fn lock(&self) -> FutureLeaseGuard<T> {
+        FutureLeaseGuard {
+            inner: self.inner.clone(),
+            future_guard: self.inner.grant.clone().lock(),
+            sleep: None,
+        }
+    }
+
+    /// Returns a `LeaseGuard` if a lease can be granted without waiting,
+    /// revoking an expired one first.
+    pub fn try_lock(&self) -> Option<LeaseGuard<T>> {
+        self.inner.expiry();
+        let guard = self.inner.grant.try_lock()?;
+        Some(self.inner.begin(guard))
+    }
+}
+
+impl<T> Clone for LeasedQutex<T> {
+    #[inline]
+    fn clone(&self) -> LeasedQutex<T> {
+        LeasedQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for LeasedQutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LeasedQutex")
+            .field("lease", &self.inner.duration)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revoked_by_waiter() {
+        let lock = LeasedQutex::new(0, Duration::from_millis(20));
+        let mut first = lock.try_lock().unwrap();
+        first.with(|val| *val += 1).unwrap();
+        assert!(first.check().is_ok());
+        assert!(lock.try_lock().is_none());
+
+        // Granted onward once the lease runs out:
+        let start = Instant::now();
+        let mut second = lock.lock().wait().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert_eq!(first.with(|val| *val), Err(Error::Revoked));
+        assert_eq!(first.check(), Err(Error::Revoked));
+        assert_eq!(second.with(|val| *val).unwrap(), 1);
+
+        // The stale guard leaves the new lease be:
+        drop(first);
+        assert!(lock.try_lock().is_none());
+        drop(second);
+        assert!(lock.try_lock().is_some());
+    }
+
+    #[test]
+    fn lapses_on_access() {
+        let lock = LeasedQutex::new(0, Duration::from_millis(5));
+        let mut guard = lock.try_lock().unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(guard.with(|val| *val), Err(Error::Revoked));
+        assert!(lock.try_lock().is_some());
+    }
+}
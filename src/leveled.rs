@@ -0,0 +1,259 @@
+//! Locks ranked by a level in their type, taken in increasing order of level
+//! or not at all.
+//!
+//
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Proof that the locks a task holds are all of a level below `BELOW`,
+/// required to lock a `LeveledQutex`.
+///
+/// Implemented by `HeldBelow` and `HeldAt` alone.
+pub trait HoldsBelow: private::Sealed {
+    /// The lowest level which may be locked next.
+    const BELOW: u32;
+}
+
+/// A token standing for a task holding no `LeveledQutex` of level `L` or
+/// above, to lock its first.
+///
+/// Create one (`HeldBelow<0>`, holding none at all) at the top of each task
+/// and thread it through to where locks are taken. Creating another while
+/// holding locks defeats the check, which is only as good as the tokens.
+#[derive(Debug, Default)]
+pub struct HeldBelow<const L: u32>(());
+
+impl<const L: u32> HeldBelow<L> {
+    /// Returns a new token for a task holding no lock of level `L` or above.
+    #[inline]
+    pub fn new() -> HeldBelow<L> {
+        HeldBelow(())
+    }
+}
+
+impl<const L: u32> private::Sealed for HeldBelow<L> {}
+
+impl<const L: u32> HoldsBelow for HeldBelow<L> {
+    const BELOW: u32 = L;
+}
+
+/// A token standing for a task holding a `LeveledGuard` of level `L`, to lock
+/// those above it, as split from the guard by `LeveledGuard::split`.
+#[derive(Debug)]
+pub struct HeldAt<'g, const L: u32>(PhantomData<&'g mut ()>);
+
+impl<const L: u32> private::Sealed for HeldAt<'_, L> {}
+
+impl<const L: u32> HoldsBelow for HeldAt<'_, L> {
+    const BELOW: u32 = L + 1;
+}
+
+/// Allows access to the data contained within a `LeveledQutex` of level `L`.
+///
+/// Borrows the token it was locked with, so that no lock of a lower level
+/// can be taken with it until released.
+pub struct LeveledGuard<'h, T: ?Sized, const L: u32> {
+    guard: Guard<T>,
+    _held: PhantomData<&'h mut ()>,
+}
+
+impl<'h, T: ?Sized, const L: u32> LeveledGuard<'h, T, L> {
+    /// Splits the guard into the data and a token with which to lock those
+    /// of a higher level while using it.
+    #[inline]
+    pub fn split<'g>(guard: &'g mut LeveledGuard<'h, T, L>) -> (&'g mut T, HeldAt<'g, L>) {
+        (&mut guard.guard, HeldAt(PhantomData))
+    }
+}
+
+impl<T: ?Sized, const L: u32> Deref for LeveledGuard<'_, T, L> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized, const L: u32> DerefMut for LeveledGuard<'_, T, L> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, const L: u32> fmt::Debug for LeveledGuard<'_, T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("LeveledGuard").field(&&**self).finish()
+    }
+}
+
+/// A future which resolves to a `LeveledGuard`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureLeveledGuard<'h, T: ?Sized, const L: u32> {
+    future_guard: FutureGuard<T>,
+    _held: PhantomData<&'h mut ()>,
+}
+
+impl<'h, T: ?Sized, const L: u32> FutureLeveledGuard<'h, T, L> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<LeveledGuard<'h, T, L>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<'h, T: ?Sized, const L: u32> Future for FutureLeveledGuard<'h, T, L> {
+    type Output = Result<LeveledGuard<'h, T, L>, Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let guard = futures::ready!(Pin::new(&mut self.get_mut().future_guard).poll(cx))?;
+        Poll::Ready(Ok(LeveledGuard {
+            guard,
+            _held: PhantomData,
+        }))
+    }
+}
+
+/// A `Qutex` of level `L`, checked at compile time to be locked only by
+/// tasks holding locks of lower levels alone.
+///
+/// The type-level counterpart of `QutexBuilder::level`, costing nothing at
+/// run time, in exchange for threading a token through to every lock taken:
+/// locking takes a `HeldBelow` token, or a `HeldAt` split from the guard of
+/// a lower level, and a program locking out of order fails to build.
+///
+/// ```
+/// use qutex::{HeldBelow, LeveledGuard, LeveledQutex};
+///
+/// let accounts = LeveledQutex::<_, 1>::new(vec![10, 20]);
+/// let journal = LeveledQutex::<_, 2>::new(Vec::new());
+///
+/// let mut held = HeldBelow::<0>::new();
+/// let mut guard = accounts.lock(&mut held).wait().unwrap();
+/// let (accounts, mut held) = LeveledGuard::split(&mut guard);
+/// accounts.swap(0, 1);
+/// journal.lock(&mut held).wait().unwrap().push("swapped");
+/// ```
+///
+/// Taking the same locks the other way round does not build:
+///
+/// ```compile_fail
+/// use qutex::{HeldBelow, LeveledGuard, LeveledQutex};
+///
+/// let accounts = LeveledQutex::<_, 1>::new(vec![10, 20]);
+/// let journal = LeveledQutex::<_, 2>::new(Vec::<&str>::new());
+///
+/// let mut held = HeldBelow::<0>::new();
+/// let mut guard = journal.lock(&mut held).wait().unwrap();
+/// let (_, mut held) = LeveledGuard::split(&mut guard);
+/// accounts.lock(&mut held).wait().unwrap().swap(0, 1);
+/// ```
+pub struct LeveledQutex<T: ?Sized, const L: u32> {
+    qutex: Qutex<T>,
+}
+
+impl<T, const L: u32> LeveledQutex<T, L> {
+    /// Creates and returns a new `LeveledQutex`.
+    #[inline]
+    pub fn new(val: T) -> LeveledQutex<T, L> {
+        LeveledQutex::from_qutex(Qutex::new(val))
+    }
+}
+
+impl<T: ?Sized, const L: u32> LeveledQutex<T, L> {
+    /// The level of the lock.
+    pub const LEVEL: u32 = L;
+
+    /// Returns a new `LeveledQutex` of level `L` sharing `qutex`.
+    ///
+    /// Locking `qutex` directly is not checked.
+    #[inline]
+    pub fn from_qutex(qutex: Qutex<T>) -> LeveledQutex<T, L> {
+        LeveledQutex { qutex }
+    }
+
+    /// Returns a new `FutureLeveledGuard` which will resolve into a
+    /// `LeveledGuard`, given proof that the task only holds locks of a lower
+    /// level, and borrowing it until released.
+    ///
+    /// Fails to build if `held` allows for a lock of level `L` or above being
+    /// held.
+    #[inline]
+    #[track_caller]
+    pub fn lock<'h, H: HoldsBelow>(self, _held: &'h mut H) -> FutureLeveledGuard<'h, T, L> {
+        const {
+            assert!(
+                H::BELOW <= L,
+                "LeveledQutex::lock: Locks taken out of order."
+            );
+        }
+        FutureLeveledGuard {
+            future_guard: self.qutex.lock(),
+            _held: PhantomData,
+        }
+    }
+
+    /// Returns the `Qutex` this lock shares.
+    #[inline]
+    pub fn as_qutex(&self) -> &Qutex<T> {
+        &self.qutex
+    }
+}
+
+impl<T: ?Sized, const L: u32> Clone for LeveledQutex<T, L> {
+    #[inline]
+    fn clone(&self) -> LeveledQutex<T, L> {
+        LeveledQutex {
+            qutex: self.qutex.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized, const L: u32> fmt::Debug for LeveledQutex<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LeveledQutex")
+            .field("level", &L)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested() {
+        let outer = LeveledQutex::<_, 1>::new(1);
+        let inner = LeveledQutex::<_, 3>::new(2);
+
+        let mut held = HeldBelow::<0>::new();
+        let mut guard = outer.clone().lock(&mut held).wait().unwrap();
+        let (outer_val, mut held_outer) = LeveledGuard::split(&mut guard);
+        let mut inner_guard = inner.clone().lock(&mut held_outer).wait().unwrap();
+        *inner_guard += *outer_val;
+        *outer_val = 0;
+        assert!(outer.as_qutex().try_lock().is_none());
+        drop(inner_guard);
+        drop(guard);
+
+        // Skipping levels, or starting part way up:
+        assert_eq!(*inner.lock(&mut HeldBelow::<2>::new()).wait().unwrap(), 3);
+        assert_eq!(*outer.lock(&mut held).wait().unwrap(), 0);
+    }
+}
@@ -5,20 +5,202 @@
 //! [![](https://img.shields.io/badge/github-qutex-blue.svg)][repo] [![](http://meritbadge.herokuapp.com/qutex)](https://crates.io/crates/qutex)
 //!
 //! [repo]: https://github.com/cogciprocate/qutex
+//!
+//! Without the default `std` feature, only `Qutex`, `LocalQutex`, `SpinQutex`,
+//! `IntrusiveQutex` and `ArrayQutex` (and their guards) are available, requiring
+//! just `alloc`. Blocking (`wait`), timed operations, `submit`, and poisoning on
+//! panic require `std`, as does every other lock.
+//!
+//! The default `crossbeam` feature builds the locks on `crossbeam`'s
+//! lock-free queue. Without it, a small internal spin-locked queue is used
+//! instead, so that the crate depends on nothing beyond `futures` (and, with
+//! `std`, `arc-swap`).
+//!
+//! With the `fixed_queue` feature, each `Qutex` queues its requests in
+//! lock-free queues of a fixed capacity per priority level, which never
+//! grow or allocate once created. Requests beyond the capacity fail with
+//! `Error::QueueFull`, other than those made by blocking wrappers such as
+//! `RawQutex`, which request the lock again. The lock and each queued
+//! request are still allocated.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate arc_swap;
+#[cfg(feature = "crossbeam")]
 extern crate crossbeam;
 extern crate futures;
 
-#[cfg(feature = "async_await")]
-mod async_await;
+/// Marks each item as requiring the `std` feature.
+macro_rules! cfg_std {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "std")]
+            $item
+        )*
+    };
+}
+
+#[cfg(feature = "derive")]
+pub use qutex_derive::QutexFields;
 
-mod qrw_lock;
+mod array;
+mod async_lock;
+#[cfg(feature = "deadlock_detection")]
+mod deadlock;
+#[cfg(feature = "debug_dump")]
+mod dump;
+mod error;
+mod intrusive;
+#[cfg(all(feature = "ipc", target_os = "linux"))]
+pub mod ipc;
+mod local;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "tower")]
+mod middleware;
+mod queue;
 mod qutex;
+#[cfg(feature = "lock_api")]
+mod raw;
+#[cfg(all(feature = "readiness", target_os = "linux"))]
+mod readiness;
+#[cfg(feature = "registry")]
+mod registry;
+#[cfg(feature = "serde")]
+mod serde_impls;
+mod spin;
+#[cfg(feature = "tokio")]
+mod tokio_rt;
+mod wait_queue;
 
-pub use self::qrw_lock::{
-    FutureReadGuard, FutureWriteGuard, QrwLock, QrwRequest, ReadGuard, RequestKind, WriteGuard,
+cfg_std! {
+    pub mod compat;
+    pub mod stm;
+    pub mod testing;
+
+    mod barrier;
+    mod blocking;
+    mod chunked;
+    mod condvar;
+    mod double_buffer;
+    #[cfg(all(feature = "elision", target_arch = "x86_64"))]
+    mod elision;
+    mod entry;
+    mod finalize;
+    mod group;
+    mod hierarchical;
+    mod indexed;
+    mod latch;
+    mod leased;
+    mod leveled;
+    mod lock_manager;
+    mod map;
+    mod notify;
+    mod once;
+    mod once_cell;
+    mod pool;
+    mod projected;
+    mod qrw_lock;
+    mod rank;
+    mod rate_limiter;
+    mod rcu;
+    mod reentrant;
+    mod seq;
+    mod semaphore;
+    mod sharded;
+    mod stream;
+    mod sync;
+    mod timer;
+    mod wait_list;
+    mod watch;
+}
+
+pub use self::array::{ArrayGuard, ArrayQutex, FutureArrayGuard};
+pub use self::async_lock::AsyncLock;
+#[cfg(feature = "deadlock_detection")]
+pub use self::deadlock::{check_deadlocks, spawn_deadlock_detector, Deadlock, DeadlockedLock};
+#[cfg(feature = "debug_dump")]
+pub use self::dump::{LockDump, PendingRequest};
+pub use self::error::Error;
+pub use self::intrusive::{FutureIntrusiveGuard, IntrusiveGuard, IntrusiveQutex};
+pub use self::local::{FutureLocalGuard, LocalGuard, LocalQutex};
+pub use self::qutex::{
+    Admission, Backoff, CowGuard, CriticalSection, EventOrder, FutureCommit, FutureCowGuard,
+    FutureEventGuard, FutureGuard, FutureLockAndThen, FutureLockWithToken, FuturePeek,
+    FutureRawState, FutureRefGuard, FutureSwap, FutureWithUnlocked, Guard, GuardToken,
+    PendingLock, Priority, Qutex, QutexBuilder, QutexCore, RawQutexState, RefGuard, Request, Tag,
+    WaitEvent, WaitStrategy,
 };
-pub use self::qutex::{FutureGuard, Guard, Qutex, Request};
+#[cfg(feature = "metrics")]
+pub use self::metrics::LockStats;
+#[cfg(feature = "tower")]
+pub use self::middleware::{FutureSerialized, QutexLayer, QutexService};
+#[cfg(feature = "lock_api")]
+pub use self::raw::{
+    QrwRwLock, QrwRwLockReadGuard, QrwRwLockWriteGuard, QutexMutex, QutexMutexGuard, RawQrwLock,
+    RawQutex,
+};
+#[cfg(all(feature = "readiness", target_os = "linux"))]
+pub use self::readiness::ReadinessFd;
+#[cfg(feature = "registry")]
+pub use self::registry::{registered_locks, LockInfo};
+pub use self::spin::{FutureSpinGuard, SpinGuard, SpinQutex};
+#[cfg(feature = "tokio")]
+pub use self::tokio_rt::TokioTimer;
+
+cfg_std! {
+    pub use self::barrier::{Barrier, BarrierWaitResult, FutureBarrier};
+    pub use self::chunked::{ChunkedQutex, FutureRangeGuard, RangeGuard};
+    pub use self::condvar::{
+        Condvar, FutureWait, FutureWaitTimeout, FutureWaitWhile, WaitTimeoutResult,
+    };
+    pub use self::double_buffer::{
+        BackGuard, DoubleBuffer, FrontGuard, FutureBackGuard, FuturePublish,
+    };
+    pub use self::entry::{EntryGuard, FutureEntryGuard};
+    pub use self::group::{
+        join_guards, AcquireSlot, FutureGroupGuard, FutureLockAll, GuardTuple, LockSlot,
+        QutexGroup, SlotTuple,
+    };
+    pub use self::hierarchical::{
+        FutureHierarchyGuard, HierarchicalLock, HierarchyGuard, LockMode,
+    };
+    pub use self::indexed::{FutureIndexGuard, IndexGuard, IndexedQutex};
+    pub use self::latch::{FutureLatch, Latch};
+    pub use self::leased::{FutureLeaseGuard, LeaseGuard, LeasedQutex};
+    pub use self::leveled::{
+        FutureLeveledGuard, HeldAt, HeldBelow, HoldsBelow, LeveledGuard, LeveledQutex,
+    };
+    pub use self::lock_manager::{
+        FutureManagedGuard, LockManager, ManagedGuard, Transaction, TxnId,
+    };
+    pub use self::map::{Eviction, QrwLockMap, QutexMap};
+    pub use self::notify::{Event, FutureEvent, FutureNotified, Notify};
+    pub use self::once::{FutureOnce, Once};
+    pub use self::once_cell::{FutureGetOrInit, FutureLazy, Lazy, OnceCell};
+    pub use self::pool::{FuturePooledGuard, Pool, PooledGuard};
+    pub use self::projected::{FutureProjectedGuard, ProjectedGuard, ProjectedQutex};
+    pub use self::qrw_lock::{
+        FutureReadGuard, FutureWriteGuard, QrwLock, QrwRequest, ReadGuard, RequestKind,
+        RwPolicy, WriteGuard,
+    };
+    pub use self::qutex::{
+        FutureCheckedGuard, FutureLockTimed, FutureLockTimeout, FutureSubmit, FutureTimedGuard,
+        Holder, LongWait,
+    };
+    pub use self::rate_limiter::{FutureToken, RateLimiter};
+    pub use self::rcu::{FutureUpdate, RcuQutex};
+    pub use self::reentrant::{FutureReentrantGuard, Owner, ReentrantGuard, ReentrantQutex};
+    pub use self::seq::{FutureSeqWriteGuard, SeqQutex, SeqWriteGuard};
+    pub use self::semaphore::{FuturePermit, Permit, PermitStream, Semaphore};
+    pub use self::sharded::ShardedQutex;
+    pub use self::stream::{GuardedIo, GuardedSink, GuardedStream, LockStream, SinkError};
+    pub use self::sync::SyncQutex;
+    pub use self::timer::{set_timer, Sleep, ThreadTimer, Timer};
+    pub use self::watch::{FutureChanged, FutureWatchGuard, Subscription, Watch, WatchGuard};
+}
 
-#[cfg(feature = "async_await")]
-pub use async_await::*;
+#[cfg(feature = "futures-timer")]
+pub use self::timer::FuturesTimer;
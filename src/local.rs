@@ -0,0 +1,290 @@
+//! A single-threaded queue-backed exclusive data lock.
+//!
+//
+
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::convert::Infallible;
+use core::fmt;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use futures::executor;
+
+/// A queued lock request.
+#[derive(Debug, Default)]
+struct Waiter {
+    granted: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+struct Inner<T> {
+    locked: Cell<bool>,
+    cell: UnsafeCell<T>,
+    queue: RefCell<VecDeque<Rc<Waiter>>>,
+}
+
+impl<T> Inner<T> {
+    /// Grants the lock to the next queued request if it is free.
+    fn process_queue(&self) {
+        if self.locked.get() {
+            return;
+        }
+
+        if let Some(waiter) = self.queue.borrow_mut().pop_front() {
+            self.locked.set(true);
+            waiter.granted.set(true);
+            if let Some(waker) = waiter.waker.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Releases the lock, handing it to the next queued request.
+    fn unlock(&self) {
+        self.locked.set(false);
+        self.process_queue();
+    }
+}
+
+/// Allows access to the data contained within a `LocalQutex`.
+pub struct LocalGuard<T> {
+    qutex: LocalQutex<T>,
+}
+
+impl<T> LocalGuard<T> {
+    /// Releases the lock held by a `LocalGuard` and returns the original
+    /// `LocalQutex`.
+    pub fn unlock(guard: LocalGuard<T>) -> LocalQutex<T> {
+        let qutex = guard.qutex.clone();
+        drop(guard);
+        qutex
+    }
+}
+
+impl<T> Deref for LocalGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.qutex.inner.cell.get() }
+    }
+}
+
+impl<T> DerefMut for LocalGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.qutex.inner.cell.get() }
+    }
+}
+
+impl<T> Drop for LocalGuard<T> {
+    fn drop(&mut self) {
+        self.qutex.inner.unlock();
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for LocalGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("LocalGuard").field(&**self).finish()
+    }
+}
+
+/// A future which resolves to a `LocalGuard`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureLocalGuard<T> {
+    qutex: Option<LocalQutex<T>>,
+    waiter: Rc<Waiter>,
+}
+
+impl<T> FutureLocalGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<LocalGuard<T>, Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureLocalGuard<T> {
+    type Output = Result<LocalGuard<T>, Infallible>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.qutex.is_none() {
+            panic!("FutureLocalGuard::poll: Task already completed.");
+        }
+
+        if self.waiter.granted.get() {
+            Poll::Ready(Ok(LocalGuard {
+                qutex: self.qutex.take().unwrap(),
+            }))
+        } else {
+            *self.waiter.waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for FutureLocalGuard<T> {
+    /// Gracefully unlock if this request has been granted but has not yet
+    /// been polled to completion, otherwise leaves the queue.
+    fn drop(&mut self) {
+        if let Some(qutex) = self.qutex.take() {
+            if self.waiter.granted.get() {
+                qutex.inner.unlock();
+            } else {
+                qutex
+                    .inner
+                    .queue
+                    .borrow_mut()
+                    .retain(|waiter| !Rc::ptr_eq(waiter, &self.waiter));
+            }
+        }
+    }
+}
+
+/// A single-threaded `Qutex`.
+///
+/// Shares the shape of `Qutex`, queueing requests in FIFO order, but is
+/// built on `Rc` and `Cell` rather than atomics, and neither it nor the
+/// data it protects need be `Send`. Best suited to wasm32 and
+/// thread-per-core designs, where synchronizing across threads is pure
+/// overhead.
+pub struct LocalQutex<T> {
+    inner: Rc<Inner<T>>,
+}
+
+impl<T> LocalQutex<T> {
+    /// Creates and returns a new `LocalQutex`.
+    #[inline]
+    pub fn new(val: T) -> LocalQutex<T> {
+        LocalQutex {
+            inner: Rc::new(Inner {
+                locked: Cell::new(false),
+                cell: UnsafeCell::new(val),
+                queue: RefCell::new(VecDeque::new()),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureLocalGuard` which can be used as a future and
+    /// will resolve into a `LocalGuard`.
+    ///
+    /// The request is queued immediately.
+    pub fn lock(self) -> FutureLocalGuard<T> {
+        let waiter = Rc::new(Waiter::default());
+        self.inner.queue.borrow_mut().push_back(waiter.clone());
+        self.inner.process_queue();
+
+        FutureLocalGuard {
+            qutex: Some(self),
+            waiter,
+        }
+    }
+
+    /// Returns a `LocalGuard` if the lock is free and no requests are
+    /// queued.
+    pub fn try_lock(&self) -> Option<LocalGuard<T>> {
+        if self.inner.locked.get() || !self.inner.queue.borrow().is_empty() {
+            return None;
+        }
+
+        self.inner.locked.set(true);
+        Some(LocalGuard {
+            qutex: self.clone(),
+        })
+    }
+
+    /// Returns a mutable reference to the inner value if there are currently
+    /// no other copies of this `LocalQutex`.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Rc::get_mut(&mut self.inner).map(|inner| inner.cell.get_mut())
+    }
+
+    /// Returns a reference to the inner value.
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        self.inner.cell.get()
+    }
+
+    /// Returns a mutable reference to the inner value.
+    #[inline]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.inner.cell.get()
+    }
+}
+
+impl<T> From<T> for LocalQutex<T> {
+    #[inline]
+    fn from(val: T) -> LocalQutex<T> {
+        LocalQutex::new(val)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for LocalQutex<T> {
+    #[inline]
+    fn clone(&self) -> LocalQutex<T> {
+        LocalQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for LocalQutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalQutex")
+            .field("locked", &self.inner.locked.get())
+            .field("queued", &self.inner.queue.borrow().len())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::rc::Rc;
+
+    #[test]
+    fn fifo() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        // Not `Send`:
+        let qutex = LocalQutex::new(Rc::new(Vec::new()));
+
+        let guard = qutex.clone().lock().wait().unwrap();
+        let mut queued = (0..3).map(|_| qutex.clone().lock()).collect::<Vec<_>>();
+        assert!(queued[0].poll_unpin(cx).is_pending());
+        assert!(qutex.try_lock().is_none());
+        drop(guard);
+
+        for (i, future_guard) in queued.drain(..).enumerate() {
+            let mut guard = future_guard.wait().unwrap();
+            Rc::get_mut(&mut guard).unwrap().push(i);
+        }
+
+        assert_eq!(*qutex.lock().wait().unwrap().as_slice(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn dropped_requests() {
+        let qutex = LocalQutex::new(0);
+        let guard = qutex.clone().lock().wait().unwrap();
+        let queued = qutex.clone().lock();
+        let granted = qutex.clone().lock();
+
+        // Leaves the queue:
+        drop(queued);
+        drop(guard);
+        // Granted but never polled, so releases:
+        drop(granted);
+
+        *qutex.try_lock().unwrap() += 1;
+        let mut qutex = qutex;
+        assert_eq!(qutex.get_mut(), Some(&mut 1));
+    }
+}
@@ -0,0 +1,400 @@
+//! Deadlock detection for transactions acquiring several locks.
+//!
+//
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::channel::oneshot::{self, Receiver, Sender};
+use futures::executor;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Identifies a `Transaction`. Later transactions have greater ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxnId(u64);
+
+#[derive(Debug, Default)]
+struct LockState {
+    holder: Option<TxnId>,
+    // Transactions waiting for the lock, in request order:
+    waiters: Vec<TxnId>,
+}
+
+#[derive(Debug)]
+struct Waiting {
+    lock: usize,
+    // Completed to abort the request:
+    abort: Option<Sender<()>>,
+}
+
+/// The wait-for graph.
+#[derive(Debug, Default)]
+struct Graph {
+    locks: HashMap<usize, LockState>,
+    waiting: HashMap<TxnId, Waiting>,
+}
+
+impl Graph {
+    /// Returns the transactions `txn` is waiting for: the holder of the lock
+    /// it is waiting on, and everyone queued for that lock before it.
+    fn blockers(&self, txn: TxnId) -> Vec<TxnId> {
+        let lock = match self.waiting.get(&txn) {
+            Some(waiting) => waiting.lock,
+            None => return Vec::new(),
+        };
+        let state = &self.locks[&lock];
+
+        state
+            .holder
+            .into_iter()
+            .chain(state.waiters.iter().cloned().take_while(|&w| w != txn))
+            .collect()
+    }
+
+    /// Returns the transactions forming a cycle back to `start`, if any.
+    fn find_cycle(&self, start: TxnId) -> Option<Vec<TxnId>> {
+        fn visit(
+            graph: &Graph,
+            txn: TxnId,
+            start: TxnId,
+            path: &mut Vec<TxnId>,
+            visited: &mut HashSet<TxnId>,
+        ) -> bool {
+            for blocker in graph.blockers(txn) {
+                if blocker == start {
+                    return true;
+                }
+                if visited.insert(blocker) {
+                    path.push(blocker);
+                    if visit(graph, blocker, start, path, visited) {
+                        return true;
+                    }
+                    path.pop();
+                }
+            }
+            false
+        }
+
+        let mut path = vec![start];
+        let mut visited = HashSet::new();
+        if visit(self, start, start, &mut path, &mut visited) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Removes `txn` from the queue of the lock it is waiting on, returning
+    /// its abort sender.
+    fn withdraw(&mut self, txn: TxnId) -> Option<Sender<()>> {
+        let waiting = self.waiting.remove(&txn)?;
+
+        let state = self.locks.get_mut(&waiting.lock).unwrap();
+        state.waiters.retain(|&w| w != txn);
+        if state.holder.is_none() && state.waiters.is_empty() {
+            self.locks.remove(&waiting.lock);
+        }
+
+        waiting.abort
+    }
+
+    /// Records that `txn` no longer holds `lock`.
+    fn release(&mut self, txn: TxnId, lock: usize) {
+        if let Some(state) = self.locks.get_mut(&lock) {
+            if state.holder == Some(txn) {
+                state.holder = None;
+            }
+            if state.holder.is_none() && state.waiters.is_empty() {
+                self.locks.remove(&lock);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ManagerInner {
+    graph: Mutex<Graph>,
+    next_id: AtomicU64,
+}
+
+/// Allows access to the data contained within a lock acquired through a
+/// `Transaction`.
+#[derive(Debug)]
+pub struct ManagedGuard<T> {
+    manager: Arc<ManagerInner>,
+    txn: TxnId,
+    lock: usize,
+    guard: Option<Guard<T>>,
+}
+
+impl<T> Deref for ManagedGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for ManagedGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for ManagedGuard<T> {
+    fn drop(&mut self) {
+        self.manager
+            .graph
+            .lock()
+            .unwrap()
+            .release(self.txn, self.lock);
+        drop(self.guard.take());
+    }
+}
+
+/// A future which resolves to a `ManagedGuard`, or to `Error::Deadlocked` if
+/// the request was chosen to be aborted.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureManagedGuard<T> {
+    manager: Arc<ManagerInner>,
+    txn: TxnId,
+    lock: usize,
+    // `None` once complete or if aborted before queueing:
+    future_guard: Option<FutureGuard<T>>,
+    abort: Option<Receiver<()>>,
+}
+
+impl<T> FutureManagedGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<ManagedGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureManagedGuard<T> {
+    type Output = Result<ManagedGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let abort = match this.abort {
+            Some(ref mut abort) => abort,
+            None => return Poll::Ready(Err(Error::Deadlocked)),
+        };
+
+        // Dropping the abort sender means the manager has already withdrawn
+        // us, which also happens only to abort:
+        if Pin::new(abort).poll(cx).is_ready() {
+            this.abort = None;
+            this.future_guard = None;
+            return Poll::Ready(Err(Error::Deadlocked));
+        }
+
+        let guard = match this.future_guard {
+            Some(ref mut future_guard) => futures::ready!(Pin::new(future_guard).poll(cx))?,
+            None => panic!("FutureManagedGuard::poll: Task already completed."),
+        };
+        this.future_guard = None;
+
+        let mut graph = this.manager.graph.lock().unwrap();
+        if graph.withdraw(this.txn).is_none() {
+            // Aborted while being granted:
+            this.abort = None;
+            return Poll::Ready(Err(Error::Deadlocked));
+        }
+        graph.locks.entry(this.lock).or_default().holder = Some(this.txn);
+        drop(graph);
+
+        Poll::Ready(Ok(ManagedGuard {
+            manager: this.manager.clone(),
+            txn: this.txn,
+            lock: this.lock,
+            guard: Some(guard),
+        }))
+    }
+}
+
+impl<T> Drop for FutureManagedGuard<T> {
+    fn drop(&mut self) {
+        if self.future_guard.is_some() {
+            self.manager.graph.lock().unwrap().withdraw(self.txn);
+        }
+    }
+}
+
+/// A single logical task acquiring locks through a `LockManager`.
+///
+/// A transaction may wait for only one lock at a time.
+#[derive(Debug)]
+pub struct Transaction {
+    manager: Arc<ManagerInner>,
+    id: TxnId,
+}
+
+impl Transaction {
+    /// Returns this transaction's id.
+    #[inline]
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    /// Returns a new `FutureManagedGuard` which will resolve into a guard
+    /// for `qutex`.
+    ///
+    /// If waiting would complete a cycle of transactions waiting on one
+    /// another, the youngest transaction in the cycle is aborted: its
+    /// request resolves to `Error::Deadlocked` and is removed from the queue.
+    /// Re-locking a lock this transaction already holds is always a
+    /// deadlock.
+    ///
+    /// Panics if this transaction is already waiting for a lock.
+    pub fn lock<T>(&self, qutex: Qutex<T>) -> FutureManagedGuard<T> {
+        let lock = qutex.as_ptr() as usize;
+        let (abort_tx, abort_rx) = oneshot::channel();
+        let mut graph = self.manager.graph.lock().unwrap();
+
+        assert!(
+            !graph.waiting.contains_key(&self.id),
+            "Transaction::lock: A transaction may only wait for one lock at a time."
+        );
+
+        let state = graph.locks.entry(lock).or_default();
+        let aborted = if state.holder == Some(self.id) {
+            true
+        } else {
+            state.waiters.push(self.id);
+            graph.waiting.insert(
+                self.id,
+                Waiting {
+                    lock,
+                    abort: Some(abort_tx),
+                },
+            );
+
+            match graph.find_cycle(self.id) {
+                Some(cycle) => {
+                    let victim = cycle.into_iter().max().unwrap();
+                    let abort = graph.withdraw(victim);
+                    if victim != self.id {
+                        if let Some(abort) = abort {
+                            let _ = abort.send(());
+                        }
+                    }
+                    victim == self.id
+                }
+                None => false,
+            }
+        };
+        drop(graph);
+
+        FutureManagedGuard {
+            manager: self.manager.clone(),
+            txn: self.id,
+            lock,
+            future_guard: if aborted { None } else { Some(qutex.lock()) },
+            abort: if aborted { None } else { Some(abort_rx) },
+        }
+    }
+}
+
+/// Tracks which transactions hold and await which locks, detecting and
+/// breaking deadlocks among them.
+///
+/// Only locks acquired through this manager's transactions are tracked.
+#[derive(Debug, Clone)]
+pub struct LockManager {
+    inner: Arc<ManagerInner>,
+}
+
+impl LockManager {
+    /// Creates and returns a new `LockManager`.
+    pub fn new() -> LockManager {
+        LockManager {
+            inner: Arc::new(ManagerInner {
+                graph: Mutex::new(Graph::default()),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Begins and returns a new `Transaction`.
+    pub fn begin(&self) -> Transaction {
+        Transaction {
+            manager: self.inner.clone(),
+            id: TxnId(self.inner.next_id.fetch_add(1, SeqCst)),
+        }
+    }
+}
+
+impl Default for LockManager {
+    #[inline]
+    fn default() -> LockManager {
+        LockManager::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn detects_cycle() {
+        let manager = LockManager::new();
+        let (a, b) = (Qutex::new(0), Qutex::new(0));
+        let (txn_0, txn_1) = (manager.begin(), manager.begin());
+
+        let _a_0 = txn_0.lock(a.clone()).wait().unwrap();
+        let b_1 = txn_1.lock(b.clone()).wait().unwrap();
+
+        let thread = thread::spawn(move || {
+            // Blocks until `txn_1` is aborted and releases `b`:
+            let b_0 = txn_0.lock(b).wait().unwrap();
+            assert_eq!(*b_0, 0);
+        });
+
+        thread::sleep(::std::time::Duration::from_millis(20));
+        // `txn_1` is the youngest, so is the victim:
+        assert_eq!(txn_1.lock(a).wait().unwrap_err(), Error::Deadlocked);
+        drop(b_1);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn aborts_youngest_waiter() {
+        let manager = LockManager::new();
+        let (a, b) = (Qutex::new(()), Qutex::new(()));
+        let (txn_0, txn_1) = (manager.begin(), manager.begin());
+
+        let _b_0 = txn_0.lock(b.clone()).wait().unwrap();
+        let a_1 = txn_1.lock(a.clone()).wait().unwrap();
+        let waiting_1 = txn_1.lock(b);
+
+        // Completing the cycle aborts the already waiting `txn_1`:
+        let a_0 = txn_0.lock(a);
+        assert_eq!(waiting_1.wait().unwrap_err(), Error::Deadlocked);
+        drop(a_1);
+        a_0.wait().unwrap();
+    }
+
+    #[test]
+    fn relock_is_deadlock() {
+        let manager = LockManager::new();
+        let lock = Qutex::new(());
+        let txn = manager.begin();
+
+        let guard = txn.lock(lock.clone()).wait().unwrap();
+        assert_eq!(
+            txn.lock(lock.clone()).wait().unwrap_err(),
+            Error::Deadlocked
+        );
+        drop(guard);
+        txn.lock(lock).wait().unwrap();
+    }
+}
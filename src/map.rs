@@ -0,0 +1,649 @@
+//! Maps of independently locked values.
+//!
+//
+
+use crate::{FutureGuard, FutureReadGuard, FutureWriteGuard, QrwLock, Qutex};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+type InitFn<K, L> = dyn Fn(&K) -> L + Send + Sync;
+
+/// The minimum number of entries before an `Eviction::Idle` map sweeps.
+const MIN_SWEEP_LEN: usize = 16;
+
+/// The policy by which a `QutexMap` or `QrwLockMap` discards entries nobody
+/// is using.
+///
+/// An entry is idle when its lock is neither held nor requested. Discarding
+/// an idle entry drops its value; locking the same key again creates a new
+/// one with the map's initializer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// Entries stay in the map until removed.
+    Never,
+    /// Idle entries are discarded. The map is swept whenever it has doubled
+    /// in size since the last sweep, or when `evict_idle` is called.
+    Idle,
+    /// At most the given number of entries are kept. Inserting beyond this
+    /// bound discards the least recently used idle entry. If every entry is
+    /// in use, the bound is temporarily exceeded.
+    Lru(usize),
+}
+
+impl Default for Eviction {
+    #[inline]
+    fn default() -> Eviction {
+        Eviction::Never
+    }
+}
+
+/// A lock kept per key.
+trait KeyLock: Clone {
+    /// Returns `true` if nobody is holding or requesting the lock, nor has
+    /// a copy of it.
+    fn is_idle(&self) -> bool;
+}
+
+impl<T> KeyLock for Qutex<T> {
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.is_unique()
+    }
+}
+
+impl<T> KeyLock for QrwLock<T> {
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.is_unique()
+    }
+}
+
+#[derive(Debug)]
+struct Entry<L> {
+    lock: L,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct Entries<K, L> {
+    map: HashMap<K, Entry<L>>,
+    eviction: Eviction,
+    tick: u64,
+    sweep_len: usize,
+}
+
+impl<K, L> Entries<K, L>
+where
+    K: Eq + Hash + Clone,
+    L: KeyLock,
+{
+    /// Returns the next usage tick.
+    #[inline]
+    fn tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Removes every idle entry, returning the number removed.
+    fn evict_idle(&mut self) -> usize {
+        let len = self.map.len();
+        self.map.retain(|_, entry| !entry.lock.is_idle());
+        self.sweep_len = MIN_SWEEP_LEN.max(self.map.len() * 2);
+        len - self.map.len()
+    }
+
+    /// Removes the least recently used idle entry, if any.
+    fn evict_lru(&mut self) {
+        let key = self
+            .map
+            .iter()
+            .filter(|(_, entry)| entry.lock.is_idle())
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = key {
+            self.map.remove(&key);
+        }
+    }
+
+    /// Makes room for a new entry according to the eviction policy.
+    fn make_room(&mut self) {
+        match self.eviction {
+            Eviction::Never => (),
+            Eviction::Idle => {
+                if self.map.len() >= self.sweep_len {
+                    self.evict_idle();
+                }
+            }
+            Eviction::Lru(capacity) => {
+                if self.map.len() >= capacity {
+                    self.evict_lru();
+                }
+            }
+        }
+    }
+}
+
+struct Inner<K, L> {
+    entries: Mutex<Entries<K, L>>,
+    init: Box<InitFn<K, L>>,
+}
+
+impl<K, L> Inner<K, L>
+where
+    K: Eq + Hash + Clone,
+    L: KeyLock,
+{
+    fn new(init: Box<InitFn<K, L>>) -> Inner<K, L> {
+        Inner {
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                eviction: Eviction::Never,
+                tick: 0,
+                sweep_len: MIN_SWEEP_LEN,
+            }),
+            init,
+        }
+    }
+
+    fn entry(&self, key: K) -> L {
+        let mut entries = self.entries.lock().unwrap();
+        let tick = entries.tick();
+
+        if let Some(entry) = entries.map.get_mut(&key) {
+            entry.last_used = tick;
+            return entry.lock.clone();
+        }
+
+        entries.make_room();
+        let lock = (self.init)(&key);
+        entries.map.insert(
+            key,
+            Entry {
+                lock: lock.clone(),
+                last_used: tick,
+            },
+        );
+        lock
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<L>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        let tick = entries.tick();
+        entries.map.get_mut(key).map(|entry| {
+            entry.last_used = tick;
+            entry.lock.clone()
+        })
+    }
+
+    fn remove<Q>(&self, key: &Q) -> Option<L>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let entry = self.entries.lock().unwrap().map.remove(key);
+        entry.map(|entry| entry.lock)
+    }
+
+    fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries.lock().unwrap().map.contains_key(key)
+    }
+}
+
+impl<K: fmt::Debug, L> Inner<K, L> {
+    fn fmt(&self, name: &str, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.entries.try_lock() {
+            Ok(entries) => f
+                .debug_struct(name)
+                .field("keys", &entries.map.keys().collect::<Vec<_>>())
+                .field("eviction", &entries.eviction)
+                .finish(),
+            Err(_) => f.debug_struct(name).field("keys", &"<locked>").finish(),
+        }
+    }
+}
+
+/// A map of per-key `Qutex`es.
+///
+/// Locking a key serializes access to that key's value only; tasks locking
+/// different keys never wait on one another. Values are created on first
+/// use, either with `T::default()` or with the initializer passed to
+/// `with_init`.
+///
+/// The map itself is only briefly (and synchronously) locked while looking up
+/// or inserting an entry. By default, entries stay in the map until removed;
+/// see `Eviction` for automatic cleanup.
+pub struct QutexMap<K, T> {
+    inner: Arc<Inner<K, Qutex<T>>>,
+}
+
+impl<K, T> QutexMap<K, T>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Creates and returns a new `QutexMap` whose values are initialized with
+    /// `T::default()`.
+    #[inline]
+    pub fn new() -> QutexMap<K, T>
+    where
+        T: Default,
+    {
+        QutexMap::with_init(|_| T::default())
+    }
+
+    /// Creates and returns a new `QutexMap` whose values are initialized by
+    /// calling `init` with the key being inserted.
+    pub fn with_init<F>(init: F) -> QutexMap<K, T>
+    where
+        F: Fn(&K) -> T + Send + Sync + 'static,
+    {
+        QutexMap {
+            inner: Arc::new(Inner::new(Box::new(move |key| Qutex::new(init(key))))),
+        }
+    }
+
+    /// Sets the eviction policy and returns the map.
+    #[inline]
+    pub fn eviction(self, eviction: Eviction) -> QutexMap<K, T> {
+        self.set_eviction(eviction);
+        self
+    }
+
+    /// Sets the eviction policy, which takes effect as of the next insertion.
+    pub fn set_eviction(&self, eviction: Eviction) {
+        self.inner.entries.lock().unwrap().eviction = eviction;
+    }
+
+    /// Returns a new `FutureGuard` for the value of `key`, inserting a new
+    /// value first if it is absent.
+    pub fn lock(&self, key: K) -> FutureGuard<T> {
+        self.entry(key).lock()
+    }
+
+    /// Returns the `Qutex` for `key`, inserting a new value first if it is
+    /// absent.
+    ///
+    /// An entry is not idle while a copy of its `Qutex` exists.
+    pub fn entry(&self, key: K) -> Qutex<T> {
+        self.inner.entry(key)
+    }
+
+    /// Returns the `Qutex` for `key` if it is present.
+    pub fn get<Q>(&self, key: &Q) -> Option<Qutex<T>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.get(key)
+    }
+
+    /// Removes `key` from the map, returning its `Qutex` if it was present.
+    ///
+    /// Any outstanding guards or requests for the removed value remain valid.
+    /// A subsequent `lock` of the same key will create a new value.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Qutex<T>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.remove(key)
+    }
+
+    /// Removes every idle entry, regardless of the eviction policy, returning
+    /// the number removed.
+    pub fn evict_idle(&self) -> usize {
+        self.inner.entries.lock().unwrap().evict_idle()
+    }
+
+    /// Returns `true` if the map contains `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.contains_key(key)
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.inner.entries.lock().unwrap().map.len()
+    }
+
+    /// Returns `true` if the map contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.inner.entries.lock().unwrap().map.is_empty()
+    }
+
+    /// Returns a copy of every entry.
+    #[cfg(feature = "serde")]
+    pub(crate) fn entries(&self) -> Vec<(K, Qutex<T>)> {
+        let entries = self.inner.entries.lock().unwrap();
+        entries
+            .map
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.lock.clone()))
+            .collect()
+    }
+
+    /// Inserts `val` for `key`, replacing any value present.
+    #[cfg(feature = "serde")]
+    pub(crate) fn insert(&self, key: K, val: T) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        let tick = entries.tick();
+        entries.make_room();
+        entries.map.insert(
+            key,
+            Entry {
+                lock: Qutex::new(val),
+                last_used: tick,
+            },
+        );
+    }
+}
+
+impl<K, T> Default for QutexMap<K, T>
+where
+    K: Eq + Hash + Clone,
+    T: Default,
+{
+    #[inline]
+    fn default() -> QutexMap<K, T> {
+        QutexMap::new()
+    }
+}
+
+// Avoids needing `K: Clone, T: Clone`.
+impl<K, T> Clone for QutexMap<K, T> {
+    #[inline]
+    fn clone(&self) -> QutexMap<K, T> {
+        QutexMap {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, T> fmt::Debug for QutexMap<K, T>
+where
+    K: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt("QutexMap", f)
+    }
+}
+
+/// A map of per-key `QrwLock`s, the read/write counterpart of `QutexMap`.
+///
+/// Readers of the same key share its value, while a writer excludes
+/// everyone else using that key alone, suiting per-key caches which are
+/// mostly read. Values are created on first use and discarded as the
+/// `Eviction` policy allows, just as within a `QutexMap`.
+pub struct QrwLockMap<K, T> {
+    inner: Arc<Inner<K, QrwLock<T>>>,
+}
+
+impl<K, T> QrwLockMap<K, T>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Creates and returns a new `QrwLockMap` whose values are initialized
+    /// with `T::default()`.
+    #[inline]
+    pub fn new() -> QrwLockMap<K, T>
+    where
+        T: Default,
+    {
+        QrwLockMap::with_init(|_| T::default())
+    }
+
+    /// Creates and returns a new `QrwLockMap` whose values are initialized
+    /// by calling `init` with the key being inserted.
+    pub fn with_init<F>(init: F) -> QrwLockMap<K, T>
+    where
+        F: Fn(&K) -> T + Send + Sync + 'static,
+    {
+        QrwLockMap {
+            inner: Arc::new(Inner::new(Box::new(move |key| QrwLock::new(init(key))))),
+        }
+    }
+
+    /// Sets the eviction policy and returns the map.
+    #[inline]
+    pub fn eviction(self, eviction: Eviction) -> QrwLockMap<K, T> {
+        self.set_eviction(eviction);
+        self
+    }
+
+    /// Sets the eviction policy, which takes effect as of the next insertion.
+    pub fn set_eviction(&self, eviction: Eviction) {
+        self.inner.entries.lock().unwrap().eviction = eviction;
+    }
+
+    /// Returns a new `FutureReadGuard` for the value of `key`, inserting a
+    /// new value first if it is absent.
+    pub fn read(&self, key: K) -> FutureReadGuard<T> {
+        self.entry(key).read()
+    }
+
+    /// Returns a new `FutureWriteGuard` for the value of `key`, inserting a
+    /// new value first if it is absent.
+    pub fn write(&self, key: K) -> FutureWriteGuard<T> {
+        self.entry(key).write()
+    }
+
+    /// Returns the `QrwLock` for `key`, inserting a new value first if it is
+    /// absent.
+    ///
+    /// An entry is not idle while a copy of its `QrwLock` exists.
+    pub fn entry(&self, key: K) -> QrwLock<T> {
+        self.inner.entry(key)
+    }
+
+    /// Returns the `QrwLock` for `key` if it is present.
+    pub fn get<Q>(&self, key: &Q) -> Option<QrwLock<T>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.get(key)
+    }
+
+    /// Removes `key` from the map, returning its `QrwLock` if it was present.
+    ///
+    /// Any outstanding guards or requests for the removed value remain valid.
+    /// A subsequent `read` or `write` of the same key will create a new
+    /// value.
+    pub fn remove<Q>(&self, key: &Q) -> Option<QrwLock<T>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.remove(key)
+    }
+
+    /// Removes every idle entry, regardless of the eviction policy, returning
+    /// the number removed.
+    pub fn evict_idle(&self) -> usize {
+        self.inner.entries.lock().unwrap().evict_idle()
+    }
+
+    /// Returns `true` if the map contains `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.contains_key(key)
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.inner.entries.lock().unwrap().map.len()
+    }
+
+    /// Returns `true` if the map contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.inner.entries.lock().unwrap().map.is_empty()
+    }
+}
+
+impl<K, T> Default for QrwLockMap<K, T>
+where
+    K: Eq + Hash + Clone,
+    T: Default,
+{
+    #[inline]
+    fn default() -> QrwLockMap<K, T> {
+        QrwLockMap::new()
+    }
+}
+
+// Avoids needing `K: Clone, T: Clone`.
+impl<K, T> Clone for QrwLockMap<K, T> {
+    #[inline]
+    fn clone(&self) -> QrwLockMap<K, T> {
+        QrwLockMap {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, T> fmt::Debug for QrwLockMap<K, T>
+where
+    K: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt("QrwLockMap", f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn per_key_serialization() {
+        let map = QutexMap::<&'static str, usize>::new();
+
+        let threads = (0..8)
+            .map(|i| {
+                let map = map.clone();
+                let key = if i % 2 == 0 { "even" } else { "odd" };
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        *map.lock(key).wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(*map.lock("even").wait().unwrap(), 400);
+        assert_eq!(*map.lock("odd").wait().unwrap(), 400);
+    }
+
+    #[test]
+    fn keys_are_independent() {
+        let map = QutexMap::with_init(|key: &u32| *key * 10);
+
+        let guard_1 = map.lock(1).wait().unwrap();
+        // Would block forever if keys shared a lock:
+        let guard_2 = map.lock(2).wait().unwrap();
+        assert_eq!((*guard_1, *guard_2), (10, 20));
+
+        drop(guard_1);
+        let removed = map.remove(&1).unwrap();
+        assert!(!map.contains_key(&1));
+        assert_eq!(*removed.lock().wait().unwrap(), 10);
+
+        *map.lock(1).wait().unwrap() += 1;
+        assert_eq!(*map.get(&1).unwrap().lock().wait().unwrap(), 11);
+        drop(guard_2);
+    }
+
+    #[test]
+    fn evict_idle() {
+        let map = QutexMap::<u32, u32>::new().eviction(Eviction::Idle);
+        let held = map.lock(0).wait().unwrap();
+        let queued = map.lock(1);
+
+        for key in 2..MIN_SWEEP_LEN as u32 {
+            *map.lock(key).wait().unwrap() += 1;
+        }
+        assert_eq!(map.len(), MIN_SWEEP_LEN);
+
+        // Inserting at the sweep length discards all idle entries:
+        drop(map.lock(100));
+        assert_eq!(map.len(), 3);
+        assert!(map.contains_key(&0) && map.contains_key(&1));
+
+        drop(held);
+        drop(queued);
+        assert_eq!(map.evict_idle(), 3);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn evict_lru() {
+        let map = QutexMap::with_init(|key: &u32| *key).eviction(Eviction::Lru(2));
+        *map.lock(0).wait().unwrap() += 10;
+        drop(map.lock(1));
+
+        // Touch `0` so that `1` is least recently used:
+        drop(map.get(&0));
+        drop(map.lock(2));
+        assert!(map.contains_key(&0) && !map.contains_key(&1));
+        assert_eq!(*map.lock(0).wait().unwrap(), 10);
+
+        // Entries in use are never discarded:
+        let held = (map.lock(0).wait().unwrap(), map.lock(2).wait().unwrap());
+        drop(map.lock(3));
+        assert_eq!(map.len(), 3);
+        drop(held);
+    }
+
+    #[test]
+    fn rw_shared_reads_per_key() {
+        let map = QrwLockMap::with_init(|key: &&str| key.len());
+
+        let reads = (
+            map.read("ab").wait().unwrap(),
+            map.read("ab").wait().unwrap(),
+        );
+        // Would block forever if keys shared a lock:
+        *map.write("abc").wait().unwrap() += 1;
+        assert_eq!((*reads.0, *reads.1), (2, 2));
+
+        let writer = thread::spawn({
+            let map = map.clone();
+            move || *map.write("ab").wait().unwrap() = 0
+        });
+        drop(reads);
+        writer.join().unwrap();
+        assert_eq!(*map.read("ab").wait().unwrap(), 0);
+        assert_eq!(*map.get("abc").unwrap().read().wait().unwrap(), 4);
+
+        let map = map.eviction(Eviction::Lru(1));
+        let held = map.read("ab").wait().unwrap();
+        drop(map.read("a"));
+        // Neither entry in use, `abc` had to go, and `ab` could not:
+        assert!(!map.contains_key("abc") && map.contains_key("ab"));
+        drop(held);
+        assert_eq!(map.evict_idle(), 2);
+    }
+}
@@ -0,0 +1,167 @@
+//! Contention counters kept by locks built with the `metrics` feature.
+//!
+//
+
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::time::{Duration, Instant};
+
+/// A snapshot of a lock's contention counters, as returned by
+/// `Qutex::stats` and `QrwLock::stats`.
+///
+/// Counters are updated independently of one another, so a snapshot taken
+/// while the lock is in use may be slightly inconsistent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockStats {
+    acquisitions: u64,
+    contended: u64,
+    wait_time: Duration,
+    max_queue_depth: usize,
+}
+
+impl LockStats {
+    /// Returns the number of times the lock has been acquired.
+    #[inline]
+    pub fn acquisitions(&self) -> u64 {
+        self.acquisitions
+    }
+
+    /// Returns the number of acquisitions which had to wait for the lock.
+    #[inline]
+    pub fn contended(&self) -> u64 {
+        self.contended
+    }
+
+    /// Returns the total time contended acquisitions spent waiting.
+    #[inline]
+    pub fn wait_time(&self) -> Duration {
+        self.wait_time
+    }
+
+    /// Returns the most requests which have been queued at once.
+    #[inline]
+    pub fn max_queue_depth(&self) -> usize {
+        self.max_queue_depth
+    }
+}
+
+/// The counters behind a `LockStats`.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+    max_queue_depth: AtomicUsize,
+}
+
+impl Counters {
+    /// Counts an acquisition, contended if it started waiting at `since`.
+    #[inline]
+    pub(crate) fn acquired(&self, since: Option<Instant>) {
+        self.acquisitions.fetch_add(1, Relaxed);
+        if let Some(since) = since {
+            let nanos = u64::try_from(since.elapsed().as_nanos()).unwrap_or(u64::MAX);
+            self.contended.fetch_add(1, Relaxed);
+            self.wait_nanos.fetch_add(nanos, Relaxed);
+        }
+    }
+
+    /// Records that `depth` requests are queued.
+    #[inline]
+    pub(crate) fn queued(&self, depth: usize) {
+        self.max_queue_depth.fetch_max(depth, Relaxed);
+    }
+
+    /// Returns a snapshot of the counters.
+    pub(crate) fn snapshot(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Relaxed),
+            contended: self.contended.load(Relaxed),
+            wait_time: Duration::from_nanos(self.wait_nanos.load(Relaxed)),
+            max_queue_depth: self.max_queue_depth.load(Relaxed),
+        }
+    }
+}
+
+/// Times a request which is always queued, counting it as contended should
+/// any poll find it not yet granted.
+#[derive(Debug)]
+pub(crate) struct Wait {
+    requested: Instant,
+    pending: bool,
+}
+
+impl Wait {
+    /// Starts timing a request made now.
+    #[inline]
+    pub(crate) fn new() -> Wait {
+        Wait {
+            requested: Instant::now(),
+            pending: false,
+        }
+    }
+
+    /// Records that a poll found the request not yet granted.
+    #[inline]
+    pub(crate) fn pending(&mut self) {
+        self.pending = true;
+    }
+
+    /// Counts the request as granted.
+    #[inline]
+    pub(crate) fn acquired(&self, counters: &Counters) {
+        counters.acquired(Some(self.requested).filter(|_| self.pending));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{QrwLock, Qutex};
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::task::Context;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn qutex() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = Qutex::new(0);
+        drop(lock.try_lock().unwrap());
+        let guard = lock.clone().lock().wait().unwrap();
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions(), stats.contended()), (2, 0));
+        assert_eq!(stats.wait_time(), Duration::ZERO);
+
+        let mut first = lock.clone().lock();
+        let mut second = lock.clone().lock();
+        assert!(first.poll_unpin(cx).is_pending());
+        assert!(second.poll_unpin(cx).is_pending());
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        drop(first.wait().unwrap());
+        drop(second.wait().unwrap());
+
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions(), stats.contended()), (4, 2));
+        assert!(stats.wait_time() >= Duration::from_millis(20));
+        assert_eq!(stats.max_queue_depth(), 2);
+    }
+
+    #[test]
+    fn qrw_lock() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = QrwLock::new(0);
+        let read = lock.clone().read().wait().unwrap();
+        let mut write = lock.clone().write();
+        assert!(write.poll_unpin(cx).is_pending());
+        thread::sleep(Duration::from_millis(10));
+        drop(read);
+        drop(write.wait().unwrap());
+
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions(), stats.contended()), (2, 1));
+        assert!(stats.wait_time() >= Duration::from_millis(10));
+        assert_eq!(stats.max_queue_depth(), 1);
+    }
+}
@@ -0,0 +1,336 @@
+//! Tower middleware serializing requests through `Qutex`es.
+//!
+//
+
+use crate::{Error, Eviction, FutureCheckedGuard, Guard, Qutex, QutexMap};
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A `Layer` which wraps services in a `QutexService`.
+pub struct QutexLayer<K, F> {
+    locks: QutexMap<K, ()>,
+    key: Arc<F>,
+}
+
+impl<K, F> QutexLayer<K, F>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Creates and returns a new `QutexLayer` letting through one request
+    /// at a time per key, as extracted from each request by `key`.
+    ///
+    /// Requests with different keys still run concurrently; for one
+    /// in-flight request overall, return `()` from `key`. Idle locks are
+    /// discarded as the map of them grows (see `Eviction::Idle`).
+    pub fn new(key: F) -> QutexLayer<K, F> {
+        QutexLayer::with_locks(QutexMap::new().eviction(Eviction::Idle), key)
+    }
+
+    /// Creates and returns a new `QutexLayer` taking its locks from
+    /// `locks`, which may be shared with other layers, or locked directly
+    /// to hold requests for a key off while doing something else.
+    pub fn with_locks(locks: QutexMap<K, ()>, key: F) -> QutexLayer<K, F> {
+        QutexLayer {
+            locks,
+            key: Arc::new(key),
+        }
+    }
+
+    /// Returns the map of locks requests are serialized through.
+    #[inline]
+    pub fn locks(&self) -> &QutexMap<K, ()> {
+        &self.locks
+    }
+}
+
+impl<S, K, F> Layer<S> for QutexLayer<K, F> {
+    type Service = QutexService<S, K, F>;
+
+    fn layer(&self, inner: S) -> QutexService<S, K, F> {
+        QutexService {
+            inner,
+            locks: self.locks.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+// Avoids needing `K: Clone, F: Clone`.
+impl<K, F> Clone for QutexLayer<K, F> {
+    #[inline]
+    fn clone(&self) -> QutexLayer<K, F> {
+        QutexLayer {
+            locks: self.locks.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<K, F> fmt::Debug for QutexLayer<K, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QutexLayer").finish_non_exhaustive()
+    }
+}
+
+/// A `Service` which calls its inner service only while holding the lock
+/// for the request's key, released once the inner service responds.
+///
+/// Requests for a key are let through in the order they are called. The
+/// inner service is cloned per request, as it is called only once the lock
+/// is held, and may be called out of order with requests for other keys.
+pub struct QutexService<S, K, F> {
+    inner: S,
+    locks: QutexMap<K, ()>,
+    key: Arc<F>,
+}
+
+impl<S, K, F> QutexService<S, K, F> {
+    /// Returns a reference to the inner service.
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the inner service.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, Req, K, F> Service<Req> for QutexService<S, K, F>
+where
+    S: Service<Req> + Clone,
+    K: Eq + Hash + Clone,
+    F: Fn(&Req) -> K,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = FutureSerialized<S, Req>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> FutureSerialized<S, Req> {
+        let qutex = self.locks.entry((self.key)(&req));
+        // The service just found ready is the one to be called:
+        let clone = self.inner.clone();
+        let inner = mem::replace(&mut self.inner, clone);
+        FutureSerialized {
+            stage: Stage::Lock(qutex.clone(), qutex.lock_checked(), Some((inner, req))),
+        }
+    }
+}
+
+impl<S: Clone, K, F> Clone for QutexService<S, K, F> {
+    #[inline]
+    fn clone(&self) -> QutexService<S, K, F> {
+        QutexService {
+            inner: self.inner.clone(),
+            locks: self.locks.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<S: fmt::Debug, K, F> fmt::Debug for QutexService<S, K, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QutexService")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+enum Stage<S: Service<Req>, Req> {
+    Lock(Qutex<()>, FutureCheckedGuard<()>, Option<(S, Req)>),
+    Call {
+        fut: S::Future,
+        // Released as the service responds:
+        _guard: Guard<()>,
+    },
+    Done,
+}
+
+/// A future which resolves to the inner service's response, calling it
+/// once the lock for the request's key is held, and releasing the lock as
+/// it responds.
+///
+/// A request turned away by a full `fixed_queue` is made again once other
+/// tasks have had a turn. Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureSerialized<S: Service<Req>, Req> {
+    stage: Stage<S, Req>,
+}
+
+impl<S: Service<Req>, Req> Future for FutureSerialized<S, Req> {
+    type Output = Result<S::Response, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Only the inner service's future is pinned structurally, and it is
+        // only ever dropped in place:
+        let stage = unsafe { &mut self.get_unchecked_mut().stage };
+        loop {
+            match stage {
+                Stage::Lock(qutex, future_guard, call) => {
+                    let guard = match futures::ready!(Pin::new(&mut *future_guard).poll(cx)) {
+                        Ok(guard) => guard,
+                        Err(Error::QueueFull) => {
+                            *future_guard = qutex.clone().lock_checked();
+                            cx.waker().wake_by_ref();
+                            return Poll::Pending;
+                        }
+                        // The map's locks never detect recursion:
+                        Err(_) => panic!("FutureSerialized::poll: Request canceled."),
+                    };
+                    let (mut inner, req) = call
+                        .take()
+                        .expect("FutureSerialized::poll: Task already completed.");
+                    *stage = Stage::Call {
+                        fut: inner.call(req),
+                        _guard: guard,
+                    };
+                }
+                Stage::Call { fut, .. } => {
+                    let output = futures::ready!(unsafe { Pin::new_unchecked(fut) }.poll(cx));
+                    *stage = Stage::Done;
+                    return Poll::Ready(output);
+                }
+                Stage::Done => panic!("FutureSerialized::poll: Task already completed."),
+            }
+        }
+    }
+}
+
+// Never pinned structurally but for the inner service's future.
+impl<S: Service<Req>, Req> Unpin for FutureSerialized<S, Req> where S::Future: Unpin {}
+
+impl<S: Service<Req>, Req> fmt::Debug for FutureSerialized<S, Req> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureSerialized")
+            .field("locked", &matches!(self.stage, Stage::Call { .. }))
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::{self, BoxFuture};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    /// Pends once, so that other requests get polled in between.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if mem::replace(&mut self.0, true) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[derive(Default)]
+    struct Counts {
+        in_flight: HashMap<u32, usize>,
+        // The most ever in flight for any one key, and overall:
+        per_key: usize,
+        overall: usize,
+    }
+
+    /// Counts the requests in flight.
+    #[derive(Clone, Default)]
+    struct Tracked(Arc<Mutex<Counts>>);
+
+    impl Service<(u32, u32)> for Tracked {
+        type Response = u32;
+        type Error = Infallible;
+        type Future = BoxFuture<'static, Result<u32, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, (key, id): (u32, u32)) -> Self::Future {
+            let counts = self.0.clone();
+            Box::pin(async move {
+                {
+                    let mut counts = counts.lock().unwrap();
+                    *counts.in_flight.entry(key).or_default() += 1;
+                    counts.per_key = counts.per_key.max(counts.in_flight[&key]);
+                    counts.overall = counts.overall.max(counts.in_flight.values().sum());
+                }
+                YieldNow(false).await;
+                *counts.lock().unwrap().in_flight.get_mut(&key).unwrap() -= 1;
+                Ok(id)
+            })
+        }
+    }
+
+    #[test]
+    fn serialized_by_key() {
+        let tracked = Tracked::default();
+        let layer = QutexLayer::new(|&(key, _): &(u32, u32)| key);
+        let mut service = layer.layer(tracked.clone());
+
+        let calls = (0..6)
+            .map(|id| service.call((id % 2, id)))
+            .collect::<Vec<_>>();
+        let responses = block_on(future::join_all(calls));
+        assert_eq!(responses, (0..6).map(Ok).collect::<Vec<_>>());
+
+        // One at a time per key, but both keys at once:
+        let counts = tracked.0.lock().unwrap();
+        assert_eq!((counts.per_key, counts.overall), (1, 2));
+        assert_eq!(layer.locks().evict_idle(), 2);
+    }
+
+    #[cfg(feature = "fixed_queue")]
+    #[test]
+    fn queue_full() {
+        use crate::wait_queue::FIXED_QUEUE_CAPACITY;
+
+        let layer = QutexLayer::new(|_: &(u32, u32)| ());
+        let mut service = layer.layer(Tracked::default());
+        let guard = layer.locks().lock(()).wait().unwrap();
+
+        let count = FIXED_QUEUE_CAPACITY as u32 + 4;
+        let mut calls = (0..count).map(|id| service.call((0, id))).collect::<Vec<_>>();
+        let cx = &mut Context::from_waker(futures::task::noop_waker_ref());
+        for call in &mut calls {
+            assert!(Pin::new(call).poll(cx).is_pending());
+        }
+        drop(guard);
+        let mut responses = block_on(future::join_all(calls));
+        responses.sort();
+        assert_eq!(responses, (0..count).map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn held_off_directly() {
+        let layer = QutexLayer::new(|_: &(u32, u32)| ());
+        let mut service = layer.layer(Tracked::default());
+        let guard = layer.locks().lock(()).wait().unwrap();
+
+        let mut call = service.call((0, 7));
+        let cx = &mut Context::from_waker(futures::task::noop_waker_ref());
+        assert!(Pin::new(&mut call).poll(cx).is_pending());
+        drop(guard);
+        assert_eq!(block_on(call), Ok(7));
+    }
+}
@@ -0,0 +1,349 @@
+//! Task notification primitives.
+//!
+//
+
+use crate::wait_list::WaitList;
+use futures::channel::oneshot::Receiver;
+use futures::executor;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// How a waiter was woken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wake {
+    // By `notify_one`. Must be passed on if not acted upon.
+    One,
+    // By `notify_waiters`.
+    All,
+}
+
+#[derive(Debug)]
+struct NotifyInner {
+    permit: AtomicBool,
+    waiters: WaitList<Wake>,
+}
+
+impl NotifyInner {
+    /// Wakes the first waiter or, if there is none, stores a permit.
+    fn notify_one(&self) {
+        loop {
+            if self.waiters.send_one(Wake::One).is_ok() {
+                return;
+            }
+
+            self.permit.store(true, SeqCst);
+
+            // A waiter may have queued itself after we found the queue empty
+            // but before the permit was stored. If so, and it has not yet
+            // taken the permit, take it back and wake the waiter directly.
+            if self.waiters.is_empty() || !self.permit.swap(false, SeqCst) {
+                return;
+            }
+        }
+    }
+
+    /// Closes a receiver which is no longer needed, passing on any
+    /// `notify_one` notification which was received but not acted upon.
+    fn release(&self, mut rx: Receiver<Wake>) {
+        rx.close();
+
+        if let Ok(Some(Wake::One)) = rx.try_recv() {
+            self.notify_one();
+        }
+    }
+}
+
+/// A future which resolves once the `Notify` it was created from has been
+/// notified.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureNotified {
+    inner: Arc<NotifyInner>,
+    rx: Option<Receiver<Wake>>,
+}
+
+impl FutureNotified {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<(), Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl Future for FutureNotified {
+    type Output = Result<(), Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let rx = match this.rx {
+            Some(ref mut rx) => rx,
+            None => panic!("FutureNotified::poll: Task already completed."),
+        };
+
+        match Pin::new(rx).poll(cx) {
+            // The sender can not be dropped while we hold a reference to the
+            // queue containing it, but a wakeup is the safest interpretation.
+            Poll::Ready(_) => {
+                this.rx = None;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => {
+                if this.inner.permit.swap(false, SeqCst) {
+                    this.inner.release(this.rx.take().unwrap());
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FutureNotified {
+    fn drop(&mut self) {
+        if let Some(rx) = self.rx.take() {
+            self.inner.release(rx);
+        }
+    }
+}
+
+/// Notifies tasks of events without any associated data.
+///
+/// `notify_one` wakes a single waiter or, if nobody is waiting, stores a
+/// single permit which the next call to `notified` will consume immediately.
+/// `notify_waiters` wakes everyone currently waiting and stores no permit.
+///
+/// Waiters are registered, in FIFO order, when `notified` is called rather
+/// than when the returned future is first polled.
+#[derive(Debug, Clone)]
+pub struct Notify {
+    inner: Arc<NotifyInner>,
+}
+
+impl Notify {
+    /// Creates and returns a new `Notify`.
+    #[inline]
+    pub fn new() -> Notify {
+        Notify {
+            inner: Arc::new(NotifyInner {
+                permit: AtomicBool::new(false),
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureNotified` which will resolve once this `Notify`
+    /// has been notified.
+    #[inline]
+    pub fn notified(&self) -> FutureNotified {
+        FutureNotified {
+            inner: self.inner.clone(),
+            rx: Some(self.inner.waiters.push()),
+        }
+    }
+
+    /// Wakes the longest waiting task or, if there is none, stores a permit
+    /// for the next.
+    #[inline]
+    pub fn notify_one(&self) {
+        self.inner.notify_one()
+    }
+
+    /// Wakes every task currently waiting.
+    #[inline]
+    pub fn notify_waiters(&self) {
+        self.inner.waiters.send_all(Wake::All);
+    }
+}
+
+impl Default for Notify {
+    #[inline]
+    fn default() -> Notify {
+        Notify::new()
+    }
+}
+
+#[derive(Debug)]
+struct EventInner {
+    set: AtomicBool,
+    waiters: WaitList,
+}
+
+/// A future which resolves once the `Event` it was created from is set.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureEvent {
+    inner: Arc<EventInner>,
+    rx: Option<Receiver<()>>,
+}
+
+impl FutureEvent {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<(), Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl Future for FutureEvent {
+    type Output = Result<(), Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.inner.set.load(SeqCst) {
+            return Poll::Ready(Ok(()));
+        }
+
+        if this.rx.is_none() {
+            this.rx = Some(this.inner.waiters.push());
+
+            // Re-check in case we were set before being queued:
+            if this.inner.set.load(SeqCst) {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        match Pin::new(this.rx.as_mut().unwrap()).poll(cx) {
+            // Set (possibly since reset, which still counts) or dropped.
+            Poll::Ready(_) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A manual-reset event.
+///
+/// Once set, an `Event` stays set, and every wait on it resolves
+/// immediately, until it is explicitly reset.
+#[derive(Debug, Clone)]
+pub struct Event {
+    inner: Arc<EventInner>,
+}
+
+impl Event {
+    /// Creates and returns a new, unset, `Event`.
+    #[inline]
+    pub fn new() -> Event {
+        Event {
+            inner: Arc::new(EventInner {
+                set: AtomicBool::new(false),
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureEvent` which will resolve once this event is set.
+    #[inline]
+    pub fn wait(&self) -> FutureEvent {
+        FutureEvent {
+            inner: self.inner.clone(),
+            rx: None,
+        }
+    }
+
+    /// Sets this event, waking every waiting task.
+    pub fn set(&self) {
+        self.inner.set.store(true, SeqCst);
+        self.inner.waiters.notify_all();
+    }
+
+    /// Resets this event so that subsequent waits will block until it is set
+    /// again.
+    #[inline]
+    pub fn reset(&self) {
+        self.inner.set.store(false, SeqCst);
+    }
+
+    /// Returns `true` if this event is currently set.
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.inner.set.load(SeqCst)
+    }
+}
+
+impl Default for Event {
+    #[inline]
+    fn default() -> Event {
+        Event::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::{executor, future, FutureExt};
+    use std::thread;
+
+    #[test]
+    fn notify_one_stores_permit() {
+        let notify = Notify::new();
+        notify.notify_one();
+        notify.notify_one();
+        notify.notified().wait().unwrap();
+
+        // Only a single permit is ever stored:
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert!(notify.notified().poll_unpin(cx).is_pending());
+    }
+
+    #[test]
+    fn notify_one_across_threads() {
+        let notify = Notify::new();
+        let notified = notify.notified();
+
+        let notify_c = notify.clone();
+        let thread = thread::spawn(move || notify_c.notify_one());
+
+        notified.wait().unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn notify_waiters() {
+        let notify = Notify::new();
+        let notified = (0..4).map(|_| notify.notified()).collect::<Vec<_>>();
+
+        notify.notify_waiters();
+        executor::block_on(future::try_join_all(notified)).unwrap();
+
+        // No permit is stored:
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert!(notify.notified().poll_unpin(cx).is_pending());
+    }
+
+    #[test]
+    fn dropped_waiter_passes_notification_on() {
+        let notify = Notify::new();
+        let notified_0 = notify.notified();
+        let notified_1 = notify.notified();
+
+        notify.notify_one();
+        drop(notified_0);
+        notified_1.wait().unwrap();
+    }
+
+    #[test]
+    fn event() {
+        let event = Event::new();
+        let waits = (0..4).map(|_| event.wait()).collect::<Vec<_>>();
+
+        let event_c = event.clone();
+        let thread = thread::spawn(move || event_c.set());
+        executor::block_on(future::try_join_all(waits)).unwrap();
+        thread.join().unwrap();
+
+        // Stays set:
+        assert!(event.is_set());
+        event.wait().wait().unwrap();
+
+        event.reset();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert!(event.wait().poll_unpin(cx).is_pending());
+    }
+}
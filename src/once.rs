@@ -0,0 +1,334 @@
+//! A one-time asynchronous initialization primitive.
+//!
+//
+
+use crate::wait_list::WaitList;
+use futures::channel::oneshot::Receiver;
+use futures::future::IntoFuture;
+use futures::{executor, TryFuture, TryFutureExt};
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::task::{Context, Poll};
+
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+
+/// The state of a `OnceDriver`.
+enum Stage<U> {
+    // Not yet registered as either the initializer or a waiter.
+    Idle,
+    // This caller is running the initializer.
+    Running(U),
+    // Another caller is running the initializer.
+    Waiting(Receiver<()>),
+    Complete,
+}
+
+/// Drives a single caller's attempt at a `Once`, either running an
+/// initializer or waiting on someone else's.
+///
+/// Shared by each of the futures built on `Once`.
+pub(crate) struct OnceDriver<'o, U> {
+    once: &'o Once,
+    stage: Stage<U>,
+}
+
+impl<'o, U> OnceDriver<'o, U>
+where
+    U: TryFuture<Ok = ()> + Unpin,
+{
+    /// Returns a new `OnceDriver`.
+    #[inline]
+    pub(crate) fn new(once: &'o Once) -> OnceDriver<'o, U> {
+        OnceDriver {
+            once,
+            stage: Stage::Idle,
+        }
+    }
+
+    /// Polls this caller's attempt, calling `start` to obtain the initializer
+    /// if (and only if) this caller is chosen to run it.
+    pub(crate) fn poll<S>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut start: S,
+    ) -> Poll<Result<(), U::Error>>
+    where
+        S: FnMut() -> U,
+    {
+        loop {
+            match self.stage {
+                Stage::Idle => {
+                    match self
+                        .once
+                        .state
+                        .compare_exchange(INCOMPLETE, RUNNING, SeqCst, SeqCst)
+                    {
+                        Ok(_) => {
+                            // Should `start` panic, let the next caller in:
+                            let unclaim = Unclaim(self.once);
+                            self.stage = Stage::Running(start());
+                            mem::forget(unclaim);
+                        }
+                        Err(COMPLETE) => {
+                            self.stage = Stage::Complete;
+                            return Poll::Ready(Ok(()));
+                        }
+                        Err(_) => {
+                            let rx = self.once.waiters.push();
+                            // Re-check in case the initializer finished before
+                            // we were queued and will never wake us:
+                            if self.once.state.load(SeqCst) == RUNNING {
+                                self.stage = Stage::Waiting(rx);
+                            }
+                        }
+                    }
+                }
+                Stage::Running(ref mut future) => match Pin::new(future).try_poll(cx) {
+                    Poll::Ready(Ok(())) => {
+                        self.stage = Stage::Complete;
+                        self.once.finish(COMPLETE);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(err)) => {
+                        self.stage = Stage::Complete;
+                        self.once.finish(INCOMPLETE);
+                        return Poll::Ready(Err(err));
+                    }
+                },
+                Stage::Waiting(ref mut rx) => match Pin::new(rx).poll(cx) {
+                    // The initializer has either completed or failed. Either
+                    // way, start over (and possibly run our own initializer):
+                    Poll::Ready(_) => self.stage = Stage::Idle,
+                    Poll::Pending => return Poll::Pending,
+                },
+                Stage::Complete => panic!("OnceDriver::poll: Task already completed."),
+            }
+        }
+    }
+}
+
+/// Gives up a claim on a `Once` when dropped.
+struct Unclaim<'o>(&'o Once);
+
+impl<'o> Drop for Unclaim<'o> {
+    fn drop(&mut self) {
+        self.0.finish(INCOMPLETE);
+    }
+}
+
+impl<'o, U> Drop for OnceDriver<'o, U> {
+    /// Hands the initialization off to the next waiter if this caller was
+    /// running the initializer but did not finish.
+    fn drop(&mut self) {
+        if let Stage::Running(_) = self.stage {
+            self.once.finish(INCOMPLETE);
+        }
+    }
+}
+
+/// A future which resolves once the initializer passed to `Once::call_once`
+/// (by this or any other caller) has completed.
+///
+/// If the initializer fails, or the future running it is dropped before it
+/// completes, the error is returned to that caller alone and the next queued
+/// caller runs its own initializer instead.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureOnce<'o, F, U> {
+    driver: OnceDriver<'o, Pin<Box<IntoFuture<U>>>>,
+    init: Option<F>,
+}
+
+impl<'o, F, U> FutureOnce<'o, F, U>
+where
+    F: FnOnce() -> U,
+    U: TryFuture<Ok = ()>,
+{
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<(), U::Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<'o, F, U> Future for FutureOnce<'o, F, U>
+where
+    F: FnOnce() -> U,
+    U: TryFuture<Ok = ()>,
+{
+    type Output = Result<(), U::Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let init = &mut this.init;
+        this.driver
+            .poll(cx, || Box::pin((init.take().unwrap())().into_future()))
+    }
+}
+
+// Never pinned structurally.
+impl<'o, F, U> Unpin for FutureOnce<'o, F, U> {}
+
+impl<'o, F, U> fmt::Debug for FutureOnce<'o, F, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureOnce")
+            .field("once", &self.driver.once)
+            .finish()
+    }
+}
+
+/// An asynchronous one-time initialization primitive.
+///
+/// Unlike `std::sync::Once`, callers which find initialization already in
+/// progress are queued and resolve once it has completed rather than
+/// blocking.
+#[derive(Debug)]
+pub struct Once {
+    state: AtomicUsize,
+    waiters: WaitList,
+}
+
+impl Once {
+    /// Creates and returns a new `Once`.
+    #[inline]
+    pub const fn new() -> Once {
+        Once {
+            state: AtomicUsize::new(INCOMPLETE),
+            waiters: WaitList::new(),
+        }
+    }
+
+    /// Returns a new `FutureOnce` which will run the future returned by `f`
+    /// if no other initializer has completed successfully, and resolve once
+    /// one has.
+    ///
+    /// `f` is only called if and when this caller is chosen to perform the
+    /// initialization.
+    pub fn call_once<F, U>(&self, f: F) -> FutureOnce<'_, F, U>
+    where
+        F: FnOnce() -> U,
+        U: TryFuture<Ok = ()>,
+    {
+        FutureOnce {
+            driver: OnceDriver::new(self),
+            init: Some(f),
+        }
+    }
+
+    /// Returns `true` if an initializer has completed successfully.
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(SeqCst) == COMPLETE
+    }
+
+    /// Claims the right to initialize synchronously, returning `false` if an
+    /// initializer is running or has already completed.
+    #[inline]
+    pub(crate) fn try_begin(&self) -> bool {
+        self.state
+            .compare_exchange(INCOMPLETE, RUNNING, SeqCst, SeqCst)
+            .is_ok()
+    }
+
+    /// Marks a synchronous initialization begun with `try_begin` as
+    /// complete.
+    #[inline]
+    pub(crate) fn complete(&self) {
+        self.finish(COMPLETE)
+    }
+
+    /// Sets the final state of an initialization attempt and wakes everyone
+    /// waiting on it.
+    fn finish(&self, state: usize) {
+        self.state.store(state, SeqCst);
+        self.waiters.notify_all();
+    }
+}
+
+impl Default for Once {
+    #[inline]
+    fn default() -> Once {
+        Once::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn runs_once() {
+        let thread_count = 16;
+        let once = Arc::new(Once::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut threads = Vec::with_capacity(thread_count);
+
+        for _ in 0..thread_count {
+            let (once, runs) = (once.clone(), runs.clone());
+            threads.push(thread::spawn(move || {
+                once.call_once(|| {
+                    thread::sleep(::std::time::Duration::from_millis(10));
+                    runs.fetch_add(1, SeqCst);
+                    future::ok::<(), ()>(())
+                })
+                .wait()
+                .unwrap();
+                assert!(once.is_completed());
+            }));
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(runs.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn failed_initializer_is_retried() {
+        let once = Once::new();
+
+        assert_eq!(
+            once.call_once(|| future::err::<(), _>("nope")).wait(),
+            Err("nope")
+        );
+        assert!(!once.is_completed());
+
+        once.call_once(|| future::ok::<(), ()>(())).wait().unwrap();
+        assert!(once.is_completed());
+
+        // Completed, so this initializer must never run:
+        once.call_once(|| -> future::Ready<Result<(), ()>> { unreachable!() })
+            .wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn dropped_initializer_hands_off() {
+        let once = Once::new();
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut first = once.call_once(future::pending::<Result<(), ()>>);
+        assert_eq!(first.poll_unpin(cx), Poll::Pending);
+
+        let mut second = once.call_once(|| future::ok::<(), ()>(()));
+        assert_eq!(second.poll_unpin(cx), Poll::Pending);
+
+        drop(first);
+        assert_eq!(second.poll_unpin(cx), Poll::Ready(Ok(())));
+
+        assert!(once.is_completed());
+    }
+}
@@ -0,0 +1,380 @@
+//! Asynchronously initialized cells built on `Once`.
+//!
+//
+
+use crate::once::{Once, OnceDriver};
+use futures::{executor, TryFuture};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Stores the output of an initializer future into its cell.
+struct StoreValue<'c, T, U> {
+    cell: &'c OnceCell<T>,
+    future: Pin<Box<U>>,
+}
+
+impl<'c, T, U> Future for StoreValue<'c, T, U>
+where
+    U: TryFuture<Ok = T>,
+{
+    type Output = Result<(), U::Error>;
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let val = futures::ready!(self.future.as_mut().try_poll(cx))?;
+        // We are the only initializer running (guaranteed by the `Once`) and
+        // nobody reads the value until it completes.
+        unsafe { *self.cell.value.get() = Some(val) };
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A future which resolves to a reference to the value within a `OnceCell`,
+/// initializing it first if necessary.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureGetOrInit<'c, T, F, U> {
+    cell: &'c OnceCell<T>,
+    driver: OnceDriver<'c, StoreValue<'c, T, U>>,
+    init: Option<F>,
+}
+
+impl<'c, T, F, U> FutureGetOrInit<'c, T, F, U>
+where
+    F: FnOnce() -> U,
+    U: TryFuture<Ok = T>,
+{
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<&'c T, U::Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<'c, T, F, U> Future for FutureGetOrInit<'c, T, F, U>
+where
+    F: FnOnce() -> U,
+    U: TryFuture<Ok = T>,
+{
+    type Output = Result<&'c T, U::Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let (cell, init) = (this.cell, &mut this.init);
+        futures::ready!(this.driver.poll(cx, || StoreValue {
+            cell,
+            future: Box::pin((init.take().unwrap())()),
+        }))?;
+        Poll::Ready(Ok(cell.get().unwrap()))
+    }
+}
+
+// Never pinned structurally.
+impl<'c, T, F, U> Unpin for FutureGetOrInit<'c, T, F, U> {}
+
+impl<'c, T, F, U> fmt::Debug for FutureGetOrInit<'c, T, F, U>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureGetOrInit")
+            .field("cell", &self.cell)
+            .finish()
+    }
+}
+
+/// A cell which can be written to only once, initialized asynchronously.
+///
+/// Concurrent callers of `get_or_init` are queued behind whichever caller is
+/// running its initializer rather than racing to initialize.
+pub struct OnceCell<T> {
+    once: Once,
+    value: UnsafeCell<Option<T>>,
+}
+
+impl<T> OnceCell<T> {
+    /// Creates and returns a new, empty `OnceCell`.
+    #[inline]
+    pub const fn new() -> OnceCell<T> {
+        OnceCell {
+            once: Once::new(),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Returns a reference to the value if the cell has been initialized.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        if self.once.is_completed() {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the value if the cell has been
+    /// initialized.
+    ///
+    /// Since this call borrows the cell mutably, no synchronization needs to
+    /// take place.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.value.get_mut().as_mut()
+    }
+
+    /// Initializes the cell with `val` if it has not already been (and is not
+    /// currently being) initialized. Returns `val` otherwise.
+    pub fn set(&self, val: T) -> Result<(), T> {
+        if self.once.try_begin() {
+            unsafe { *self.value.get() = Some(val) };
+            self.once.complete();
+            Ok(())
+        } else {
+            Err(val)
+        }
+    }
+
+    /// Returns a new `FutureGetOrInit` which will resolve to a reference to
+    /// the value, first initializing it with the output of the future
+    /// returned by `f` if the cell is empty.
+    ///
+    /// `f` is only called if and when this caller is chosen to perform the
+    /// initialization. If the initializer fails, the error is returned to
+    /// this caller and the cell remains empty.
+    pub fn get_or_init<F, U>(&self, f: F) -> FutureGetOrInit<'_, T, F, U>
+    where
+        F: FnOnce() -> U,
+        U: TryFuture<Ok = T>,
+    {
+        FutureGetOrInit {
+            cell: self,
+            driver: OnceDriver::new(&self.once),
+            init: Some(f),
+        }
+    }
+
+    /// Consumes the cell, returning the value if it has been initialized.
+    #[inline]
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    #[inline]
+    fn default() -> OnceCell<T> {
+        OnceCell::new()
+    }
+}
+
+impl<T> fmt::Debug for OnceCell<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OnceCell")
+            .field("value", &self.get())
+            .finish()
+    }
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+/// A future which resolves to a reference to the value within a `Lazy`,
+/// initializing it first if necessary.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureLazy<'l, T, F, U> {
+    lazy: &'l Lazy<T, F>,
+    driver: OnceDriver<'l, StoreValue<'l, T, U>>,
+}
+
+impl<'l, T, F, U> FutureLazy<'l, T, F, U>
+where
+    F: FnOnce() -> U,
+    U: TryFuture<Ok = T>,
+{
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<&'l T, U::Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<'l, T, F, U> Future for FutureLazy<'l, T, F, U>
+where
+    F: FnOnce() -> U,
+    U: TryFuture<Ok = T>,
+{
+    type Output = Result<&'l T, U::Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let lazy = this.lazy;
+        futures::ready!(this.driver.poll(cx, || {
+            // Only the caller chosen by the `Once` ever gets here.
+            let init = unsafe { (*lazy.init.get()).take() }
+                .expect("Lazy instance has previously been poisoned");
+            StoreValue {
+                cell: &lazy.cell,
+                future: Box::pin(init()),
+            }
+        }))?;
+        Poll::Ready(Ok(lazy.cell.get().unwrap()))
+    }
+}
+
+// Never pinned structurally.
+impl<'l, T, F, U> Unpin for FutureLazy<'l, T, F, U> {}
+
+impl<'l, T, F, U> fmt::Debug for FutureLazy<'l, T, F, U>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureLazy")
+            .field("lazy", &self.lazy)
+            .finish()
+    }
+}
+
+/// A value which is initialized asynchronously on first access.
+///
+/// Suitable for use in a `static`. If the initializer fails (or the future
+/// driving it is dropped before it completes), the `Lazy` is poisoned and
+/// every subsequent access panics.
+pub struct Lazy<T, F> {
+    cell: OnceCell<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+impl<T, F, U> Lazy<T, F>
+where
+    F: FnOnce() -> U,
+    U: TryFuture<Ok = T>,
+{
+    /// Creates and returns a new `Lazy` which will be initialized with the
+    /// output of the future returned by `init`.
+    #[inline]
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Returns a new `FutureLazy` which will resolve to a reference to the
+    /// value, running the initializer first if nobody has yet.
+    #[inline]
+    pub fn get(&self) -> FutureLazy<'_, T, F, U> {
+        FutureLazy {
+            lazy: self,
+            driver: OnceDriver::new(&self.cell.once),
+        }
+    }
+}
+
+impl<T, F> Lazy<T, F> {
+    /// Returns a reference to the value if it has already been initialized.
+    #[inline]
+    pub fn try_get(&self) -> Option<&T> {
+        self.cell.get()
+    }
+}
+
+impl<T, F> fmt::Debug for Lazy<T, F>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lazy").field("cell", &self.cell).finish()
+    }
+}
+
+unsafe impl<T: Send, F: Send> Send for Lazy<T, F> {}
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{self, Ready};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn get_or_init() {
+        let thread_count = 16;
+        let cell = Arc::new(OnceCell::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut threads = Vec::with_capacity(thread_count);
+
+        for i in 0..thread_count {
+            let (cell, runs) = (cell.clone(), runs.clone());
+            threads.push(thread::spawn(move || {
+                let val = cell
+                    .get_or_init(|| {
+                        runs.fetch_add(1, SeqCst);
+                        future::ok::<_, ()>(i)
+                    })
+                    .wait()
+                    .unwrap();
+                assert_eq!(Some(val), cell.get());
+            }));
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(runs.load(SeqCst), 1);
+        assert_eq!(cell.set(99), Err(99));
+    }
+
+    #[test]
+    fn set_then_get() {
+        let cell = OnceCell::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.set(5), Ok(()));
+        assert_eq!(cell.get_or_init(|| future::ok::<_, ()>(6)).wait(), Ok(&5));
+        assert_eq!(cell.into_inner(), Some(5));
+    }
+
+    #[test]
+    fn failed_init_leaves_cell_empty() {
+        let cell = OnceCell::new();
+        assert_eq!(
+            cell.get_or_init(|| future::err::<u8, _>(())).wait(),
+            Err(())
+        );
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.get_or_init(|| future::ok::<_, ()>(1)).wait(), Ok(&1));
+    }
+
+    type InitLazy = fn() -> Ready<Result<usize, ()>>;
+
+    static LAZY: Lazy<usize, InitLazy> = Lazy::new(|| future::ok(42));
+
+    #[test]
+    fn lazy_static() {
+        assert_eq!(LAZY.get().wait(), Ok(&42));
+        assert_eq!(LAZY.try_get(), Some(&42));
+    }
+
+    #[test]
+    fn lazy_poisoned() {
+        let lazy = Lazy::new(|| future::err::<usize, _>(()));
+        assert_eq!(lazy.get().wait(), Err(()));
+        for _ in 0..2 {
+            let access = panic::catch_unwind(AssertUnwindSafe(|| lazy.get().wait()));
+            assert!(access.is_err());
+        }
+        assert_eq!(lazy.try_get(), None);
+    }
+}
@@ -0,0 +1,277 @@
+//! An asynchronous pool of reusable objects.
+//!
+//
+
+use crate::queue::SegQueue;
+use crate::semaphore::FuturePermit;
+use crate::Semaphore;
+use futures::executor;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+struct Inner<T> {
+    // One permit per object in `objects`, so that holding a permit
+    // guarantees there is an object to take:
+    available: Semaphore,
+    objects: SegQueue<T>,
+}
+
+impl<T> Inner<T> {
+    /// Takes an object, for which a permit has been taken.
+    #[inline]
+    fn take(self: &Arc<Inner<T>>) -> PooledGuard<T> {
+        let object = self
+            .objects
+            .pop()
+            .expect("Pool: Permit taken without an object available.");
+        PooledGuard {
+            pool: self.clone(),
+            object: ManuallyDrop::new(object),
+        }
+    }
+
+    #[inline]
+    fn put(&self, object: T) {
+        self.objects.push(object);
+        self.available.add_permits(1);
+    }
+}
+
+/// An object checked out of a `Pool`, returned to it when dropped.
+pub struct PooledGuard<T> {
+    pool: Arc<Inner<T>>,
+    object: ManuallyDrop<T>,
+}
+
+impl<T> PooledGuard<T> {
+    /// Takes the object out of the pool for good, leaving the pool one
+    /// object smaller.
+    #[inline]
+    pub fn detach(guard: PooledGuard<T>) -> T {
+        let mut guard = ManuallyDrop::new(guard);
+        unsafe {
+            let object = ManuallyDrop::take(&mut guard.object);
+            std::ptr::drop_in_place(&mut guard.pool);
+            object
+        }
+    }
+}
+
+impl<T> Deref for PooledGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.object
+    }
+}
+
+impl<T> DerefMut for PooledGuard<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.object
+    }
+}
+
+impl<T> Drop for PooledGuard<T> {
+    fn drop(&mut self) {
+        let object = unsafe { ManuallyDrop::take(&mut self.object) };
+        self.pool.put(object);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PooledGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PooledGuard").field(&*self.object).finish()
+    }
+}
+
+/// A future which resolves to a `PooledGuard`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FuturePooledGuard<T> {
+    pool: Arc<Inner<T>>,
+    permit: FuturePermit,
+}
+
+impl<T> FuturePooledGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<PooledGuard<T>, Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FuturePooledGuard<T> {
+    type Output = Result<PooledGuard<T>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        futures::ready!(Pin::new(&mut this.permit).poll(cx))
+            // Its semaphore is never closed:
+            .expect("FuturePooledGuard::poll: Pool closed.")
+            .forget();
+        Poll::Ready(Ok(this.pool.take()))
+    }
+}
+
+impl<T> fmt::Debug for FuturePooledGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FuturePooledGuard")
+            .field("permit", &self.permit)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A pool of objects, such as connections or buffers, each checked out by
+/// one user at a time.
+///
+/// Callers waiting for an object are served in FIFO order, as by a
+/// `Semaphore`. Objects are handed out in the order returned, so each is
+/// used in turn.
+pub struct Pool<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Pool<T> {
+    /// Creates and returns a new `Pool` of `objects`.
+    pub fn new<I>(objects: I) -> Pool<T>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let pool = Pool {
+            inner: Arc::new(Inner {
+                available: Semaphore::new(0),
+                objects: SegQueue::new(),
+            }),
+        };
+        for object in objects {
+            pool.add(object);
+        }
+        pool
+    }
+
+    /// Creates and returns a new `Pool` of `size` objects, each created by
+    /// `factory`.
+    pub fn from_fn<F>(size: usize, factory: F) -> Pool<T>
+    where
+        F: FnMut() -> T,
+    {
+        Pool::new(std::iter::repeat_with(factory).take(size))
+    }
+
+    /// Adds `object` to the pool, handing it to the first caller waiting,
+    /// if any.
+    #[inline]
+    pub fn add(&self, object: T) {
+        self.inner.put(object);
+    }
+
+    /// Returns the number of objects currently in the pool, not checked out.
+    #[inline]
+    pub fn available(&self) -> usize {
+        self.inner.available.available_permits()
+    }
+
+    /// Returns a new `FuturePooledGuard` which will resolve to an object
+    /// once one is available.
+    #[inline]
+    pub fn checkout(&self) -> FuturePooledGuard<T> {
+        FuturePooledGuard {
+            pool: self.inner.clone(),
+            permit: self.inner.available.acquire(),
+        }
+    }
+
+    /// Returns an object if one is available and nobody is waiting.
+    pub fn try_checkout(&self) -> Option<PooledGuard<T>> {
+        let permit = self.inner.available.try_acquire()?;
+        permit.forget();
+        Some(self.inner.take())
+    }
+}
+
+impl<T> Clone for Pool<T> {
+    #[inline]
+    fn clone(&self) -> Pool<T> {
+        Pool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("available", &self.available())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::thread;
+
+    #[test]
+    fn checkout() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let pool = Pool::new(vec![0, 1]);
+        let mut first = pool.try_checkout().unwrap();
+        let second = pool.checkout().wait().unwrap();
+        assert_eq!((*first, *second, pool.available()), (0, 1, 0));
+
+        // Waiters are served in order, as objects are returned:
+        let (mut a, mut b) = (pool.checkout(), pool.checkout());
+        assert!(a.poll_unpin(cx).is_pending() && b.poll_unpin(cx).is_pending());
+        *first += 10;
+        drop(first);
+        assert!(pool.try_checkout().is_none());
+        let a = a.wait().unwrap();
+        drop(second);
+        assert_eq!((*a, *b.wait().unwrap()), (10, 1));
+        drop(a);
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn detach() {
+        let pool = Pool::from_fn(2, String::new);
+        let object = PooledGuard::detach(pool.try_checkout().unwrap());
+        assert_eq!(pool.available(), 1);
+        pool.add(object);
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn contended() {
+        let pool = Pool::from_fn(3, || 0usize);
+        let threads = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        *pool.checkout().wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let objects = (0..3)
+            .map(|_| pool.try_checkout().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(objects.iter().map(|object| **object).sum::<usize>(), 1600);
+    }
+}
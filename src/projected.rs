@@ -0,0 +1,217 @@
+//! Handles to a single part of the data within a `Qutex`.
+//!
+//
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+type ProjectFn<T, U> = dyn Fn(&mut T) -> &mut U + Send + Sync;
+
+/// Pins down the higher-ranked signature of a projection closure.
+#[inline]
+fn projection<T, U, F>(f: F) -> F
+where
+    F: Fn(&mut T) -> &mut U,
+{
+    f
+}
+
+/// Allows access to the projected part of the data within a `Qutex`.
+pub struct ProjectedGuard<T, U> {
+    // Keeps the parent locked:
+    _guard: Guard<T>,
+    part: *mut U,
+}
+
+impl<T, U> Deref for ProjectedGuard<T, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        unsafe { &*self.part }
+    }
+}
+
+impl<T, U> DerefMut for ProjectedGuard<T, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.part }
+    }
+}
+
+unsafe impl<T: Send, U: Send> Send for ProjectedGuard<T, U> {}
+unsafe impl<T: Send, U: Sync> Sync for ProjectedGuard<T, U> {}
+
+impl<T, U: fmt::Debug> fmt::Debug for ProjectedGuard<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ProjectedGuard").field(&**self).finish()
+    }
+}
+
+/// A future which resolves to a `ProjectedGuard`.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureProjectedGuard<T, U> {
+    future_guard: FutureGuard<T>,
+    project: Arc<ProjectFn<T, U>>,
+}
+
+impl<T, U> FutureProjectedGuard<T, U> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<ProjectedGuard<T, U>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T, U> Future for FutureProjectedGuard<T, U> {
+    type Output = Result<ProjectedGuard<T, U>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut guard = futures::ready!(Pin::new(&mut this.future_guard).poll(cx))?;
+        // The data lives within the `Qutex` allocation, so stays put:
+        let part = (this.project)(&mut *guard) as *mut U;
+
+        Poll::Ready(Ok(ProjectedGuard {
+            _guard: guard,
+            part,
+        }))
+    }
+}
+
+impl<T, U> fmt::Debug for FutureProjectedGuard<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureProjectedGuard")
+            .finish_non_exhaustive()
+    }
+}
+
+/// A handle to one part (e.g. a field) of the data within a `Qutex`.
+///
+/// Locking a `ProjectedQutex` locks the entire parent, sharing its queue
+/// with every other handle to it, but only grants access to the projected
+/// part. This lets a subsystem be handed just the part it may touch while
+/// still serializing with everyone else.
+pub struct ProjectedQutex<T, U> {
+    qutex: Qutex<T>,
+    project: Arc<ProjectFn<T, U>>,
+}
+
+impl<T, U> ProjectedQutex<T, U> {
+    /// Returns a new `FutureProjectedGuard` which will resolve once the
+    /// parent `Qutex` is locked.
+    #[inline]
+    pub fn lock(self) -> FutureProjectedGuard<T, U> {
+        FutureProjectedGuard {
+            future_guard: self.qutex.lock(),
+            project: self.project,
+        }
+    }
+
+    /// Returns a further projection of this projection.
+    pub fn project<V, F>(&self, f: F) -> ProjectedQutex<T, V>
+    where
+        T: 'static,
+        U: 'static,
+        F: Fn(&mut U) -> &mut V + Send + Sync + 'static,
+    {
+        let project = self.project.clone();
+
+        ProjectedQutex {
+            qutex: self.qutex.clone(),
+            project: Arc::new(projection(move |t: &mut T| f(project(t)))),
+        }
+    }
+}
+
+impl<T> Qutex<T> {
+    /// Returns a handle to the part of the data selected by `f`, sharing
+    /// this `Qutex`'s lock.
+    ///
+    /// ```
+    /// use qutex::Qutex;
+    ///
+    /// struct State {
+    ///     name: String,
+    ///     hits: u64,
+    /// }
+    ///
+    /// let state = Qutex::new(State { name: "index".into(), hits: 0 });
+    /// let hits = state.project(|state| &mut state.hits);
+    ///
+    /// *hits.lock().wait().unwrap() += 1;
+    /// assert_eq!(state.lock().wait().unwrap().hits, 1);
+    /// ```
+    pub fn project<U, F>(&self, f: F) -> ProjectedQutex<T, U>
+    where
+        F: Fn(&mut T) -> &mut U + Send + Sync + 'static,
+    {
+        ProjectedQutex {
+            qutex: self.clone(),
+            project: Arc::new(f),
+        }
+    }
+}
+
+// Avoids needing `T: Clone, U: Clone`.
+impl<T, U> Clone for ProjectedQutex<T, U> {
+    #[inline]
+    fn clone(&self) -> ProjectedQutex<T, U> {
+        ProjectedQutex {
+            qutex: self.qutex.clone(),
+            project: self.project.clone(),
+        }
+    }
+}
+
+impl<T, U> fmt::Debug for ProjectedQutex<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProjectedQutex")
+            .field("parent", &self.qutex.as_ptr())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+
+    #[derive(Debug, Default)]
+    struct Config {
+        name: String,
+        limits: (u32, u32),
+    }
+
+    #[test]
+    fn shares_parent_lock() {
+        let config = Qutex::new(Config::default());
+        let name = config.project(|config| &mut config.name);
+
+        let guard = config.clone().lock().wait().unwrap();
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut name_guard = name.clone().lock();
+        assert!(name_guard.poll_unpin(cx).is_pending());
+
+        drop(guard);
+        name_guard.wait().unwrap().push_str("server");
+
+        assert_eq!(config.lock().wait().unwrap().name, "server");
+    }
+
+    #[test]
+    fn nested_projection() {
+        let config = Qutex::new(Config::default());
+        let limits = config.project(|config| &mut config.limits);
+        let upper = limits.project(|limits| &mut limits.1);
+
+        *upper.lock().wait().unwrap() = 10;
+        assert_eq!(*limits.lock().wait().unwrap(), (0, 10));
+    }
+}
@@ -17,14 +17,20 @@
 //   Doing some spinning now]
 //
 
-use crossbeam::queue::SegQueue;
-use futures::sync::oneshot::{self, Canceled, Receiver, Sender};
-use futures::{Async, Future, Poll};
+#[cfg(feature = "metrics")]
+use crate::metrics::{Counters, LockStats, Wait};
+use crate::queue::SegQueue;
+use futures::channel::oneshot::{self, Canceled, Receiver, Sender};
+use futures::executor;
 use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::future::Future;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::Ordering::{Acquire, SeqCst};
+use std::pin::Pin;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, SeqCst};
 use std::sync::atomic::{fence, AtomicUsize};
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::thread;
 
 const READ_COUNT_MASK: usize = 0x00FFFFFF;
@@ -73,9 +79,16 @@ where
     Self: ::std::marker::Sized,
 {
     fn lock(&self) -> &QrwLock<T>;
+}
 
-    unsafe fn forget(self) {
-        ::std::mem::forget(self);
+/// Counts a poll of a read or write request for `QrwLock::stats`.
+#[cfg(feature = "metrics")]
+#[inline]
+fn record_poll<T, G: Guard<T>>(wait: &mut Wait, poll: &Poll<Result<G, Canceled>>) {
+    match *poll {
+        Poll::Pending => wait.pending(),
+        Poll::Ready(Ok(ref guard)) => wait.acquired(&guard.lock().inner.stats),
+        Poll::Ready(Err(_)) => (),
     }
 }
 
@@ -86,6 +99,13 @@ pub struct ReadGuard<T> {
 }
 
 impl<T> ReadGuard<T> {
+    /// Converts this `ReadGuard` into a `FutureUpgrade`, which resolves to a
+    /// `WriteGuard` once every other reader has released the lock.
+    ///
+    /// Unless this is the sole reader, the read lock is given up while
+    /// waiting, so another upgrade may write in between. A waiting upgrade
+    /// takes priority over read requests made since, which are held off
+    /// until it is granted, as well as over queued writes.
     pub fn upgrade(guard: ReadGuard<T>) -> FutureUpgrade<T> {
         debug_assert!(guard.lock.read_count().unwrap() > 0);
 
@@ -101,6 +121,22 @@ impl<T> ReadGuard<T> {
         }
     }
 
+    /// Converts this `ReadGuard` into a `WriteGuard` without ever releasing
+    /// the lock, if this is the sole reader and no write or upgrade is
+    /// pending, or returns it unchanged otherwise.
+    ///
+    /// Nothing can be written between reading and writing through a guard
+    /// so upgraded, unlike one upgraded with `upgrade`.
+    pub fn try_upgrade(guard: ReadGuard<T>) -> Result<WriteGuard<T>, ReadGuard<T>> {
+        if unsafe { guard.lock.try_upgrade_read_lock() } {
+            Ok(WriteGuard {
+                lock: unsafe { extract_lock(guard) },
+            })
+        } else {
+            Err(guard)
+        }
+    }
+
     /// Releases the lock held by this `ReadGuard` and returns the original `QrwLock`.
     pub fn release(guard: ReadGuard<T>) -> QrwLock<T> {
         unsafe {
@@ -202,41 +238,43 @@ impl<T> FutureUpgrade<T> {
     fn new(lock: QrwLock<T>, rx: Option<Receiver<()>>) -> FutureUpgrade<T> {
         FutureUpgrade {
             lock: Some(lock),
-            rx: rx,
+            rx,
         }
     }
 
     /// Blocks the current thread until this future resolves.
     #[inline]
     pub fn wait(self) -> Result<WriteGuard<T>, Canceled> {
-        <Self as Future>::wait(self)
+        executor::block_on(self)
     }
 }
 
 impl<T> Future for FutureUpgrade<T> {
-    type Item = WriteGuard<T>;
-    type Error = Canceled;
+    type Output = Result<WriteGuard<T>, Canceled>;
 
     #[inline]
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if self.lock.is_some() {
-            // FUTURE NOTE: Lexical borrowing should allow this to be
-            // restructured without the extra `.unwrap()` below.
-            if self.rx.is_none() {
-                print_debug("qutex::FutureUpgrade::poll: Uncontended. Upgrading.");
-                Ok(Async::Ready(WriteGuard {
-                    lock: self.lock.take().unwrap(),
-                }))
-            } else {
-                unsafe { self.lock.as_ref().unwrap().process_queues() }
-                self.rx.as_mut().unwrap().poll().map(|res| {
-                    res.map(|_| {
-                        print_debug("qutex::FutureUpgrade::poll: Ready. Upgrading.");
-                        WriteGuard {
-                            lock: self.lock.take().unwrap(),
-                        }
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.lock.is_some() {
+            match this.rx {
+                Some(ref mut rx) => {
+                    unsafe { this.lock.as_ref().unwrap().process_queues() }
+                    let lock = &mut this.lock;
+                    Pin::new(rx).poll(cx).map(|res| {
+                        res.map(|_| {
+                            print_debug("qutex::FutureUpgrade::poll: Ready. Upgrading.");
+                            WriteGuard {
+                                lock: lock.take().unwrap(),
+                            }
+                        })
                     })
-                })
+                }
+                None => {
+                    print_debug("qutex::FutureUpgrade::poll: Uncontended. Upgrading.");
+                    Poll::Ready(Ok(WriteGuard {
+                        lock: this.lock.take().unwrap(),
+                    }))
+                }
             }
         } else {
             panic!("FutureUpgrade::poll: Task already completed.");
@@ -252,13 +290,8 @@ impl<T> Drop for FutureUpgrade<T> {
             match self.rx.take() {
                 Some(mut rx) => {
                     rx.close();
-                    match rx.try_recv() {
-                        Ok(status) => {
-                            if status.is_some() {
-                                unsafe { lock.release_write_lock() }
-                            }
-                        }
-                        Err(_) => (),
+                    if let Ok(Some(())) = rx.try_recv() {
+                        unsafe { lock.release_write_lock() }
                     }
                 }
                 None => unsafe { lock.release_write_lock() },
@@ -273,6 +306,8 @@ impl<T> Drop for FutureUpgrade<T> {
 pub struct FutureReadGuard<T> {
     lock: Option<QrwLock<T>>,
     rx: Receiver<()>,
+    #[cfg(feature = "metrics")]
+    wait: Wait,
 }
 
 impl<T> FutureReadGuard<T> {
@@ -280,33 +315,39 @@ impl<T> FutureReadGuard<T> {
     fn new(lock: QrwLock<T>, rx: Receiver<()>) -> FutureReadGuard<T> {
         FutureReadGuard {
             lock: Some(lock),
-            rx: rx,
+            rx,
+            #[cfg(feature = "metrics")]
+            wait: Wait::new(),
         }
     }
 
     /// Blocks the current thread until this future resolves.
     #[inline]
     pub fn wait(self) -> Result<ReadGuard<T>, Canceled> {
-        <Self as Future>::wait(self)
+        executor::block_on(self)
     }
 }
 
 impl<T> Future for FutureReadGuard<T> {
-    type Item = ReadGuard<T>;
-    type Error = Canceled;
+    type Output = Result<ReadGuard<T>, Canceled>;
 
     #[inline]
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if self.lock.is_some() {
-            unsafe { self.lock.as_ref().unwrap().process_queues() }
-            self.rx.poll().map(|res| {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.lock.is_some() {
+            unsafe { this.lock.as_ref().unwrap().process_queues() }
+            let lock = &mut this.lock;
+            let poll = Pin::new(&mut this.rx).poll(cx).map(|res| {
                 res.map(|_| {
                     print_debug("qutex::FutureReadGuard::poll: ReadGuard acquired.");
                     ReadGuard {
-                        lock: self.lock.take().unwrap(),
+                        lock: lock.take().unwrap(),
                     }
                 })
-            })
+            });
+            #[cfg(feature = "metrics")]
+            record_poll(&mut this.wait, &poll);
+            poll
         } else {
             panic!("FutureReadGuard::poll: Task already completed.");
         }
@@ -319,13 +360,8 @@ impl<T> Drop for FutureReadGuard<T> {
     fn drop(&mut self) {
         if let Some(lock) = self.lock.take() {
             self.rx.close();
-            match self.rx.try_recv() {
-                Ok(status) => {
-                    if status.is_some() {
-                        unsafe { lock.release_read_lock() }
-                    }
-                }
-                Err(_) => (),
+            if let Ok(Some(())) = self.rx.try_recv() {
+                unsafe { lock.release_read_lock() }
             }
         }
     }
@@ -337,6 +373,8 @@ impl<T> Drop for FutureReadGuard<T> {
 pub struct FutureWriteGuard<T> {
     lock: Option<QrwLock<T>>,
     rx: Receiver<()>,
+    #[cfg(feature = "metrics")]
+    wait: Wait,
 }
 
 impl<T> FutureWriteGuard<T> {
@@ -344,33 +382,39 @@ impl<T> FutureWriteGuard<T> {
     fn new(lock: QrwLock<T>, rx: Receiver<()>) -> FutureWriteGuard<T> {
         FutureWriteGuard {
             lock: Some(lock),
-            rx: rx,
+            rx,
+            #[cfg(feature = "metrics")]
+            wait: Wait::new(),
         }
     }
 
     /// Blocks the current thread until this future resolves.
     #[inline]
     pub fn wait(self) -> Result<WriteGuard<T>, Canceled> {
-        <Self as Future>::wait(self)
+        executor::block_on(self)
     }
 }
 
 impl<T> Future for FutureWriteGuard<T> {
-    type Item = WriteGuard<T>;
-    type Error = Canceled;
+    type Output = Result<WriteGuard<T>, Canceled>;
 
     #[inline]
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if self.lock.is_some() {
-            unsafe { self.lock.as_ref().unwrap().process_queues() }
-            self.rx.poll().map(|res| {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.lock.is_some() {
+            unsafe { this.lock.as_ref().unwrap().process_queues() }
+            let lock = &mut this.lock;
+            let poll = Pin::new(&mut this.rx).poll(cx).map(|res| {
                 res.map(|_| {
                     print_debug("qutex::FutureWriteGuard::poll: WriteGuard acquired.");
                     WriteGuard {
-                        lock: self.lock.take().unwrap(),
+                        lock: lock.take().unwrap(),
                     }
                 })
-            })
+            });
+            #[cfg(feature = "metrics")]
+            record_poll(&mut this.wait, &poll);
+            poll
         } else {
             panic!("FutureWriteGuard::poll: Task already completed.");
         }
@@ -383,13 +427,8 @@ impl<T> Drop for FutureWriteGuard<T> {
     fn drop(&mut self) {
         if let Some(lock) = self.lock.take() {
             self.rx.close();
-            match self.rx.try_recv() {
-                Ok(status) => {
-                    if status.is_some() {
-                        unsafe { lock.release_write_lock() }
-                    }
-                }
-                Err(_) => (),
+            if let Ok(Some(())) = self.rx.try_recv() {
+                unsafe { lock.release_write_lock() }
             }
         }
     }
@@ -412,7 +451,69 @@ pub struct QrwRequest {
 impl QrwRequest {
     /// Returns a new `QrwRequest`.
     pub fn new(tx: Sender<()>, kind: RequestKind) -> QrwRequest {
-        QrwRequest { tx: tx, kind: kind }
+        QrwRequest { tx, kind }
+    }
+}
+
+/// The order in which a `QrwLock` grants queued read and write requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RwPolicy {
+    /// Requests are granted strictly in the order made, each run of
+    /// consecutive read requests together.
+    ///
+    /// Nobody starves, but a read request queued behind several writes
+    /// waits for every one of them, and readers interleaved with writers
+    /// are granted one small batch at a time.
+    #[default]
+    TaskFair,
+    /// Reader phases, granting every read request waiting, alternate with
+    /// writer phases, granting one write request at a time in the order
+    /// made.
+    ///
+    /// A read request waits for at most the writer holding the lock and
+    /// one more, however many writes are queued, and a write request waits
+    /// for at most one reader phase per write queued ahead of it: read
+    /// requests made while a write is waiting join the next reader phase
+    /// rather than the current one. Neither side can starve the other under
+    /// sustained load.
+    PhaseFair,
+}
+
+/// The requests a `PhaseFair` lock has sorted out of its queue.
+#[derive(Debug)]
+struct Phases {
+    readers: VecDeque<Sender<()>>,
+    writers: VecDeque<Sender<()>>,
+    // The kind of phase last granted:
+    last: RequestKind,
+}
+
+impl Phases {
+    /// Grants every waiting read request, returning the number granted.
+    fn grant_readers(&mut self) -> usize {
+        // Requesters which have dropped their receivers are skipped:
+        let granted = self.readers.drain(..).filter_map(|tx| tx.send(()).ok()).count();
+        if granted != 0 {
+            self.last = RequestKind::Read;
+        }
+        granted
+    }
+
+    /// Grants the first waiting write request, returning `true`, if any.
+    fn grant_writer(&mut self) -> bool {
+        while let Some(tx) = self.writers.pop_front() {
+            if tx.send(()).is_ok() {
+                self.last = RequestKind::Write;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns `true` if a write request is waiting.
+    fn write_pending(&mut self) -> bool {
+        self.writers.retain(|tx| !tx.is_canceled());
+        !self.writers.is_empty()
     }
 }
 
@@ -425,6 +526,12 @@ struct Inner<T> {
     queue: SegQueue<QrwRequest>,
     tip: UnsafeCell<Option<QrwRequest>>,
     upgrade_queue: SegQueue<Sender<()>>,
+    policy: RwPolicy,
+    // Only touched while `CONTENDED`, the number sorted kept aside:
+    phases: UnsafeCell<Phases>,
+    sorted: AtomicUsize,
+    #[cfg(feature = "metrics")]
+    stats: Counters,
 }
 
 impl<T> From<T> for Inner<T> {
@@ -436,6 +543,15 @@ impl<T> From<T> for Inner<T> {
             queue: SegQueue::new(),
             tip: UnsafeCell::new(None),
             upgrade_queue: SegQueue::new(),
+            policy: RwPolicy::TaskFair,
+            phases: UnsafeCell::new(Phases {
+                readers: VecDeque::new(),
+                writers: VecDeque::new(),
+                last: RequestKind::Write,
+            }),
+            sorted: AtomicUsize::new(0),
+            #[cfg(feature = "metrics")]
+            stats: Counters::default(),
         }
     }
 }
@@ -462,6 +578,22 @@ impl<T> QrwLock<T> {
         }
     }
 
+    /// Creates and returns a new `QrwLock` granting requests according to
+    /// `policy`.
+    pub fn with_policy(val: T, policy: RwPolicy) -> QrwLock<T> {
+        let mut inner = Inner::from(val);
+        inner.policy = policy;
+        QrwLock {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Returns the order in which this lock grants requests.
+    #[inline]
+    pub fn policy(&self) -> RwPolicy {
+        self.inner.policy
+    }
+
     /// Returns a new `FutureReadGuard` which can be used as a future and will
     /// resolve into a `ReadGuard`.
     #[inline]
@@ -488,6 +620,11 @@ impl<T> QrwLock<T> {
 
     /// Pushes a lock request onto the queue.
     ///
+    /// # Safety
+    ///
+    /// The request's sender will be completed when the lock is granted. The
+    /// holder of the matching receiver becomes responsible for releasing.
+    ///
     //
     // TODO: Evaluate unsafe-ness (appears unlikely this can be misused except
     // to deadlock the queue which is fine).
@@ -495,6 +632,20 @@ impl<T> QrwLock<T> {
     #[inline]
     pub unsafe fn enqueue_lock_request(&self, req: QrwRequest) {
         self.inner.queue.push(req);
+        #[cfg(feature = "metrics")]
+        self.inner.stats.queued(self.inner.queue.len());
+    }
+
+    /// Returns a snapshot of this lock's contention counters.
+    ///
+    /// Every request made through `read` or `write` which resolves to a
+    /// guard is counted. Those not yet granted when first polled count as
+    /// contended and add the time since they were made to the total wait
+    /// time. Upgrades are not counted.
+    #[cfg(feature = "metrics")]
+    #[inline]
+    pub fn stats(&self) -> LockStats {
+        self.inner.stats.snapshot()
     }
 
     /// Returns a mutable reference to the inner `Vec` if there are currently
@@ -522,6 +673,34 @@ impl<T> QrwLock<T> {
         self.inner.cell.get()
     }
 
+    /// Returns `true` if the lock is held, for reading or writing.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.inner.state.load(SeqCst) & (READ_COUNT_MASK | WRITE_LOCKED) != 0
+    }
+
+    /// Returns the number of readers holding the lock.
+    #[inline]
+    pub fn readers(&self) -> usize {
+        self.inner.state.load(SeqCst) & READ_COUNT_MASK
+    }
+
+    /// Returns the number of read, write and upgrade requests queued for the
+    /// lock, including any given up on but yet to be discarded.
+    ///
+    /// A request being granted as this is called may not be counted.
+    #[inline]
+    pub fn queued_waiters(&self) -> usize {
+        self.inner.queue.len() + self.inner.upgrade_queue.len() + self.inner.sorted.load(Relaxed)
+    }
+
+    /// Returns `true` if there are no other copies of this `QrwLock`, in
+    /// which case nobody can be holding or waiting for it.
+    #[inline]
+    pub(crate) fn is_unique(&self) -> bool {
+        Arc::strong_count(&self.inner) == 1
+    }
+
     /// Pops the next read or write lock request and returns it or `None` if the queue is empty.
     #[inline]
     fn pop_request(&self) -> Option<QrwRequest> {
@@ -556,9 +735,75 @@ impl<T> QrwLock<T> {
         }
     }
 
+    /// Sorts every queued request into its phase and returns the phases.
+    ///
+    /// # Safety
+    ///
+    /// The state must be `CONTENDED`, and the phases returned released
+    /// (with `sorted`) before it is cleared.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn phases(&self) -> &mut Phases {
+        debug_assert_eq!(self.inner.state.load(Acquire) & CONTENDED, CONTENDED);
+        let phases = &mut *self.inner.phases.get();
+        while let Some(req) = self.pop_request() {
+            match req.kind {
+                RequestKind::Read => phases.readers.push_back(req.tx),
+                RequestKind::Write => phases.writers.push_back(req.tx),
+            }
+        }
+        phases
+    }
+
+    /// Records the number of requests left sorted into `phases`.
+    #[inline]
+    fn sorted(&self, phases: &Phases) {
+        self.inner.sorted.store(phases.readers.len() + phases.writers.len(), Relaxed);
+    }
+
+    /// Grants the next phase if the lock is free, or lets waiting readers
+    /// join the current one if no write is waiting, returning the new
+    /// state.
+    fn fulfill_phase(&self, state: usize) -> usize {
+        let phases = unsafe { self.phases() };
+        let state = if state == 0 {
+            let readers_turn = phases.last == RequestKind::Write || !phases.write_pending();
+            match readers_turn {
+                true => match phases.grant_readers() {
+                    // Any read requests were all dropped:
+                    0 if phases.grant_writer() => WRITE_LOCKED,
+                    granted => granted,
+                },
+                false if phases.grant_writer() => WRITE_LOCKED,
+                false => phases.grant_readers(),
+            }
+        } else if !phases.write_pending() {
+            state + phases.grant_readers()
+        } else {
+            state
+        };
+        self.sorted(phases);
+        state
+    }
+
+    /// Returns `true` if a write request is next in line to be granted.
+    fn write_pending(&self) -> bool {
+        match self.inner.policy {
+            RwPolicy::TaskFair => self.peek_request_kind() == Some(RequestKind::Write),
+            RwPolicy::PhaseFair => {
+                let phases = unsafe { self.phases() };
+                let pending = phases.write_pending();
+                self.sorted(phases);
+                pending
+            }
+        }
+    }
+
     /// Fulfill a request if possible.
     #[inline]
     fn fulfill_request(&self, mut state: usize) -> usize {
+        if self.inner.policy == RwPolicy::PhaseFair {
+            return self.fulfill_phase(state);
+        }
         loop {
             debug_assert_eq!(self.inner.state.load(Acquire) & CONTENDED, CONTENDED);
             debug_assert_eq!(self.inner.state.load(Acquire) & WRITE_LOCKED, 0);
@@ -645,21 +890,20 @@ impl<T> QrwLock<T> {
         print_debug("qutex::QrwLock::process_upgrade_queue: Processing upgrade queue...");
         debug_assert!(self.inner.state.load(Acquire) == CONTENDED);
 
-        loop {
-            match self.inner.upgrade_queue.pop() {
-                Some(tx) => match tx.send(()) {
-                    Ok(_) => {
-                        print_debug("qutex::QrwLock::process_upgrade_queue: \
-                            Upgrading to write lock...");
-                        return true;
-                    }
-                    Err(()) => {
-                        print_debug("qutex::QrwLock::process_upgrade_queue: \
-                            Unable to upgrade: error completing oneshot.");
-                        continue;
-                    }
-                },
-                None => break,
+        while let Some(tx) = self.inner.upgrade_queue.pop() {
+            match tx.send(()) {
+                Ok(_) => {
+                    print_debug("qutex::QrwLock::process_upgrade_queue: \
+                        Upgrading to write lock...");
+                    // A writer phase, as far as `PhaseFair` is concerned:
+                    unsafe { (*self.inner.phases.get()).last = RequestKind::Write }
+                    return true;
+                }
+                Err(()) => {
+                    print_debug("qutex::QrwLock::process_upgrade_queue: \
+                        Unable to upgrade: error completing oneshot.");
+                    continue;
+                }
             }
         }
         false
@@ -667,6 +911,11 @@ impl<T> QrwLock<T> {
 
     /// Pops the next lock request in the queue if possible.
     ///
+    /// # Safety
+    ///
+    /// Granting a request transfers a read or write lock to its requester.
+    /// Only call this when managing lock state manually.
+    ///
     //
     // TODO: Clarify the following (or remove):
     //
@@ -724,15 +973,24 @@ impl<T> QrwLock<T> {
                     );
                 }
 
-                if self.peek_request_kind() == Some(RequestKind::Read) {
-                    // We are read locked and the next request is a read.
+                if self.inner.upgrade_queue.is_empty()
+                    && self.inner.policy == RwPolicy::PhaseFair
+                {
+                    // Waiting readers join unless a write is waiting:
+                    let new_state = self.fulfill_phase(state);
+                    self.inner.state.store(new_state, SeqCst);
+                } else if self.inner.upgrade_queue.is_empty()
+                    && self.peek_request_kind() == Some(RequestKind::Read)
+                {
+                    // We are read locked, the next request is a read, and
+                    // no upgrade is waiting for the readers to drain.
                     let new_state = self.fulfill_request(state);
                     self.inner.state.store(new_state, SeqCst);
                 } else {
-                    // Either the next request is empty or a write and
-                    // we are already read locked. Leave the request
-                    // there and restore our original state, removing
-                    // the CONTENDED flag.
+                    // Either the next request is empty or a write, or an
+                    // upgrade is waiting, and we are already read locked.
+                    // Leave the request there and restore our original
+                    // state, removing the CONTENDED flag.
                     self.inner.state.store(state, SeqCst);
                 }
             }
@@ -759,6 +1017,10 @@ impl<T> QrwLock<T> {
     /// Do not call this method directly unless you are using a custom guard
     /// or are otherwise managing the lock state manually. Use
     /// `ReadGuard::upgrade` instead.
+    ///
+    /// # Safety
+    ///
+    /// The caller must currently hold a read lock.
     #[inline]
     pub unsafe fn upgrade_read_lock(&self) -> Result<(), Receiver<()>> {
         print_debug("qutex::QrwLock::upgrade_read_lock: Attempting to upgrade reader to writer...");
@@ -784,10 +1046,36 @@ impl<T> QrwLock<T> {
         }
     }
 
+    /// Converts a single read lock into a write lock, returning `true`, if
+    /// there is no other read lock and no pending write or upgrade request.
+    /// Otherwise returns `false`, leaving the read lock held.
+    ///
+    /// Use `ReadGuard::try_upgrade` rather than calling this directly.
+    ///
+    /// # Safety
+    ///
+    /// The caller must currently hold a read lock.
+    #[inline]
+    pub unsafe fn try_upgrade_read_lock(&self) -> bool {
+        print_debug("qutex::QrwLock::try_upgrade_read_lock: Attempting to upgrade reader...");
+
+        let state = self.contend();
+        debug_assert!(state > 0 && state <= READ_COUNT_MASK);
+        let upgrade = state == 1
+            && self.inner.upgrade_queue.is_empty()
+            && !self.write_pending();
+        self.inner.state.store(if upgrade { WRITE_LOCKED } else { state }, SeqCst);
+        upgrade
+    }
+
     /// Converts a write lock into a read lock then processes the queue,
     /// allowing additional read requests to acquire locks.
     ///
     /// Use `WriteGuard::downgrade` rather than calling this directly.
+    ///
+    /// # Safety
+    ///
+    /// The caller must currently hold the write lock.
     #[inline]
     pub unsafe fn downgrade_write_lock(&self) {
         print_debug("qutex::QrwLock::downgrade_write_lock: Attempting to downgrade write lock...");
@@ -796,7 +1084,18 @@ impl<T> QrwLock<T> {
         match self.contend() {
             0 => debug_assert!(false, "unreachable"),
             WRITE_LOCKED => {
-                self.inner.state.store(1, SeqCst);
+                // Ending the writer phase, under `PhaseFair`:
+                let state = match self.inner.policy {
+                    RwPolicy::TaskFair => 1,
+                    RwPolicy::PhaseFair => {
+                        let phases = self.phases();
+                        let granted = phases.grant_readers();
+                        phases.last = RequestKind::Read;
+                        self.sorted(phases);
+                        1 + granted
+                    }
+                };
+                self.inner.state.store(state, SeqCst);
             }
             _state => debug_assert!(false, "unreachable"),
         }
@@ -810,6 +1109,10 @@ impl<T> QrwLock<T> {
     ///
     /// If a reader is waiting to be upgraded and the read lock count reaches
     /// 1, the upgrade sender will be completed.
+    ///
+    /// # Safety
+    ///
+    /// The caller must currently hold a read lock.
     //
     // TODO: Consider using `Ordering::Release`.
     // TODO: Wire up upgrade checking (if reader count == 1, complete `upgrade_tx`).
@@ -835,6 +1138,10 @@ impl<T> QrwLock<T> {
 
     /// Unlocks this (the caller's) lock and unparks the next requester task
     /// in the queue if possible.
+    ///
+    /// # Safety
+    ///
+    /// The caller must currently hold the write lock.
     //
     // TODO: Consider using `Ordering::Release`.
     #[inline]
@@ -884,63 +1191,58 @@ impl<T> Clone for QrwLock<T> {
 // Woefully incomplete.
 mod tests {
     use super::*;
-    use futures::{future, Future};
+    use futures::future::{self, LocalBoxFuture};
+    use futures::TryFutureExt;
     use std::thread;
 
     #[test]
     fn simple() {
         let lock = QrwLock::from(0i32);
 
-        let future_r0 = Box::new(lock.clone().read().and_then(|guard| {
+        let future_r0 = Box::pin(lock.clone().read().map_ok(|guard| {
             assert_eq!(*guard, 0);
             println!("val[r0]: {}", *guard);
             ReadGuard::release(guard);
-            Ok(())
         }));
 
-        let future_w0 = Box::new(lock.clone().write().and_then(|mut guard| {
+        let future_w0 = Box::pin(lock.clone().write().map_ok(|mut guard| {
             *guard = 5;
             println!("val is now: {}", *guard);
-            Ok(())
         }));
 
-        let future_r1 = Box::new(lock.clone().read().and_then(|guard| {
+        let future_r1 = Box::pin(lock.clone().read().map_ok(|guard| {
             assert_eq!(*guard, 5);
             println!("val[r1]: {}", *guard);
-            Ok(())
         }));
 
-        let future_r2 = Box::new(lock.clone().read().and_then(|guard| {
+        let future_r2 = Box::pin(lock.clone().read().map_ok(|guard| {
             assert_eq!(*guard, 5);
             println!("val[r2]: {}", *guard);
-            Ok(())
         }));
 
-        let future_u0 = Box::new(lock.clone().read().and_then(|read_guard| {
+        let future_u0 = Box::pin(lock.clone().read().and_then(|read_guard| {
             println!("Upgrading read guard...");
-            ReadGuard::upgrade(read_guard).and_then(|mut write_guard| {
+            ReadGuard::upgrade(read_guard).map_ok(|mut write_guard| {
                 println!("Read guard upgraded.");
                 *write_guard = 6;
-                Ok(())
             })
         }));
 
         // This read will take place before the above read lock can be
         // upgraded because read requests are processed in a chained fashion:
-        let future_r3 = Box::new(lock.clone().read().and_then(|guard| {
+        let future_r3 = Box::pin(lock.clone().read().map_ok(|guard| {
             // Value should not yet be affected by the events following the
             // above write guard upgrade.
             assert_eq!(*guard, 5);
             println!("val[r3]: {}", *guard);
-            Ok(())
         }));
 
         // future_r0.join4(future_w0, future_r1, future_r2).wait().unwrap();
 
-        let futures: Vec<Box<dyn Future<Item = (), Error = Canceled>>> = vec![
+        let futures: Vec<LocalBoxFuture<'_, Result<(), Canceled>>> = vec![
             future_r0, future_w0, future_r1, future_r2, future_u0, future_r3,
         ];
-        future::join_all(futures).wait().unwrap();
+        executor::block_on(future::try_join_all(futures)).unwrap();
 
         let future_guard = lock.clone().read();
         let guard = future_guard.wait().unwrap();
@@ -963,19 +1265,18 @@ mod tests {
             let future_write_guard = lock.clone().write();
             let future_read_guard = lock.clone().read();
 
-            let future_write = future_write_guard.and_then(|mut guard| {
+            let future_write = future_write_guard.map_ok(|mut guard| {
                 *guard += 1;
                 WriteGuard::downgrade(guard);
-                Ok(())
             });
 
-            let future_read = future_read_guard.and_then(move |guard| {
+            let future_read = future_read_guard.map_ok(move |guard| {
                 // println!("Value for thread '{}' is: {}", _i, *_guard);
-                Ok(guard)
+                guard
             });
 
             threads.push(thread::spawn(|| {
-                future_write.join(future_read).wait().unwrap();
+                executor::block_on(future::try_join(future_write, future_read)).unwrap();
             }));
         }
 
@@ -1067,6 +1368,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn try_upgrade() {
+        let lock = QrwLock::new(0i32);
+        let reader = lock.clone().read().wait().unwrap();
+        let other = lock.clone().read().wait().unwrap();
+        let reader = ReadGuard::try_upgrade(reader).unwrap_err();
+        drop(other);
+
+        // Nothing in between:
+        let mut writer = ReadGuard::try_upgrade(reader).unwrap();
+        *writer += 1;
+        drop(writer);
+
+        // Nor ahead of a pending write:
+        let reader = lock.clone().read().wait().unwrap();
+        let write = lock.clone().write();
+        let reader = ReadGuard::try_upgrade(reader).unwrap_err();
+        drop(reader);
+        assert_eq!(*write.wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn upgrade_ahead_of_readers() {
+        use futures::task::noop_waker_ref;
+        use futures::FutureExt;
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = QrwLock::new(0i32);
+        let reader = lock.clone().read().wait().unwrap();
+        let other = lock.clone().read().wait().unwrap();
+        let mut upgrade = ReadGuard::upgrade(reader);
+        assert!(upgrade.poll_unpin(cx).is_pending());
+
+        // Held off until the upgrade is done with:
+        let mut read = lock.clone().read();
+        assert!(read.poll_unpin(cx).is_pending());
+        drop(other);
+        let mut writer = upgrade.wait().unwrap();
+        assert!(read.poll_unpin(cx).is_pending());
+        *writer = 5;
+        drop(writer);
+        assert_eq!(*read.wait().unwrap(), 5);
+    }
+
     #[test]
     fn multiple_upgrades() {
         let lock = QrwLock::new(0usize);
@@ -1106,10 +1451,108 @@ mod tests {
             let name = handle.thread().name().unwrap().to_owned();
             handle
                 .join()
-                .expect(&format!("Error joining thread: {:?}", name));
+                .unwrap_or_else(|_| panic!("Error joining thread: {:?}", name));
         }
 
         let guard = lock.read().wait().expect("[6]");
         assert_eq!(*guard, upgrade_count);
     }
+
+    #[test]
+    fn introspection() {
+        let lock = QrwLock::new(0u32);
+        assert!(!lock.is_locked());
+        let readers = (lock.clone().read().wait().unwrap(), lock.clone().read().wait().unwrap());
+        assert_eq!(lock.readers(), 2);
+
+        let writer = lock.clone().write();
+        assert_eq!(lock.queued_waiters(), 1);
+        drop(readers);
+        let mut guard = writer.wait().unwrap();
+        *guard += 1;
+        assert!(lock.is_locked());
+        assert_eq!((lock.readers(), lock.queued_waiters()), (0, 0));
+        drop(guard);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn phase_fair() {
+        use futures::task::noop_waker_ref;
+        use futures::FutureExt;
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = QrwLock::with_policy(Vec::new(), RwPolicy::PhaseFair);
+        let writer = lock.clone().write().wait().unwrap();
+        let mut writes = (0..3).map(|_| lock.clone().write()).collect::<Vec<_>>();
+        let mut reads = (0..2).map(|_| lock.clone().read()).collect::<Vec<_>>();
+        for write in &mut writes {
+            assert!(write.poll_unpin(cx).is_pending());
+        }
+        for read in &mut reads {
+            assert!(read.poll_unpin(cx).is_pending());
+        }
+        assert_eq!(lock.queued_waiters(), 5);
+
+        // Readers go ahead of every queued writer but the next:
+        drop(writer);
+        let readers = reads.into_iter().map(|read| read.wait().unwrap()).collect::<Vec<_>>();
+        assert!(writes[0].poll_unpin(cx).is_pending());
+
+        // Readers made meanwhile wait for that writer:
+        let mut late = lock.clone().read();
+        assert!(late.poll_unpin(cx).is_pending());
+        drop(readers);
+        let mut writer = writes.remove(0).wait().unwrap();
+        writer.push(1);
+        drop(writer);
+        let reader = late.wait().unwrap();
+        assert_eq!(*reader, [1]);
+        assert!(writes[0].poll_unpin(cx).is_pending());
+        drop(reader);
+
+        for write in writes {
+            write.wait().unwrap().push(2);
+        }
+        assert_eq!(*lock.clone().read().wait().unwrap(), [1, 2, 2]);
+        assert_eq!(lock.queued_waiters(), 0);
+    }
+
+    #[test]
+    fn phase_fair_sustained() {
+        use std::sync::atomic::AtomicUsize;
+
+        const ROUNDS: usize = 100;
+        let lock = QrwLock::with_policy(0usize, RwPolicy::PhaseFair);
+        let readers_left = Arc::new(AtomicUsize::new(4));
+        let writers_left = Arc::new(AtomicUsize::new(2));
+
+        // Each side keeps the other under load until both are done, so that
+        // either starving would never finish:
+        let spawn = |read: bool| {
+            let lock = lock.clone();
+            let (left, others_left) = match read {
+                true => (readers_left.clone(), writers_left.clone()),
+                false => (writers_left.clone(), readers_left.clone()),
+            };
+            thread::spawn(move || {
+                let mut rounds = 0;
+                while rounds < ROUNDS || others_left.load(SeqCst) != 0 {
+                    match read {
+                        true => drop(lock.clone().read().wait().unwrap()),
+                        false => *lock.clone().write().wait().unwrap() += 1,
+                    }
+                    rounds += 1;
+                    if rounds == ROUNDS {
+                        left.fetch_sub(1, SeqCst);
+                    }
+                }
+            })
+        };
+        let threads = [true, true, false, true, false, true].map(spawn);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(*lock.clone().read().wait().unwrap() >= 2 * ROUNDS);
+    }
 }
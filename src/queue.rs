@@ -0,0 +1,426 @@
+//! The concurrent building blocks the locks are made of: `crossbeam`'s by
+//! default, or minimal stand-ins without the `crossbeam` feature, for
+//! builds which must depend on nothing beyond `futures`. `FixedQueue`, a
+//! bounded queue stored inline, backs the wait queues with the
+//! `fixed_queue` feature.
+//
+// The stand-in `SegQueue` is a `VecDeque` behind a spin lock. Each
+// operation holds the lock only for a push or pop, so contention is brief,
+// but a holder preempted mid-operation stalls everyone else until it is
+// rescheduled, which `crossbeam`'s lock-free queue never does.
+
+#[cfg(feature = "crossbeam")]
+pub(crate) use crossbeam::queue::SegQueue;
+#[cfg(feature = "crossbeam")]
+pub(crate) use crossbeam::utils::CachePadded;
+
+#[cfg(not(feature = "crossbeam"))]
+pub(crate) use self::fallback::{CachePadded, SegQueue};
+
+/// Aligns a value, which may be unsized, to the length of a cache line, as
+/// `CachePadded` does for sized ones. Being unsized, it can only come last
+/// within a struct, so pads nothing after it.
+#[derive(Debug, Default)]
+#[cfg_attr(
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64"
+    ),
+    repr(C, align(128))
+)]
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64"
+    )),
+    repr(C, align(64))
+)]
+pub(crate) struct CacheAligned<T: ?Sized> {
+    value: T,
+}
+
+impl<T> CacheAligned<T> {
+    #[inline]
+    pub(crate) const fn new(value: T) -> CacheAligned<T> {
+        CacheAligned { value }
+    }
+}
+
+impl<T: ?Sized> core::ops::Deref for CacheAligned<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: ?Sized> core::ops::DerefMut for CacheAligned<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+#[cfg(any(test, feature = "fixed_queue"))]
+pub(crate) use self::fixed::FixedQueue;
+
+#[cfg(any(test, feature = "fixed_queue"))]
+mod fixed {
+    use super::CachePadded;
+    use core::cell::UnsafeCell;
+    use core::fmt;
+    use core::mem::MaybeUninit;
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+
+    struct Slot<T> {
+        // The position the slot is next to be pushed to at, or, once pushed
+        // to, popped from at plus one:
+        seq: AtomicUsize,
+        val: UnsafeCell<MaybeUninit<T>>,
+    }
+
+    /// A lock-free multi-producer, multi-consumer FIFO queue of at most `N`
+    /// items, stored inline.
+    //
+    // Dmitry Vyukov's bounded queue: each position claims the slot at
+    // `position % N` once the slot's sequence number shows it is free.
+    pub(crate) struct FixedQueue<T, const N: usize> {
+        head: CachePadded<AtomicUsize>,
+        tail: CachePadded<AtomicUsize>,
+        slots: [Slot<T>; N],
+    }
+
+    impl<T, const N: usize> FixedQueue<T, N> {
+        /// Returns a new, empty queue.
+        pub(crate) fn new() -> FixedQueue<T, N> {
+            assert!(N > 0, "FixedQueue::new: Capacity must be non-zero.");
+            FixedQueue {
+                head: CachePadded::new(AtomicUsize::new(0)),
+                tail: CachePadded::new(AtomicUsize::new(0)),
+                slots: core::array::from_fn(|i| Slot {
+                    seq: AtomicUsize::new(i),
+                    val: UnsafeCell::new(MaybeUninit::uninit()),
+                }),
+            }
+        }
+
+        /// Pushes `item` onto the back of the queue, or returns it if the
+        /// queue is full.
+        pub(crate) fn push(&self, item: T) -> Result<(), T> {
+            let mut pos = self.tail.load(Relaxed);
+            loop {
+                let slot = &self.slots[pos % N];
+                let lag = slot.seq.load(Acquire).wrapping_sub(pos) as isize;
+                if lag == 0 {
+                    match self
+                        .tail
+                        .compare_exchange_weak(pos, pos.wrapping_add(1), Relaxed, Relaxed)
+                    {
+                        Ok(_) => {
+                            unsafe { (*slot.val.get()).write(item) };
+                            slot.seq.store(pos.wrapping_add(1), Release);
+                            return Ok(());
+                        }
+                        Err(tail) => pos = tail,
+                    }
+                } else if lag < 0 {
+                    // Not yet popped from a lap ago:
+                    return Err(item);
+                } else {
+                    pos = self.tail.load(Relaxed);
+                }
+            }
+        }
+
+        /// Pops the item at the front of the queue, if any.
+        pub(crate) fn pop(&self) -> Option<T> {
+            let mut pos = self.head.load(Relaxed);
+            loop {
+                let slot = &self.slots[pos % N];
+                let lag = slot.seq.load(Acquire).wrapping_sub(pos.wrapping_add(1)) as isize;
+                if lag == 0 {
+                    match self
+                        .head
+                        .compare_exchange_weak(pos, pos.wrapping_add(1), Relaxed, Relaxed)
+                    {
+                        Ok(_) => {
+                            let item = unsafe { (*slot.val.get()).assume_init_read() };
+                            slot.seq.store(pos.wrapping_add(N), Release);
+                            return Some(item);
+                        }
+                        Err(head) => pos = head,
+                    }
+                } else if lag < 0 {
+                    // Not yet pushed to:
+                    return None;
+                } else {
+                    pos = self.head.load(Relaxed);
+                }
+            }
+        }
+
+        /// Returns `true` if the queue is empty.
+        #[inline]
+        pub(crate) fn is_empty(&self) -> bool {
+            self.head.load(SeqCst) == self.tail.load(SeqCst)
+        }
+    }
+
+    impl<T, const N: usize> Default for FixedQueue<T, N> {
+        #[inline]
+        fn default() -> FixedQueue<T, N> {
+            FixedQueue::new()
+        }
+    }
+
+    impl<T, const N: usize> Drop for FixedQueue<T, N> {
+        fn drop(&mut self) {
+            while self.pop().is_some() {}
+        }
+    }
+
+    impl<T, const N: usize> fmt::Debug for FixedQueue<T, N> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.pad("FixedQueue { .. }")
+        }
+    }
+
+    // Each item is only ever reached by whoever claimed its slot:
+    unsafe impl<T: Send, const N: usize> Send for FixedQueue<T, N> {}
+    unsafe impl<T: Send, const N: usize> Sync for FixedQueue<T, N> {}
+
+    #[cfg(all(test, feature = "std"))]
+    mod tests {
+        use super::*;
+        use std::sync::Arc;
+        use std::thread;
+
+        #[test]
+        fn concurrent_fixed() {
+            let queue = Arc::new(FixedQueue::<_, 8>::new());
+            let producers = (0..4)
+                .map(|producer| {
+                    let queue = queue.clone();
+                    thread::spawn(move || {
+                        for i in 0..1000 {
+                            let mut item = (producer, i);
+                            while let Err(rejected) = queue.push(item) {
+                                item = rejected;
+                                thread::yield_now();
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // Each producer's items stay in the order pushed:
+            let mut next = [0; 4];
+            while next != [1000; 4] {
+                match queue.pop() {
+                    Some((producer, i)) => {
+                        assert_eq!(next[producer], i);
+                        next[producer] += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+            for producer in producers {
+                producer.join().unwrap();
+            }
+            assert!(queue.is_empty() && queue.pop().is_none());
+        }
+    }
+}
+
+#[cfg(any(test, not(feature = "crossbeam")))]
+mod fallback {
+    use alloc::collections::VecDeque;
+    use core::cell::UnsafeCell;
+    use core::fmt;
+    use core::hint;
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::AtomicBool;
+    use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+    /// The number of spins after which a contended lock yields the thread.
+    #[cfg(feature = "std")]
+    const SPINS_BEFORE_YIELD: u32 = 64;
+
+    /// An unbounded multi-producer, multi-consumer FIFO queue.
+    pub(crate) struct SegQueue<T> {
+        locked: AtomicBool,
+        items: UnsafeCell<VecDeque<T>>,
+    }
+
+    /// Releases the spin lock once dropped, even should the operation
+    /// panic.
+    struct Locked<'a>(&'a AtomicBool);
+
+    impl Drop for Locked<'_> {
+        #[inline]
+        fn drop(&mut self) {
+            self.0.store(false, Release);
+        }
+    }
+
+    impl<T> SegQueue<T> {
+        /// Returns a new, empty queue.
+        #[inline]
+        pub(crate) const fn new() -> SegQueue<T> {
+            SegQueue {
+                locked: AtomicBool::new(false),
+                items: UnsafeCell::new(VecDeque::new()),
+            }
+        }
+
+        /// Runs `f` with the spin lock held.
+        fn with<R>(&self, f: impl FnOnce(&mut VecDeque<T>) -> R) -> R {
+            let mut _spins = 0u32;
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Acquire, Relaxed)
+                .is_err()
+            {
+                while self.locked.load(Relaxed) {
+                    hint::spin_loop();
+                    #[cfg(feature = "std")]
+                    {
+                        _spins += 1;
+                        if _spins == SPINS_BEFORE_YIELD {
+                            _spins = 0;
+                            std::thread::yield_now();
+                        }
+                    }
+                }
+            }
+            let _locked = Locked(&self.locked);
+            f(unsafe { &mut *self.items.get() })
+        }
+
+        /// Pushes `item` onto the back of the queue.
+        #[inline]
+        pub(crate) fn push(&self, item: T) {
+            self.with(|items| items.push_back(item))
+        }
+
+        /// Pops the item at the front of the queue, if any.
+        #[inline]
+        pub(crate) fn pop(&self) -> Option<T> {
+            self.with(VecDeque::pop_front)
+        }
+
+        /// Returns `true` if the queue is empty.
+        #[inline]
+        pub(crate) fn is_empty(&self) -> bool {
+            self.with(|items| items.is_empty())
+        }
+
+        /// Returns the number of items queued.
+        #[inline]
+        pub(crate) fn len(&self) -> usize {
+            self.with(|items| items.len())
+        }
+    }
+
+    impl<T> Default for SegQueue<T> {
+        #[inline]
+        fn default() -> SegQueue<T> {
+            SegQueue::new()
+        }
+    }
+
+    impl<T> fmt::Debug for SegQueue<T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.pad("SegQueue { .. }")
+        }
+    }
+
+    // The items are only ever reached with the spin lock held:
+    unsafe impl<T: Send> Send for SegQueue<T> {}
+    unsafe impl<T: Send> Sync for SegQueue<T> {}
+
+    /// Aligns a value to (at least) the length of a cache line.
+    #[derive(Debug, Default)]
+    #[cfg_attr(
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "powerpc64"
+        ),
+        repr(align(128))
+    )]
+    #[cfg_attr(
+        not(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "powerpc64"
+        )),
+        repr(align(64))
+    )]
+    pub(crate) struct CachePadded<T> {
+        value: T,
+    }
+
+    impl<T> CachePadded<T> {
+        #[inline]
+        pub(crate) const fn new(value: T) -> CachePadded<T> {
+            CachePadded { value }
+        }
+    }
+
+    impl<T> Deref for CachePadded<T> {
+        type Target = T;
+
+        #[inline]
+        fn deref(&self) -> &T {
+            &self.value
+        }
+    }
+
+    impl<T> DerefMut for CachePadded<T> {
+        #[inline]
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.value
+        }
+    }
+
+    #[cfg(all(test, feature = "std"))]
+    mod tests {
+        use super::*;
+        use std::sync::Arc;
+        use std::thread;
+
+        #[test]
+        fn concurrent() {
+            let queue = Arc::new(SegQueue::new());
+            let producers = (0..4)
+                .map(|producer| {
+                    let queue = queue.clone();
+                    thread::spawn(move || {
+                        for i in 0..1000 {
+                            queue.push((producer, i));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for producer in producers {
+                producer.join().unwrap();
+            }
+
+            // Each producer's items stay in the order pushed:
+            let mut next = [0; 4];
+            while let Some((producer, i)) = queue.pop() {
+                assert_eq!(next[producer], i);
+                next[producer] += 1;
+            }
+            assert_eq!(next, [1000; 4]);
+            assert!(queue.is_empty());
+            assert_eq!(queue.len(), 0);
+            assert_eq!(*CachePadded::new(5), 5);
+        }
+    }
+}
@@ -3,386 +3,7143 @@
 //
 // * It is unclear how many of the unsafe methods within need actually remain
 //   unsafe.
+// * Requests queue in `Waiter`s on the heap, as `lock` queues its request
+//   immediately, ordering requests by creation, and as every future wrapping
+//   a `FutureGuard` polls it through `Pin::new`. `IntrusiveQutex` instead
+//   links nodes stored in its pinned futures, on first poll, allocating
+//   nothing per request.
 
-use crossbeam::queue::SegQueue;
-use futures::sync::oneshot::{self, Canceled, Receiver, Sender};
-use futures::{Future, Poll};
-use std::cell::UnsafeCell;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering::SeqCst;
-use std::sync::Arc;
+#[cfg(feature = "std")]
+use crate::blocking;
+#[cfg(feature = "std")]
+use crate::finalize;
+#[cfg(feature = "std")]
+use crate::timer::{self, Sleep};
+#[cfg(feature = "deadlock_detection")]
+use crate::deadlock;
+#[cfg(feature = "debug_dump")]
+use crate::dump::{LockDump, PendingRequest};
+#[cfg(feature = "metrics")]
+use crate::metrics::{Counters, LockStats};
+#[cfg(feature = "registry")]
+use crate::registry;
+use crate::queue::{CacheAligned, CachePadded, SegQueue};
+#[cfg(feature = "std")]
+use crate::rank;
+use crate::wait_queue::{RequestQueue, WaitQueue};
+use crate::Error;
+#[cfg(feature = "std")]
+use crate::Owner;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::sync::Weak;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::borrow::{Borrow, BorrowMut};
+use core::cell::UnsafeCell;
+use core::convert::Infallible;
+use core::future::Future;
+use core::hint;
+use core::mem::{self, ManuallyDrop};
+use core::ptr;
+use core::ops::{Deref, DerefMut};
+use core::panic::{Location, RefUnwindSafe, UnwindSafe};
+use core::pin::Pin;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst};
+#[cfg(feature = "std")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU8, AtomicUsize};
+use core::task::{Context, Poll, Waker};
+use futures::channel::oneshot::{self, Receiver, Sender};
+use futures::task::AtomicWaker;
+#[cfg(feature = "std")]
+use futures::executor;
+#[cfg(feature = "std")]
+use futures::future::BoxFuture;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "std")]
+use std::sync::{Mutex, PoisonError};
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// An optional deadline for a request. Deadlines require `std`, so without
+/// it there never is one.
+#[cfg(feature = "std")]
+type Deadline = Option<Instant>;
+#[cfg(not(feature = "std"))]
+type Deadline = Option<Infallible>;
+
+/// When a request started waiting, as counted by `Qutex::stats`, traced,
+/// and passed to any `on_acquire` hook. Without `std`, nothing is timed.
+#[cfg(feature = "std")]
+type WaitStart = Option<Instant>;
+#[cfg(not(feature = "std"))]
+type WaitStart = Option<Infallible>;
+
+/// Returns the start of a wait, if timed.
+#[inline]
+fn wait_start() -> WaitStart {
+    #[cfg(feature = "std")]
+    return Some(Instant::now());
+    #[cfg(not(feature = "std"))]
+    None
+}
 
 /// Allows access to the data contained within a lock just like a mutex guard.
-#[derive(Debug)]
-pub struct Guard<T> {
+pub struct Guard<T: ?Sized> {
     qutex: Qutex<T>,
 }
 
-impl<T> Guard<T> {
+impl<T: ?Sized> Guard<T> {
     /// Releases the lock held by a `Guard` and returns the original `Qutex`.
     pub fn unlock(guard: Guard<T>) -> Qutex<T> {
-        let qutex = unsafe { ::std::ptr::read(&guard.qutex) };
-        ::std::mem::forget(guard);
-        unsafe { qutex.direct_unlock() }
+        let qutex = unsafe { ::core::ptr::read(&guard.qutex) };
+        ::core::mem::forget(guard);
+        unsafe { Guard::release(&qutex, false) }
+        qutex
+    }
+
+    /// Releases the lock held by a `Guard`, handing it straight to the next
+    /// queued request, and returns the original `Qutex`.
+    ///
+    /// Under `Admission::Barging`, a request woken by an ordinary release
+    /// must still race to take the lock, and is often beaten to it by the
+    /// task which just released it. A fair unlock leaves nobody the chance.
+    /// Under `Admission::Fair` this is just `unlock`.
+    pub fn unlock_fair(guard: Guard<T>) -> Qutex<T> {
+        let qutex = unsafe { ::core::ptr::read(&guard.qutex) };
+        ::core::mem::forget(guard);
+        unsafe { Guard::release(&qutex, true) }
         qutex
     }
+
+    /// Releases the lock held through `qutex`, unless a finalizer is due to
+    /// run first, in which case the lock passes to its guard instead.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the caller, through a guard since forgotten.
+    #[inline]
+    unsafe fn release(qutex: &Qutex<T>, fair: bool) {
+        #[cfg(feature = "std")]
+        if let Some(finalize) = qutex.next_finalizer() {
+            return finalize::spawn(finalize);
+        }
+        qutex.release_guard(fair)
+    }
+
+    /// Lets any queued requests go first, returning a `FutureGuard` which
+    /// resolves once the lock comes back round, or straight away if nobody
+    /// is waiting.
+    ///
+    /// The new request is queued while the lock is still held, behind every
+    /// request already queued (of its priority or higher), so a long-running
+    /// holder can let waiters in without giving up its place entirely. The
+    /// lock is handed over as by `unlock_fair`. Should the queue be full,
+    /// the lock is kept.
+    #[track_caller]
+    pub fn bump(guard: Guard<T>) -> FutureGuard<T> {
+        if guard.qutex.has_requests() {
+            if let Ok(future_guard) = guard.qutex.clone().try_enqueue() {
+                Guard::unlock_fair(guard);
+                return future_guard;
+            }
+        }
+        let qutex = unsafe { ::core::ptr::read(&guard.qutex) };
+        ::core::mem::forget(guard);
+        FutureGuard {
+            acquire: Acquisition {
+                state: State::Acquired(qutex),
+            },
+        }
+    }
+
+    /// Releases the lock, runs the future `f` returns, then re-acquires the
+    /// lock, resolving to the future's output along with the regained
+    /// `Guard` (or the `Error` the new request resolved to).
+    ///
+    /// Nothing is released until first polled. The lock is handed over as
+    /// by `unlock_fair`, and re-requested behind everyone already queued,
+    /// as by `lock`, once the future completes, so that a holder awaiting,
+    /// say, a network call lets others in meanwhile. The data may have
+    /// changed by the time it is regained. Dropped at any point, nothing
+    /// waits: the guard, the future, or the request is dropped with it.
+    #[inline]
+    pub fn with_unlocked<F, Fut>(guard: Guard<T>, f: F) -> FutureWithUnlocked<T, F, Fut>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future,
+    {
+        FutureWithUnlocked {
+            stage: UnlockedStage::Start(Some((guard, f))),
+        }
+    }
+
+    /// Returns the data pinned, for driving futures and other `!Unpin`
+    /// values kept within the lock.
+    ///
+    /// The data lives in the lock's shared allocation, so never moves while
+    /// the `Qutex` is alive. Every guard still has `&mut T` access however,
+    /// through which the data could be moved out, as can `Qutex::get_mut`
+    /// and `Qutex::into_inner`.
+    ///
+    /// # Safety
+    ///
+    /// Once the data has been pinned through any guard, it must never be
+    /// moved again, by any holder, until dropped in place. Storing a
+    /// `Pin<Box<T>>` instead is the safe alternative.
+    #[inline]
+    pub unsafe fn as_pin_mut(guard: &mut Guard<T>) -> Pin<&mut T> {
+        Pin::new_unchecked(&mut **guard)
+    }
+
+    /// Registers `callback` to be called with the data as the lock is
+    /// released, once this guard is dropped or unlocked.
+    ///
+    /// Callbacks are called in the order registered, while the lock is
+    /// still held, so that nothing can touch the data in between, and carry
+    /// over to the guard a `handoff` passes the lock to. Should one panic,
+    /// those after it are dropped uncalled, and the lock is released (and
+    /// poisoned, if enabled) all the same.
+    pub fn on_release<F>(guard: &mut Guard<T>, callback: F)
+    where
+        F: FnOnce(&T) + Send + 'static,
+    {
+        unsafe { guard.qutex.push_release_callback(Box::new(callback)) }
+    }
+
+    /// Registers `finalizer` to run once this guard is dropped or unlocked,
+    /// but before the lock is granted to anyone else, such as to write a
+    /// dirty buffer back to disk.
+    ///
+    /// Rather than being released, the lock passes to a new guard, handed to
+    /// `finalizer`, and the future it returns is run to completion, the
+    /// lock being released as that guard is dropped. No executor is needed:
+    /// the future is first polled as this guard is dropped, and from then on
+    /// by a finalizer thread the crate spawns, never inline within a wakeup,
+    /// which may come from within the release of another lock the finalizer
+    /// waits on. Finalizers run in the order registered, ahead of any set
+    /// with `Qutex::set_finalizer`. None run should the guard be dropped
+    /// while panicking, the lock being released (and poisoned, if enabled)
+    /// straight away.
+    #[cfg(feature = "std")]
+    pub fn finalize_with<F, Fut>(guard: &mut Guard<T>, finalizer: F)
+    where
+        F: FnOnce(Guard<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let finalizer: Finalizer<T> = Box::new(move |guard| Box::pin(finalizer(guard)));
+        unsafe { (*guard.qutex.finalizers.pending.get()).push_back(finalizer) }
+    }
+
+    /// Converts a `Guard` into a `GuardToken` which keeps the lock held, to
+    /// be passed to another task and redeemed with `Qutex::redeem`.
+    ///
+    /// The lock never returns to the queue in between, so nobody else can
+    /// interleave between the two holders.
+    pub fn handoff(guard: Guard<T>) -> GuardToken
+    where
+        T: Send + 'static,
+    {
+        let qutex = unsafe { ::core::ptr::read(&guard.qutex) };
+        ::core::mem::forget(guard);
+        // Held by whoever redeems the token:
+        qutex.inner.set_holder(None);
+
+        GuardToken {
+            lock: qutex.as_ptr().cast::<()>() as usize,
+            // Released as `Guard::drop` would, callbacks and all:
+            unlock: Some(Box::new(move || unsafe { Guard::release(&qutex, false) })),
+        }
+    }
+
+    /// Returns the version of the lock as this guard found it, which its
+    /// own release will increment (see `QutexCore::version`).
+    #[inline]
+    pub fn version(guard: &Guard<T>) -> usize {
+        guard.qutex.version()
+    }
+
+    /// Labels the holder of the lock, as reported by `Qutex::current_holder`
+    /// if the holder is tracked.
+    #[cfg(feature = "std")]
+    pub fn set_label<S: Into<String>>(guard: &Guard<T>, label: S) {
+        guard.qutex.inner.set_holder_label(label.into());
+    }
 }
 
-impl<T> Deref for Guard<T> {
-    type Target = T;
+impl<T> Guard<T> {
+    /// Converts a `Guard` into a pair of raw pointers which keep the lock
+    /// held: one to the lock itself, opaque, and one to the data, so that a
+    /// held lock can be carried through a C callback's `void *` argument.
+    ///
+    /// The lock stays held until the pair is converted back with
+    /// `Guard::from_raw` and dropped. The data pointer is valid for reads and
+    /// writes until then.
+    pub fn into_raw(guard: Guard<T>) -> (*const (), *mut T) {
+        let qutex = unsafe { ::core::ptr::read(&guard.qutex) };
+        ::core::mem::forget(guard);
+        let data = qutex.as_mut_ptr();
+        (Arc::into_raw(qutex.inner) as *const (), data)
+    }
 
-    fn deref(&self) -> &T {
-        unsafe { &*self.qutex.inner.cell.get() }
+    /// Reconstructs a `Guard` from the pointers returned by
+    /// `Guard::into_raw`, taking over the lock they hold.
+    ///
+    /// # Safety
+    ///
+    /// `lock` and `data` must come from a single call to `Guard::into_raw`
+    /// for a `Guard<T>` of this same `T`, and may be converted back only
+    /// once.
+    pub unsafe fn from_raw(lock: *const (), data: *mut T) -> Guard<T> {
+        let inner = Arc::from_raw(lock as *const QutexCore<T>);
+        debug_assert_eq!(inner.as_mut_ptr(), data, "Guard::from_raw: Mismatched pointers.");
+        Guard {
+            qutex: Qutex { inner },
+        }
     }
 }
 
-impl<T> DerefMut for Guard<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.qutex.inner.cell.get() }
+/// Who holds a `Qutex`, as tracked if enabled with
+/// `QutexBuilder::track_holder`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct Holder {
+    thread: thread::Thread,
+    location: Option<&'static Location<'static>>,
+    label: Option<String>,
+    since: Instant,
+}
+
+#[cfg(feature = "std")]
+impl Holder {
+    /// Returns the thread which acquired the lock.
+    #[inline]
+    pub fn thread(&self) -> &thread::Thread {
+        &self.thread
+    }
+
+    /// Returns where the request granted the lock was made.
+    #[inline]
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.location
+    }
+
+    /// Returns the label set with `Guard::set_label`, if any.
+    #[inline]
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Returns how long the lock has been held.
+    #[inline]
+    pub fn held_for(&self) -> Duration {
+        self.since.elapsed()
     }
 }
 
-impl<T> Drop for Guard<T> {
-    fn drop(&mut self) {
-        // unsafe { self.qutex.direct_unlock().expect("Error dropping Guard") };
-        unsafe { self.qutex.direct_unlock() }
+#[cfg(feature = "std")]
+impl ::core::fmt::Display for Holder {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        match self.thread.name() {
+            Some(name) => write!(f, "thread '{}'", name)?,
+            None => write!(f, "thread {:?}", self.thread.id())?,
+        }
+        if let Some(location) = self.location {
+            write!(f, " at {}", location)?;
+        }
+        if let Some(ref label) = self.label {
+            write!(f, " ({})", label)?;
+        }
+        write!(f, ", for {:?}", self.held_for())
     }
 }
 
-/// A future which resolves to a `Guard`.
-#[must_use = "futures do nothing unless polled"]
-#[derive(Debug)]
-pub struct FutureGuard<T> {
-    qutex: Option<Qutex<T>>,
-    rx: Receiver<()>,
+/// A request found waiting longer than the threshold set with
+/// `QutexBuilder::on_long_wait`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct LongWait<'a> {
+    name: Option<&'a str>,
+    waited: Duration,
+    queued: usize,
+    holder: Option<Holder>,
+    granted: bool,
 }
 
-impl<T> FutureGuard<T> {
-    /// Returns a new `FutureGuard`.
-    fn new(qutex: Qutex<T>, rx: Receiver<()>) -> FutureGuard<T> {
-        FutureGuard {
-            qutex: Some(qutex),
-            rx: rx,
-        }
+#[cfg(feature = "std")]
+impl<'a> LongWait<'a> {
+    /// Returns the name of the lock, if any.
+    #[inline]
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
     }
 
-    /// Blocks the current thread until this future resolves.
+    /// Returns how long the request had waited when found.
     #[inline]
-    pub fn wait(self) -> Result<Guard<T>, Canceled> {
-        <Self as Future>::wait(self)
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+
+    /// Returns how many requests were queued for the lock at the time.
+    #[inline]
+    pub fn queued(&self) -> usize {
+        self.queued
     }
-}
 
-impl<T> Future for FutureGuard<T> {
-    type Item = Guard<T>;
-    type Error = Canceled;
+    /// Returns who held the lock, if its holder is tracked (see
+    /// `QutexBuilder::track_holder`) and the request was still waiting.
+    #[inline]
+    pub fn holder(&self) -> Option<&Holder> {
+        self.holder.as_ref()
+    }
 
+    /// Returns `true` if the request was found as it was finally granted
+    /// the lock, rather than still waiting at the head of the queue.
     #[inline]
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if self.qutex.is_some() {
-            unsafe { self.qutex.as_ref().unwrap().process_queue() }
+    pub fn granted(&self) -> bool {
+        self.granted
+    }
+}
 
-            match self.rx.poll() {
-                Ok(status) => Ok(status.map(|_| Guard {
-                    qutex: self.qutex.take().unwrap(),
-                })),
-                Err(e) => Err(e.into()),
-            }
-        } else {
-            panic!("FutureGuard::poll: Task already completed.");
+#[cfg(feature = "std")]
+impl ::core::fmt::Display for LongWait<'_> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "request for lock '{}'", name)?,
+            None => f.write_str("request for unnamed lock")?,
+        }
+        match self.granted {
+            true => write!(f, " granted after {:?}", self.waited)?,
+            false => write!(f, " waiting for {:?}", self.waited)?,
         }
+        write!(f, ", {} queued", self.queued)?;
+        if let Some(ref holder) = self.holder {
+            write!(f, ", held by {}", holder)?;
+        }
+        Ok(())
     }
 }
 
-impl<T> Drop for FutureGuard<T> {
-    /// Gracefully unlock if this guard has a lock acquired but has not yet
-    /// been polled to completion.
-    fn drop(&mut self) {
-        if let Some(qutex) = self.qutex.take() {
-            self.rx.close();
+/// A held lock in transit between two tasks, created by `Guard::handoff`.
+///
+/// Dropping a token without redeeming it releases the lock.
+#[must_use = "dropping a token releases the lock"]
+pub struct GuardToken {
+    lock: usize,
+    unlock: Option<Box<dyn FnOnce() + Send>>,
+}
 
-            match self.rx.try_recv() {
-                Ok(status) => {
-                    if status.is_some() {
-                        unsafe {
-                            qutex.direct_unlock();
-                        }
-                    }
-                }
-                Err(_) => (),
-            }
+impl Drop for GuardToken {
+    fn drop(&mut self) {
+        if let Some(unlock) = self.unlock.take() {
+            unlock();
         }
     }
 }
 
-/// A request to lock the qutex for exclusive access.
-#[derive(Debug)]
-pub struct Request {
-    tx: Sender<()>,
+impl ::core::fmt::Debug for GuardToken {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("GuardToken")
+            .field("lock", &(self.lock as *const ()))
+            .finish()
+    }
 }
 
-impl Request {
-    /// Returns a new `Request`.
-    pub fn new(tx: Sender<()>) -> Request {
-        Request { tx: tx }
+impl<T: ?Sized> Deref for Guard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.qutex.inner.cell.get() }
     }
 }
 
-#[derive(Debug)]
-struct Inner<T> {
-    // TODO: Convert to `AtomicBool` if no additional states are needed:
-    state: AtomicUsize,
-    cell: UnsafeCell<T>,
-    queue: SegQueue<Request>,
+impl<T: ?Sized> DerefMut for Guard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.qutex.inner.cell.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for Guard<T> {
+    fn drop(&mut self) {
+        // unsafe { self.qutex.direct_unlock().expect("Error dropping Guard") };
+        unsafe { Guard::release(&self.qutex, false) }
+    }
 }
 
-impl<T> From<T> for Inner<T> {
+impl<T: ?Sized> AsRef<T> for Guard<T> {
     #[inline]
-    fn from(val: T) -> Inner<T> {
-        Inner {
-            state: AtomicUsize::new(0),
-            cell: UnsafeCell::new(val),
-            queue: SegQueue::new(),
-        }
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> AsMut<T> for Guard<T> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut T {
+        self
     }
 }
 
-unsafe impl<T: Send> Send for Inner<T> {}
-unsafe impl<T: Send> Sync for Inner<T> {}
+impl<T: ?Sized> Borrow<T> for Guard<T> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        self
+    }
+}
 
-/// A lock-free-queue-backed exclusive data lock.
-#[derive(Debug)]
-pub struct Qutex<T> {
-    inner: Arc<Inner<T>>,
+impl<T: ?Sized> BorrowMut<T> for Guard<T> {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut T {
+        self
+    }
 }
 
-impl<T> Qutex<T> {
-    /// Creates and returns a new `Qutex`.
+impl<T: ::core::fmt::Debug + ?Sized> ::core::fmt::Debug for Guard<T> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_tuple("Guard").field(&&**self).finish()
+    }
+}
+
+impl<T: ::core::fmt::Display + ?Sized> ::core::fmt::Display for Guard<T> {
     #[inline]
-    pub fn new(val: T) -> Qutex<T> {
-        Qutex {
-            inner: Arc::new(Inner::from(val)),
-        }
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        (**self).fmt(f)
     }
+}
 
-    /// Returns a new `FutureGuard` which can be used as a future and will
-    /// resolve into a `Guard`.
-    pub fn lock(self) -> FutureGuard<T> {
-        let (tx, rx) = oneshot::channel();
-        unsafe {
-            self.push_request(Request::new(tx));
+/// The signalling state shared by a queued request and its requester.
+///
+/// Takes the place of a oneshot channel, the requester's waker being
+/// stored alongside the request itself. Also keeps the request's priority,
+/// deadline, wait start, tag and the location it was made at, should it
+/// need to queue again, so that the future need not.
+#[derive(Debug, Default)]
+struct Waiter {
+    state: AtomicU8,
+    waker: AtomicWaker,
+    priority: Priority,
+    deadline: Deadline,
+    since: WaitStart,
+    location: Option<&'static Location<'static>>,
+    tag: Option<Tag>,
+    // How long, in nanoseconds, the request had waited once granted:
+    #[cfg(feature = "std")]
+    waited: AtomicU64,
+}
+
+/// Still queued.
+const WAITING: u8 = 0;
+/// Granted the lock (or, under `Admission::Barging`, woken).
+const GRANTED: u8 = 1;
+/// Dropped from the queue having expired.
+const EXPIRED: u8 = 2;
+/// Abandoned by the requester.
+const CLOSED: u8 = 3;
+/// Handed the lock outright, even under `Admission::Barging`.
+const HANDED: u8 = 4;
+/// Never queued, the queue being full.
+const REJECTED: u8 = 5;
+
+impl Waiter {
+    /// Returns a new `Waiter` for a request made by the caller now.
+    #[track_caller]
+    #[inline]
+    fn new(priority: Priority, deadline: Deadline) -> Waiter {
+        Waiter {
+            priority,
+            deadline,
+            since: wait_start(),
+            location: Some(Location::caller()),
+            ..Waiter::default()
         }
-        FutureGuard::new(self, rx)
     }
 
-    /// Pushes a lock request onto the queue.
-    ///
-    //
-    // TODO: Evaluate unsafe-ness.
-    //
+    /// Returns a new `Waiter` for the same request, to be queued again.
     #[inline]
-    pub unsafe fn push_request(&self, req: Request) {
-        self.inner.queue.push(req);
+    fn requeued(&self) -> Waiter {
+        Waiter {
+            priority: self.priority,
+            deadline: self.deadline,
+            since: self.since,
+            location: self.location,
+            tag: self.tag,
+            ..Waiter::default()
+        }
     }
 
-    /// Returns a mutable reference to the inner `Vec` if there are currently
-    /// no other copies of this `Qutex`.
-    ///
-    /// Since this call borrows the inner lock mutably, no actual locking needs to
-    /// take place---the mutable borrow statically guarantees no locks exist.
-    ///
-    #[inline]
-    pub fn get_mut(&mut self) -> Option<&mut T> {
-        Arc::get_mut(&mut self.inner).map(|inn| unsafe { &mut *inn.cell.get() })
+    /// Returns a new `Waiter` for a request which was never queued.
+    fn rejected() -> Arc<Waiter> {
+        let waiter = Waiter::default();
+        waiter.state.store(REJECTED, Relaxed);
+        Arc::new(waiter)
     }
 
-    /// Returns a reference to the inner value.
+    /// Completes a waiting request, waking its requester. Returns `false`
+    /// if the requester has already abandoned it.
     ///
+    /// A grant hands over the lock, so releases the previous holder's
+    /// writes to the requester, which acquires them when polling.
+    fn complete(&self, state: u8) -> bool {
+        #[cfg(feature = "std")]
+        if state == GRANTED || state == HANDED {
+            self.record_waited();
+        }
+        if self
+            .state
+            .compare_exchange(WAITING, state, AcqRel, Acquire)
+            .is_ok()
+        {
+            self.waker.wake();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Records how long the request has waited, as it is granted.
+    #[cfg(feature = "std")]
     #[inline]
-    pub fn as_ptr(&self) -> *const T {
-        self.inner.cell.get()
+    fn record_waited(&self) {
+        if let Some(since) = self.since {
+            let waited = u64::try_from(since.elapsed().as_nanos()).unwrap_or(u64::MAX);
+            self.waited.store(waited, Relaxed);
+        }
     }
 
-    /// Returns a mutable reference to the inner value.
-    ///
+    /// Returns how long the request waited before being granted, as read
+    /// once granted.
+    #[cfg(feature = "std")]
     #[inline]
-    pub fn as_mut_ptr(&self) -> *mut T {
-        self.inner.cell.get()
+    fn waited(&self) -> Duration {
+        Duration::from_nanos(self.waited.load(Relaxed))
     }
 
-    /// Pops the next lock request in the queue if this (the caller's) lock is
-    /// unlocked.
-    //
-    // TODO:
-    // * This is currently public due to 'derivers' (aka. sub-types). Evaluate.
-    // * Consider removing unsafe qualifier.
-    // * Return proper error type.
-    // * [performance] Determine whether or not `compare_exchange_weak` should be used instead.
-    // * [performance] Consider failure ordering.
-    //
-    pub unsafe fn process_queue(&self) {
-        match self.inner.state.compare_exchange(0, 1, SeqCst, SeqCst) {
-            // Unlocked:
-            Ok(0) => {
-                loop {
-                    if let Some(req) = self.inner.queue.pop() {
-                        // If there is a send error, a requester has dropped
-                        // its receiver so just go to the next.
-                        if req.tx.send(()).is_err() {
-                            continue;
-                        } else {
-                            break;
-                        }
-                    } else {
-                        self.inner.state.store(0, SeqCst);
-                        break;
-                    }
-                }
+    /// Resolves once the request has been granted, or to `Error::Expired`
+    /// or `Error::QueueFull` once dropped or rejected.
+    fn poll(&self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let state = match self.state.load(Acquire) {
+            WAITING => {
+                self.waker.register(cx.waker());
+                // Re-check in case of completion before registering:
+                self.state.load(Acquire)
             }
-            // Already locked, leave it alone:
-            Err(1) => (),
-            // Already locked, leave it alone:
-            //
-            // TODO: Remove this option. Should be unreachable.
-            //
-            Ok(1) => unreachable!(),
-            // Something else:
-            Ok(n) => panic!("Qutex::process_queue: inner.state: {}.", n),
-            Err(n) => panic!("Qutex::process_queue: error: {}.", n),
+            state => state,
+        };
+
+        match state {
+            WAITING => Poll::Pending,
+            GRANTED | HANDED => Poll::Ready(Ok(())),
+            EXPIRED => Poll::Ready(Err(Error::Expired)),
+            // Never queued, as no requester polls a request it has closed:
+            _ => Poll::Ready(Err(Error::QueueFull)),
         }
     }
 
-    /// Unlocks this (the caller's) lock and wakes up the next task in the
-    /// queue.
-    //
-    // TODO:
-    // * Evaluate unsafe-ness.
-    // * Return proper error type
-    // pub unsafe fn direct_unlock(&self) -> Result<(), ()> {
-    pub unsafe fn direct_unlock(&self) {
-        // TODO: Consider using `Ordering::Release`.
-        self.inner.state.store(0, SeqCst);
-        self.process_queue()
+    /// Returns `true` if the request is still queued.
+    #[cfg(feature = "debug_dump")]
+    #[inline]
+    fn is_waiting(&self) -> bool {
+        self.state.load(Relaxed) == WAITING
     }
-}
 
-impl<T> From<T> for Qutex<T> {
+    /// Returns `true` if the lock was handed over outright, rather than the
+    /// request only woken.
     #[inline]
-    fn from(val: T) -> Qutex<T> {
-        Qutex::new(val)
+    fn handed(&self) -> bool {
+        self.state.load(Acquire) == HANDED
     }
-}
 
-// Avoids needing `T: Clone`.
-impl<T> Clone for Qutex<T> {
+    /// Abandons the request, returning its previous state.
     #[inline]
-    fn clone(&self) -> Qutex<T> {
-        Qutex {
-            inner: self.inner.clone(),
-        }
+    fn close(&self) -> u8 {
+        self.state.swap(CLOSED, AcqRel)
     }
 }
 
-#[cfg(test)]
-// Woefully incomplete:
-mod tests {
-    use super::*;
-    use futures::Future;
-
-    #[test]
-    fn simple() {
-        let val = Qutex::from(999i32);
+/// The requests queued for a lock, as listed by `Qutex::debug_dump`.
+#[cfg(feature = "debug_dump")]
+#[derive(Debug, Default)]
+struct Pending {
+    waiters: Vec<Weak<Waiter>>,
+    // The length at which `waiters` is next pruned of requests no longer
+    // waiting, doubling the length left so that pruning costs amortized
+    // constant time per request:
+    prune_at: usize,
+}
 
-        println!("Reading val...");
-        {
-            let future_guard = val.clone().lock();
-            let guard = future_guard.wait().unwrap();
-            println!("val: {}", *guard);
+#[cfg(feature = "debug_dump")]
+impl Pending {
+    /// Lists a newly queued request.
+    fn push(&mut self, waiter: &Arc<Waiter>) {
+        self.waiters.push(Arc::downgrade(waiter));
+        if self.waiters.len() >= self.prune_at {
+            self.waiters.retain(|waiter| waiter.upgrade().is_some_and(|w| w.is_waiting()));
+            self.prune_at = (self.waiters.len() * 2).max(16);
         }
+    }
+}
 
-        println!("Storing new val...");
-        {
-            let future_guard = val.clone().lock();
-            let mut guard = future_guard.wait().unwrap();
+/// How a request refers to its lock: a `Qutex` owned by a `FutureGuard`,
+/// or a `QutexCore` borrowed by a `FutureRefGuard`.
+trait Handle {
+    type Data: ?Sized;
 
-            *guard = 5;
-        }
+    fn core(&self) -> &QutexCore<Self::Data>;
+}
 
-        println!("Reading val...");
-        {
-            let future_guard = val.clone().lock();
-            let guard = future_guard.wait().unwrap();
-            println!("val: {}", *guard);
-        }
+impl<T: ?Sized> Handle for Qutex<T> {
+    type Data = T;
+
+    #[inline]
+    fn core(&self) -> &QutexCore<T> {
+        &self.inner
     }
+}
 
-    #[test]
-    fn concurrent() {
-        use std::thread;
+impl<T: ?Sized> Handle for &QutexCore<T> {
+    type Data = T;
 
-        let thread_count = 20;
-        let mut threads = Vec::with_capacity(thread_count);
-        let start_val = 0i32;
-        let qutex = Qutex::new(start_val);
+    #[inline]
+    fn core(&self) -> &QutexCore<T> {
+        self
+    }
+}
 
-        for i in 0..thread_count {
-            let future_guard = qutex.clone().lock();
+/// Resolves to a request's handle once granted, along with its waiter if
+/// it was queued.
+type PollGranted<H> = Poll<Result<(H, Option<Arc<Waiter>>), Error>>;
 
-            let future_write = future_guard.and_then(|mut guard| {
-                *guard += 1;
+/// The progress of a request.
+#[derive(Debug)]
+enum State<H> {
+    /// Queued, awaiting the grant (or, when barging, a wakeup), but with
+    /// the queue not yet processed since.
+    Queued(H, Arc<Waiter>),
+    /// Queued over the acquisition budget, to yield to the executor once
+    /// before the queue is processed.
+    Deferred(H, Arc<Waiter>),
+    /// Queued, with the queue processed since. Whoever next releases the
+    /// lock processes it again, so re-polls only check the waiter.
+    Waiting(H, Arc<Waiter>),
+    /// Holding the lock, taken without queueing.
+    Acquired(H),
+    /// Resolved, or rejected.
+    Done,
+}
+
+/// The state machine shared by `FutureGuard` and `FutureRefGuard`,
+/// resolving to the handle once the lock is held.
+#[derive(Debug)]
+struct Acquisition<H: Handle> {
+    state: State<H>,
+}
+
+impl<H: Handle> Acquisition<H> {
+    /// Returns a new `Acquisition`, taking the lock straight away if it is
+    /// free and otherwise queueing its request.
+    #[track_caller]
+    #[inline]
+    fn new(handle: H, priority: Priority, deadline: Deadline) -> Acquisition<H> {
+        Acquisition::tagged(handle, priority, deadline, None)
+    }
+
+    /// Returns a new `Acquisition` as `new` does, its request tagged with
+    /// `tag` should it queue.
+    #[track_caller]
+    fn tagged(
+        handle: H,
+        priority: Priority,
+        deadline: Deadline,
+        tag: Option<Tag>,
+    ) -> Acquisition<H> {
+        let core = handle.core();
+        let spent = core.budget_spent();
+        let state = if !spent && unsafe { core.acquire_unqueued() } {
+            // Uncontended, so the queue is never touched:
+            core.set_hold_priority(priority);
+            State::Acquired(handle)
+        } else {
+            let waiter = unsafe {
+                core.enqueue(Waiter { tag, ..Waiter::new(priority, deadline) })
+                    .unwrap_or_else(|_| Waiter::rejected())
+            };
+            if spent {
+                State::Deferred(handle, waiter)
+            } else {
+                State::Queued(handle, waiter)
+            }
+        };
+
+        Acquisition { state }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>, name: &str) -> Poll<Result<H, Error>> {
+        self.poll_granted(cx, name).map(|res| res.map(|(handle, _)| handle))
+    }
+
+    /// Polls as `poll`, resolving along with the request's waiter if it
+    /// was queued.
+    fn poll_granted(&mut self, cx: &mut Context<'_>, name: &str) -> PollGranted<H> {
+        // Before waiting, rather than only once deadlocked:
+        match self.state {
+            State::Queued(ref handle, _)
+            | State::Deferred(ref handle, _)
+            | State::Acquired(ref handle) => handle.core().check_rank(cx.waker()),
+            State::Waiting(..) | State::Done => (),
+        }
+        let res = futures::ready!(self.poll_acquire(cx, name));
+        if let Ok((ref handle, ref waiter)) = res {
+            let core = handle.core();
+            core.set_holder(Some(cx.waker()));
+            core.record_streak(cx.waker(), waiter.is_none());
+        }
+        Poll::Ready(res)
+    }
+
+    fn poll_acquire(&mut self, cx: &mut Context<'_>, name: &str) -> PollGranted<H> {
+        loop {
+            // Left `Done` on every path which resolves:
+            match mem::replace(&mut self.state, State::Done) {
+                State::Queued(handle, waiter) => {
+                    // The lock may have been released before the request
+                    // was queued:
+                    unsafe { handle.core().process_queue() }
+                    self.state = State::Waiting(handle, waiter);
+                }
+                // Letting whoever was last woken take the lock first, should
+                // it be free:
+                State::Deferred(handle, waiter) => {
+                    self.state = State::Queued(handle, waiter);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                State::Waiting(handle, waiter) => {
+                    let poll = waiter.poll(cx);
+                    let waiting = poll.is_pending().then_some(cx.waker());
+                    handle.core().record_waiting(&waiter, waiting);
+                    match poll {
+                        Poll::Pending => {
+                            self.state = State::Waiting(handle, waiter);
+                            return Poll::Pending;
+                        }
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        // Under barging admission being woken only means the
+                        // lock was released, and it may since have been taken
+                        // by someone else:
+                        Poll::Ready(Ok(())) => {
+                            let core = handle.core();
+                            let handed = core.admission == Admission::Fair || waiter.handed();
+                            if handed || unsafe { core.try_acquire() } {
+                                // Granted already at its priority if handed:
+                                if !handed {
+                                    core.set_hold_priority(waiter.priority);
+                                    // Only now acquired:
+                                    #[cfg(feature = "std")]
+                                    waiter.record_waited();
+                                }
+                                core.record_acquisition(waiter.since, waiter.location, waiter.tag);
+                                return Poll::Ready(Ok((handle, Some(waiter))));
+                            }
+                            match unsafe { core.enqueue(waiter.requeued()) } {
+                                Ok(waiter) => self.state = State::Queued(handle, waiter),
+                                Err(err) => return Poll::Ready(Err(err)),
+                            }
+                        }
+                    }
+                }
+                State::Acquired(handle) => return Poll::Ready(Ok((handle, None))),
+                State::Done => panic!("{}::poll: Task already completed.", name),
+            }
+        }
+    }
+
+    /// Returns the handle to the lock, unless resolved.
+    #[inline]
+    fn handle(&self) -> Option<&H> {
+        match self.state {
+            State::Queued(ref handle, _)
+            | State::Deferred(ref handle, _)
+            | State::Waiting(ref handle, _)
+            | State::Acquired(ref handle) => Some(handle),
+            State::Done => None,
+        }
+    }
+
+    /// Withdraws the request, gracefully unlocking if the lock has been
+    /// acquired but not yet handed out.
+    fn withdraw(&mut self) {
+        match mem::replace(&mut self.state, State::Done) {
+            State::Queued(handle, waiter)
+            | State::Deferred(handle, waiter)
+            | State::Waiting(handle, waiter) => {
+                let core = handle.core();
+                core.record_waiting(&waiter, None);
+                // Counted before closing, so that whoever skips the request
+                // never finds it uncounted:
+                core.abandoned.fetch_add(1, Relaxed);
+                match waiter.close() {
+                    // Left queued until skipped:
+                    WAITING => (),
+                    prev => {
+                        core.abandoned.fetch_sub(1, Relaxed);
+                        unsafe {
+                            match (prev, core.admission) {
+                                (GRANTED, Admission::Fair) | (HANDED, _) => core.direct_unlock(),
+                                // Only woken, so pass the wakeup on:
+                                (GRANTED, Admission::Barging) => core.process_queue(),
+                                _ => (),
+                            }
+                        }
+                    }
+                }
+            }
+            State::Acquired(handle) => unsafe { handle.core().direct_unlock() },
+            State::Done => (),
+        }
+    }
+
+    /// Blocks the current thread until the lock is held through `handle`,
+    /// making a new request, after yielding, whenever the queue is too full
+    /// to take one.
+    #[cfg(feature = "std")]
+    #[track_caller]
+    fn block_on(handle: H, name: &str) -> H
+    where
+        H: Clone + Unpin,
+    {
+        loop {
+            let mut acquire = Acquisition::new(handle.clone(), Priority::Normal, None);
+            // Without a deadline, only rejected if never queued:
+            match blocking::park_on(core::future::poll_fn(|cx| acquire.poll(cx, name))) {
+                Ok(handle) => return handle,
+                Err(_) => thread::yield_now(),
+            }
+        }
+    }
+}
+
+impl<H: Handle> Drop for Acquisition<H> {
+    /// Gracefully unlock if the lock has been acquired but the future has
+    /// not yet been polled to completion.
+    #[inline]
+    fn drop(&mut self) {
+        self.withdraw()
+    }
+}
+
+/// A future which resolves to a `Guard`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureGuard<T: ?Sized> {
+    acquire: Acquisition<Qutex<T>>,
+}
+
+impl<T: ?Sized> FutureGuard<T> {
+    /// Returns a new `FutureGuard`, taking the lock straight away if it is
+    /// free and otherwise queueing its request unless barging.
+    #[track_caller]
+    #[inline]
+    fn new(qutex: Qutex<T>, priority: Priority, deadline: Deadline) -> FutureGuard<T> {
+        FutureGuard {
+            acquire: Acquisition::new(qutex, priority, deadline),
+        }
+    }
+
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<Guard<T>, Error> {
+        executor::block_on(self)
+    }
+
+    /// Withdraws the request (releasing the lock if already taken),
+    /// returning the lock it was for.
+    ///
+    /// Panics if this future has already resolved.
+    #[cfg(feature = "std")]
+    pub(crate) fn into_qutex(self) -> Qutex<T> {
+        match self.acquire.state {
+            State::Queued(ref qutex, _)
+            | State::Deferred(ref qutex, _)
+            | State::Waiting(ref qutex, _)
+            | State::Acquired(ref qutex) => qutex.clone(),
+            State::Done => panic!("FutureGuard::into_qutex: Task already completed."),
+        }
+    }
+}
+
+impl<T: ?Sized> Future for FutureGuard<T> {
+    type Output = Result<Guard<T>, Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut()
+            .acquire
+            .poll(cx, "FutureGuard")
+            .map(|res| res.map(|qutex| Guard { qutex }))
+    }
+}
+
+/// The request, if any, of a hand-written `Future` or `Stream` polling a
+/// `Qutex` with `Qutex::poll_lock`.
+///
+/// Holds nothing between requests, so may be stored inline in a state
+/// machine and reused for request after request. A pending request is
+/// withdrawn once canceled or dropped, just as a dropped `FutureGuard`'s
+/// is.
+pub struct PendingLock<T: ?Sized> {
+    acquire: Option<Acquisition<Qutex<T>>>,
+}
+
+impl<T: ?Sized> PendingLock<T> {
+    /// Returns a new `PendingLock`, holding no request.
+    #[inline]
+    pub fn new() -> PendingLock<T> {
+        PendingLock { acquire: None }
+    }
+
+    /// Returns `true` if a request has been made and has yet to resolve.
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        self.acquire.is_some()
+    }
+
+    /// Withdraws the pending request, if any (releasing the lock if it has
+    /// been granted but not yet polled for).
+    #[inline]
+    pub fn cancel(&mut self) {
+        self.acquire = None;
+    }
+}
+
+impl<T: ?Sized> Default for PendingLock<T> {
+    #[inline]
+    fn default() -> PendingLock<T> {
+        PendingLock::new()
+    }
+}
+
+impl<T: ?Sized> ::core::fmt::Debug for PendingLock<T> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("PendingLock")
+            .field("is_pending", &self.is_pending())
+            .finish()
+    }
+}
+
+/// Allows access to the data contained within a borrowed `Qutex` or
+/// `QutexCore`.
+///
+/// Unlike a `Guard`, does not hold a reference count of its own.
+pub struct RefGuard<'a, T: ?Sized> {
+    core: &'a QutexCore<T>,
+}
+
+impl<'a, T: ?Sized> RefGuard<'a, T> {
+    /// Labels the holder of the lock, just like `Guard::set_label`.
+    #[cfg(feature = "std")]
+    pub fn set_label<S: Into<String>>(guard: &RefGuard<'_, T>, label: S) {
+        guard.core.set_holder_label(label.into());
+    }
+
+    /// Registers `callback` to be called with the data as the lock is
+    /// released, just like `Guard::on_release`.
+    pub fn on_release<F>(guard: &mut RefGuard<'_, T>, callback: F)
+    where
+        F: FnOnce(&T) + Send + 'static,
+    {
+        unsafe { guard.core.push_release_callback(Box::new(callback)) }
+    }
+
+    /// Converts the guard into a `RawQutexState` holding the lock.
+    #[inline]
+    pub fn into_raw(guard: RefGuard<'a, T>) -> RawQutexState<'a, T> {
+        let guard = ManuallyDrop::new(guard);
+        RawQutexState {
+            core: guard.core,
+            held: true,
+        }
+    }
+
+    /// Releases the lock, handing it straight to the next queued request,
+    /// just like `Guard::unlock_fair`.
+    pub fn unlock_fair(guard: RefGuard<'a, T>) {
+        let guard = ManuallyDrop::new(guard);
+        unsafe { guard.core.release_guard(true) }
+    }
+
+    /// Lets any queued requests go first, as `Guard::bump` does, blocking
+    /// the current thread until the lock comes back round to `guard`.
+    #[cfg(feature = "std")]
+    #[track_caller]
+    pub(crate) fn bump_blocking(guard: &mut RefGuard<'a, T>) {
+        let core = guard.core;
+        if !core.has_requests() {
+            return;
+        }
+        let Ok(waiter) = (unsafe { core.enqueue(Waiter::new(Priority::Normal, None)) }) else {
+            return;
+        };
+        let future_guard = FutureRefGuard {
+            acquire: Acquisition {
+                state: State::Queued(core, waiter),
+            },
+        };
+        // `guard` is dropped all the same should a callback panic, so must
+        // hold the lock again by then:
+        let released = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+            core.release_guard(true)
+        }));
+        let regained = match blocking::park_on(future_guard) {
+            Ok(regained) => regained,
+            // Rejected only by a full `fixed_queue`:
+            Err(_) => loop {
+                if let Ok(regained) = blocking::park_on(core.lock_ref()) {
+                    break regained;
+                }
+                thread::yield_now();
+            },
+        };
+        // Held through `guard` once more:
+        mem::forget(regained);
+        if let Err(payload) = released {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RefGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.core.cell.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RefGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.core.cell.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RefGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.core.release_guard(false) }
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for RefGuard<'_, T> {
+    #[inline]
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> AsMut<T> for RefGuard<'_, T> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T: ?Sized> Borrow<T> for RefGuard<'_, T> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> BorrowMut<T> for RefGuard<'_, T> {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T: ::core::fmt::Debug + ?Sized> ::core::fmt::Debug for RefGuard<'_, T> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_tuple("RefGuard").field(&&**self).finish()
+    }
+}
+
+impl<T: ::core::fmt::Display + ?Sized> ::core::fmt::Display for RefGuard<'_, T> {
+    #[inline]
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// A future which resolves to a `RefGuard`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureRefGuard<'a, T: ?Sized> {
+    acquire: Acquisition<&'a QutexCore<T>>,
+}
+
+impl<'a, T: ?Sized> FutureRefGuard<'a, T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<RefGuard<'a, T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<'a, T: ?Sized> Future for FutureRefGuard<'a, T> {
+    type Output = Result<RefGuard<'a, T>, Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut()
+            .acquire
+            .poll(cx, "FutureRefGuard")
+            .map(|res| res.map(|core| RefGuard { core }))
+    }
+}
+
+/// The lock state of a borrowed `QutexCore`, driven by hand, for building
+/// primitives of one's own on top of the lock.
+///
+/// A `RawQutexState` is either free or holding the lock, and moves between
+/// the two only through its methods:
+///
+/// * `try_acquire` takes the lock if it can be taken without waiting, just
+///   like `try_lock_ref`, and `acquire` queues a request for it.
+/// * `release` releases it, granting it to the next queued request as the
+///   lock's `Admission` dictates: directly under `Admission::Fair`, or by
+///   waking it to retry under `Admission::Barging`.
+///
+/// A state holding the lock releases it once dropped, so the lock can be
+/// neither left held by accident nor released by someone who does not hold
+/// it. Unlike a guard, a state can release the lock and take it again.
+pub struct RawQutexState<'a, T: ?Sized> {
+    core: &'a QutexCore<T>,
+    held: bool,
+}
+
+impl<'a, T: ?Sized> RawQutexState<'a, T> {
+    /// Returns `true` if this state holds the lock.
+    #[inline]
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// Takes the lock if it is free and, under `Admission::Fair`, nobody is
+    /// queued, returning `true` if this state now holds it.
+    #[track_caller]
+    pub fn try_acquire(&mut self) -> bool {
+        if !self.held {
+            self.held = unsafe { self.core.try_take() };
+        }
+        self.held
+    }
+
+    /// Returns a new `FutureRawState` which will resolve into this state
+    /// once it holds the lock, queueing a request for it unless it is held
+    /// already or can be taken straight away.
+    #[track_caller]
+    pub fn acquire(self) -> FutureRawState<'a, T> {
+        let this = ManuallyDrop::new(self);
+        let acquire = match this.held {
+            true => Acquisition {
+                state: State::Acquired(this.core),
+            },
+            false => Acquisition::new(this.core, Priority::Normal, None),
+        };
+        FutureRawState { acquire }
+    }
+
+    /// Releases the lock, if held, passing it on to the next queued request.
+    #[inline]
+    pub fn release(&mut self) {
+        if mem::replace(&mut self.held, false) {
+            unsafe { self.core.release_guard(false) }
+        }
+    }
+
+    /// Returns a mutable reference to the data, if this state holds the
+    /// lock.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        match self.held {
+            true => Some(unsafe { &mut **self.core.cell.get() }),
+            false => None,
+        }
+    }
+
+    /// Converts this state into a `RefGuard` if it holds the lock, or
+    /// returns it otherwise.
+    #[inline]
+    pub fn into_guard(self) -> Result<RefGuard<'a, T>, RawQutexState<'a, T>> {
+        match self.held {
+            true => {
+                let this = ManuallyDrop::new(self);
+                Ok(RefGuard { core: this.core })
+            }
+            false => Err(self),
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for RawQutexState<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.release()
+    }
+}
+
+impl<T: ?Sized> ::core::fmt::Debug for RawQutexState<'_, T> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("RawQutexState")
+            .field("held", &self.held)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A future which resolves to a `RawQutexState` holding the lock.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureRawState<'a, T: ?Sized> {
+    acquire: Acquisition<&'a QutexCore<T>>,
+}
+
+impl<'a, T: ?Sized> FutureRawState<'a, T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<RawQutexState<'a, T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<'a, T: ?Sized> Future for FutureRawState<'a, T> {
+    type Output = Result<RawQutexState<'a, T>, Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut()
+            .acquire
+            .poll(cx, "FutureRawState")
+            .map(|res| res.map(|core| RawQutexState { core, held: true }))
+    }
+}
+
+/// A clone of the value of a `Qutex`, written back to the `Qutex` once
+/// dropped or committed, but only if modified.
+///
+/// The lock is not held meanwhile: the write-back is deferred to whoever
+/// next holds the lock, or made straight away if nobody does, and
+/// replaces the value outright, but only if nobody else has held the lock
+/// (or had a submitted closure run) since the clone was taken, as told by
+/// `QutexCore::version`. Otherwise the clone is given up rather than
+/// overwriting their changes: handed back by `commit`, dropped if the
+/// guard is.
+#[derive(Debug)]
+pub struct CowGuard<T> {
+    qutex: Qutex<T>,
+    // Only taken when dropped, committed or discarded:
+    snapshot: Option<T>,
+    // The version of the lock as the clone left it:
+    version: usize,
+    modified: bool,
+    write_back: WriteBack<T>,
+}
+
+/// Submits the write-back of a clone of the value, for `CowGuard`.
+type WriteBack<T> = fn(&Qutex<T>, usize, T) -> Receiver<Result<(), T>>;
+
+impl<T> CowGuard<T> {
+    /// Returns `true` if the clone has been mutably borrowed, and so is to
+    /// be written back.
+    #[inline]
+    pub fn is_modified(guard: &CowGuard<T>) -> bool {
+        guard.modified
+    }
+
+    /// Drops the clone without writing it back, returning the original
+    /// `Qutex`.
+    pub fn discard(mut guard: CowGuard<T>) -> Qutex<T> {
+        guard.modified = false;
+        guard.qutex.clone()
+    }
+
+    /// Writes the clone back if modified, returning a `FutureCommit` which
+    /// resolves once written, or to an error handing the clone back should
+    /// anyone else have held the lock since it was taken.
+    pub fn commit(mut guard: CowGuard<T>) -> FutureCommit<T> {
+        let rx = match (guard.modified, guard.snapshot.take()) {
+            (true, Some(val)) => Some((guard.write_back)(&guard.qutex, guard.version, val)),
+            _ => None,
+        };
+        FutureCommit {
+            _qutex: guard.qutex.clone(),
+            rx,
+        }
+    }
+}
+
+impl<T> Deref for CowGuard<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        self.snapshot.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for CowGuard<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        self.modified = true;
+        self.snapshot.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for CowGuard<T> {
+    fn drop(&mut self) {
+        if let (true, Some(val)) = (self.modified, self.snapshot.take()) {
+            // With nobody to hear of a conflict:
+            drop((self.write_back)(&self.qutex, self.version, val));
+        }
+    }
+}
+
+/// Writes `val` back to `qutex` by way of a submitted job, run once the
+/// lock is next free, unless the lock's version has moved on from `version`
+/// by then, returning the outcome.
+fn write_back<T: Send + 'static>(
+    qutex: &Qutex<T>,
+    version: usize,
+    val: T,
+) -> Receiver<Result<(), T>> {
+    let (tx, rx) = oneshot::channel();
+    // Run by a holder, who keeps the lock alive, unless dropped with it:
+    let core = Arc::downgrade(&qutex.inner);
+    qutex.inner.jobs.push(Box::new(move |cur: &mut T| {
+        let res = match Weak::upgrade(&core).is_some_and(|core| core.version() == version) {
+            true => {
+                *cur = val;
                 Ok(())
+            }
+            false => Err(val),
+        };
+        let _ = tx.send(res);
+    }));
+    unsafe { qutex.process_queue() };
+    rx
+}
+
+/// A future which resolves once a `CowGuard`'s clone has been written back,
+/// or to an error handing it back if written to by someone else since.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureCommit<T> {
+    // Keeps the lock, and with it the write-back, alive:
+    _qutex: Qutex<T>,
+    // Not written back should the clone be unmodified:
+    rx: Option<Receiver<Result<(), T>>>,
+}
+
+impl<T> FutureCommit<T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<(), T> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureCommit<T> {
+    type Output = Result<(), T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let Some(ref mut rx) = this.rx else {
+            return Poll::Ready(Ok(()));
+        };
+        let res = futures::ready!(Pin::new(rx).poll(cx));
+        this.rx = None;
+        Poll::Ready(res.expect("FutureCommit::poll: Write-back dropped."))
+    }
+}
+
+/// A future which resolves to a `CowGuard`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureCowGuard<T> {
+    future_guard: FutureGuard<T>,
+    write_back: WriteBack<T>,
+}
+
+impl<T: Clone> FutureCowGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<CowGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T: Clone> Future for FutureCowGuard<T> {
+    type Output = Result<CowGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut this.future_guard).poll(cx).map(|res| {
+            res.map(|guard| {
+                let snapshot = Some((*guard).clone());
+                // Incremented by its own release:
+                let version = Guard::version(&guard).wrapping_add(1);
+                CowGuard {
+                    qutex: Guard::unlock(guard),
+                    snapshot,
+                    version,
+                    modified: false,
+                    write_back: this.write_back,
+                }
+            })
+        })
+    }
+}
+
+/// A future which resolves to a clone of the data, holding the lock only
+/// while cloning it.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FuturePeek<T> {
+    future_guard: FutureGuard<T>,
+}
+
+impl<T: Clone> FuturePeek<T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<T, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T: Clone> Future for FuturePeek<T> {
+    type Output = Result<T, Error>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().future_guard)
+            .poll(cx)
+            .map(|res| res.map(|guard| (*guard).clone()))
+    }
+}
+
+/// The `WaitEvent` of a `FutureEventGuard`.
+struct AttachedEvent(Box<dyn WaitEvent>);
+
+// Only ever reached through `&mut`:
+unsafe impl Sync for AttachedEvent {}
+
+/// How far a `FutureEventGuard` has got.
+enum EventStage<T: ?Sized> {
+    /// Deferred, waiting for the event before queueing.
+    Signal(Qutex<T>),
+    /// Requested.
+    Lock(FutureGuard<T>),
+    /// Reserved and granted, holding the lock until the event signals.
+    Held(Guard<T>),
+    /// Resolved, or rejected.
+    Done,
+}
+
+/// A future which resolves to a `Guard` once the lock is granted and the
+/// request's `WaitEvent` has signaled.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureEventGuard<T: ?Sized> {
+    stage: EventStage<T>,
+    // Taken once signaled:
+    event: Option<AttachedEvent>,
+}
+
+impl<T: ?Sized> FutureEventGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<Guard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T: ?Sized> Future for FutureEventGuard<T> {
+    type Output = Result<Guard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            // Only polled before queueing, or once granted:
+            let requested = matches!(this.stage, EventStage::Lock(_));
+            if let (Some(event), false) = (&mut this.event, requested) {
+                futures::ready!(event.0.poll_signaled(cx));
+                this.event = None;
+            }
+
+            this.stage = match mem::replace(&mut this.stage, EventStage::Done) {
+                EventStage::Signal(qutex) => EventStage::Lock(qutex.lock()),
+                EventStage::Lock(mut future_guard) => match Pin::new(&mut future_guard).poll(cx) {
+                    Poll::Ready(Ok(guard)) if this.event.is_none() => return Poll::Ready(Ok(guard)),
+                    Poll::Ready(Ok(guard)) => EventStage::Held(guard),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => {
+                        this.stage = EventStage::Lock(future_guard);
+                        return Poll::Pending;
+                    }
+                },
+                EventStage::Held(guard) => return Poll::Ready(Ok(guard)),
+                EventStage::Done => panic!("FutureEventGuard::poll: Task already completed."),
+            };
+        }
+    }
+}
+
+impl<T: ?Sized> ::core::fmt::Debug for FutureEventGuard<T> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("FutureEventGuard")
+            .field("granted", &matches!(self.stage, EventStage::Held(_)))
+            .finish_non_exhaustive()
+    }
+}
+
+/// How far a `FutureLockAndThen` has got.
+enum AndThenStage<T: ?Sized, F, Fut> {
+    /// Requested, with the closure to run once granted.
+    Lock(FutureGuard<T>, Option<F>),
+    /// Granted, running the closure's future, which owns the guard.
+    Run(Fut),
+    /// Resolved, or rejected.
+    Done,
+}
+
+/// A future which resolves to the output of the future a closure returns
+/// once handed the `Guard`, dropping that future, and with it the guard,
+/// before resolving.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureLockAndThen<T: ?Sized, F, Fut> {
+    stage: AndThenStage<T, F, Fut>,
+}
+
+impl<T: ?Sized, F, Fut> FutureLockAndThen<T, F, Fut>
+where
+    F: FnOnce(Guard<T>) -> Fut,
+    Fut: Future,
+{
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<Fut::Output, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T: ?Sized, F, Fut> Future for FutureLockAndThen<T, F, Fut>
+where
+    F: FnOnce(Guard<T>) -> Fut,
+    Fut: Future,
+{
+    type Output = Result<Fut::Output, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Only the closure's future is pinned structurally, and it is only
+        // ever dropped in place:
+        let stage = unsafe { &mut self.get_unchecked_mut().stage };
+        loop {
+            match stage {
+                AndThenStage::Lock(future_guard, f) => {
+                    let res = futures::ready!(Pin::new(future_guard).poll(cx));
+                    let f = f.take().expect("FutureLockAndThen::poll: Task already completed.");
+                    match res {
+                        Ok(guard) => *stage = AndThenStage::Run(f(guard)),
+                        Err(err) => {
+                            *stage = AndThenStage::Done;
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                }
+                AndThenStage::Run(fut) => {
+                    let output = futures::ready!(unsafe { Pin::new_unchecked(fut) }.poll(cx));
+                    // Released before resolving, unless `f` hands the guard back:
+                    *stage = AndThenStage::Done;
+                    return Poll::Ready(Ok(output));
+                }
+                AndThenStage::Done => panic!("FutureLockAndThen::poll: Task already completed."),
+            }
+        }
+    }
+}
+
+// Never pinned structurally but for the closure's future.
+impl<T: ?Sized, F, Fut: Unpin> Unpin for FutureLockAndThen<T, F, Fut> {}
+
+/// How far a `FutureWithUnlocked` has got.
+enum UnlockedStage<T: ?Sized, F, Fut, Out> {
+    /// Still holding the lock until first polled.
+    Start(Option<(Guard<T>, F)>),
+    /// Released, running the closure's future.
+    Run(Qutex<T>, Fut),
+    /// Re-requesting the lock, with the output of the closure's future.
+    Lock(Option<Out>, FutureGuard<T>),
+    /// Resolved.
+    Done,
+}
+
+/// A future which releases a `Guard`, runs a closure's future, then
+/// re-acquires the lock, resolving to the future's output along with the
+/// regained `Guard`, created by `Guard::with_unlocked`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureWithUnlocked<T: ?Sized, F, Fut: Future> {
+    stage: UnlockedStage<T, F, Fut, Fut::Output>,
+}
+
+impl<T: ?Sized, F, Fut> FutureWithUnlocked<T, F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> (Fut::Output, Result<Guard<T>, Error>) {
+        executor::block_on(self)
+    }
+}
+
+impl<T: ?Sized, F, Fut> Future for FutureWithUnlocked<T, F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    type Output = (Fut::Output, Result<Guard<T>, Error>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Only the closure's future is pinned structurally, and it is only
+        // ever dropped in place:
+        let stage = unsafe { &mut self.get_unchecked_mut().stage };
+        loop {
+            match stage {
+                UnlockedStage::Start(start) => {
+                    let (guard, f) =
+                        start.take().expect("FutureWithUnlocked::poll: Task already completed.");
+                    let qutex = Guard::unlock_fair(guard);
+                    *stage = UnlockedStage::Run(qutex, f());
+                }
+                UnlockedStage::Run(qutex, fut) => {
+                    let output = futures::ready!(unsafe { Pin::new_unchecked(fut) }.poll(cx));
+                    // Dropping the closure's future in place:
+                    *stage = UnlockedStage::Lock(Some(output), qutex.clone().lock());
+                }
+                UnlockedStage::Lock(output, future_guard) => {
+                    let res = futures::ready!(Pin::new(future_guard).poll(cx));
+                    let output = output.take().unwrap();
+                    *stage = UnlockedStage::Done;
+                    return Poll::Ready((output, res));
+                }
+                UnlockedStage::Done => panic!("FutureWithUnlocked::poll: Task already completed."),
+            }
+        }
+    }
+}
+
+// Never pinned structurally but for the closure's future.
+impl<T: ?Sized, F, Fut: Future + Unpin> Unpin for FutureWithUnlocked<T, F, Fut> {}
+
+impl<T: ?Sized, F, Fut: Future> ::core::fmt::Debug for FutureWithUnlocked<T, F, Fut> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("FutureWithUnlocked")
+            .field("released", &!matches!(self.stage, UnlockedStage::Start(_)))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: ?Sized, F, Fut> ::core::fmt::Debug for FutureLockAndThen<T, F, Fut> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("FutureLockAndThen")
+            .field("granted", &matches!(self.stage, AndThenStage::Run(_)))
+            .finish_non_exhaustive()
+    }
+}
+
+/// A future which resolves to a `Guard`, or to `Error::Canceled` if its
+/// cancellation token resolves first.
+///
+/// The request leaves the queue as soon as the token resolves, without
+/// waiting for this future to be dropped. Should the lock be granted by the
+/// time the token resolves, the guard wins. Panics if polled again after
+/// resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureLockWithToken<T: ?Sized, C> {
+    future_guard: FutureGuard<T>,
+    token: C,
+}
+
+impl<T: ?Sized, C: Future<Output = ()>> FutureLockWithToken<T, C> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<Guard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T: ?Sized, C: Future<Output = ()>> Future for FutureLockWithToken<T, C> {
+    type Output = Result<Guard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Only the token is pinned structurally, and it is only ever dropped
+        // in place:
+        let this = unsafe { self.get_unchecked_mut() };
+        let acquire = &mut this.future_guard.acquire;
+        if let Poll::Ready(res) = acquire.poll(cx, "FutureLockWithToken") {
+            return Poll::Ready(res.map(|qutex| Guard { qutex }));
+        }
+        futures::ready!(unsafe { Pin::new_unchecked(&mut this.token) }.poll(cx));
+        acquire.withdraw();
+        Poll::Ready(Err(Error::Canceled))
+    }
+}
+
+// Never pinned structurally but for the token.
+impl<T: ?Sized, C: Unpin> Unpin for FutureLockWithToken<T, C> {}
+
+impl<T: ?Sized, C> ::core::fmt::Debug for FutureLockWithToken<T, C> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("FutureLockWithToken").finish_non_exhaustive()
+    }
+}
+
+/// A future which exchanges the values of two locks once it holds both,
+/// releasing them as it resolves.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureSwap<T> {
+    // The request being waited for, that for the first lock in order of
+    // address, then that for the second:
+    pending: Option<FutureGuard<T>>,
+    // The second lock, until requested, unless both are the same:
+    last: Option<Qutex<T>>,
+    first: Option<Guard<T>>,
+}
+
+impl<T> FutureSwap<T> {
+    /// Blocks the current thread until this future resolves.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn wait(self) -> Result<(), Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureSwap<T> {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let future_guard = this
+                .pending
+                .as_mut()
+                .expect("FutureSwap::poll: Task already completed.");
+            let mut guard = futures::ready!(Pin::new(future_guard).poll(cx))?;
+            this.pending = None;
+            match (this.first.take(), this.last.take()) {
+                (Some(mut first), _) => {
+                    mem::swap(&mut *first, &mut *guard);
+                    return Poll::Ready(Ok(()));
+                }
+                (None, Some(last)) => {
+                    this.first = Some(guard);
+                    this.pending = Some(last.lock());
+                }
+                // The same lock, so nothing to exchange:
+                (None, None) => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+cfg_std! {
+    /// A future which resolves to a `Guard`, or to `Error::Expired` if its
+    /// deadline passes first (or `Error::QueueFull` if it could not be queued).
+    #[must_use = "futures do nothing unless polled"]
+    #[derive(Debug)]
+    pub struct FutureTimedGuard<T: ?Sized> {
+        future_guard: FutureGuard<T>,
+        deadline: Instant,
+    }
+
+    impl<T: ?Sized> FutureTimedGuard<T> {
+        /// Blocks the current thread until this future resolves.
+        #[inline]
+        pub fn wait(self) -> Result<Guard<T>, Error> {
+            executor::block_on(self)
+        }
+    }
+
+    impl<T: ?Sized> Future for FutureTimedGuard<T> {
+        type Output = Result<Guard<T>, Error>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let this = self.get_mut();
+            let deadline = this.deadline;
+            let expired = || Instant::now() >= deadline;
+
+            match this.future_guard.acquire.poll(cx, "FutureTimedGuard") {
+                Poll::Ready(Ok(qutex)) => Poll::Ready(Ok(Guard { qutex })),
+                Poll::Pending if expired() => Poll::Ready(Err(Error::Expired)),
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    /// A future which resolves to a `Guard`, or to an `Error` telling why
+    /// not.
+    #[must_use = "futures do nothing unless polled"]
+    #[derive(Debug)]
+    pub struct FutureCheckedGuard<T: ?Sized> {
+        future_guard: FutureGuard<T>,
+        owner: Option<Owner>,
+    }
+
+    impl<T: ?Sized> FutureCheckedGuard<T> {
+        /// Blocks the current thread until this future resolves.
+        #[inline]
+        pub fn wait(self) -> Result<Guard<T>, Error> {
+            executor::block_on(self)
+        }
+    }
+
+    impl<T: ?Sized> Future for FutureCheckedGuard<T> {
+        type Output = Result<Guard<T>, Error>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let this = self.get_mut();
+            let acquire = &mut this.future_guard.acquire;
+            if let Some(owner) = this.owner {
+                if acquire.handle().is_some_and(|qutex| qutex.inner.is_held_by(owner)) {
+                    acquire.withdraw();
+                    return Poll::Ready(Err(Error::WouldDeadlock));
+                }
+            }
+            match futures::ready!(acquire.poll(cx, "FutureCheckedGuard")) {
+                Ok(qutex) => {
+                    if let Some(owner) = this.owner {
+                        qutex.inner.set_owner(owner);
+                    }
+                    Poll::Ready(Ok(Guard { qutex }))
+                }
+                Err(err) => Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    /// A future which resolves to a `Guard`, or to `Error::Expired` if its
+    /// timeout elapses first.
+    ///
+    /// Unlike a `FutureTimedGuard`, the task is woken by the installed `Timer`
+    /// once the timeout elapses. The request leaves the queue as soon as this
+    /// future is dropped, so it is safe to abandon at any point.
+    #[must_use = "futures do nothing unless polled"]
+    #[derive(Debug)]
+    pub struct FutureLockTimeout<T: ?Sized> {
+        future_guard: FutureTimedGuard<T>,
+        sleep: Sleep,
+    }
+
+    impl<T: ?Sized> FutureLockTimeout<T> {
+        /// Blocks the current thread until this future resolves.
+        #[inline]
+        pub fn wait(self) -> Result<Guard<T>, Error> {
+            executor::block_on(self)
+        }
+    }
+
+    impl<T: ?Sized> Future for FutureLockTimeout<T> {
+        type Output = Result<Guard<T>, Error>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            // Only registers the wakeup. Once elapsed, the timed guard itself
+            // resolves to `Error::Expired`:
+            let _ = Pin::new(&mut self.sleep).poll(cx);
+            Pin::new(&mut self.future_guard).poll(cx)
+        }
+    }
+
+    /// A future which resolves to a `Guard` along with how long its request
+    /// spent queued.
+    ///
+    /// The wait ends as the lock is granted, not as the task next runs, so
+    /// leaves out any delay in scheduling it. Panics if polled again after
+    /// resolving.
+    #[must_use = "futures do nothing unless polled"]
+    #[derive(Debug)]
+    pub struct FutureLockTimed<T: ?Sized> {
+        future_guard: FutureGuard<T>,
+    }
+
+    impl<T: ?Sized> FutureLockTimed<T> {
+        /// Blocks the current thread until this future resolves.
+        #[inline]
+        pub fn wait(self) -> Result<(Guard<T>, Duration), Error> {
+            executor::block_on(self)
+        }
+    }
+
+    impl<T: ?Sized> Future for FutureLockTimed<T> {
+        type Output = Result<(Guard<T>, Duration), Error>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let acquire = &mut self.get_mut().future_guard.acquire;
+            match futures::ready!(acquire.poll_granted(cx, "FutureLockTimed")) {
+                // Taken straight away if never queued:
+                Ok((qutex, waiter)) => {
+                    let waited = waiter.map_or(Duration::ZERO, |waiter| waiter.waited());
+                    Poll::Ready(Ok((Guard { qutex }, waited)))
+                }
+                Err(err) => Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    /// A future which resolves to the result of a closure passed to
+    /// `Qutex::submit`.
+    ///
+    /// Submitted closures are always run, as only a lock of which no other
+    /// copies exist can be dropped (or coerced), so this future can never
+    /// find its closure gone.
+    #[must_use = "futures do nothing unless polled"]
+    #[derive(Debug)]
+    pub struct FutureSubmit<T: ?Sized, R> {
+        qutex: Qutex<T>,
+        rx: Receiver<thread::Result<R>>,
+    }
+
+    impl<T: ?Sized, R> FutureSubmit<T, R> {
+        /// Blocks the current thread until this future resolves.
+        #[inline]
+        pub fn wait(self) -> Result<R, Infallible> {
+            executor::block_on(self)
+        }
+    }
+
+    impl<T: ?Sized, R> Future for FutureSubmit<T, R> {
+        type Output = Result<R, Infallible>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            unsafe { self.qutex.process_queue() }
+
+            let res = futures::ready!(Pin::new(&mut self.rx).poll(cx));
+            match res.expect("FutureSubmit::poll: Closure dropped.") {
+                Ok(r) => Poll::Ready(Ok(r)),
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+    }
+}
+
+/// The lock as held by `process_queue` on nobody's behalf while it runs
+/// jobs and pops requests.
+///
+/// Waking a requester runs arbitrary code, which may panic. Should that
+/// happen while armed, the lock is released as the panic unwinds rather
+/// than being left held by nobody. Requests still queued are then granted
+/// by the next release or request.
+struct Held<'a> {
+    state: &'a AtomicBool,
+    armed: bool,
+}
+
+impl Held<'_> {
+    /// Disarms and releases the lock.
+    #[inline]
+    fn release(&mut self) {
+        self.armed = false;
+        self.state.store(false, Release);
+    }
+}
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.store(false, Release);
+        }
+    }
+}
+
+/// How a queued request's requester is signalled.
+#[derive(Debug)]
+enum Signal {
+    Waiter(Arc<Waiter>),
+    // Pushed by hand through `push_request`:
+    Sender(Sender<()>),
+}
+
+impl Signal {
+    /// Hands the lock to the requester outright, even under
+    /// `Admission::Barging`. Returns `false` if the requester is gone, as
+    /// `grant` does.
+    ///
+    /// Only requests made through a `Waiter` can be handed the lock.
+    #[inline]
+    fn hand(self, abandoned: &AtomicUsize) -> bool {
+        match self {
+            Signal::Waiter(waiter) => {
+                let handed = waiter.complete(HANDED);
+                if !handed {
+                    abandoned.fetch_sub(1, Relaxed);
+                }
+                handed
+            }
+            Signal::Sender(_) => unreachable!("Signal::hand: Request pushed by hand."),
+        }
+    }
+
+    /// Grants the request. Returns `false` if the requester is gone, in
+    /// which case an abandoned request is uncounted from `abandoned`.
+    #[inline]
+    fn grant(self, abandoned: &AtomicUsize) -> bool {
+        match self {
+            Signal::Waiter(waiter) => {
+                let granted = waiter.complete(GRANTED);
+                if !granted {
+                    abandoned.fetch_sub(1, Relaxed);
+                }
+                granted
+            }
+            Signal::Sender(tx) => tx.send(()).is_ok(),
+        }
+    }
+
+    /// Drops the request without granting it.
+    #[inline]
+    fn drop_request(self, abandoned: &AtomicUsize) {
+        match self {
+            Signal::Waiter(waiter) => {
+                if !waiter.complete(EXPIRED) {
+                    abandoned.fetch_sub(1, Relaxed);
+                }
+            }
+            // Resolves the receiver to `Canceled`, as hand-built requests
+            // predate `Error`:
+            Signal::Sender(tx) => drop(tx),
+        }
+    }
+}
+
+/// A request to lock the qutex for exclusive access.
+///
+/// Requests made through `lock` and friends signal their requester through
+/// a waker stored with the request. Those built by hand for `push_request`
+/// complete a oneshot sender instead.
+#[derive(Debug)]
+pub struct Request {
+    signal: Signal,
+    priority: Priority,
+    deadline: Deadline,
+}
+
+impl Request {
+    /// Returns a new `Request` of normal priority.
+    pub fn new(tx: Sender<()>) -> Request {
+        Request::with_priority(tx, Priority::Normal)
+    }
+
+    /// Returns a new `Request` of the given priority.
+    pub fn with_priority(tx: Sender<()>, priority: Priority) -> Request {
+        Request {
+            signal: Signal::Sender(tx),
+            priority,
+            deadline: None,
+        }
+    }
+
+    /// Sets a deadline after which this request is dropped from the queue
+    /// rather than granted.
+    #[cfg(feature = "std")]
+    pub fn deadline(mut self, deadline: Instant) -> Request {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Returns `true` if this request's deadline has passed.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Returns `true` if this request's deadline has passed.
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn is_expired(&self) -> bool {
+        // Never set without `std`:
+        self.deadline.is_some()
+    }
+}
+
+/// A precondition which a lock request waits for, once granted, before
+/// resolving, such as the completion of a GPU command.
+///
+/// Implemented for every `Unpin` future, which signals by resolving,
+/// whatever its output: a `FutureEvent`, or a `oneshot::Receiver` (whose
+/// sender may also signal by being dropped).
+pub trait WaitEvent: Send {
+    /// Returns `Poll::Ready` if the event has signaled, and otherwise
+    /// arranges for the task `cx` belongs to to be woken once it does.
+    fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+impl<F: Future + Unpin + Send> WaitEvent for F {
+    #[inline]
+    fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(self).poll(cx).map(|_| ())
+    }
+}
+
+/// Where a request gated on a `WaitEvent` takes its place in the queue (see
+/// `Qutex::lock_after_with`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EventOrder {
+    /// Queue straight away and, once granted, hold the lock until the event
+    /// signals, so that requests made later never overtake this one.
+    #[default]
+    Reserve,
+    /// Queue only once the event has signaled, so that the lock is never
+    /// held waiting for it, at the cost of requests made meanwhile going
+    /// first.
+    Defer,
+}
+
+/// The priority of a lock request.
+///
+/// Queued requests of higher priority are always granted before those of
+/// lower priority. Requests of equal priority are granted in FIFO order.
+/// A steady stream of higher priority requests will starve lower ones,
+/// unless the lock ages its requests (see `QutexBuilder::aging`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// A tag telling a lock request apart in diagnostics, as attached with
+/// `Qutex::lock_tagged`.
+///
+/// Shown by debug dumps, deadlock reports and `tracing` events, so that
+/// they name who is waiting rather than listing anonymous requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tag {
+    /// A name, such as that of the task or handler making the request.
+    Name(&'static str),
+    /// A number, such as a request or connection id.
+    Id(u64),
+}
+
+impl From<&'static str> for Tag {
+    #[inline]
+    fn from(name: &'static str) -> Tag {
+        Tag::Name(name)
+    }
+}
+
+impl From<u64> for Tag {
+    #[inline]
+    fn from(id: u64) -> Tag {
+        Tag::Id(id)
+    }
+}
+
+impl ::core::fmt::Display for Tag {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        match *self {
+            Tag::Name(name) => f.write_str(name),
+            Tag::Id(id) => write!(f, "#{}", id),
+        }
+    }
+}
+
+/// How a `Qutex` admits new lock requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Admission {
+    /// Every request joins the back of the queue, even if the lock is free,
+    /// and releasing the lock hands it directly to the next in line, so
+    /// requests are granted strictly in order (per priority).
+    ///
+    /// The order holds across cancellations: a request which is dropped,
+    /// times out, or is rejected is skipped without disturbing the order of
+    /// those behind it, and one dropped after being granted hands the lock
+    /// on to the next in line.
+    #[default]
+    Fair,
+    /// A new request takes the lock if it is free, regardless of any
+    /// requests already queued, and only queues otherwise. Releasing the
+    /// lock wakes the next in line without handing it over; should someone
+    /// else take it first, the woken request queues again at the back.
+    ///
+    /// For short critical sections this greatly improves throughput, since
+    /// the lock is never left idle while a woken task is scheduled, at the
+    /// cost of fairness: queued requests may be overtaken indefinitely.
+    Barging,
+}
+
+/// How a new lock request waits for a `Qutex` which is taken.
+///
+/// A free lock is always taken straight away. Different data wants very
+/// different strategies: a counter held for a few instructions is cheapest
+/// to spin for, while a handle held across I/O should never be spun for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum WaitStrategy {
+    /// Queue straight away.
+    #[default]
+    Queue,
+    /// Retry taking the lock this many times, backing off exponentially,
+    /// then queue.
+    Hybrid(u32),
+    /// Retry taking the lock, backing off exponentially, until it is taken.
+    ///
+    /// Spinning blocks the thread polling the request, so this suits only
+    /// the shortest critical sections. Under `Admission::Fair` a request
+    /// still queues as soon as others are queued ahead of it.
+    Spin,
+}
+
+/// How a thread backs off between failed attempts to take a lock.
+///
+/// After each of the first `spins` attempts the thread busy-waits, twice as
+/// long each time, up to `1 << max_spin_shift` spin loop hints. After each
+/// attempt beyond, it yields (or, without `std`, busy-waits its longest).
+/// A blocking waiter makes `yields` such yielding attempts before it queues
+/// and parks; requests spinning as their `WaitStrategy` allows keep this up
+/// until their attempts run out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Backoff {
+    spins: u32,
+    yields: u32,
+    max_spin_shift: u32,
+}
+
+impl Backoff {
+    /// Returns a new `Backoff` with the default limits: six spinning and
+    /// four yielding attempts, busy-waiting at most 64 spin loop hints.
+    #[inline]
+    pub const fn new() -> Backoff {
+        Backoff {
+            spins: 6,
+            yields: 4,
+            max_spin_shift: 6,
+        }
+    }
+
+    /// Returns a `Backoff` which parks straight away.
+    #[inline]
+    pub const fn park() -> Backoff {
+        Backoff {
+            spins: 0,
+            yields: 0,
+            max_spin_shift: 6,
+        }
+    }
+
+    /// Sets the number of spinning attempts.
+    pub fn spins(mut self, spins: u32) -> Backoff {
+        self.spins = spins;
+        self
+    }
+
+    /// Sets the number of yielding attempts.
+    pub fn yields(mut self, yields: u32) -> Backoff {
+        self.yields = yields;
+        self
+    }
+
+    /// Sets the longest busy-wait, as a power of two of spin loop hints.
+    ///
+    /// Capped at 31.
+    pub fn max_spin_shift(mut self, shift: u32) -> Backoff {
+        self.max_spin_shift = shift.min(u32::BITS - 1);
+        self
+    }
+
+    /// Returns the number of spinning attempts.
+    #[inline]
+    pub fn spin_count(&self) -> u32 {
+        self.spins
+    }
+
+    /// Returns the number of yielding attempts.
+    #[inline]
+    pub fn yield_count(&self) -> u32 {
+        self.yields
+    }
+
+    /// Returns the longest busy-wait, as a power of two of spin loop hints.
+    #[inline]
+    pub fn spin_shift_limit(&self) -> u32 {
+        self.max_spin_shift
+    }
+
+    /// Backs off after failed attempt number `attempt`, counting from zero.
+    #[inline]
+    pub(crate) fn snooze(&self, attempt: u32) {
+        #[cfg(feature = "std")]
+        if attempt >= self.spins {
+            thread::yield_now();
+            return;
+        }
+        for _ in 0..1u32 << attempt.min(self.max_spin_shift) {
+            hint::spin_loop();
+        }
+    }
+}
+
+impl Default for Backoff {
+    #[inline]
+    fn default() -> Backoff {
+        Backoff::new()
+    }
+}
+
+/// The number of `Priority` levels.
+const PRIORITY_LEVELS: usize = 3;
+
+/// The maximum number of submitted jobs run by a single holder in one go.
+const MAX_COMBINE_BATCH: usize = 64;
+
+// A biased lock's bias word holds the id of the owner it is biased toward
+// above one of these tags, or is zero until first claimed:
+/// Held by the owner the lock is biased toward.
+#[cfg(feature = "std")]
+const BIAS_HELD: u64 = 1;
+/// Released by that owner, but left locked for it to take back.
+#[cfg(feature = "std")]
+const BIAS_PARKED: u64 = 2;
+/// Contended, and so unbiased for good.
+#[cfg(feature = "std")]
+const BIAS_REVOKED: u64 = 3;
+
+/// Returns `true` if the bias word `word` has the lock parked.
+#[cfg(feature = "std")]
+#[inline]
+fn bias_parked(word: u64) -> bool {
+    word != BIAS_REVOKED && word & BIAS_REVOKED == BIAS_PARKED
+}
+
+/// A closure submitted to run under the lock.
+type Job<T> = Box<dyn FnOnce(&mut T) + Send>;
+
+/// A closure called with the data as the lock is released.
+type ReleaseCallback<T> = Box<dyn FnOnce(&T) + Send>;
+
+/// A closure handed the lock as it is released, returning the future to
+/// run before it is.
+#[cfg(feature = "std")]
+type Finalizer<T> = Box<dyn FnOnce(Guard<T>) -> BoxFuture<'static, ()> + Send>;
+
+/// A closure returning the future to run before each release of a lock.
+#[cfg(feature = "std")]
+type FinalizeFn<T> = dyn Fn(Guard<T>) -> BoxFuture<'static, ()> + Send + Sync;
+
+/// The finalizers of a lock (see `Guard::finalize_with`).
+#[cfg(feature = "std")]
+struct Finalizers<T: ?Sized> {
+    // Registered through the current holder's guard, and so only ever
+    // touched by the holder, as is whether `every` is now running:
+    pending: UnsafeCell<VecDeque<Finalizer<T>>>,
+    running_every: UnsafeCell<bool>,
+    every: Mutex<Option<Arc<FinalizeFn<T>>>>,
+    has_every: AtomicBool,
+}
+
+#[cfg(feature = "std")]
+impl<T: ?Sized> Default for Finalizers<T> {
+    fn default() -> Finalizers<T> {
+        Finalizers {
+            pending: UnsafeCell::new(VecDeque::new()),
+            running_every: UnsafeCell::new(false),
+            every: Mutex::new(None),
+            has_every: AtomicBool::new(false),
+        }
+    }
+}
+
+/// One request queue per priority level, lowest first.
+type Queues = [RequestQueue<Request>; PRIORITY_LEVELS];
+
+/// Ages queued requests passed over by those of higher priority, so that
+/// none starve (see `QutexBuilder::aging`).
+#[derive(Debug)]
+struct Aging {
+    rate: u32,
+    // The requests served ahead of the oldest of each level since it last
+    // was, while any of that level waited:
+    skipped: [AtomicU32; PRIORITY_LEVELS],
+}
+
+impl Aging {
+    fn new(rate: u32) -> Aging {
+        Aging {
+            rate,
+            skipped: Default::default(),
+        }
+    }
+
+    /// Pops the oldest request of the highest priority, of those levels
+    /// passed over `rate` times if any, aging those below it.
+    fn pop(&self, queues: &Queues) -> Option<Request> {
+        let aged = (0..PRIORITY_LEVELS)
+            .rev()
+            .filter(|&level| self.skipped[level].load(Relaxed) >= self.rate);
+        let (level, req) = aged
+            .chain((0..PRIORITY_LEVELS).rev())
+            .find_map(|level| WaitQueue::pop(&queues[level]).map(|req| (level, req)))?;
+
+        self.skipped[level].store(0, Relaxed);
+        for (queue, skipped) in queues.iter().zip(&self.skipped).take(level) {
+            if !WaitQueue::is_empty(queue) {
+                skipped.fetch_add(1, Relaxed);
+            }
+        }
+        Some(req)
+    }
+}
+
+/// Called with a lock's name and how long something took.
+#[cfg(feature = "std")]
+type TimedCallback = dyn Fn(Option<&str>, Duration) + Send + Sync;
+
+/// Called with a lock's name.
+#[cfg(feature = "std")]
+type ContendCallback = dyn Fn(Option<&str>) + Send + Sync;
+
+/// Called with a lock's name and the priority to boost its holder to, or
+/// `None` to restore it.
+#[cfg(feature = "std")]
+type BoostCallback = dyn Fn(Option<&str>, Option<Priority>) + Send + Sync;
+
+/// Called with a report of a long wait.
+#[cfg(feature = "std")]
+type WaitCallback = dyn Fn(&LongWait<'_>) + Send + Sync;
+
+/// A callback for holds longer than `threshold`.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+struct LongHold {
+    threshold: Duration,
+    callback: Arc<TimedCallback>,
+}
+
+#[cfg(feature = "std")]
+impl ::core::fmt::Debug for LongHold {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("LongHold")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A callback for waits longer than `threshold`.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+struct WaitAlarm {
+    threshold: Duration,
+    callback: Arc<WaitCallback>,
+}
+
+#[cfg(feature = "std")]
+impl ::core::fmt::Debug for WaitAlarm {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("WaitAlarm")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lifecycle callbacks set through `QutexBuilder`.
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+struct Hooks {
+    on_contend: Option<Arc<ContendCallback>>,
+    on_acquire: Option<Arc<TimedCallback>>,
+    on_release: Option<Arc<TimedCallback>>,
+    on_priority_boost: Option<Arc<BoostCallback>>,
+}
+
+#[cfg(feature = "std")]
+impl ::core::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_contend", &self.on_contend.is_some())
+            .field("on_acquire", &self.on_acquire.is_some())
+            .field("on_release", &self.on_release.is_some())
+            .field("on_priority_boost", &self.on_priority_boost.is_some())
+            .finish()
+    }
+}
+
+/// The priority of the current hold, for priority inheritance.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct Inheritance {
+    // The priority the holder requested the lock with:
+    base: AtomicU8,
+    // The highest of that and of the priorities of the requests queued
+    // during the hold:
+    effective: AtomicU8,
+}
+
+#[cfg(feature = "std")]
+impl Inheritance {
+    fn new() -> Inheritance {
+        Inheritance {
+            base: AtomicU8::new(Priority::Normal as u8),
+            effective: AtomicU8::new(Priority::Normal as u8),
+        }
+    }
+}
+
+/// Counts the immediate acquisitions of a lock made in a row by one task,
+/// against a budget.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct Coop {
+    budget: u32,
+    // The task last to acquire the lock immediately, and how many times in
+    // a row it has:
+    streak: Mutex<(Option<Waker>, u32)>,
+}
+
+/// Times each hold of a lock, reporting those which are too long.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct HoldWatch {
+    long_hold: Option<LongHold>,
+    epoch: Instant,
+    // When the current hold began, in nanoseconds since `epoch` plus one,
+    // or zero if the lock is not known to be held:
+    since: AtomicU64,
+    // Whether the current hold has been reported:
+    reported: AtomicBool,
+}
+
+#[cfg(feature = "std")]
+impl HoldWatch {
+    fn new(long_hold: Option<LongHold>) -> HoldWatch {
+        HoldWatch {
+            long_hold,
+            epoch: Instant::now(),
+            since: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> u64 {
+        u64::try_from(self.epoch.elapsed().as_nanos()).unwrap_or(u64::MAX - 1) + 1
+    }
+
+    /// Returns how long the hold which began at `since` has lasted.
+    #[inline]
+    fn held(&self, since: u64) -> Duration {
+        Duration::from_nanos(self.now().saturating_sub(since))
+    }
+
+    /// Reports the hold which began at `since`, unless short or already
+    /// reported.
+    #[inline]
+    fn check(&self, since: u64, name: Option<&str>) {
+        let Some(ref long_hold) = self.long_hold else {
+            return;
+        };
+        let held = self.held(since);
+        if since != 0 && held > long_hold.threshold && !self.reported.swap(true, Relaxed) {
+            (long_hold.callback)(name, held);
+        }
+    }
+}
+
+/// Times how long the queue of a lock goes without being served, reporting
+/// requests which wait too long.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct WaitWatch {
+    alarm: WaitAlarm,
+    epoch: Instant,
+    // When the request now at the head of the queue reached it, at the
+    // latest, in nanoseconds since `epoch` plus one, or zero if none is
+    // known to be queued:
+    stalled: AtomicU64,
+    // Whether the current stall has been reported:
+    reported: AtomicBool,
+}
+
+#[cfg(feature = "std")]
+impl WaitWatch {
+    fn new(alarm: WaitAlarm) -> WaitWatch {
+        WaitWatch {
+            alarm,
+            epoch: Instant::now(),
+            stalled: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> u64 {
+        u64::try_from(self.epoch.elapsed().as_nanos()).unwrap_or(u64::MAX - 1) + 1
+    }
+
+    /// Records that the queue has been served, leaving `queued` requests.
+    #[inline]
+    fn served(&self, queued: usize) {
+        self.reported.store(false, Relaxed);
+        self.stalled.store(if queued == 0 { 0 } else { self.now() }, Relaxed);
+    }
+}
+
+// Orderings:
+//
+// * `state` is acquired with `Acquire` and released with `Release`, and a
+//   grant handed over through a `Waiter` is likewise a release/acquire
+//   pair, so each holder sees everything its predecessor wrote.
+// * Releasing the lock and then checking the queues, racing a requester
+//   pushing to a queue and then checking the lock, is a store-buffering
+//   pattern: with release/acquire alone both could miss the other, leaving
+//   a request queued with nobody to grant it. A `SeqCst` fence between the
+//   store and the load on both sides (see `process_queue`) rules this out.
+// * `queued`, `abandoned` and the counts of requests passed over while
+//   aging are only counts, and `poisoned` is only informational, so all are
+//   `Relaxed`.
+// * `version` is incremented with `Release` and loaded with `Acquire`, so
+//   whoever finds a version sees at least the writes it counts.
+// * `queues` is published with a release CAS and loaded with `Acquire`, so
+//   whoever finds the queues sees them initialized.
+//
+// `state` and the data each get a cache line of their own, so that
+// requesters hammering the lock word do not keep invalidating the line the
+// holder is working on, and vice versa. The queues are only allocated once
+// first needed, since most locks are never contended and the queues are by
+// far the largest part of a `Qutex`. Being boxed, they sit apart from both.
+/// The lock behind a `Qutex`, for embedding in a structure of one's own.
+///
+/// A `QutexCore` is neither reference counted nor `Clone`: it is locked
+/// through `lock_ref` and `try_lock_ref`, whose guards borrow it, so that a
+/// lock kept within an `Arc` of one's own costs no second indirection. A
+/// `Qutex` is a `QutexCore` behind an `Arc`, and dereferences to it.
+///
+/// A `QutexCore` can be named, but is listed by the registry only once made
+/// into a `Qutex` with `Qutex::from_core`, as it may otherwise move.
+#[repr(C)]
+pub struct QutexCore<T: ?Sized> {
+    // Whether the lock is held:
+    state: CachePadded<AtomicBool>,
+    taken: bool,
+    name: Option<String>,
+    admission: Admission,
+    // The maximum number of queued requests, if bounded:
+    queue_bound: Option<usize>,
+    aging: Option<Aging>,
+    queued: AtomicUsize,
+    // Queued requests whose requesters have since given up. These are left
+    // in place and skipped once popped: a `SegQueue` cannot be compacted
+    // without reordering the requests pushed meanwhile, and each is popped
+    // only once either way. They are not counted towards `queue_bound`:
+    abandoned: AtomicUsize,
+    wait_strategy: WaitStrategy,
+    backoff: Backoff,
+    poison: bool,
+    poisoned: AtomicBool,
+    // The number of guards released:
+    version: AtomicUsize,
+    // The id of the `Owner` holding the lock, or zero, if recursion is
+    // detected:
+    #[cfg(feature = "std")]
+    owner: Option<AtomicU32>,
+    // The bias word, if biased toward the last holder:
+    #[cfg(feature = "std")]
+    bias: Option<AtomicU64>,
+    // Who holds the lock, if tracked:
+    #[cfg(feature = "std")]
+    holder_info: Option<Mutex<Option<Holder>>>,
+    // Streaks of immediate acquisitions, if budgeted:
+    #[cfg(feature = "std")]
+    coop: Option<Coop>,
+    // Times holds, if either reported when long or passed to `on_release`:
+    #[cfg(feature = "std")]
+    hold_watch: Option<HoldWatch>,
+    // Times waits, if reported when long:
+    #[cfg(feature = "std")]
+    wait_watch: Option<WaitWatch>,
+    // The level locks are ranked by, if any:
+    #[cfg(feature = "std")]
+    level: Option<u32>,
+    #[cfg(feature = "std")]
+    hooks: Hooks,
+    // Tracks the holder's priority, if boosted:
+    #[cfg(feature = "std")]
+    inheritance: Option<Inheritance>,
+    // The labels reported along with each metric:
+    #[cfg(feature = "metrics-rs")]
+    metric_labels: Vec<metrics_rs::Label>,
+    #[cfg(feature = "debug_dump")]
+    pending: Mutex<Pending>,
+    // Null until the first request is queued:
+    queues: AtomicPtr<Queues>,
+    jobs: SegQueue<Job<T>>,
+    // Registered through the current holder's guard, and so only ever
+    // touched by the holder:
+    release_callbacks: UnsafeCell<Vec<ReleaseCallback<T>>>,
+    #[cfg(feature = "std")]
+    finalizers: Finalizers<T>,
+    #[cfg(feature = "metrics")]
+    stats: Counters,
+    // Dropped by hand, unless `taken` by `Qutex::into_inner`. Last, as it
+    // may be unsized, with the layout fixed so that `from_boxed` can find
+    // it:
+    cell: CacheAligned<UnsafeCell<ManuallyDrop<T>>>,
+}
+
+impl<T: ?Sized> QutexCore<T> {
+    /// Returns the request queues, if any request has ever been queued.
+    #[inline]
+    fn queues(&self) -> Option<&Queues> {
+        unsafe { self.queues.load(Acquire).as_ref() }
+    }
+
+    /// Returns the request queues, allocating them if need be.
+    fn queues_or_init(&self) -> &Queues {
+        if let Some(queues) = self.queues() {
+            return queues;
+        }
+
+        let new = Box::into_raw(Box::<Queues>::default());
+        match self
+            .queues
+            .compare_exchange(ptr::null_mut(), new, AcqRel, Acquire)
+        {
+            Ok(_) => unsafe { &*new },
+            // Allocated concurrently by another requester:
+            Err(queues) => unsafe {
+                drop(Box::from_raw(new));
+                &*queues
+            },
+        }
+    }
+
+    /// Pushes a request onto the queue for its priority, without counting
+    /// it, or returns it if that queue is full.
+    #[inline]
+    fn push_queued(&self, req: Request) -> Result<(), Request> {
+        WaitQueue::push(&self.queues_or_init()[req.priority as usize], req)
+    }
+
+    /// Pops the oldest request of the highest priority, or of a lower one
+    /// aged enough to go first.
+    #[inline]
+    fn pop_request(&self) -> Option<Request> {
+        let queues = self.queues()?;
+        let req = match self.aging {
+            Some(ref aging) => aging.pop(queues),
+            None => queues.iter().rev().find_map(WaitQueue::pop),
+        };
+        if req.is_some() {
+            let _queued = self.queued.fetch_sub(1, Relaxed) - 1;
+            #[cfg(feature = "std")]
+            if let Some(ref watch) = self.wait_watch {
+                watch.served(_queued);
+            }
+        }
+        req
+    }
+
+    /// Records the task now holding the lock, or that it is held by nobody
+    /// in particular, if recursion or deadlocks are detected, or the lock
+    /// is ranked.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn set_holder(&self, waker: Option<&Waker>) {
+        if waker.is_none() {
+            if let Some(ref holder) = self.holder_info {
+                *holder.lock().unwrap_or_else(PoisonError::into_inner) = None;
+            }
+            if let Some(ref owner) = self.owner {
+                owner.store(0, Relaxed);
+            }
+        }
+        #[cfg(feature = "deadlock_detection")]
+        match waker {
+            Some(waker) => deadlock::acquired(self.id(), self.name.as_deref(), waker),
+            None => deadlock::released(self.id()),
+        }
+        if let (Some(level), true) = (self.level, rank::ENABLED) {
+            match waker {
+                Some(waker) => rank::acquired(self.id(), level, self.name.as_deref(), waker),
+                None => rank::released(self.id()),
+            }
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn set_holder(&self, _waker: Option<&Waker>) {}
+
+    /// Returns whether the last task to acquire the lock immediately has
+    /// spent its acquisition budget, if budgeted. Its streak only ends once
+    /// a request is served from the queue.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn budget_spent(&self) -> bool {
+        self.coop.as_ref().is_some_and(|coop| {
+            coop.streak.lock().unwrap_or_else(PoisonError::into_inner).1 >= coop.budget
+        })
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn budget_spent(&self) -> bool {
+        false
+    }
+
+    /// Counts an acquisition by the task of `waker` towards its streak, if
+    /// budgeted, extending it if `immediate` and otherwise ending it.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn record_streak(&self, waker: &Waker, immediate: bool) {
+        if let Some(ref coop) = self.coop {
+            let mut streak = coop.streak.lock().unwrap_or_else(PoisonError::into_inner);
+            *streak = match *streak {
+                (Some(ref task), count) if immediate && task.will_wake(waker) => {
+                    (Some(waker.clone()), count.saturating_add(1))
+                }
+                _ if immediate => (Some(waker.clone()), 1),
+                _ => (None, 0),
+            };
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn record_streak(&self, _waker: &Waker, _immediate: bool) {}
+
+    /// Records the current thread as the holder, if tracked, having made its
+    /// request at `location`.
+    #[cfg(feature = "std")]
+    fn record_holder(&self, location: Option<&'static Location<'static>>) {
+        if let Some(ref holder) = self.holder_info {
+            *holder.lock().unwrap_or_else(PoisonError::into_inner) = Some(Holder {
+                thread: thread::current(),
+                location,
+                label: None,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Labels the holder, if tracked.
+    #[cfg(feature = "std")]
+    fn set_holder_label(&self, label: String) {
+        if let Some(ref holder) = self.holder_info {
+            if let Some(ref mut holder) = *holder.lock().unwrap_or_else(PoisonError::into_inner) {
+                holder.label = Some(label);
+            }
+        }
+    }
+
+    /// Records, if deadlocks are detected, that the task `waker` wakes is
+    /// waiting through `waiter`, or that nobody is.
+    #[inline]
+    fn record_waiting(&self, _waiter: &Arc<Waiter>, _waker: Option<&Waker>) {
+        #[cfg(feature = "deadlock_detection")]
+        match _waker {
+            Some(waker) => {
+                let request = Arc::as_ptr(_waiter) as usize;
+                deadlock::waiting(request, self.id(), waker, _waiter.tag);
+            }
+            None => deadlock::done_waiting(Arc::as_ptr(_waiter) as usize),
+        }
+    }
+
+    /// Identifies this lock to the deadlock detector and ranking checks.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn id(&self) -> usize {
+        (self as *const QutexCore<T>).cast::<()>() as usize
+    }
+
+    /// Counts an acquisition for `Qutex::stats`, contended if it started
+    /// waiting at `since`, traces it, records the holder if tracked, calls
+    /// any `on_acquire` hook, and reports it through `metrics`.
+    #[inline]
+    fn record_acquisition(
+        &self,
+        _since: WaitStart,
+        _location: Option<&'static Location<'static>>,
+        _tag: Option<Tag>,
+    ) {
+        #[cfg(feature = "std")]
+        self.record_holder(_location);
+        #[cfg(feature = "std")]
+        let _wait = _since.map_or(Duration::ZERO, |since| since.elapsed());
+        #[cfg(feature = "std")]
+        if let Some(ref watch) = self.wait_watch {
+            if _wait > watch.alarm.threshold {
+                self.report_wait(watch, _wait, true);
+            }
+        }
+        #[cfg(feature = "std")]
+        if let Some(ref on_acquire) = self.hooks.on_acquire {
+            on_acquire(self.name.as_deref(), _wait);
+        }
+        #[cfg(feature = "metrics-rs")]
+        {
+            metrics_rs::counter!("qutex.acquire", self.metric_labels.iter()).increment(1);
+            metrics_rs::histogram!("qutex.wait_seconds", self.metric_labels.iter()).record(_wait);
+        }
+        #[cfg(feature = "metrics")]
+        self.stats.acquired(_since);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            lock = self.name.as_deref(),
+            contended = _since.is_some(),
+            wait = ?_wait,
+            tag = _tag.as_ref().map(tracing::field::display),
+            "acquired",
+        );
+    }
+
+    /// Records a request, tagged `tag` if at all, being queued, leaving
+    /// `depth` requests queued, for `Qutex::stats`, traces it, and calls any
+    /// `on_contend` hook.
+    #[inline]
+    fn record_enqueue(&self, _depth: usize, _tag: Option<Tag>) {
+        #[cfg(feature = "std")]
+        if let Some(ref on_contend) = self.hooks.on_contend {
+            on_contend(self.name.as_deref());
+        }
+        #[cfg(feature = "std")]
+        if let Some(ref watch) = self.wait_watch {
+            // Stalled from now, unless already:
+            let _ = watch.stalled.compare_exchange(0, watch.now(), Relaxed, Relaxed);
+        }
+        self.check_wait();
+        #[cfg(feature = "metrics")]
+        self.stats.queued(_depth);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            lock = self.name.as_deref(),
+            depth = _depth,
+            tag = _tag.as_ref().map(tracing::field::display),
+            "enqueued",
+        );
+    }
+
+    /// Traces a release, reports the hold if watched and too long, and
+    /// passes its length to any `on_release` hook and to `metrics`.
+    #[inline]
+    fn record_release(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(lock = self.name.as_deref(), "released");
+        self.check_wait();
+        #[cfg(feature = "std")]
+        if let (Some(inheritance), Some(on_boost)) =
+            (&self.inheritance, &self.hooks.on_priority_boost)
+        {
+            let base = inheritance.base.load(Relaxed);
+            if inheritance.effective.swap(base, Relaxed) > base {
+                on_boost(self.name.as_deref(), None);
+            }
+        }
+        #[cfg(feature = "std")]
+        if let Some(ref watch) = self.hold_watch {
+            let since = watch.since.swap(0, Relaxed);
+            watch.check(since, self.name.as_deref());
+            if since == 0 {
+                return;
+            }
+            if let Some(ref on_release) = self.hooks.on_release {
+                on_release(self.name.as_deref(), watch.held(since));
+            }
+            #[cfg(feature = "metrics-rs")]
+            metrics_rs::histogram!("qutex.hold_seconds", self.metric_labels.iter())
+                .record(watch.held(since));
+        }
+    }
+
+    /// Records that the lock is now held at `_priority`, if priorities are
+    /// inherited.
+    #[inline]
+    fn set_hold_priority(&self, _priority: Priority) {
+        #[cfg(feature = "std")]
+        if let Some(ref inheritance) = self.inheritance {
+            inheritance.base.store(_priority as u8, Relaxed);
+            inheritance.effective.store(_priority as u8, Relaxed);
+        }
+    }
+
+    /// Boosts the holder, if priorities are inherited and the lock is held
+    /// at a lower priority than `_priority`, that of a newly queued request.
+    #[inline]
+    fn record_boost(&self, _priority: Priority) {
+        #[cfg(feature = "std")]
+        if let (Some(inheritance), Some(on_boost)) =
+            (&self.inheritance, &self.hooks.on_priority_boost)
+        {
+            if self.state.load(Relaxed)
+                && inheritance.effective.fetch_max(_priority as u8, Relaxed) < _priority as u8
+            {
+                on_boost(self.name.as_deref(), Some(_priority));
+            }
+        }
+    }
+
+    /// Lists a newly queued request for `Qutex::debug_dump`.
+    #[inline]
+    fn record_pending(&self, _waiter: &Arc<Waiter>) {
+        #[cfg(feature = "debug_dump")]
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(_waiter);
+    }
+
+    /// Records, if holds are watched, that a hold has just begun.
+    #[inline]
+    fn record_hold(&self) {
+        #[cfg(feature = "std")]
+        if let Some(ref watch) = self.hold_watch {
+            watch.reported.store(false, Relaxed);
+            watch.since.store(watch.now(), Relaxed);
+        }
+    }
+
+    /// Reports the current hold if watched and too long.
+    #[inline]
+    fn check_hold(&self) {
+        #[cfg(feature = "std")]
+        if let Some(ref watch) = self.hold_watch {
+            watch.check(watch.since.load(Relaxed), self.name.as_deref());
+        }
+    }
+
+    /// Reports the request at the head of the queue if waits are watched
+    /// and it has waited too long, unless already reported.
+    #[inline]
+    fn check_wait(&self) {
+        #[cfg(feature = "std")]
+        if let Some(ref watch) = self.wait_watch {
+            let stalled = watch.stalled.load(Relaxed);
+            let waited = Duration::from_nanos(watch.now().saturating_sub(stalled));
+            if stalled != 0 && waited > watch.alarm.threshold && !watch.reported.swap(true, Relaxed)
+            {
+                self.report_wait(watch, waited, false);
+            }
+        }
+    }
+
+    /// Calls the long wait callback for a request which has `waited`.
+    #[cfg(feature = "std")]
+    fn report_wait(&self, watch: &WaitWatch, waited: Duration, granted: bool) {
+        let queued = self.queued.load(Relaxed);
+        (watch.alarm.callback)(&LongWait {
+            name: self.name.as_deref(),
+            waited,
+            queued: queued.saturating_sub(self.abandoned.load(Relaxed)),
+            holder: if granted { None } else { self.current_holder() },
+            granted,
+        });
+    }
+
+    /// Panics if the lock is ranked and the task `waker` wakes already holds
+    /// one of the same or a higher level.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn check_rank(&self, waker: &Waker) {
+        if let (Some(level), true) = (self.level, rank::ENABLED) {
+            rank::check(self.id(), level, self.name.as_deref(), waker);
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn check_rank(&self, _waker: &Waker) {}
+
+    /// Returns `true` if recursion is detected and the lock is held on
+    /// behalf of `owner`.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn is_held_by(&self, owner: Owner) -> bool {
+        self.owner
+            .as_ref()
+            .is_some_and(|held| held.load(Relaxed) == owner.id())
+    }
+
+    /// Records that the lock, just acquired, is held on behalf of `owner`,
+    /// if recursion is detected. Cleared as the lock is released.
+    ///
+    /// Biases the lock toward `owner`, if biased and not yet claimed.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn set_owner(&self, owner: Owner) {
+        if let Some(ref held) = self.owner {
+            held.store(owner.id(), Relaxed);
+        }
+        if let Some(ref bias) = self.bias {
+            let held = (owner.id() as u64) << 2 | BIAS_HELD;
+            let _ = bias.compare_exchange(0, held, Relaxed, Relaxed);
+        }
+    }
+
+    /// Takes back the lock parked for `owner`, if biased toward it and
+    /// nobody has contended for it since.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn take_biased(&self, owner: Owner) -> bool {
+        self.bias.as_ref().is_some_and(|bias| {
+            let id = (owner.id() as u64) << 2;
+            bias.compare_exchange(id | BIAS_PARKED, id | BIAS_HELD, Acquire, Relaxed)
+                .is_ok()
+        })
+    }
+
+    /// Parks the lock, rather than releasing it, if held by the owner it
+    /// is biased toward, returning `true` if so.
+    #[inline]
+    fn park_biased(&self) -> bool {
+        #[cfg(feature = "std")]
+        if let Some(ref bias) = self.bias {
+            let word = bias.load(Relaxed);
+            if word & BIAS_REVOKED == BIAS_HELD {
+                let parked = word ^ BIAS_HELD | BIAS_PARKED;
+                return bias.compare_exchange(word, parked, Release, Relaxed).is_ok();
+            }
+        }
+        false
+    }
+
+    /// Revokes the bias for good, if biased, returning `true` if the lock
+    /// was parked, in which case it is now the caller's.
+    #[inline]
+    fn revoke_bias(&self) -> bool {
+        #[cfg(feature = "std")]
+        if let Some(ref bias) = self.bias {
+            // Left alone once revoked, so as not to contend on it for good:
+            return bias.load(Relaxed) != BIAS_REVOKED
+                && bias_parked(bias.swap(BIAS_REVOKED, AcqRel));
+        }
+        false
+    }
+
+    /// Takes the lock if it is free, or parked for a biased owner, whose
+    /// bias is then revoked.
+    #[inline]
+    fn take_state(&self) -> bool {
+        self.state.compare_exchange(false, true, Acquire, Relaxed).is_ok() || self.revoke_bias()
+    }
+
+    /// Reads whether the `QutexCore` at `inner` is locked, and how many
+    /// requests are waiting, for the registry.
+    ///
+    /// Only fields laid out alike whatever `T` are read, so this serves
+    /// locks of any other type as well, sized or not.
+    #[cfg(feature = "registry")]
+    unsafe fn inspect(inner: usize) -> (bool, usize)
+    where
+        T: Sized,
+    {
+        let inner = inner as *const QutexCore<T>;
+        let state = &*ptr::addr_of!((*inner).state);
+        let queued = &*ptr::addr_of!((*inner).queued);
+        let abandoned = &*ptr::addr_of!((*inner).abandoned);
+        let bias = &*ptr::addr_of!((*inner).bias);
+        let waiting = queued.load(Relaxed).saturating_sub(abandoned.load(Relaxed));
+        let parked = bias.as_ref().is_some_and(|bias| bias_parked(bias.load(Relaxed)));
+        (state.load(Relaxed) && !parked, waiting)
+    }
+
+    /// Returns `true` if any requests are queued.
+    #[inline]
+    fn has_requests(&self) -> bool {
+        self.queues()
+            .is_some_and(|queues| queues.iter().any(|queue| !WaitQueue::is_empty(queue)))
+    }
+}
+
+impl<T: ?Sized> Drop for QutexCore<T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "registry")]
+        if self.name.is_some() {
+            registry::unregister((self as *const QutexCore<T>).cast::<()>() as usize);
+        }
+        let queues = *self.queues.get_mut();
+        if !queues.is_null() {
+            unsafe { drop(Box::from_raw(queues)) }
+        }
+        if !self.taken {
+            unsafe { ManuallyDrop::drop(self.cell.get_mut()) }
+        }
+    }
+}
+
+impl<T> From<T> for QutexCore<T> {
+    #[inline]
+    fn from(val: T) -> QutexCore<T> {
+        QutexCore {
+            state: CachePadded::new(AtomicBool::new(false)),
+            taken: false,
+            name: None,
+            admission: Admission::Fair,
+            queue_bound: None,
+            aging: None,
+            queued: AtomicUsize::new(0),
+            abandoned: AtomicUsize::new(0),
+            wait_strategy: WaitStrategy::Queue,
+            backoff: Backoff::new(),
+            poison: false,
+            poisoned: AtomicBool::new(false),
+            version: AtomicUsize::new(0),
+            #[cfg(feature = "std")]
+            owner: None,
+            #[cfg(feature = "std")]
+            bias: None,
+            #[cfg(feature = "std")]
+            holder_info: None,
+            #[cfg(feature = "std")]
+            coop: None,
+            // Hold times are always reported through `metrics`:
+            #[cfg(feature = "std")]
+            hold_watch: cfg!(feature = "metrics-rs").then(|| HoldWatch::new(None)),
+            #[cfg(feature = "std")]
+            wait_watch: None,
+            #[cfg(feature = "std")]
+            level: None,
+            #[cfg(feature = "std")]
+            hooks: Hooks::default(),
+            #[cfg(feature = "std")]
+            inheritance: None,
+            #[cfg(feature = "metrics-rs")]
+            metric_labels: Vec::new(),
+            #[cfg(feature = "debug_dump")]
+            pending: Mutex::default(),
+            queues: AtomicPtr::new(ptr::null_mut()),
+            jobs: SegQueue::new(),
+            release_callbacks: UnsafeCell::new(Vec::new()),
+            #[cfg(feature = "std")]
+            finalizers: Finalizers::default(),
+            #[cfg(feature = "metrics")]
+            stats: Counters::default(),
+            cell: CacheAligned::new(UnsafeCell::new(ManuallyDrop::new(val))),
+        }
+    }
+}
+
+unsafe impl<T: Send + ?Sized> Send for QutexCore<T> {}
+unsafe impl<T: Send + ?Sized> Sync for QutexCore<T> {}
+
+impl<T> QutexCore<T> {
+    /// Creates and returns a new `QutexCore`.
+    #[inline]
+    pub fn new(val: T) -> QutexCore<T> {
+        QutexCore::from(val)
+    }
+
+    /// Consumes this `QutexCore`, returning the inner value.
+    pub fn into_inner(mut self) -> T {
+        self.taken = true;
+        unsafe { ManuallyDrop::take(self.cell.get_mut()) }
+    }
+}
+
+impl<T: ?Sized> QutexCore<T> {
+    /// Moves the value within `boxed`, which may be unsized, into a new
+    /// boxed `QutexCore`.
+    fn from_boxed(boxed: Box<T>) -> Box<QutexCore<T>> {
+        let template = ManuallyDrop::new(QutexCore::from(()));
+        unsafe { QutexCore::from_parts(&*template, boxed) }
+    }
+
+    /// Moves the value within `boxed` into a new boxed `QutexCore`,
+    /// configured as `template`, whose fields but the value, `jobs`,
+    /// `release_callbacks` and `finalizers` are moved into it.
+    ///
+    /// # Safety
+    ///
+    /// `template` must not be used again, nor dropped but for freeing its
+    /// memory, and its `jobs`, `release_callbacks` and `finalizers` must
+    /// hold nothing more to be dropped.
+    unsafe fn from_parts<S: ?Sized>(template: &QutexCore<S>, boxed: Box<T>) -> Box<QutexCore<T>> {
+        // Every field but `cell` is laid out alike whatever `T`, and all but
+        // `jobs`, `release_callbacks` and `finalizers` have the same type
+        // too, so their values can be copied from a template of any type:
+        let header = mem::offset_of!(QutexCore<()>, cell);
+        let value = Layout::for_value::<T>(&boxed);
+        let (layout, offset) = Layout::from_size_align(header, mem::align_of::<QutexCore<()>>())
+            .and_then(|header| header.extend(value.align_to(mem::align_of::<CacheAligned<()>>())?))
+            .expect("QutexCore::from_parts: Value too large.");
+        let layout = layout.pad_to_align();
+
+        let raw = alloc::alloc::alloc(layout);
+        if raw.is_null() {
+            alloc::alloc::handle_alloc_error(layout);
+        }
+        let template = template as *const QutexCore<S> as *const u8;
+        ptr::copy_nonoverlapping(template, raw, header);
+        let src = Box::into_raw(boxed);
+        ptr::copy_nonoverlapping(src as *const u8, raw.add(offset), value.size());
+
+        // Points to `raw`, with the metadata of `src`. A wide pointer's
+        // address comes first:
+        let mut core = src as *mut QutexCore<T>;
+        *(&mut core as *mut *mut QutexCore<T> as *mut *mut u8) = raw;
+        ptr::addr_of_mut!((*core).jobs).write(SegQueue::new());
+        ptr::addr_of_mut!((*core).release_callbacks).write(UnsafeCell::new(Vec::new()));
+        #[cfg(feature = "std")]
+        ptr::addr_of_mut!((*core).finalizers).write(Finalizers::default());
+
+        // Freed without dropping the value, now moved:
+        drop(Box::from_raw(src as *mut ManuallyDrop<T>));
+        Box::from_raw(core)
+    }
+
+    /// Returns a mutable reference to the inner value.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to
+    /// take place.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.cell.get() }
+    }
+
+    /// Returns this lock's name, if one was set.
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the maximum number of queued requests, if bounded.
+    #[inline]
+    pub fn queue_bound(&self) -> Option<usize> {
+        self.queue_bound
+    }
+
+    /// Returns the number of requests of higher priority served ahead of a
+    /// queued request before it goes first, if requests age.
+    #[inline]
+    pub fn aging_rate(&self) -> Option<u32> {
+        self.aging.as_ref().map(|aging| aging.rate)
+    }
+
+    /// Returns the number of retries a new request makes before queueing,
+    /// `u32::MAX` standing for `WaitStrategy::Spin`.
+    #[inline]
+    pub fn spin_limit(&self) -> u32 {
+        match self.wait_strategy {
+            WaitStrategy::Queue => 0,
+            WaitStrategy::Hybrid(attempts) => attempts,
+            WaitStrategy::Spin => u32::MAX,
+        }
+    }
+
+    /// Returns how new requests wait for the lock if it is taken.
+    #[inline]
+    pub fn wait_strategy(&self) -> WaitStrategy {
+        self.wait_strategy
+    }
+
+    /// Returns how requests back off between attempts to take the lock.
+    #[inline]
+    pub fn backoff(&self) -> Backoff {
+        self.backoff
+    }
+
+    /// Returns the number of immediate acquisitions a task may make in a
+    /// row, if limited.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn acquisition_budget(&self) -> Option<u32> {
+        self.coop.as_ref().map(|coop| coop.budget)
+    }
+
+    /// Returns this lock's admission policy.
+    #[inline]
+    pub fn admission(&self) -> Admission {
+        self.admission
+    }
+
+    /// Returns `true` if poisoning is enabled and a guard was dropped while
+    /// its thread was panicking.
+    ///
+    /// Poisoning is purely informational: locking a poisoned lock still
+    /// succeeds, leaving it to the caller to decide whether the data can be
+    /// trusted.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Relaxed)
+    }
+
+    /// Returns `true` if the lock is held.
+    ///
+    /// As with the other introspection methods, the answer may be out of
+    /// date by the time it is returned.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) && !self.is_parked()
+    }
+
+    /// Returns `true` if the lock is parked for the owner it is biased
+    /// toward, so only looks held.
+    #[inline]
+    fn is_parked(&self) -> bool {
+        #[cfg(feature = "std")]
+        if let Some(ref bias) = self.bias {
+            return bias_parked(bias.load(Relaxed));
+        }
+        false
+    }
+
+    /// Returns the number of requests queued for the lock, not counting
+    /// those since given up on.
+    #[inline]
+    pub fn queued_waiters(&self) -> usize {
+        let queued = self.queued.load(Relaxed);
+        queued.saturating_sub(self.abandoned.load(Relaxed))
+    }
+
+    /// Returns the lock's version, counting the guards released and the
+    /// submitted closures run so far.
+    ///
+    /// Every guard has mutable access, so each release counts as a write,
+    /// whether or not the data was actually changed. A version found
+    /// unchanged since last looked at means nothing has written to the data
+    /// meanwhile, without having to lock it. The count wraps on overflow.
+    #[inline]
+    pub fn version(&self) -> usize {
+        self.version.load(Acquire)
+    }
+
+    /// Clears the poisoned state.
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Relaxed);
+    }
+
+    /// Returns who holds the lock, if its holder is tracked (see
+    /// `QutexBuilder::track_holder`) and it is held by a known holder.
+    ///
+    /// A request which resolves to `Error::Expired` can use this to report
+    /// who held the lock meanwhile. A lock handed off with `Guard::handoff`
+    /// has no known holder until redeemed.
+    #[cfg(feature = "std")]
+    pub fn current_holder(&self) -> Option<Holder> {
+        let holder = self.holder_info.as_ref()?;
+        holder.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Returns a snapshot of this lock's state: whether it is held, and by
+    /// whom if tracked, and the requests waiting for it.
+    ///
+    /// Requests are listed highest priority first, then in the order they
+    /// were queued, although those queued concurrently may be listed out of
+    /// order. Requests pushed by hand with `push_request` are not listed.
+    /// Listing requests costs a mutex per queued request.
+    #[cfg(feature = "debug_dump")]
+    pub fn debug_dump(&self) -> LockDump {
+        let mut pending = self
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .waiters
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|waiter| waiter.is_waiting())
+            .map(|waiter| {
+                let since = waiter.since.unwrap_or_else(Instant::now);
+                PendingRequest::new(
+                    waiter.priority,
+                    since,
+                    waiter.deadline,
+                    waiter.location,
+                    waiter.tag,
+                )
+            })
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|req| ::core::cmp::Reverse(req.priority()));
+
+        LockDump::new(
+            self.name.clone(),
+            self.is_locked(),
+            self.is_poisoned(),
+            self.current_holder(),
+            pending,
+        )
+    }
+
+    /// Returns a snapshot of this lock's contention counters.
+    ///
+    /// Every acquisition through `lock` and friends, `try_lock` and
+    /// `try_lock_ref` is counted. Those which found the lock taken, whether
+    /// they then spun or queued, count as contended and add the time until
+    /// they were granted to the total wait time. Lock requests pushed by
+    /// hand count towards the queue depth only.
+    #[cfg(feature = "metrics")]
+    #[inline]
+    pub fn stats(&self) -> LockStats {
+        self.stats.snapshot()
+    }
+
+    /// Returns a reference to the inner value.
+    ///
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        self.cell.get() as *const T
+    }
+
+    /// Returns a mutable reference to the inner value.
+    ///
+    #[inline]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.cell.get() as *mut T
+    }
+
+    /// Returns a new `FutureRefGuard` which will resolve into a `RefGuard`
+    /// borrowing this lock.
+    ///
+    /// Behaves just like `Qutex::lock`, but without cloning the handle, so that
+    /// hot loops locking the same `Qutex` leave its reference count alone.
+    /// Being borrowed, the guard cannot outlive the lock, nor escape the scope
+    /// the lock is borrowed for:
+    ///
+    /// ```compile_fail
+    /// use qutex::{QutexCore, RefGuard};
+    ///
+    /// fn leak(core: QutexCore<u32>) -> RefGuard<'static, u32> {
+    ///     core.lock_ref().wait().unwrap()
+    /// }
+    /// ```
+    #[inline]
+    #[track_caller]
+    pub fn lock_ref(&self) -> FutureRefGuard<'_, T> {
+        FutureRefGuard {
+            acquire: Acquisition::new(self, Priority::Normal, None),
+        }
+    }
+
+    /// Blocks the current thread until the lock is acquired, requesting it
+    /// anew whenever a bounded queue turns the request away.
+    #[cfg(feature = "std")]
+    #[inline]
+    #[track_caller]
+    pub(crate) fn lock_ref_blocking(&self) -> RefGuard<'_, T> {
+        RefGuard {
+            core: Acquisition::block_on(self, "FutureRefGuard"),
+        }
+    }
+
+    /// Returns a `RefGuard` if the lock can be acquired without waiting.
+    ///
+    /// Behaves just like `Qutex::try_lock`.
+    #[track_caller]
+    pub fn try_lock_ref(&self) -> Option<RefGuard<'_, T>> {
+        if unsafe { self.try_take() } {
+            Some(RefGuard { core: self })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a `RawQutexState` for driving this lock by hand, not yet
+    /// holding it.
+    #[inline]
+    pub fn raw_state(&self) -> RawQutexState<'_, T> {
+        RawQutexState {
+            core: self,
+            held: false,
+        }
+    }
+
+    /// Queues a new request, returning its waiter, unless the queue is full.
+    unsafe fn enqueue(&self, waiter: Waiter) -> Result<Arc<Waiter>, Error> {
+        let queued = self.queued.fetch_add(1, Relaxed);
+        let live = queued.saturating_sub(self.abandoned.load(Relaxed));
+        if self.queue_bound.is_some_and(|bound| live >= bound) {
+            self.queued.fetch_sub(1, Relaxed);
+            return Err(Error::QueueFull);
+        }
+        self.record_enqueue(live + 1, waiter.tag);
+        self.check_hold();
+
+        let (priority, deadline) = (waiter.priority, waiter.deadline);
+        let waiter = Arc::new(waiter);
+        self.record_pending(&waiter);
+        let req = Request {
+            signal: Signal::Waiter(waiter.clone()),
+            priority,
+            deadline,
+        };
+        if self.push_queued(req).is_err() {
+            self.queued.fetch_sub(1, Relaxed);
+            return Err(Error::QueueFull);
+        }
+        self.record_boost(priority);
+        Ok(waiter)
+    }
+
+    /// Acquires the lock if it is free and, under `Admission::Fair`, nobody
+    /// is queued.
+    #[inline]
+    #[track_caller]
+    unsafe fn try_take(&self) -> bool {
+        if self.admission == Admission::Fair && self.has_requests() {
+            return false;
+        }
+        let acquired = self.try_acquire();
+        if acquired {
+            self.record_acquisition(None, Some(Location::caller()), None);
+        }
+        acquired
+    }
+
+    /// Retries `try_take` until it succeeds or `budget` has passed.
+    #[cfg(feature = "std")]
+    #[inline]
+    #[track_caller]
+    unsafe fn try_take_for(&self, budget: Duration) -> bool {
+        let start = Instant::now();
+        self.retry_take(|| start.elapsed() >= budget)
+    }
+
+    /// Retries `try_take` until it succeeds or `deadline` has passed.
+    #[cfg(feature = "std")]
+    #[inline]
+    #[track_caller]
+    unsafe fn try_take_until(&self, deadline: Instant) -> bool {
+        self.retry_take(|| Instant::now() >= deadline)
+    }
+
+    /// Retries `try_take` until it succeeds or `expired` returns `true`,
+    /// backing off between attempts as the lock's `Backoff` allows.
+    #[cfg(feature = "std")]
+    #[track_caller]
+    unsafe fn retry_take(&self, expired: impl Fn() -> bool) -> bool {
+        let mut attempt = 0u32;
+        loop {
+            if self.try_take() {
+                return true;
+            }
+            if expired() {
+                return false;
+            }
+            self.backoff.snooze(attempt);
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// Releases the lock on behalf of a dropped guard, first calling any
+    /// callbacks registered through it, then marking it poisoned if enabled
+    /// and the thread is panicking. If `fair`, the lock is handed straight
+    /// to the next queued request (see `unlock_fair`).
+    #[inline]
+    unsafe fn release_guard(&self, fair: bool) {
+        self.version.fetch_add(1, Release);
+        // Released even should a callback panic:
+        let _releasing = Releasing(self, fair);
+        let callbacks = &mut *self.release_callbacks.get();
+        if !callbacks.is_empty() {
+            let val = &**self.cell.get();
+            for callback in mem::take(callbacks) {
+                callback(val)
+            }
+        }
+    }
+
+    /// Registers a callback to be called as the lock is released. The lock
+    /// must be held by the caller.
+    #[inline]
+    unsafe fn push_release_callback(&self, callback: ReleaseCallback<T>) {
+        (*self.release_callbacks.get()).push(callback)
+    }
+
+    /// Acquires the lock if it is free, running any submitted jobs first.
+    unsafe fn try_acquire(&self) -> bool {
+        if self.take_state() {
+            self.record_hold();
+            self.set_hold_priority(Priority::Normal);
+            self.run_jobs();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Acquires the lock without queueing if it is free, retrying as the
+    /// `WaitStrategy` allows if not, backing off as the `Backoff` does.
+    /// Gives up early if fairness forbids taking it.
+    #[track_caller]
+    unsafe fn acquire_unqueued(&self) -> bool {
+        let limit = match self.wait_strategy {
+            WaitStrategy::Queue => Some(0),
+            WaitStrategy::Hybrid(attempts) => Some(attempts),
+            WaitStrategy::Spin => None,
+        };
+
+        let mut attempt = 0u32;
+        let mut since = None;
+        loop {
+            if self.admission == Admission::Fair && self.has_requests() {
+                return false;
+            }
+            if self.try_acquire() {
+                self.record_acquisition(since, Some(Location::caller()), None);
+                return true;
+            }
+            if limit == Some(attempt) {
+                return false;
+            }
+            if attempt == 0 {
+                since = wait_start();
+            }
+            self.backoff.snooze(attempt);
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// Pushes a lock request onto the queue for its priority.
+    ///
+    /// # Safety
+    ///
+    /// The request's sender will be completed when the lock is granted. The
+    /// holder of the matching receiver becomes responsible for unlocking.
+    /// Under `Admission::Barging` completion only signals that the lock was
+    /// released; it must still be taken (e.g. with `try_lock`), and if that
+    /// fails, the holder of the receiver must queue again or call
+    /// `process_queue` to pass the wakeup on.
+    ///
+    /// With the `fixed_queue` feature, a request which does not fit is
+    /// dropped, canceling it.
+    //
+    // TODO: Evaluate unsafe-ness.
+    //
+    #[inline]
+    pub unsafe fn push_request(&self, req: Request) {
+        // Not subject to the queue bound:
+        let queued = self.queued.fetch_add(1, Relaxed);
+        self.record_enqueue(queued + 1, None);
+        if self.push_queued(req).is_err() {
+            self.queued.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// Pops the next lock request in the queue if this (the caller's) lock is
+    /// unlocked.
+    ///
+    /// # Safety
+    ///
+    /// Granting a request transfers the lock to its requester. Only call this
+    /// when managing lock state manually (i.e. from a custom guard).
+    //
+    // Leaves the lock either held by the requester granted it, or released,
+    // on every path. Abandoned requests are skipped, never granted.
+    //
+    // TODO:
+    // * This is public for 'derivers' (aka. sub-types) predating
+    //   `RawQutexState`, which covers the same ground safely.
+    // * Consider removing unsafe qualifier.
+    // * [performance] Determine whether or not `compare_exchange_weak` should be used instead.
+    //
+    pub unsafe fn process_queue(&self) {
+        // Every requester calls this after queueing, and every holder after
+        // releasing, so orders either side's store before its load:
+        fence(SeqCst);
+
+        loop {
+            match self.take_state() {
+                // Unlocked:
+                true => {
+                    let mut held = Held { state: &self.state, armed: true };
+                    self.run_jobs();
+
+                    loop {
+                        if let Some(req) = self.pop_request() {
+                            // If the grant fails, a requester has abandoned
+                            // its request so just go to the next. Expired
+                            // requests are dropped, waking their requesters.
+                            if req.is_expired() {
+                                req.signal.drop_request(&self.abandoned);
+                                continue;
+                            } else if self.admission == Admission::Barging {
+                                // Only wake the requester, which must still
+                                // take the lock, so release it first:
+                                held.release();
+                                if !req.signal.grant(&self.abandoned) {
+                                    break;
+                                } else {
+                                    return;
+                                }
+                            } else {
+                                // Once granted, the lock is the requester's
+                                // even should waking it panic:
+                                self.set_hold_priority(req.priority);
+                                held.armed = false;
+                                if !req.signal.grant(&self.abandoned) {
+                                    held.armed = true;
+                                    continue;
+                                } else {
+                                    self.record_hold();
+                                    return;
+                                }
+                            }
+                        } else {
+                            held.release();
+                            break;
+                        }
+                    }
+
+                    // A request or job may have been queued after we looked
+                    // but before we unlocked, while its submitter found us
+                    // locked:
+                    fence(SeqCst);
+                    if !self.has_requests() && self.jobs.is_empty() {
+                        return;
+                    }
+                }
+                // Already locked, leave it alone:
+                false => return,
+            }
+        }
+    }
+
+    /// Runs a batch of the jobs submitted so far. The lock must be held by
+    /// the caller.
+    ///
+    /// At most `MAX_COMBINE_BATCH` jobs, all submitted before this call, are
+    /// run. Later jobs are left for the next holder so that neither the
+    /// caller nor queued lock requests are delayed indefinitely.
+    unsafe fn run_jobs(&self) {
+        let batch = self.jobs.len().min(MAX_COMBINE_BATCH);
+        for _ in 0..batch {
+            match self.jobs.pop() {
+                Some(job) => {
+                    job(&mut *self.cell.get());
+                    // Counted as a write, as a guard's release is:
+                    self.version.fetch_add(1, Release);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Unlocks this (the caller's) lock and wakes up the next task in the
+    /// queue.
+    ///
+    /// # Safety
+    ///
+    /// The caller must currently hold the lock. Unlocking a lock held by
+    /// someone else breaks mutual exclusion.
+    //
+    // TODO:
+    // * Evaluate unsafe-ness.
+    // * Return proper error type
+    // pub unsafe fn direct_unlock(&self) -> Result<(), ()> {
+    pub unsafe fn direct_unlock(&self) {
+        // Combine: run any submitted jobs while we still hold the lock rather
+        // than releasing it only for someone to re-acquire it for them.
+        self.run_jobs();
+
+        self.set_holder(None);
+        debug_assert!(
+            self.state.load(Relaxed),
+            "QutexCore::direct_unlock: Lock not held."
+        );
+        self.record_release();
+        if self.park_biased() {
+            return;
+        }
+        self.state.store(false, Release);
+        self.process_queue()
+    }
+
+    /// Unlocks this (the caller's) lock as `direct_unlock` does, but hands
+    /// it straight to the next queued request even under
+    /// `Admission::Barging`, so that nobody can take it in between.
+    ///
+    /// # Safety
+    ///
+    /// The caller must currently hold the lock.
+    unsafe fn unlock_fair(&self) {
+        if self.admission == Admission::Fair {
+            return self.direct_unlock();
+        }
+        self.run_jobs();
+
+        self.set_holder(None);
+        debug_assert!(
+            self.state.load(Relaxed),
+            "QutexCore::unlock_fair: Lock not held."
+        );
+        self.record_release();
+        if self.park_biased() {
+            return;
+        }
+        while let Some(req) = self.pop_request() {
+            if req.is_expired() {
+                req.signal.drop_request(&self.abandoned);
+                continue;
+            }
+            match req.signal {
+                Signal::Waiter(_) => {
+                    self.set_hold_priority(req.priority);
+                    if req.signal.hand(&self.abandoned) {
+                        self.record_hold();
+                        return;
+                    }
+                }
+                // Only ever woken, so released first:
+                Signal::Sender(_) => {
+                    self.state.store(false, Release);
+                    if !req.signal.grant(&self.abandoned) {
+                        self.process_queue();
+                    }
+                    return;
+                }
+            }
+        }
+        self.state.store(false, Release);
+        self.process_queue()
+    }
+}
+
+/// Configures and creates a `Qutex`.
+///
+/// ```
+/// use qutex::{Admission, QutexBuilder};
+///
+/// let qutex = QutexBuilder::new()
+///     .name("sessions")
+///     .admission(Admission::Barging)
+///     .queue_bound(64)
+///     .build(Vec::<u32>::new());
+///
+/// assert_eq!(qutex.name(), Some("sessions"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct QutexBuilder {
+    name: Option<String>,
+    admission: Admission,
+    queue_bound: Option<usize>,
+    aging: Option<u32>,
+    wait_strategy: WaitStrategy,
+    backoff: Backoff,
+    poison: bool,
+    #[cfg(feature = "std")]
+    detect_recursion: bool,
+    #[cfg(feature = "std")]
+    biased: bool,
+    #[cfg(feature = "std")]
+    track_holder: bool,
+    #[cfg(feature = "std")]
+    acquisition_budget: Option<u32>,
+    #[cfg(feature = "std")]
+    long_hold: Option<LongHold>,
+    #[cfg(feature = "std")]
+    long_wait: Option<WaitAlarm>,
+    #[cfg(feature = "std")]
+    level: Option<u32>,
+    #[cfg(feature = "std")]
+    hooks: Hooks,
+}
+
+impl QutexBuilder {
+    /// Returns a new `QutexBuilder` with the default configuration: unnamed,
+    /// fair, unbounded, without spinning, and without poisoning.
+    #[inline]
+    pub fn new() -> QutexBuilder {
+        QutexBuilder::default()
+    }
+
+    /// Sets a name, used to tell locks apart when debugging.
+    ///
+    /// With the `registry` feature, a named lock is listed by
+    /// `registered_locks` for as long as it lives.
+    ///
+    /// With the `tracing` feature, the name is attached to the `enqueued`,
+    /// `acquired` and `released` events traced for the lock. `acquired`
+    /// also records whether the lock was contended and how long it took.
+    ///
+    /// With the `metrics-rs` feature, every `Qutex` reports a
+    /// `qutex.acquire` counter and `qutex.wait_seconds` and
+    /// `qutex.hold_seconds` histograms through the `metrics` facade, and
+    /// those of a named lock are labeled with its name as `lock`.
+    pub fn name<S: Into<String>>(mut self, name: S) -> QutexBuilder {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the admission policy.
+    pub fn admission(mut self, admission: Admission) -> QutexBuilder {
+        self.admission = admission;
+        self
+    }
+
+    /// Sets the maximum number of requests queued at once.
+    ///
+    /// Rather than turning overload into ever growing latency, requests
+    /// made while the queue is full are rejected: `try_enqueue` returns
+    /// `Error::QueueFull`, as does the future returned by `lock`. Neither
+    /// the lock holder nor requests since abandoned are counted.
+    pub fn queue_bound(mut self, bound: usize) -> QutexBuilder {
+        self.queue_bound = Some(bound);
+        self
+    }
+
+    /// Ages queued requests, so that once `rate` requests of higher
+    /// priority have been served ahead of the oldest of a lower priority,
+    /// it is served next, ahead of any not yet aged.
+    ///
+    /// Requests of low priority then still wait their turn behind those of
+    /// higher priority, but are no longer starved by a steady stream of
+    /// them. The lower the rate, the sooner they go. A rate of zero turns
+    /// aging off. Abandoned requests are counted as served.
+    pub fn aging(mut self, rate: u32) -> QutexBuilder {
+        self.aging = (rate > 0).then_some(rate);
+        self
+    }
+
+    /// Sets the number of times a new request retries taking the lock,
+    /// backing off exponentially, before it is queued. A free lock is
+    /// always taken without queueing.
+    ///
+    /// For very short critical sections spinning briefly is cheaper than
+    /// queueing and waiting. Under `Admission::Fair` spinning gives up as
+    /// soon as any requests are queued, so never jumps the queue.
+    ///
+    /// Shorthand for `wait_strategy(WaitStrategy::Hybrid(attempts))`.
+    pub fn spin_limit(self, attempts: u32) -> QutexBuilder {
+        self.wait_strategy(match attempts {
+            0 => WaitStrategy::Queue,
+            attempts => WaitStrategy::Hybrid(attempts),
+        })
+    }
+
+    /// Sets how new requests wait for the lock if it is taken.
+    pub fn wait_strategy(mut self, wait_strategy: WaitStrategy) -> QutexBuilder {
+        self.wait_strategy = wait_strategy;
+        self
+    }
+
+    /// Sets how requests back off between attempts to take the lock, both
+    /// while spinning as the `WaitStrategy` allows and in blocking waits
+    /// such as `Qutex::lock_blocking`. Defaults to `Backoff::new`.
+    pub fn backoff(mut self, backoff: Backoff) -> QutexBuilder {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets whether dropping a guard while panicking marks the lock as
+    /// poisoned (see `Qutex::is_poisoned`).
+    pub fn poison(mut self, poison: bool) -> QutexBuilder {
+        self.poison = poison;
+        self
+    }
+
+    /// Sets whether a request made on behalf of the `Owner` already holding
+    /// the lock is rejected rather than left waiting forever.
+    ///
+    /// Only requests made through `Qutex::lock_as` name an owner, and they
+    /// resolve to `Error::WouldDeadlock` should their owner hold the lock
+    /// through an earlier one. As with `ReentrantQutex`, an owner stands for
+    /// a task or thread of one's choosing, and the lock counts as held by it
+    /// until released, wherever its guard has been sent. Other requests are
+    /// never rejected, nor recorded as held by anyone. Tracking the owner
+    /// costs a word per lock, and a store per acquisition through `lock_as`.
+    #[cfg(feature = "std")]
+    pub fn detect_recursion(mut self, detect_recursion: bool) -> QutexBuilder {
+        self.detect_recursion = detect_recursion;
+        self
+    }
+
+    /// Sets whether the lock is biased toward the first `Owner` to acquire
+    /// it through `Qutex::lock_as`, for a task which re-locks it time and
+    /// again while nobody else wants it.
+    ///
+    /// Releasing the lock parks it for that owner rather than unlocking it,
+    /// so that its next `lock_as` takes it back with a compare-and-swap on
+    /// the bias word alone, skipping the admission checks, and its release
+    /// skips looking for anyone to wake. The first request, `try_lock` or
+    /// submitted closure of anyone else to find the lock held or parked
+    /// revokes the bias for good, taking a parked lock over directly, and
+    /// the lock behaves as an unbiased one from then on. Costs nothing
+    /// unless enabled.
+    #[cfg(feature = "std")]
+    pub fn biased(mut self, biased: bool) -> QutexBuilder {
+        self.biased = biased;
+        self
+    }
+
+    /// Sets the level of the lock, to check that tasks take ranked locks in
+    /// increasing order of level, and so can never deadlock among them.
+    ///
+    /// Requesting a lock while holding a ranked lock of the same or a
+    /// higher level panics, naming both locks, turning a latent ordering bug
+    /// into an immediate, reproducible failure. Unranked locks may be taken
+    /// at any point. Levels are checked in debug builds, and in release
+    /// builds with the `lock_ranking` feature, costing a global mutex per
+    /// acquisition of a ranked lock. Tasks are recognized by the waker they
+    /// poll their requests with, and locks taken through `try_lock` and the
+    /// like are not counted as held.
+    #[cfg(feature = "std")]
+    pub fn level(mut self, level: u32) -> QutexBuilder {
+        self.level = Some(level);
+        self
+    }
+
+    /// Sets whether the holder of the lock is tracked, to be reported by
+    /// `Qutex::current_holder`.
+    ///
+    /// The holder is recorded as the thread which acquired the lock, the
+    /// location of the call to `lock` (or `try_lock`, and so on) for the
+    /// request, and any label set with `Guard::set_label`. For a request
+    /// made by an `async` task, the thread is the one the task was polled on
+    /// when it acquired the lock. This costs a mutex per acquisition.
+    #[cfg(feature = "std")]
+    pub fn track_holder(mut self, track_holder: bool) -> QutexBuilder {
+        self.track_holder = track_holder;
+        self
+    }
+
+    /// Sets the number of times in a row a task may acquire the lock
+    /// immediately, without queueing, before its next request is made to
+    /// wait its turn.
+    ///
+    /// When critical sections are tiny, a task which locks over and over
+    /// can otherwise keep taking the free lock before anyone it woke gets
+    /// to run, particularly under `Admission::Barging`. Once a task's
+    /// streak reaches `budget`, requests made for the lock are queued even
+    /// if it is free, until one has been served from the queue, and each
+    /// yields to the executor once before taking its turn, as tokio's
+    /// cooperative budget does. Tasks
+    /// are recognized by the waker they poll their requests with, so a
+    /// thread blocking in `wait` counts as one task. `try_lock` neither
+    /// counts towards nor is held to the budget. Tracking streaks costs a
+    /// mutex per acquisition.
+    #[cfg(feature = "std")]
+    pub fn acquisition_budget(mut self, budget: u32) -> QutexBuilder {
+        self.acquisition_budget = Some(budget);
+        self
+    }
+
+    /// Sets a callback to be called with the lock's name and how long it
+    /// has been held whenever it is held for longer than `threshold`, such
+    /// as by a guard accidentally held across a slow `.await`.
+    ///
+    /// Holds are checked lazily: by each request finding the lock taken,
+    /// and on release. Each hold is reported at most once, by whichever
+    /// check first finds it too long, and from within that check, so the
+    /// callback should be quick. An uncontended hold is reported only once
+    /// released. Timing each hold costs a clock read per acquisition.
+    #[cfg(feature = "std")]
+    pub fn on_long_hold<F>(mut self, threshold: Duration, callback: F) -> QutexBuilder
+    where
+        F: Fn(Option<&str>, Duration) + Send + Sync + 'static,
+    {
+        self.long_hold = Some(LongHold {
+            threshold,
+            callback: Arc::new(callback),
+        });
+        self
+    }
+
+    /// Sets a callback to be called whenever a request waits for the lock
+    /// for longer than `threshold`, reporting how long, how many requests
+    /// are queued, and who holds the lock (see `track_holder`), so as to
+    /// catch convoys forming behind it.
+    ///
+    /// Waits are checked lazily. The request at the head of the queue is
+    /// checked by each request queued behind it and on each release, and
+    /// reported at most once while still waiting; from the time it reached
+    /// the head, so perhaps late. Every request is checked again as it
+    /// acquires the lock, and reported once more if it waited too long in
+    /// all, this time as `LongWait::granted`. The callback is called from
+    /// within the lock's operation, so should be quick and must not lock
+    /// the same `Qutex`. `LongWait` implements `Display`, for logging.
+    #[cfg(feature = "std")]
+    pub fn on_long_wait<F>(mut self, threshold: Duration, callback: F) -> QutexBuilder
+    where
+        F: Fn(&LongWait<'_>) + Send + Sync + 'static,
+    {
+        self.long_wait = Some(WaitAlarm {
+            threshold,
+            callback: Arc::new(callback),
+        });
+        self
+    }
+
+    /// Sets a callback to be called with the lock's name whenever a request
+    /// is queued because the lock is taken.
+    ///
+    /// Like the other hooks, the callback is called from within the lock's
+    /// operation, so should be quick and must not lock the same `Qutex`.
+    #[cfg(feature = "std")]
+    pub fn on_contend<F>(mut self, callback: F) -> QutexBuilder
+    where
+        F: Fn(Option<&str>) + Send + Sync + 'static,
+    {
+        self.hooks.on_contend = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets a callback to be called with the lock's name and how long the
+    /// request waited whenever the lock is acquired. Requests which never
+    /// had to wait pass `Duration::ZERO`.
+    ///
+    /// For a queued request, the callback is called once the request,
+    /// having been granted the lock, is next polled.
+    #[cfg(feature = "std")]
+    pub fn on_acquire<F>(mut self, callback: F) -> QutexBuilder
+    where
+        F: Fn(Option<&str>, Duration) + Send + Sync + 'static,
+    {
+        self.hooks.on_acquire = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets a callback to be called with the lock's name and how long it
+    /// was held whenever it is released. Timing each hold costs a clock
+    /// read per acquisition.
+    #[cfg(feature = "std")]
+    pub fn on_release<F>(mut self, callback: F) -> QutexBuilder
+    where
+        F: Fn(Option<&str>, Duration) + Send + Sync + 'static,
+    {
+        self.hooks.on_release = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets a callback through which the holder of the lock inherits the
+    /// priority of those waiting for it, so that it is not outrun by
+    /// lower priority work while holding up higher priority requests.
+    ///
+    /// Whenever a request is queued while the lock is held at a lower
+    /// priority, the callback is called, from the requesting thread, with
+    /// the lock's name and the new priority to boost the holder to, such as
+    /// by raising the priority of its thread (see `track_holder`). It is
+    /// called again with `None`, from the releasing thread, as a boosted
+    /// holder releases the lock, to restore it.
+    ///
+    /// A hold's priority is that of the request granted it, or
+    /// `Priority::Normal` for locks taken through `try_lock` and the like.
+    #[cfg(feature = "std")]
+    pub fn on_priority_boost<F>(mut self, callback: F) -> QutexBuilder
+    where
+        F: Fn(Option<&str>, Option<Priority>) + Send + Sync + 'static,
+    {
+        self.hooks.on_priority_boost = Some(Arc::new(callback));
+        self
+    }
+
+    /// Creates and returns a new `Qutex` holding `val`.
+    #[inline]
+    pub fn build<T>(self, val: T) -> Qutex<T> {
+        Qutex::from_core(self.build_core(val))
+    }
+
+    /// Creates and returns a new `QutexCore` holding `val`.
+    pub fn build_core<T>(self, val: T) -> QutexCore<T> {
+        let mut inner = QutexCore::from(val);
+        inner.name = self.name;
+        inner.admission = self.admission;
+        inner.queue_bound = self.queue_bound;
+        inner.wait_strategy = self.wait_strategy;
+        inner.backoff = self.backoff;
+        inner.poison = self.poison;
+        inner.aging = self.aging.map(Aging::new);
+        #[cfg(feature = "std")]
+        if self.detect_recursion {
+            inner.owner = Some(AtomicU32::new(0));
+        }
+        #[cfg(feature = "std")]
+        if self.biased {
+            inner.bias = Some(AtomicU64::new(0));
+        }
+        #[cfg(feature = "std")]
+        if self.track_holder {
+            inner.holder_info = Some(Mutex::new(None));
+        }
+        #[cfg(feature = "std")]
+        if let Some(budget) = self.acquisition_budget {
+            inner.coop = Some(Coop {
+                budget,
+                streak: Mutex::new((None, 0)),
+            });
+        }
+        #[cfg(feature = "std")]
+        if self.long_hold.is_some() || self.hooks.on_release.is_some() {
+            inner.hold_watch = Some(HoldWatch::new(self.long_hold));
+        }
+        #[cfg(feature = "std")]
+        if let Some(alarm) = self.long_wait {
+            inner.wait_watch = Some(WaitWatch::new(alarm));
+        }
+
+        #[cfg(feature = "metrics-rs")]
+        if let Some(ref name) = inner.name {
+            let name = Arc::<str>::from(name.as_str());
+            inner.metric_labels = vec![metrics_rs::Label::new("lock", name)];
+        }
+        #[cfg(feature = "std")]
+        {
+            if self.hooks.on_priority_boost.is_some() {
+                inner.inheritance = Some(Inheritance::new());
+            }
+            inner.level = self.level;
+            inner.hooks = self.hooks;
+        }
+
+        inner
+    }
+}
+
+/// A lock-free-queue-backed exclusive data lock.
+///
+/// Each `Qutex` is a reference counted handle to a `QutexCore`, which it
+/// dereferences to.
+pub struct Qutex<T: ?Sized> {
+    inner: Arc<QutexCore<T>>,
+}
+
+impl<T> Qutex<T> {
+    /// Creates and returns a new `Qutex`.
+    #[inline]
+    pub fn new(val: T) -> Qutex<T> {
+        Qutex {
+            inner: Arc::new(QutexCore::from(val)),
+        }
+    }
+
+    /// Creates and returns a new `Qutex` sharing `core`, registering it if
+    /// named.
+    pub fn from_core(core: QutexCore<T>) -> Qutex<T> {
+        let inner = Arc::new(core);
+        #[cfg(feature = "registry")]
+        if let Some(ref name) = inner.name {
+            let inspect = QutexCore::<T>::inspect;
+            unsafe { registry::register(Arc::as_ptr(&inner) as usize, name, inspect) }
+        }
+
+        Qutex { inner }
+    }
+
+    /// Returns the inner value if there are no other copies of this
+    /// `Qutex`, or the `Qutex` itself otherwise.
+    pub fn into_inner(mut self) -> Result<T, Qutex<T>> {
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => {
+                // Taken while in place, so that whatever refers to the `QutexCore`
+                // by address (such as the registry) is left intact:
+                inner.taken = true;
+                Ok(unsafe { ManuallyDrop::take(inner.cell.get_mut()) })
+            }
+            None => Err(self),
+        }
+    }
+
+    /// Returns the value as a plain `Arc<T>`, to be shared immutably without
+    /// locking, if there are no other copies of this `Qutex`, or the `Qutex`
+    /// itself otherwise.
+    ///
+    /// With no other copy there can be no guard or request either, so the
+    /// lock is free. The value is moved into the `Arc`, never cloned, for a
+    /// value built under the lock and then only ever read.
+    ///
+    /// The `Qutex`'s own allocation, holding its queue and configuration as
+    /// well, cannot be reused: the value is moved out of it into a newly
+    /// allocated `Arc`, and so to a new address, before it is freed. A
+    /// value which is large to move is best boxed.
+    #[inline]
+    pub fn freeze(self) -> Result<Arc<T>, Qutex<T>> {
+        self.into_inner().map(Arc::new)
+    }
+
+    /// Returns a new `FutureSwap` which exchanges the values of this lock
+    /// and `other` once it holds both.
+    ///
+    /// The locks are acquired in order of address, as `lock_all!` does, so
+    /// swaps between the same locks made from either side never deadlock
+    /// against one another. Swapping a lock with itself does nothing, once
+    /// it has been acquired.
+    pub fn swap_with(&self, other: &Qutex<T>) -> FutureSwap<T> {
+        let (a, b) = (self.as_ptr() as usize, other.as_ptr() as usize);
+        let (first, last) = if a <= b { (self, other) } else { (other, self) };
+        FutureSwap {
+            pending: Some(first.clone().lock()),
+            last: (a != b).then(|| last.clone()),
+            first: None,
+        }
+    }
+
+    /// Converts this `Qutex` into one holding an unsized view of the same
+    /// data, such as a `dyn Trait` which `T` implements, keeping its
+    /// configuration. `f` unsizes the boxed data, and is normally just
+    /// `|boxed| boxed as Box<_>`, with the type wanted named.
+    ///
+    /// Every copy of a lock must agree on the type of its data, so the
+    /// `Qutex` itself is returned if there are other copies of it. It is
+    /// also returned if a finalizer is set (see `set_finalizer`), as it
+    /// could never be handed a guard of the new type, and would otherwise
+    /// be skipped. Jobs still queued through `submit` are run first.
+    ///
+    /// ```
+    /// use qutex::Qutex;
+    /// use std::fmt::Display;
+    ///
+    /// let shown: Vec<Qutex<dyn Display + Send>> = vec![
+    ///     Qutex::new(7).coerce(|boxed| boxed as Box<_>).unwrap(),
+    ///     Qutex::new("seven").coerce(|boxed| boxed as Box<_>).unwrap(),
+    /// ];
+    /// assert_eq!(shown[1].try_lock().unwrap().to_string(), "seven");
+    /// ```
+    pub fn coerce<U, F>(mut self, f: F) -> Result<Qutex<U>, Qutex<T>>
+    where
+        U: ?Sized,
+        F: FnOnce(Box<T>) -> Box<U>,
+    {
+        let Some(core) = Arc::get_mut(&mut self.inner) else {
+            return Err(self);
+        };
+        #[cfg(feature = "std")]
+        if *core.finalizers.has_every.get_mut() {
+            return Err(self);
+        }
+        while let Some(job) = core.jobs.pop() {
+            job(core.get_mut());
+        }
+        // Left to the lock should `f` panic:
+        core.taken = true;
+        let boxed = f(Box::new(unsafe { ManuallyDrop::take(core.cell.get_mut()) }));
+
+        let mut new_core = unsafe {
+            // Nobody holds the lock, so no release callbacks are left:
+            ptr::drop_in_place(&mut core.jobs);
+            ptr::drop_in_place(core.release_callbacks.get_mut());
+            #[cfg(feature = "std")]
+            ptr::drop_in_place(&mut core.finalizers);
+            QutexCore::from_parts(core, boxed)
+        };
+        new_core.taken = false;
+        #[cfg(feature = "registry")]
+        if core.name.is_some() {
+            registry::unregister((core as *const QutexCore<T>).cast::<()>() as usize);
+        }
+        // Freed with everything else moved out:
+        let old = Arc::into_raw(self.inner) as *const ManuallyDrop<QutexCore<T>>;
+        drop(unsafe { Arc::from_raw(old) });
+
+        let inner = Arc::<QutexCore<U>>::from(new_core);
+        #[cfg(feature = "registry")]
+        if let Some(ref name) = inner.name {
+            let inspect = QutexCore::<()>::inspect;
+            unsafe { registry::register(Arc::as_ptr(&inner).cast::<()>() as usize, name, inspect) }
+        }
+        Ok(Qutex { inner })
+    }
+}
+
+impl<T: ?Sized> Qutex<T> {
+    /// Returns the future of the next finalizer to run before the lock is
+    /// released, if any, handing it the lock: those registered through
+    /// guards first, then any set for every release.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the caller, through a guard since forgotten.
+    #[cfg(feature = "std")]
+    unsafe fn next_finalizer(&self) -> Option<BoxFuture<'static, ()>> {
+        let finalizers = &self.inner.finalizers;
+        // The data may be left inconsistent by a panic, so is not to be
+        // written anywhere:
+        if thread::panicking() {
+            (*finalizers.pending.get()).clear();
+            return None;
+        }
+        let guard = || Guard {
+            qutex: self.clone(),
+        };
+        if let Some(finalizer) = (*finalizers.pending.get()).pop_front() {
+            return Some(finalizer(guard()));
+        }
+        // Released as the guard handed to `every` is dropped:
+        let running_every = &mut *finalizers.running_every.get();
+        if mem::take(running_every) || !finalizers.has_every.load(Acquire) {
+            return None;
+        }
+        let every = finalizers
+            .every
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()?;
+        *running_every = true;
+        Some(every(guard()))
+    }
+
+    /// Creates and returns a new `Qutex` holding the value within `boxed`,
+    /// which may be unsized, such as a `str`, a slice or a `dyn Trait`.
+    ///
+    /// The value is moved into the lock's own allocation, as by `Arc::from`.
+    pub fn from_boxed(boxed: Box<T>) -> Qutex<T> {
+        Qutex {
+            inner: Arc::from(QutexCore::from_boxed(boxed)),
+        }
+    }
+
+    /// Sets `finalizer` to run before every release of the lock, after any
+    /// registered through the guard with `Guard::finalize_with`, so that no
+    /// path dropping a `Guard` can skip it. Locks held through a `RefGuard`
+    /// or `RawQutexState` are released without it.
+    ///
+    /// The lock is passed to the guard handed to `finalizer`, and only
+    /// released once the future it returns completes and drops that guard.
+    /// Replaces any finalizer set before. Just like those registered through
+    /// guards, it is skipped should a guard be dropped while panicking.
+    #[cfg(feature = "std")]
+    pub fn set_finalizer<F, Fut>(&self, finalizer: F)
+    where
+        F: Fn(Guard<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let finalizer: Arc<FinalizeFn<T>> = Arc::new(move |guard| Box::pin(finalizer(guard)));
+        let finalizers = &self.inner.finalizers;
+        *finalizers.every.lock().unwrap_or_else(PoisonError::into_inner) = Some(finalizer);
+        finalizers.has_every.store(true, Release);
+    }
+
+    /// Removes any finalizer set with `set_finalizer`.
+    #[cfg(feature = "std")]
+    pub fn clear_finalizer(&self) {
+        let finalizers = &self.inner.finalizers;
+        finalizers.has_every.store(false, Release);
+        *finalizers.every.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Returns a new `FutureGuard` which can be used as a future and will
+    /// resolve into a `Guard`, or to `Error::QueueFull` should the queue be
+    /// at its bound.
+    ///
+    /// If the lock is free (and, under `Admission::Fair`, nobody is queued)
+    /// it is taken straight away, without queueing a request. A task
+    /// re-locking a lock nobody else wants, as it might thousands of times
+    /// over, thus only ever pays for one compare-and-swap per acquisition,
+    /// and with `QutexBuilder::biased` skips the release's wakeup as well.
+    ///
+    /// The guard owns a handle to the lock. For one borrowing it instead,
+    /// costing no reference count, use `lock_ref` (through `QutexCore`).
+    #[inline]
+    #[track_caller]
+    pub fn lock(self) -> FutureGuard<T> {
+        self.lock_with_priority(Priority::Normal)
+    }
+
+    /// Returns a new `FutureCowGuard` which will resolve into a `CowGuard`,
+    /// holding the lock only long enough to clone the value.
+    ///
+    /// Suits callers which mostly only read but might need to write: the
+    /// lock is taken a second time only if they do, to write back their
+    /// clone (see `CowGuard`).
+    #[inline]
+    #[track_caller]
+    pub fn lock_cow(self) -> FutureCowGuard<T>
+    where
+        T: Clone + Send + 'static,
+    {
+        FutureCowGuard {
+            future_guard: self.lock(),
+            write_back: write_back::<T>,
+        }
+    }
+
+    /// Returns a new `FuturePeek` which will resolve to a clone of the data,
+    /// releasing the lock as soon as it has been cloned.
+    #[inline]
+    #[track_caller]
+    pub fn peek(self) -> FuturePeek<T>
+    where
+        T: Clone,
+    {
+        FuturePeek {
+            future_guard: self.lock(),
+        }
+    }
+
+    /// Returns a new `FutureEventGuard` for a request which, once granted,
+    /// only resolves after `event` has signaled.
+    ///
+    /// The request is queued straight away and, once granted, holds the lock
+    /// until the event signals, so that requests queued behind it keep
+    /// waiting for the event too (see `EventOrder::Reserve`). Dropping the
+    /// future before the event signals releases the lock.
+    ///
+    /// Futures which are not `Unpin` can be passed pinned with `Box::pin`.
+    #[inline]
+    #[track_caller]
+    pub fn lock_after<E: WaitEvent + 'static>(self, event: E) -> FutureEventGuard<T> {
+        self.lock_after_with(event, EventOrder::Reserve)
+    }
+
+    /// Returns a new `FutureEventGuard` for a request which resolves only
+    /// once `event` has signaled, taking its place in the queue as `order`
+    /// dictates.
+    #[track_caller]
+    pub fn lock_after_with<E>(self, event: E, order: EventOrder) -> FutureEventGuard<T>
+    where
+        E: WaitEvent + 'static,
+    {
+        let stage = match order {
+            EventOrder::Reserve => EventStage::Lock(self.lock()),
+            EventOrder::Defer => EventStage::Signal(self),
+        };
+        FutureEventGuard {
+            stage,
+            event: Some(AttachedEvent(Box::new(event))),
+        }
+    }
+
+    /// Returns a new `FutureLockAndThen` which, once the lock is granted,
+    /// hands the guard to `f` and resolves to the output of the future it
+    /// returns.
+    ///
+    /// That future, along with the guard it owns, is dropped before the
+    /// returned future resolves, so the lock is never held past the
+    /// composed operation, however long the caller goes on to await other
+    /// futures. Only a guard returned as part of the output escapes.
+    #[inline]
+    #[track_caller]
+    pub fn lock_and_then<F, Fut>(self, f: F) -> FutureLockAndThen<T, F, Fut>
+    where
+        F: FnOnce(Guard<T>) -> Fut,
+        Fut: Future,
+    {
+        FutureLockAndThen {
+            stage: AndThenStage::Lock(self.lock(), Some(f)),
+        }
+    }
+
+    /// Returns a new `FutureLockWithToken` which resolves to
+    /// `Error::Canceled`, its request withdrawn from the queue, if `token`
+    /// resolves before the lock is granted.
+    ///
+    /// Any future will do as the token, such as tokio's
+    /// `CancellationToken::cancelled_owned`, to give up on the lock as a
+    /// whole group of tasks is shut down.
+    #[inline]
+    #[track_caller]
+    pub fn lock_with_token<C>(self, token: C) -> FutureLockWithToken<T, C>
+    where
+        C: Future<Output = ()>,
+    {
+        FutureLockWithToken {
+            future_guard: self.lock(),
+            token,
+        }
+    }
+
+    /// Returns a new `FutureGuard` for a request of the given priority.
+    ///
+    /// The request is granted ahead of any queued requests of lower
+    /// priority, but never preempts the current holder.
+    #[track_caller]
+    pub fn lock_with_priority(self, priority: Priority) -> FutureGuard<T> {
+        FutureGuard::new(self, priority, None)
+    }
+
+    /// Returns a new `FutureGuard` whose request is tagged with `tag`, a
+    /// name or number identifying it in debug dumps, deadlock reports and
+    /// `tracing` events while it waits.
+    ///
+    /// Tags cost nothing unless the request queues.
+    #[track_caller]
+    pub fn lock_tagged<G: Into<Tag>>(self, tag: G) -> FutureGuard<T> {
+        FutureGuard {
+            acquire: Acquisition::tagged(self, Priority::Normal, None, Some(tag.into())),
+        }
+    }
+
+    /// Returns a new `FutureGuard` whose request has been queued, or
+    /// `Error::QueueFull` if the queue is already at its bound.
+    ///
+    /// The request is queued even under `Admission::Barging`.
+    #[track_caller]
+    pub fn try_enqueue(self) -> Result<FutureGuard<T>, Error> {
+        let waiter = unsafe { self.enqueue(Waiter::new(Priority::Normal, None))? };
+        Ok(FutureGuard {
+            acquire: Acquisition {
+                state: State::Queued(self, waiter),
+            },
+        })
+    }
+
+    /// Polls for the lock within a hand-written `Future` or `Stream`,
+    /// making a request through `pending` unless it already holds one,
+    /// just as polling the `FutureGuard` returned by `lock` would.
+    ///
+    /// Once resolved, `pending` holds no request again, so that the next
+    /// call makes a new one. Panics if `pending` holds a request for
+    /// another lock.
+    ///
+    /// ```
+    /// use qutex::{PendingLock, Qutex};
+    /// use std::future::Future;
+    /// use std::pin::Pin;
+    /// use std::task::{Context, Poll};
+    ///
+    /// struct Increment {
+    ///     counter: Qutex<u32>,
+    ///     pending: PendingLock<u32>,
+    /// }
+    ///
+    /// impl Future for Increment {
+    ///     type Output = u32;
+    ///
+    ///     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+    ///         let this = self.get_mut();
+    ///         let mut guard = match this.counter.poll_lock(&mut this.pending, cx) {
+    ///             Poll::Ready(res) => res.unwrap(),
+    ///             Poll::Pending => return Poll::Pending,
+    ///         };
+    ///         *guard += 1;
+    ///         Poll::Ready(*guard)
+    ///     }
+    /// }
+    ///
+    /// let counter = Qutex::new(0);
+    /// let increment = Increment { counter, pending: PendingLock::new() };
+    /// # #[cfg(feature = "std")]
+    /// assert_eq!(futures::executor::block_on(increment), 1);
+    /// ```
+    #[track_caller]
+    pub fn poll_lock(
+        &self,
+        pending: &mut PendingLock<T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Guard<T>, Error>> {
+        let acquire = pending
+            .acquire
+            .get_or_insert_with(|| Acquisition::new(self.clone(), Priority::Normal, None));
+        assert!(
+            acquire.handle().is_none_or(|qutex| Arc::ptr_eq(&qutex.inner, &self.inner)),
+            "Qutex::poll_lock: Request pending for another lock."
+        );
+        let res = futures::ready!(acquire.poll(cx, "Qutex::poll_lock"));
+        // Left resolved, so dropping it is a no-op:
+        pending.acquire = None;
+        Poll::Ready(res.map(|qutex| Guard { qutex }))
+    }
+
+    /// Returns a `Guard` if the lock can be acquired without waiting.
+    ///
+    /// Under `Admission::Fair` this fails while any requests are queued,
+    /// even if the lock itself is free.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<Guard<T>> {
+        if unsafe { self.try_take() } {
+            Some(Guard {
+                qutex: self.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a `Guard` if the lock can be acquired within `budget`,
+    /// spinning and then yielding the thread until then.
+    ///
+    /// The request is never queued, so nothing is allocated and nothing is
+    /// left behind on failure, and the call returns soon after `budget` has
+    /// passed however contended the lock. As with `try_lock`, this fails
+    /// under `Admission::Fair` while any requests are queued.
+    #[cfg(feature = "std")]
+    #[track_caller]
+    pub fn try_lock_for(&self, budget: Duration) -> Option<Guard<T>> {
+        if unsafe { self.try_take_for(budget) } {
+            Some(Guard {
+                qutex: self.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a `Guard` if the lock can be acquired before `deadline`,
+    /// spinning and then yielding the thread until then.
+    ///
+    /// As `try_lock_for` but taking an absolute deadline, so that one passed
+    /// down through several layers need not be turned back into a duration
+    /// at each. A deadline already passed makes a single attempt.
+    #[cfg(feature = "std")]
+    #[track_caller]
+    pub fn try_lock_until(&self, deadline: Instant) -> Option<Guard<T>> {
+        if unsafe { self.try_take_until(deadline) } {
+            Some(Guard {
+                qutex: self.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a new `FutureTimedGuard` for a request which is dropped from
+    /// the queue if not granted before `deadline`.
+    ///
+    /// Expired requests are skipped when the lock is handed off, so waiters
+    /// behind them are not delayed by granting the lock to a requester which
+    /// has already given up. The future resolves to `Error::Expired` when
+    /// polled after the deadline, or once the request is skipped. Nothing
+    /// wakes the task at the deadline itself; it is woken at the latest when
+    /// the lock is next released.
+    #[cfg(feature = "std")]
+    #[track_caller]
+    pub fn lock_until(self, deadline: Instant) -> FutureTimedGuard<T> {
+        FutureTimedGuard {
+            future_guard: FutureGuard::new(self, Priority::Normal, Some(deadline)),
+            deadline,
+        }
+    }
+
+    /// Returns a new `FutureLockTimeout` which resolves to `Error::Expired`
+    /// if the lock is not granted within `timeout`.
+    ///
+    /// As with `lock_until`, the request is skipped once expired.
+    #[cfg(feature = "std")]
+    #[track_caller]
+    pub fn lock_timeout(self, timeout: Duration) -> FutureLockTimeout<T> {
+        let deadline = Instant::now() + timeout;
+
+        FutureLockTimeout {
+            future_guard: self.lock_until(deadline),
+            sleep: timer::sleep_until(deadline),
+        }
+    }
+
+    /// Returns a new `FutureLockTimed` which will resolve into a `Guard`
+    /// along with how long the request spent queued, such as to attach to
+    /// a trace.
+    #[cfg(feature = "std")]
+    #[inline]
+    #[track_caller]
+    pub fn lock_timed(self) -> FutureLockTimed<T> {
+        FutureLockTimed {
+            future_guard: self.lock(),
+        }
+    }
+
+    /// Returns a new `FutureCheckedGuard` which will resolve into a `Guard`,
+    /// or to an `Error` such as `Error::QueueFull`.
+    #[cfg(feature = "std")]
+    #[inline]
+    #[track_caller]
+    pub fn lock_checked(self) -> FutureCheckedGuard<T> {
+        FutureCheckedGuard {
+            future_guard: self.lock(),
+            owner: None,
+        }
+    }
+
+    /// Returns a new `FutureCheckedGuard` as `lock_checked` does, made on
+    /// behalf of `owner`, so resolving to `Error::WouldDeadlock` should
+    /// `owner` already hold the lock (see `QutexBuilder::detect_recursion`).
+    ///
+    /// ```
+    /// use qutex::{Error, Owner, QutexBuilder};
+    ///
+    /// let lock = QutexBuilder::new().detect_recursion(true).build(0);
+    /// let owner = Owner::new();
+    /// let guard = lock.clone().lock_as(owner).wait().unwrap();
+    /// assert_eq!(lock.clone().lock_as(owner).wait().unwrap_err(), Error::WouldDeadlock);
+    /// ```
+    ///
+    /// Under `QutexBuilder::biased`, the owner the lock is biased toward
+    /// takes it back here without queueing, as long as nobody else has
+    /// contended for it.
+    #[cfg(feature = "std")]
+    #[inline]
+    #[track_caller]
+    pub fn lock_as(self, owner: Owner) -> FutureCheckedGuard<T> {
+        let future_guard = match self.inner.take_biased(owner) {
+            true => {
+                self.inner.record_hold();
+                self.inner.record_acquisition(None, Some(Location::caller()), None);
+                FutureGuard {
+                    acquire: Acquisition {
+                        state: State::Acquired(self),
+                    },
+                }
+            }
+            false => self.lock(),
+        };
+        FutureCheckedGuard {
+            future_guard,
+            owner: Some(owner),
+        }
+    }
+
+    /// Redeems a `GuardToken` created from a guard of this `Qutex`, taking
+    /// over the lock it holds.
+    ///
+    /// Panics if `token` belongs to a different lock.
+    #[track_caller]
+    pub fn redeem(&self, mut token: GuardToken) -> Guard<T> {
+        assert_eq!(
+            token.lock,
+            self.as_ptr().cast::<()>() as usize,
+            "Qutex::redeem: Token belongs to a different lock."
+        );
+        // Dropping the closure drops its handle without unlocking:
+        token.unlock = None;
+        #[cfg(feature = "std")]
+        self.inner.record_holder(Some(Location::caller()));
+
+        Guard {
+            qutex: self.clone(),
+        }
+    }
+
+    /// Queues `f` to be run with exclusive access to the data, returning a
+    /// future which resolves to its result.
+    ///
+    /// Rather than a guard being handed to the submitter, `f` is run by
+    /// whichever thread next releases (or finds unlocked) the lock, which
+    /// avoids a task handoff for small mutations. Each holder runs a batch of
+    /// pending closures on its way out (flat combining), so that under heavy
+    /// contention many operations complete per lock handoff rather than one.
+    /// Submitted closures run before the next queued lock request is granted.
+    /// Should `f` panic, the panic is caught and resumed when the returned
+    /// future is polled.
+    #[cfg(feature = "std")]
+    pub fn submit<F, R>(self, f: F) -> FutureSubmit<T, R>
+    where
+        F: FnOnce(&mut T) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.inner.jobs.push(Box::new(move |val: &mut T| {
+            let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(|| f(val))));
+        }));
+        unsafe { self.process_queue() }
+
+        FutureSubmit { qutex: self, rx }
+    }
+
+    /// Returns a mutable reference to the inner `Vec` if there are currently
+    /// no other copies of this `Qutex`.
+    ///
+    /// Since this call borrows the inner lock mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    ///
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(&mut self.inner).map(QutexCore::get_mut)
+    }
+
+    /// Returns `true` if there are no other copies of this `Qutex`, in which
+    /// case nobody can be holding or waiting for it.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub(crate) fn is_unique(&self) -> bool {
+        Arc::strong_count(&self.inner) == 1
+    }
+
+    /// Returns the word which is `true` while the lock is held.
+    #[cfg(all(feature = "elision", target_arch = "x86_64"))]
+    #[inline]
+    pub(crate) fn lock_word(&self) -> &AtomicBool {
+        &self.inner.state
+    }
+}
+
+/// A `Qutex` guarding no data, for mutual exclusion alone.
+///
+/// Suits locks which protect something outside the lock, such as a file or
+/// a device, rather than a value in memory.
+pub type CriticalSection = Qutex<()>;
+
+impl Qutex<()> {
+    /// Returns a new `FutureGuard` which resolves once the critical section
+    /// has been entered, which lasts until the guard is dropped.
+    #[inline]
+    #[track_caller]
+    pub fn enter(&self) -> FutureGuard<()> {
+        self.clone().lock()
+    }
+
+    /// Blocks the current thread until the critical section has been
+    /// entered, returning a guard which leaves it when dropped.
+    #[cfg(feature = "std")]
+    #[inline]
+    #[track_caller]
+    pub fn enter_blocking(&self) -> Guard<()> {
+        // Requested anew whenever a bounded queue turns the request away:
+        Guard {
+            qutex: Acquisition::block_on(self.clone(), "FutureGuard"),
+        }
+    }
+
+    /// Runs `future` within the critical section, entering it first and
+    /// leaving once `future` completes (or is dropped).
+    pub async fn guarded<F: Future>(&self, future: F) -> Result<F::Output, Error> {
+        let _guard = self.enter().await?;
+        Ok(future.await)
+    }
+}
+
+/// The halves a `Qutex` over a pair is split into.
+type Halves<A, B> = (Qutex<A>, Qutex<B>);
+
+impl<A, B> Qutex<(A, B)> {
+    /// Splits a lock over a pair into a lock over each half, if there are
+    /// no other copies of this `Qutex`, or returns it otherwise.
+    ///
+    /// Lets a coarse lock be broken up gradually: callers can move to the
+    /// finer locks one at a time, with `Qutex::join` putting the pair back
+    /// together where needed. The new locks have the default configuration,
+    /// whatever this one was built with.
+    pub fn split(self) -> Result<Halves<A, B>, Qutex<(A, B)>> {
+        let (a, b) = self.into_inner()?;
+        Ok((Qutex::new(a), Qutex::new(b)))
+    }
+
+    /// Joins a lock over each half of a pair into one lock over the pair,
+    /// if there are no other copies of either, or returns both otherwise.
+    ///
+    /// The new lock has the default configuration.
+    pub fn join(a: Qutex<A>, b: Qutex<B>) -> Result<Qutex<(A, B)>, Halves<A, B>> {
+        if Arc::strong_count(&a.inner) != 1 || Arc::strong_count(&b.inner) != 1 {
+            return Err((a, b));
+        }
+        match (a.into_inner(), b.into_inner()) {
+            (Ok(a), Ok(b)) => Ok(Qutex::new((a, b))),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<T> From<T> for Qutex<T> {
+    #[inline]
+    fn from(val: T) -> Qutex<T> {
+        Qutex::new(val)
+    }
+}
+
+impl<T: Default> Default for Qutex<T> {
+    #[inline]
+    fn default() -> Qutex<T> {
+        Qutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for Qutex<T> {
+    type Target = QutexCore<T>;
+
+    #[inline]
+    fn deref(&self) -> &QutexCore<T> {
+        &self.inner
+    }
+}
+
+impl<T: Default> Default for QutexCore<T> {
+    #[inline]
+    fn default() -> QutexCore<T> {
+        QutexCore::new(T::default())
+    }
+}
+
+/// Releases a lock on behalf of a guard once dropped, marking it poisoned
+/// if enabled and the thread is panicking, and handing it over fairly if
+/// so asked.
+struct Releasing<'a, T: ?Sized>(&'a QutexCore<T>, bool);
+
+impl<T: ?Sized> Drop for Releasing<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        if self.0.poison && thread::panicking() {
+            self.0.poisoned.store(true, Relaxed);
+        }
+        match self.1 {
+            true => unsafe { self.0.unlock_fair() },
+            false => unsafe { self.0.direct_unlock() },
+        }
+    }
+}
+
+/// Holds a lock taken by hand, without counting as an acquisition, and so
+/// releases it without counting as a release, nor ever poisoning it.
+struct Peek<'a, T: ?Sized>(&'a QutexCore<T>);
+
+impl<T: ?Sized> Deref for Peek<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0.cell.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for Peek<'_, T> {
+    fn drop(&mut self) {
+        self.0.state.store(false, Release);
+        // Requests queued meanwhile found the lock taken:
+        unsafe { self.0.process_queue() }
+    }
+}
+
+impl<T: ::core::fmt::Debug + ?Sized> QutexCore<T> {
+    /// Shows the value only if the lock is free, briefly taking it without
+    /// counting as an acquisition, so never waits.
+    fn fmt_as(&self, name: &str, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        let mut d = f.debug_struct(name);
+        if let Some(name) = self.name() {
+            d.field("name", &name);
+        }
+        let acquired = self.state.compare_exchange(false, true, Acquire, Relaxed).is_ok();
+        match acquired {
+            true => d.field("data", &&*Peek(self)),
+            false => d.field("data", &format_args!("<locked>")),
+        };
+        let queued = self.queued.load(Relaxed);
+        d.field("poisoned", &self.is_poisoned())
+            .field("queued", &queued.saturating_sub(self.abandoned.load(Relaxed)))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: ::core::fmt::Debug + ?Sized> ::core::fmt::Debug for QutexCore<T> {
+    #[inline]
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        self.fmt_as("QutexCore", f)
+    }
+}
+
+impl<T: ::core::fmt::Debug + ?Sized> ::core::fmt::Debug for Qutex<T> {
+    #[inline]
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        self.inner.fmt_as("Qutex", f)
+    }
+}
+
+// As with std's `Mutex`, a `Qutex` can be shared across `catch_unwind`:
+// guards release the lock and pending requests withdraw as a panic unwinds.
+// Data left mid-update by a panic can be detected by enabling poisoning
+// (see `QutexBuilder::poison`). `Guard`s and futures inherit these.
+impl<T: ?Sized> UnwindSafe for QutexCore<T> {}
+impl<T: ?Sized> RefUnwindSafe for QutexCore<T> {}
+impl<T: ?Sized> UnwindSafe for Qutex<T> {}
+impl<T: ?Sized> RefUnwindSafe for Qutex<T> {}
+
+// Only ever changed atomically:
+impl RefUnwindSafe for Waiter {}
+
+// Only ever polled, by the request it is attached to, whose guard is
+// released should polling it panic:
+impl UnwindSafe for AttachedEvent {}
+impl RefUnwindSafe for AttachedEvent {}
+
+// Avoids needing `T: Clone`.
+impl<T: ?Sized> Clone for Qutex<T> {
+    #[inline]
+    fn clone(&self) -> Qutex<T> {
+        Qutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+// Woefully incomplete:
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::{FutureExt, TryFutureExt};
+
+    #[test]
+    fn simple() {
+        let val = Qutex::from(999i32);
+
+        println!("Reading val...");
+        {
+            let future_guard = val.clone().lock();
+            let guard = future_guard.wait().unwrap();
+            println!("val: {}", *guard);
+        }
+
+        println!("Storing new val...");
+        {
+            let future_guard = val.clone().lock();
+            let mut guard = future_guard.wait().unwrap();
+
+            *guard = 5;
+        }
+
+        println!("Reading val...");
+        {
+            let future_guard = val.clone().lock();
+            let guard = future_guard.wait().unwrap();
+            println!("val: {}", *guard);
+        }
+    }
+
+    #[test]
+    fn concurrent() {
+        use std::thread;
+
+        let thread_count = 20;
+        let mut threads = Vec::with_capacity(thread_count);
+        let start_val = 0i32;
+        let qutex = Qutex::new(start_val);
+
+        for i in 0..thread_count {
+            let future_guard = qutex.clone().lock();
+
+            let future_write = future_guard.map_ok(|mut guard| {
+                *guard += 1;
+            });
+
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("test_thread_{}", i))
+                    .spawn(|| executor::block_on(future_write).unwrap())
+                    .unwrap(),
+            );
+        }
+
+        for i in 0..thread_count {
+            let future_guard = qutex.clone().lock();
+
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("test_thread_{}", i + thread_count))
+                    .spawn(|| {
+                        let mut guard = future_guard.wait().unwrap();
+                        *guard -= 1;
+                    })
+                    .unwrap(),
+            )
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = qutex.clone().lock().wait().unwrap();
+        assert_eq!(*guard, start_val);
+    }
+
+    #[test]
+    fn future_guard_drop() {
+        let lock = Qutex::from(true);
+        let _future_guard_0 = lock.clone().lock();
+        let _future_guard_1 = lock.clone().lock();
+        let _future_guard_2 = lock.clone().lock();
+
+        // TODO: FINISH ME
+    }
+
+    #[test]
+    fn explicit_unlock() {
+        let lock = Qutex::from(true);
+
+        let mut guard_0 = lock.clone().lock().wait().unwrap();
+        *guard_0 = false;
+        let _ = Guard::unlock(guard_0);
+        // Will deadlock if this doesn't work:
+        let guard_1 = lock.clone().lock().wait().unwrap();
+        assert!(!*guard_1);
+    }
+
+    #[test]
+    fn async_await() {
+        let qutex = Qutex::new(Vec::new());
+
+        let tasks = (0..4).map(|i| {
+            let qutex = qutex.clone();
+            async move {
+                let mut guard = qutex.lock().await?;
+                guard.push(i);
+                Ok::<_, Error>(())
+            }
+        });
+        executor::block_on(futures::future::try_join_all(tasks)).unwrap();
+
+        let guard = executor::block_on(qutex.lock()).unwrap();
+        assert_eq!(*guard, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn submit() {
+        use std::thread;
+
+        let qutex = Qutex::new(0usize);
+        let guard = qutex.clone().lock().wait().unwrap();
+
+        // Queued behind the guard, then run by whoever unlocks:
+        let submits = (0..4)
+            .map(|i| {
+                let qutex = qutex.clone();
+                thread::spawn(move || {
+                    qutex
+                        .submit(move |val| {
+                            *val += 1;
+                            i
+                        })
+                        .wait()
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        thread::sleep(::std::time::Duration::from_millis(20));
+        drop(guard);
+
+        let mut results = submits
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        results.sort();
+        assert_eq!(results, vec![0, 1, 2, 3]);
+        assert_eq!(*qutex.lock().wait().unwrap(), 4);
+    }
+
+    #[test]
+    fn submit_panic() {
+        let qutex = Qutex::new(());
+        let submit = qutex.clone().submit(|_| panic!("boom"));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| submit.wait()));
+        assert!(result.is_err());
+
+        // Still usable:
+        qutex.lock().wait().unwrap();
+    }
+
+    #[test]
+    fn priority() {
+        use futures::future;
+
+        let lock = Qutex::new(Vec::new());
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let requests = vec![
+            (0, lock.clone().lock_with_priority(Priority::Low)),
+            (1, lock.clone().lock()),
+            (2, lock.clone().lock_with_priority(Priority::High)),
+            (3, lock.clone().lock_with_priority(Priority::Low)),
+            (4, lock.clone().lock_with_priority(Priority::High)),
+        ];
+        drop(guard);
+
+        executor::block_on(future::try_join_all(
+            requests
+                .into_iter()
+                .map(|(i, request)| request.map_ok(move |mut guard| guard.push(i))),
+        ))
+        .unwrap();
+
+        assert_eq!(*lock.lock().wait().unwrap(), vec![2, 4, 1, 0, 3]);
+    }
+
+    #[test]
+    fn introspection() {
+        let lock = Qutex::new(());
+        assert!(!lock.is_locked());
+        let guard = lock.clone().lock().wait().unwrap();
+        assert!(lock.is_locked());
+
+        let (first, second) = (lock.clone().lock(), lock.clone().lock());
+        assert_eq!(lock.queued_waiters(), 2);
+        drop(first);
+        assert_eq!(lock.queued_waiters(), 1);
+        drop(guard);
+        drop(second.wait().unwrap());
+        assert!(!lock.is_locked());
+        assert_eq!(lock.queued_waiters(), 0);
+    }
+
+    #[test]
+    fn priority_aging() {
+        use futures::future;
+
+        let lock = QutexBuilder::new().aging(1).build(Vec::new());
+        assert_eq!(lock.aging_rate(), Some(1));
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let requests = vec![
+            (0, lock.clone().lock_with_priority(Priority::Low)),
+            (1, lock.clone().lock()),
+            (2, lock.clone().lock_with_priority(Priority::High)),
+            (3, lock.clone().lock_with_priority(Priority::Low)),
+            (4, lock.clone().lock_with_priority(Priority::High)),
+        ];
+        drop(guard);
+
+        executor::block_on(future::try_join_all(
+            requests
+                .into_iter()
+                .map(|(i, request)| request.map_ok(move |mut guard| guard.push(i))),
+        ))
+        .unwrap();
+
+        // Each passed over once goes next, the higher priority first:
+        assert_eq!(*lock.lock().wait().unwrap(), vec![2, 1, 0, 4, 3]);
+    }
+
+    #[test]
+    fn expired_requests_skipped() {
+        use std::time::Duration;
+
+        let lock = Qutex::new(0);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let timed = lock
+            .clone()
+            .lock_until(Instant::now() + Duration::from_millis(10));
+        let next = lock.clone().lock();
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+
+        // Granted straight past the expired request:
+        *next.wait().unwrap() += 1;
+        assert_eq!(timed.wait().unwrap_err(), Error::Expired);
+    }
+
+    #[test]
+    fn granted_before_deadline() {
+        use std::time::Duration;
+
+        let lock = Qutex::new(0);
+        let guard = lock.clone().lock().wait().unwrap();
+        let timed = lock
+            .clone()
+            .lock_until(Instant::now() + Duration::from_secs(60));
+        drop(guard);
+
+        *timed.wait().unwrap() += 1;
+        assert_eq!(*lock.lock().wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let lock = Qutex::new(0);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        // Woken by the timer, even though the lock is never released:
+        let timed_out = lock.clone().lock_timeout(Duration::from_millis(20));
+        assert_eq!(timed_out.wait().unwrap_err(), Error::Expired);
+
+        drop(guard);
+        *lock
+            .clone()
+            .lock_timeout(Duration::from_secs(60))
+            .wait()
+            .unwrap() += 1;
+        assert_eq!(*lock.lock().wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn fair_admission() {
+        let lock = Qutex::new(0);
+        // Queued but not yet polled, so the lock itself is still free:
+        let queued = lock.clone().lock();
+
+        assert!(lock.try_lock().is_none());
+        *queued.wait().unwrap() += 1;
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn barging_admission() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = QutexBuilder::new().admission(Admission::Barging).build(0);
+        let guard = lock.try_lock().unwrap();
+
+        let mut queued = lock.clone().lock();
+        assert!(queued.poll_unpin(cx).is_pending());
+
+        // Releasing only wakes the queued request, so the lock can be taken
+        // from under it:
+        drop(guard);
+        let mut barger = lock.try_lock().unwrap();
+        assert!(queued.poll_unpin(cx).is_pending());
+
+        *barger += 1;
+        drop(barger);
+        assert_eq!(*queued.wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn barging_concurrent() {
+        let lock = QutexBuilder::new()
+            .admission(Admission::Barging)
+            .build(0usize);
+
+        let threads = (0..8)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        *lock.clone().lock().wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.lock().wait().unwrap(), 4000);
+    }
+
+    #[test]
+    fn manual_request() {
+        let lock = Qutex::new(0);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        unsafe { lock.push_request(Request::new(tx)) };
+        let queued = lock.clone().lock();
+        drop(guard);
+
+        // Granted first, so responsible for unlocking:
+        executor::block_on(rx).unwrap();
+        unsafe { lock.direct_unlock() };
+        *queued.wait().unwrap() += 1;
+        assert_eq!(*lock.lock().wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn dead_manual_requests() {
+        for admission in [Admission::Fair, Admission::Barging] {
+            let lock = QutexBuilder::new().admission(admission).build(0);
+            let guard = lock.clone().lock().wait().unwrap();
+
+            // Receivers dropped, so never granted:
+            for _ in 0..3 {
+                unsafe { lock.push_request(Request::new(oneshot::channel().0)) };
+            }
+            let queued = lock.clone().lock();
+            drop(guard);
+
+            *queued.wait().unwrap() += 1;
+            assert!(!lock.inner.has_requests());
+            assert_eq!(*lock.try_lock().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "FutureGuard::poll: Task already completed.")]
+    fn poll_after_completion() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut future_guard = Qutex::new(()).lock();
+
+        match future_guard.poll_unpin(cx) {
+            Poll::Ready(Ok(guard)) => drop(guard),
+            _ => panic!("FutureGuard should resolve immediately"),
+        }
+        let _ = future_guard.poll_unpin(cx);
+    }
+
+    #[test]
+    fn mixed_contention() {
+        // Exercises every path racing an unlock against a new request, with
+        // non-atomic data to catch a missing happens-before:
+        for admission in [Admission::Fair, Admission::Barging] {
+            let lock = QutexBuilder::new().admission(admission).build(Vec::new());
+
+            let threads = (0..6)
+                .map(|i| {
+                    let lock = lock.clone();
+                    thread::spawn(move || {
+                        for j in 0..300 {
+                            match (i + j) % 3 {
+                                0 => lock.clone().lock().wait().unwrap().push(j),
+                                1 => lock.clone().submit(move |v| v.push(j)).wait().unwrap(),
+                                _ => match lock.try_lock() {
+                                    Some(mut guard) => guard.push(j),
+                                    None => lock.clone().lock().wait().unwrap().push(j),
+                                },
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(lock.lock().wait().unwrap().len(), 1800);
+        }
+    }
+
+    #[test]
+    fn queues_allocated_lazily() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = Qutex::new(0);
+        drop(lock.clone().lock().wait().unwrap());
+        assert!(lock.inner.queues().is_none());
+
+        let guard = lock.clone().lock().wait().unwrap();
+        let mut queued = lock.clone().lock();
+        assert!(queued.poll_unpin(cx).is_pending());
+        assert!(lock.inner.queues().is_some());
+        drop(guard);
+        queued.wait().unwrap();
+    }
+
+    /// Polls each request once, returning the index and guard of any granted.
+    fn granted<T>(requests: &mut [Option<FutureGuard<T>>]) -> Option<(usize, Guard<T>)> {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut granted = None;
+        for (i, request) in requests.iter_mut().enumerate() {
+            if let Some(ref mut future_guard) = *request {
+                if let Poll::Ready(res) = future_guard.poll_unpin(cx) {
+                    assert!(granted.is_none(), "granted twice");
+                    granted = Some((i, res.unwrap()));
+                    *request = None;
+                }
+            }
+        }
+        granted
+    }
+
+    #[test]
+    fn fifo_across_cancellations() {
+        let lock = Qutex::new(());
+        let guard = lock.clone().lock().wait().unwrap();
+        let mut requests = (0..6).map(|_| Some(lock.clone().lock())).collect::<Vec<_>>();
+        assert!(granted(&mut requests).is_none());
+
+        // Dropped while queued, after being polled:
+        requests[1] = None;
+        drop(guard);
+        let (i, guard) = granted(&mut requests).unwrap();
+        assert_eq!(i, 0);
+
+        // Dropped while queued, and once granted but before being polled:
+        requests[2] = None;
+        drop(guard);
+        requests[3] = None;
+
+        let (i, guard) = granted(&mut requests).unwrap();
+        assert_eq!(i, 4);
+        assert!(granted(&mut requests).is_none());
+        drop(guard);
+        assert_eq!(granted(&mut requests).unwrap().0, 5);
+    }
+
+    #[test]
+    fn fifo_across_expiry() {
+        let lock = Qutex::new(Vec::new());
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let first = lock.clone().lock();
+        let expiring = lock.clone().lock_until(Instant::now());
+        let third = lock.clone().lock();
+        let mut fourth = lock.clone().lock();
+        drop(guard);
+
+        first.wait().unwrap().push(1);
+        let mut third = third.wait().unwrap();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert!(fourth.poll_unpin(cx).is_pending());
+        third.push(3);
+        drop(third);
+        fourth.wait().unwrap().push(4);
+
+        assert_eq!(expiring.wait().unwrap_err(), Error::Expired);
+        assert_eq!(*lock.try_lock().unwrap(), [1, 3, 4]);
+    }
+
+    #[test]
+    fn detect_recursion() {
+        let lock = QutexBuilder::new().detect_recursion(true).build(0);
+        let owner = Owner::new();
+
+        // Locking twice on behalf of one owner:
+        let guard = lock.clone().lock_as(owner).wait().unwrap();
+        assert_eq!(lock.clone().lock_as(owner).wait().unwrap_err(), Error::WouldDeadlock);
+
+        // Sibling requests within one task share its waker, yet naming no
+        // owner are simply contended:
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let mut sibling = lock.clone().lock();
+        assert!(sibling.poll_unpin(cx).is_pending());
+        let mut other = lock.clone().lock_as(Owner::new());
+        assert!(other.poll_unpin(cx).is_pending());
+        drop(other);
+
+        // Held on behalf of its owner wherever the guard is sent:
+        let lock_c = lock.clone();
+        let thread = thread::spawn(move || {
+            let mut guard = guard;
+            *guard += 1;
+            lock_c.lock_as(owner).wait().unwrap_err()
+        });
+        assert_eq!(thread.join().unwrap(), Error::WouldDeadlock);
+
+        // and no longer once released:
+        *sibling.wait().unwrap() += 1;
+        *lock.clone().lock_as(owner).wait().unwrap() += 1;
+        assert_eq!(*lock.try_lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn biased() {
+        let lock = QutexBuilder::new().biased(true).build(0);
+        let owner = Owner::new();
+
+        // Parked for its owner once released, and taken back in place:
+        for _ in 0..3 {
+            *lock.clone().lock_as(owner).wait().unwrap() += 1;
+            assert!(lock.inner.is_parked() && !lock.is_locked());
+        }
+        // Submitted closures take a parked lock over, revoking the bias:
+        lock.clone().submit(|val| *val += 1).wait().unwrap();
+        *lock.clone().lock_as(owner).wait().unwrap() += 1;
+        assert!(!lock.inner.is_parked() && !lock.is_locked());
+        assert_eq!(*lock.try_lock().unwrap(), 5);
+
+        // Contended while held, so released rather than parked:
+        let lock = QutexBuilder::new().biased(true).build(0);
+        let guard = lock.clone().lock_as(owner).wait().unwrap();
+        let lock_c = lock.clone();
+        let thread = thread::spawn(move || *lock_c.lock().wait().unwrap() += 1);
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        thread.join().unwrap();
+        assert!(!lock.inner.is_parked() && !lock.is_locked());
+    }
+
+    #[test]
+    fn biased_contended() {
+        let lock = QutexBuilder::new().biased(true).build(0usize);
+        let threads = (0..4)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    let owner = Owner::new();
+                    for _ in 0..250 {
+                        match i {
+                            0 => *lock.clone().lock_as(owner).wait().unwrap() += 1,
+                            _ => *lock.clone().lock().wait().unwrap() += 1,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.try_lock().unwrap(), 1000);
+    }
+
+    #[test]
+    fn footprint() {
+        let word = mem::size_of::<usize>();
+        assert_eq!(mem::size_of::<Guard<()>>(), word);
+        assert_eq!(mem::size_of::<RefGuard<()>>(), word);
+        // A handle, a waiter and the state:
+        assert_eq!(mem::size_of::<FutureGuard<()>>(), 3 * word);
+        assert_eq!(mem::size_of::<FutureRefGuard<()>>(), 3 * word);
+    }
+
+    #[test]
+    fn repoll_skips_queue() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = Qutex::new(0);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let mut queued = lock.clone().lock();
+        assert!(matches!(queued.acquire.state, State::Queued(..)));
+        for _ in 0..3 {
+            assert!(queued.poll_unpin(cx).is_pending());
+            assert!(matches!(queued.acquire.state, State::Waiting(..)));
+        }
+
+        // Granted by the release alone:
+        drop(guard);
+        *queued.wait().unwrap() += 1;
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn lock_ref() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = Qutex::new(Vec::new());
+
+        for i in 0..3 {
+            lock.lock_ref().wait().unwrap().push(i);
+        }
+        // No handle is held by the guards or their futures:
+        assert!(lock.is_unique());
+
+        let guard = lock.try_lock_ref().unwrap();
+        let mut queued = lock.lock_ref();
+        assert!(queued.poll_unpin(cx).is_pending());
+        assert!(lock.try_lock_ref().is_none());
+        drop(guard);
+
+        queued.wait().unwrap().push(3);
+        assert_eq!(*lock.lock().wait().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn uncontended_never_queues() {
+        // Nothing may be queued at all:
+        let lock = QutexBuilder::new().queue_bound(0).build(0);
+
+        let guard = lock.clone().lock().wait().unwrap();
+        assert_eq!(lock.clone().lock().wait().unwrap_err(), Error::QueueFull);
+        drop(guard);
+
+        *lock.clone().lock().wait().unwrap() += 1;
+        assert_eq!(*lock.lock().wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn spin() {
+        let lock = QutexBuilder::new()
+            .wait_strategy(WaitStrategy::Spin)
+            .build(0usize);
+        assert_eq!(lock.spin_limit(), u32::MAX);
+
+        let threads = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        *lock.clone().lock().wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // Never queued:
+        assert!(lock.inner.queues().is_none());
+        assert_eq!(*lock.try_lock().unwrap(), 2000);
+    }
+
+    #[test]
+    fn spin_limit() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = QutexBuilder::new().spin_limit(4).build(0);
+        assert_eq!(lock.spin_limit(), 4);
+        assert_eq!(lock.wait_strategy(), WaitStrategy::Hybrid(4));
+
+        // Taken while spinning, before ever being polled:
+        let mut spun = lock.clone().lock();
+        assert!(lock.try_lock().is_none());
+        let guard = match spun.poll_unpin(cx) {
+            Poll::Ready(Ok(guard)) => guard,
+            _ => panic!("FutureGuard should resolve immediately"),
+        };
+
+        // Gives up while the lock is held, then queues:
+        let mut queued = lock.clone().lock();
+        assert!(queued.poll_unpin(cx).is_pending());
+        // Never jumps the queue once it is non-empty:
+        let behind = lock.clone().lock();
+        drop(guard);
+        *queued.wait().unwrap() += 1;
+        *behind.wait().unwrap() += 1;
+
+        // Dropped unpolled, so releases:
+        drop(lock.clone().lock());
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn acquisition_budget() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let lock = QutexBuilder::new()
+            .admission(Admission::Barging)
+            .acquisition_budget(2)
+            .build(Vec::new());
+        assert_eq!(lock.acquisition_budget(), Some(2));
+
+        let first = lock.clone().lock().wait().unwrap();
+        drop(first);
+        let second = lock.clone().lock().wait().unwrap();
+        let mut other = lock.clone().lock();
+        assert!(other.poll_unpin(cx).is_pending());
+        // Woken, but not yet run:
+        drop(second);
+
+        // Over budget, so yields rather than barging:
+        let mut third = lock.clone().lock();
+        assert!(matches!(third.acquire.state, State::Deferred(..)));
+        assert!(third.poll_unpin(cx).is_pending());
+        match other.poll_unpin(cx) {
+            Poll::Ready(Ok(mut guard)) => guard.push("other"),
+            _ => panic!("FutureGuard should resolve once woken"),
+        }
+        third.wait().unwrap().push("third");
+
+        // Queued, so the streak begins afresh:
+        drop(lock.clone().lock().wait().unwrap());
+        assert!(matches!(lock.clone().lock().acquire.state, State::Acquired(..)));
+        assert_eq!(*lock.try_lock().unwrap(), ["other", "third"]);
+    }
+
+    #[test]
+    fn swap_with() {
+        let (a, b) = (Qutex::new(1), Qutex::new(2));
+        a.swap_with(&b).wait().unwrap();
+        a.swap_with(&a).wait().unwrap();
+        assert_eq!((*a.try_lock().unwrap(), *b.try_lock().unwrap()), (2, 1));
+
+        // Swapped from either side at once, without deadlocking:
+        let threads = (0..4)
+            .map(|i| {
+                let (a, b) = (a.clone(), b.clone());
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        let swap = if i % 2 == 0 { a.swap_with(&b) } else { b.swap_with(&a) };
+                        swap.wait().unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // An even number of swaps in all:
+        assert_eq!((*a.try_lock().unwrap(), *b.try_lock().unwrap()), (2, 1));
+    }
+
+    #[test]
+    fn queue_bound() {
+        let lock = QutexBuilder::new().queue_bound(1).build(0);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        let queued = lock.clone().try_enqueue().unwrap();
+        assert_eq!(lock.clone().try_enqueue().unwrap_err(), Error::QueueFull);
+        assert_eq!(lock.clone().lock().wait().unwrap_err(), Error::QueueFull);
+
+        drop(guard);
+        *queued.wait().unwrap() += 1;
+        // Room again once granted:
+        assert_eq!(*lock.try_enqueue().unwrap().wait().unwrap(), 1);
+    }
+
+    #[cfg(feature = "fixed_queue")]
+    #[test]
+    fn fixed_queue() {
+        use crate::wait_queue::FIXED_QUEUE_CAPACITY;
+
+        let lock = Qutex::new(0);
+        let guard = lock.clone().lock().wait().unwrap();
+        let queued = (0..FIXED_QUEUE_CAPACITY)
+            .map(|_| lock.clone().try_enqueue().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lock.clone().try_enqueue().unwrap_err(), Error::QueueFull);
+
+        // Each priority has a queue of its own:
+        let high = lock.clone().lock_with_priority(Priority::High);
+        drop(guard);
+        *high.wait().unwrap() += 1;
+        for queued in queued {
+            *queued.wait().unwrap() += 1;
+        }
+        assert_eq!(*lock.try_lock().unwrap(), FIXED_QUEUE_CAPACITY + 1);
+    }
+
+    #[test]
+    fn abandoned_requests() {
+        let lock = QutexBuilder::new().queue_bound(2).build(0);
+        let guard = lock.clone().lock().wait().unwrap();
+
+        // A burst of cancellations leaves room for live requests:
+        for _ in 0..4 {
+            drop(lock.clone().try_enqueue().unwrap());
+        }
+        assert_eq!(lock.inner.abandoned.load(Relaxed), 4);
+        let queued = lock.clone().try_enqueue().unwrap();
+
+        drop(guard);
+        *queued.wait().unwrap() += 1;
+        assert_eq!(lock.inner.abandoned.load(Relaxed), 0);
+        assert_eq!(lock.inner.queued.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn handoff() {
+        let lock = Qutex::new(Vec::new());
+        let mut guard = lock.clone().lock().wait().unwrap();
+        guard.push(0);
+        let interloper = lock.clone().lock();
+
+        let stage = lock.clone();
+        let token = Guard::handoff(guard);
+        thread::spawn(move || stage.redeem(token).push(1))
+            .join()
+            .unwrap();
+
+        interloper.wait().unwrap().push(2);
+        assert_eq!(*lock.lock().wait().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn handoff_dropped() {
+        let released = Arc::new(AtomicUsize::new(0));
+        let lock = Qutex::new(0);
+        let mut guard = lock.clone().lock().wait().unwrap();
+        let counter = released.clone();
+        Guard::on_release(&mut guard, move |_| {
+            counter.fetch_add(1, SeqCst);
+        });
+
+        drop(Guard::handoff(guard));
+        assert_eq!(released.load(SeqCst), 1);
+        drop(lock.lock().wait().unwrap());
+        assert_eq!(released.load(SeqCst), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "different lock")]
+    fn redeem_wrong_lock() {
+        let token = Guard::handoff(Qutex::new(0).lock().wait().unwrap());
+        Qutex::new(0).redeem(token);
+    }
+
+    #[test]
+    fn poison() {
+        let lock = QutexBuilder::new().poison(true).build(0);
+
+        let panicking = lock.clone();
+        thread::spawn(move || {
+            let _guard = panicking.lock().wait().unwrap();
+            panic!("poisoning");
+        })
+        .join()
+        .unwrap_err();
+
+        assert!(lock.is_poisoned());
+        lock.clear_poison();
+        assert!(!lock.is_poisoned());
+        drop(lock.lock().wait().unwrap());
+    }
+
+    #[test]
+    fn current_holder() {
+        let lock = QutexBuilder::new().track_holder(true).build(0);
+        assert!(lock.current_holder().is_none());
+
+        let line = line!() + 1;
+        let guard = lock.clone().lock().wait().unwrap();
+        Guard::set_label(&guard, "testing");
+        let holder = lock.current_holder().unwrap();
+        assert_eq!(holder.thread().id(), thread::current().id());
+        assert_eq!(holder.location().unwrap().line(), line);
+        assert_eq!(holder.label(), Some("testing"));
+        assert!(holder.to_string().contains(&format!("{}:{}", file!(), line)));
+
+        // To be reported by a request timing out:
+        let timed = lock.clone().lock_until(Instant::now());
+        assert_eq!(timed.wait().unwrap_err(), Error::Expired);
+        assert_eq!(lock.current_holder().unwrap().label(), Some("testing"));
+
+        // Queued, then granted on another thread:
+        let line = line!() + 1;
+        let queued = lock.clone().lock();
+        let thread = thread::spawn(move || {
+            let guard = queued.wait().unwrap();
+            let holder = guard.qutex.current_holder().unwrap();
+            assert_eq!(holder.thread().id(), thread::current().id());
+            assert_eq!(holder.location().unwrap().line(), line);
+            assert_eq!(holder.label(), None);
+        });
+        drop(guard);
+        thread.join().unwrap();
+        assert!(lock.current_holder().is_none());
+    }
+
+    #[test]
+    fn hooks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (contend, acquire, release) = (events.clone(), events.clone(), events.clone());
+        let lock = QutexBuilder::new()
+            .name("hooks")
+            .on_contend(move |name| contend.lock().unwrap().push((name.unwrap().to_owned(), None)))
+            .on_acquire(move |_, wait| acquire.lock().unwrap().push(("acquire".into(), Some(wait))))
+            .on_release(move |_, held| release.lock().unwrap().push(("release".into(), Some(held))))
+            .build(0);
+
+        let guard = lock.clone().lock().wait().unwrap();
+        let queued = lock.clone().lock();
+        let thread = thread::spawn(move || drop(queued.wait().unwrap()));
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        thread.join().unwrap();
+
+        let events = events.lock().unwrap();
+        let kinds = events.iter().map(|(kind, _)| kind.as_str()).collect::<Vec<_>>();
+        assert_eq!(kinds, ["acquire", "hooks", "release", "acquire", "release"]);
+        assert_eq!(events[0].1, Some(Duration::ZERO));
+        assert!(events[2].1.unwrap() >= Duration::from_millis(20));
+        assert!(events[3].1.unwrap() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn priority_boost() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let boosts = Arc::new(Mutex::new(Vec::new()));
+        let on_boost = boosts.clone();
+        let lock = QutexBuilder::new()
+            .on_priority_boost(move |_, priority| on_boost.lock().unwrap().push(priority))
+            .build(0);
+
+        let guard = lock.clone().lock_with_priority(Priority::Low).wait().unwrap();
+        let mut waiting = [Priority::Normal, Priority::Low, Priority::High, Priority::Normal]
+            .map(|priority| lock.clone().lock_with_priority(priority));
+        for req in waiting.iter_mut() {
+            assert!(req.poll_unpin(cx).is_pending());
+        }
+        assert_eq!(*boosts.lock().unwrap(), [Some(Priority::Normal), Some(Priority::High)]);
+
+        // Restored on release, and the high priority holder is never boosted:
+        drop(guard);
+        let [normal, low, high, later] = waiting;
+        drop(high.wait().unwrap());
+        assert_eq!(boosts.lock().unwrap().len(), 3);
+        assert_eq!(boosts.lock().unwrap()[2], None);
+
+        // Nor the normal one, by the request queued behind it:
+        let guard = normal.wait().unwrap();
+        let mut high = lock.clone().lock_with_priority(Priority::High);
+        assert!(high.poll_unpin(cx).is_pending());
+        drop(guard);
+        assert_eq!(boosts.lock().unwrap()[3..], [Some(Priority::High), None]);
+        drop((high, later, low));
+    }
+
+    #[cfg(feature = "metrics-rs")]
+    #[test]
+    fn metrics_rs() {
+        use metrics_rs::{
+            Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+            SharedString, Unit,
+        };
+
+        type Events = Arc<Mutex<Vec<(String, f64)>>>;
+
+        struct Sink(String, Events);
+
+        impl CounterFn for Sink {
+            fn increment(&self, value: u64) {
+                self.1.lock().unwrap().push((self.0.clone(), value as f64));
+            }
+
+            fn absolute(&self, _: u64) {}
+        }
+
+        impl HistogramFn for Sink {
+            fn record(&self, value: f64) {
+                self.1.lock().unwrap().push((self.0.clone(), value));
+            }
+        }
+
+        struct TestRecorder(Events);
+
+        impl TestRecorder {
+            fn sink(&self, key: &Key) -> Arc<Sink> {
+                let labels = key.labels().map(|l| format!("{}={}", l.key(), l.value()));
+                let key = format!("{}{:?}", key.name(), labels.collect::<Vec<_>>());
+                Arc::new(Sink(key, self.0.clone()))
+            }
+        }
+
+        impl Recorder for TestRecorder {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                Counter::from_arc(self.sink(key))
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+                Histogram::from_arc(self.sink(key))
+            }
+        }
+
+        let events = Events::default();
+        let recorder = TestRecorder(events.clone());
+        metrics_rs::with_local_recorder(&recorder, || {
+            let lock = QutexBuilder::new().name("metrics").build(0);
+            let guard = lock.clone().lock().wait().unwrap();
+            let mut queued = lock.clone().lock();
+            assert!(queued
+                .poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+                .is_pending());
+            thread::sleep(Duration::from_millis(10));
+            drop(guard);
+            drop(queued.wait().unwrap());
+            drop(Qutex::new(0).try_lock().unwrap());
+        });
+
+        let events = events.lock().unwrap();
+        let keys = events.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "qutex.acquire[\"lock=metrics\"]",
+                "qutex.wait_seconds[\"lock=metrics\"]",
+                "qutex.hold_seconds[\"lock=metrics\"]",
+                "qutex.acquire[\"lock=metrics\"]",
+                "qutex.wait_seconds[\"lock=metrics\"]",
+                "qutex.hold_seconds[\"lock=metrics\"]",
+                "qutex.acquire[]",
+                "qutex.wait_seconds[]",
+                "qutex.hold_seconds[]",
+            ]
+        );
+        assert_eq!(events[1].1, 0.0);
+        assert!(events[2].1 >= 0.01 && events[4].1 >= 0.01);
+    }
+
+    #[test]
+    fn traits() {
+        let qutex = QutexBuilder::new().name("traits").build(5);
+        assert_eq!(
+            format!("{:?}", qutex),
+            "Qutex { name: \"traits\", data: 5, poisoned: false, queued: 0, .. }"
+        );
+        #[cfg(feature = "metrics")]
+        assert_eq!(qutex.stats().acquisitions(), 0);
+
+        let mut guard = qutex.try_lock().unwrap();
+        assert_eq!(format!("{} {:?}", guard, guard), "5 Guard(5)");
+        *guard.as_mut() += 1;
+        assert_eq!(*Borrow::<i32>::borrow(&guard), 6);
+        assert!(format!("{:?}", qutex).contains("data: <locked>"));
+        drop(guard);
+
+        let qutex = Qutex::<Vec<u8>>::default();
+        assert!(qutex.lock_ref().wait().unwrap().as_ref().is_empty());
+    }
+
+    #[test]
+    fn lock_cow() {
+        let qutex = Qutex::new(vec![1]);
+
+        // Read only, so never written back:
+        let cow = qutex.clone().lock_cow().wait().unwrap();
+        let guard = qutex.try_lock().unwrap();
+        assert_eq!(*cow, [1]);
+        drop(cow);
+        drop(guard);
+
+        // Written back, nobody having held the lock since:
+        let mut cow = qutex.clone().lock_cow().wait().unwrap();
+        cow.push(2);
+        assert!(CowGuard::is_modified(&cow));
+        drop(cow);
+        assert_eq!(*qutex.try_lock().unwrap(), [1, 2]);
+
+        let mut cow = qutex.clone().lock_cow().wait().unwrap();
+        cow.clear();
+        CowGuard::discard(cow);
+        assert_eq!(*qutex.try_lock().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn lock_cow_conflict() {
+        let qutex = Qutex::new(0);
+        let mut cow = qutex.clone().lock_cow().wait().unwrap();
+        *cow += 1;
+        assert_eq!(CowGuard::commit(cow).wait(), Ok(()));
+        assert_eq!(*qutex.try_lock().unwrap(), 1);
+
+        // Written to by someone else since, so handed back:
+        let mut cow = qutex.clone().lock_cow().wait().unwrap();
+        *qutex.try_lock().unwrap() += 10;
+        *cow += 1;
+        assert_eq!(CowGuard::commit(cow).wait(), Err(2));
+
+        // Or dropped, rather than overwriting, once the holder is done:
+        let mut cow = qutex.clone().lock_cow().wait().unwrap();
+        let mut guard = qutex.try_lock().unwrap();
+        *cow = 0;
+        drop(cow);
+        *guard += 10;
+        drop(guard);
+        assert_eq!(*qutex.try_lock().unwrap(), 21);
+    }
+
+    #[test]
+    fn into_inner() {
+        let dropped = Arc::new(());
+        let qutex = Qutex::new(dropped.clone());
+        let copy = qutex.clone();
+        let qutex = qutex.into_inner().unwrap_err();
+        drop(copy);
+        let val = qutex.into_inner().unwrap();
+        assert_eq!(Arc::strong_count(&dropped), 2);
+        drop(val);
+        assert_eq!(Arc::strong_count(&dropped), 1);
+
+        // Otherwise dropped along with the lock:
+        drop(Qutex::new(dropped.clone()));
+        assert_eq!(Arc::strong_count(&dropped), 1);
+    }
+
+    #[test]
+    fn freeze() {
+        let qutex = Qutex::new(vec![1]);
+        let guard = qutex.clone().lock().wait().unwrap();
+        let qutex = qutex.freeze().unwrap_err();
+        drop(guard);
+
+        // Moved, not cloned:
+        let ptr = qutex.try_lock().unwrap().as_ptr();
+        let frozen = qutex.freeze().unwrap();
+        assert_eq!((*frozen).as_ptr(), ptr);
+        assert_eq!(*frozen, [1]);
+    }
+
+    #[test]
+    fn core() {
+        struct State {
+            lock: QutexCore<Vec<u32>>,
+        }
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let state = Arc::new(State {
+            lock: QutexBuilder::new().name("core").build_core(Vec::new()),
+        });
+        let mut guard = state.lock.try_lock_ref().unwrap();
+        let mut waiting = state.lock.lock_ref();
+        assert!(waiting.poll_unpin(cx).is_pending());
+        guard.push(1);
+        let debug = format!("{:?}", state.lock);
+        assert!(debug.starts_with("QutexCore { name: \"core\", data: <locked>"));
+        drop(guard);
+        waiting.wait().unwrap().push(2);
+
+        let mut state = Arc::into_inner(state).unwrap();
+        state.lock.get_mut().push(3);
+        let qutex = Qutex::from_core(state.lock);
+        assert_eq!(qutex.name(), Some("core"));
+        assert_eq!(qutex.into_inner().unwrap(), [1, 2, 3]);
+        assert_eq!(QutexCore::new(5).into_inner(), 5);
+    }
+
+    #[test]
+    fn on_release() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let qutex = QutexBuilder::new().poison(true).build(0);
+        let mut guard = qutex.clone().lock().wait().unwrap();
+        *guard = 1;
+        for i in 0..2 {
+            let (calls, qutex) = (calls.clone(), qutex.clone());
+            Guard::on_release(&mut guard, move |&val| {
+                // Still held:
+                assert!(qutex.try_lock().is_none());
+                calls.lock().unwrap().push((i, val));
             });
+        }
+        drop(guard);
+        assert_eq!(*calls.lock().unwrap(), [(0, 1), (1, 1)]);
 
-            threads.push(
-                thread::Builder::new()
-                    .name(format!("test_thread_{}", i))
-                    .spawn(|| future_write.wait().unwrap())
-                    .unwrap(),
-            );
+        // Released, and poisoned, should a callback panic:
+        let mut guard = qutex.try_lock_ref().unwrap();
+        RefGuard::on_release(&mut guard, |_| panic!("on_release"));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| drop(guard))).is_err());
+        assert!(qutex.is_poisoned() && qutex.try_lock().is_some());
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn lock_after() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(0);
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut first = qutex.clone().lock_after(rx);
+        let mut second = qutex.clone().lock();
+
+        // Granted, but held until the event signals:
+        assert!(first.poll_unpin(cx).is_pending());
+        assert!(second.poll_unpin(cx).is_pending());
+        assert!(qutex.try_lock().is_none());
+        tx.send(()).unwrap();
+        *first.wait().unwrap() += 1;
+        assert_eq!(*second.wait().unwrap(), 1);
+
+        // Released if dropped while waiting:
+        let (_tx, rx) = oneshot::channel::<()>();
+        let mut waiting = qutex.clone().lock_after(rx);
+        assert!(waiting.poll_unpin(cx).is_pending());
+        drop(waiting);
+        assert!(qutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn lock_after_deferred() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(Vec::new());
+        let guard = qutex.clone().lock().wait().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut deferred = qutex.clone().lock_after_with(rx, EventOrder::Defer);
+        assert!(deferred.poll_unpin(cx).is_pending());
+        let mut later = qutex.clone().lock();
+        assert!(later.poll_unpin(cx).is_pending());
+
+        // Not queued until signaled, so overtaken:
+        drop(guard);
+        let mut guard = later.wait().unwrap();
+        guard.push(0);
+        tx.send(()).unwrap();
+        assert!(deferred.poll_unpin(cx).is_pending());
+        drop(guard);
+        deferred.wait().unwrap().push(1);
+        assert_eq!(*qutex.try_lock().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn raw_state() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let core = QutexCore::new(0);
+        let mut first = core.raw_state();
+        assert!(first.try_acquire() && first.get_mut().is_some());
+        let mut second = core.raw_state();
+        assert!(!second.try_acquire() && second.get_mut().is_none());
+        let mut waiting = second.acquire();
+        assert!(waiting.poll_unpin(cx).is_pending());
+
+        // Granted straight to the queued request:
+        first.release();
+        assert!(!first.is_held() && !first.try_acquire());
+        let mut second = waiting.wait().unwrap();
+        *second.get_mut().unwrap() += 1;
+        let guard = second.into_guard().unwrap();
+        assert_eq!(*guard, 1);
+
+        // Released once dropped:
+        let state = RefGuard::into_raw(guard);
+        assert!(core.try_lock_ref().is_none());
+        drop(state);
+        assert!(first.try_acquire());
+        assert!(first.into_guard().is_ok());
+        assert!(core.raw_state().into_guard().is_err());
+    }
+
+    #[test]
+    fn long_hold() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_c = reports.clone();
+        let lock = QutexBuilder::new()
+            .name("slow")
+            .on_long_hold(Duration::from_millis(10), move |name, held| {
+                reports_c.lock().unwrap().push((name.map(String::from), held))
+            })
+            .build(0);
+        drop(lock.clone().lock().wait().unwrap());
+        assert!(reports.lock().unwrap().is_empty());
+
+        // Reported by a request finding it taken, once only:
+        let guard = lock.clone().lock().wait().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let queued = lock.clone().lock();
+        {
+            let reports = reports.lock().unwrap();
+            assert_eq!(reports.len(), 1);
+            assert_eq!(reports[0].0.as_deref(), Some("slow"));
+            assert!(reports[0].1 >= Duration::from_millis(20));
         }
+        drop(guard);
+        assert_eq!(reports.lock().unwrap().len(), 1);
 
-        for i in 0..thread_count {
-            let future_guard = qutex.clone().lock();
+        // Uncontended, reported on release:
+        let guard = queued.wait().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(reports.lock().unwrap().len(), 1);
+        drop(guard);
+        assert_eq!(reports.lock().unwrap().len(), 2);
+    }
 
-            threads.push(
-                thread::Builder::new()
-                    .name(format!("test_thread_{}", i + thread_count))
-                    .spawn(|| {
-                        let mut guard = future_guard.wait().unwrap();
-                        *guard -= 1;
-                    })
-                    .unwrap(),
-            )
+    #[test]
+    fn long_wait() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_c = reports.clone();
+        let lock = QutexBuilder::new()
+            .name("convoy")
+            .track_holder(true)
+            .on_long_wait(Duration::from_millis(10), move |wait| {
+                let holder = wait.holder().and_then(Holder::label).map(String::from);
+                reports_c
+                    .lock()
+                    .unwrap()
+                    .push((wait.granted(), wait.queued(), holder, wait.to_string()))
+            })
+            .build(0);
+        let guard = lock.clone().lock().wait().unwrap();
+        Guard::set_label(&guard, "slow");
+        let first = lock.clone().lock();
+        thread::sleep(Duration::from_millis(20));
+
+        // Reported by a request queued behind it, once only:
+        let second = lock.clone().lock();
+        let third = lock.clone().lock();
+        {
+            let reports = reports.lock().unwrap();
+            assert_eq!(reports.len(), 1);
+            assert!(!reports[0].0);
+            assert_eq!(reports[0].1, 2);
+            assert_eq!(reports[0].2.as_deref(), Some("slow"));
+            assert!(reports[0].3.starts_with("request for lock 'convoy' waiting for "));
         }
 
-        for thread in threads {
-            thread.join().unwrap();
+        // And again as it is granted, unlike quick waits:
+        drop(guard);
+        drop(first.wait().unwrap());
+        drop((second, third));
+        drop(lock.clone().lock().wait().unwrap());
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!((reports[1].0, reports[1].2.as_deref()), (true, None));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing() {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Collects each event's message and fields.
+        #[derive(Default)]
+        struct Events(Mutex<Vec<String>>);
+
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+                self.0.push_str(&format!("{}={:?} ", field.name(), value));
+            }
+        }
+
+        impl Subscriber for &'static Events {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(String::new());
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0);
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let events: &'static Events = Box::leak(Box::default());
+        tracing::subscriber::with_default(events, || {
+            let lock = QutexBuilder::new().name("traced").build(0);
+            let guard = lock.clone().lock().wait().unwrap();
+            let queued = lock.clone().lock_tagged("flush-task");
+            drop(guard);
+            drop(queued.wait().unwrap());
+        });
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 5);
+        assert!(events[0].starts_with("message=acquired lock=\"traced\" contended=false"));
+        assert_eq!(events[1], "message=enqueued lock=\"traced\" depth=1 tag=flush-task ");
+        assert_eq!(events[2], "message=released lock=\"traced\" ");
+        assert!(events[3].starts_with("message=acquired lock=\"traced\" contended=true"));
+        assert!(events[3].ends_with("tag=flush-task "));
+        assert_eq!(events[4], "message=released lock=\"traced\" ");
+    }
+
+    #[test]
+    fn unwind() {
+        use std::task::Wake;
+
+        fn unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+        unwind_safe::<Qutex<Vec<u32>>>();
+        unwind_safe::<Guard<Vec<u32>>>();
+        unwind_safe::<FutureGuard<Vec<u32>>>();
+        unwind_safe::<RefGuard<'_, Vec<u32>>>();
+        unwind_safe::<FutureRefGuard<'_, Vec<u32>>>();
+
+        // A live guard and a pending request, both unwound:
+        let lock = Qutex::new(Vec::new());
+        panic::catch_unwind(|| {
+            let cx = &mut Context::from_waker(noop_waker_ref());
+            let mut guard = lock.clone().lock().wait().unwrap();
+            let mut pending = lock.clone().lock();
+            assert!(pending.poll_unpin(cx).is_pending());
+            guard.push(1);
+            panic!("unwinding");
+        })
+        .unwrap_err();
+        assert_eq!(*lock.try_lock().unwrap(), [1]);
+
+        // A waker panicking as its expired request is dropped:
+        struct Panicking;
+        impl Wake for Panicking {
+            fn wake(self: Arc<Self>) {
+                panic!("waking");
+            }
         }
+        let waker = Waker::from(Arc::new(Panicking));
+        let guard = lock.clone().lock().wait().unwrap();
+        let mut expiring = lock.clone().lock_until(Instant::now() + Duration::from_millis(20));
+        assert!(expiring.poll_unpin(&mut Context::from_waker(&waker)).is_pending());
+        let waiting = lock.clone().lock();
+        thread::sleep(Duration::from_millis(30));
+        panic::catch_unwind(|| drop(guard)).unwrap_err();
 
+        // Not left held, and the queue carries on from the next request:
+        waiting.wait().unwrap().push(2);
+        assert_eq!(expiring.wait().unwrap_err(), Error::Expired);
+        assert_eq!(*lock.try_lock().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn try_lock_for() {
+        let qutex = Qutex::new(0);
         let guard = qutex.clone().lock().wait().unwrap();
-        assert_eq!(*guard, start_val);
+        let start = Instant::now();
+        assert!(qutex.try_lock_for(Duration::from_millis(10)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert!(qutex.queues().is_none());
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(guard);
+        });
+        *qutex.try_lock_for(Duration::from_secs(60)).unwrap() += 1;
+        releaser.join().unwrap();
+        assert_eq!(*qutex.try_lock().unwrap(), 1);
     }
 
     #[test]
-    fn future_guard_drop() {
-        let lock = Qutex::from(true);
-        let _future_guard_0 = lock.clone().lock();
-        let _future_guard_1 = lock.clone().lock();
-        let _future_guard_2 = lock.clone().lock();
+    fn bump() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(Vec::new());
 
-        // TODO: FINISH ME
+        // Kept while nobody waits:
+        let guard = Guard::bump(qutex.clone().lock().wait().unwrap());
+        let mut guard = guard.wait().unwrap();
+        guard.push(0);
+
+        let mut waiter = qutex.clone().lock();
+        assert!(waiter.poll_unpin(cx).is_pending());
+        let mut bumped = Guard::bump(guard);
+        assert!(bumped.poll_unpin(cx).is_pending());
+        waiter.wait().unwrap().push(1);
+        bumped.wait().unwrap().push(2);
+        assert_eq!(*qutex.try_lock().unwrap(), [0, 1, 2]);
     }
 
     #[test]
-    fn explicit_unlock() {
-        let lock = Qutex::from(true);
+    fn split() {
+        let pair = Qutex::new((1u8, String::from("b")));
+        let copy = pair.clone();
+        let pair = pair.split().unwrap_err();
+        drop(copy);
 
-        let mut guard_0 = lock.clone().lock().wait().unwrap();
-        *guard_0 = false;
-        let _ = Guard::unlock(guard_0);
-        // Will deadlock if this doesn't work:
-        let guard_1 = lock.clone().lock().wait().unwrap();
-        assert!(*guard_1 == false);
+        let (a, b) = pair.split().unwrap();
+        *a.try_lock().unwrap() += 1;
+        let b_copy = b.clone();
+        let (a, b) = Qutex::join(a, b).unwrap_err();
+        drop(b_copy);
+        let pair = Qutex::join(a, b).unwrap();
+        assert_eq!(*pair.try_lock().unwrap(), (2, String::from("b")));
+    }
+
+    #[test]
+    fn version() {
+        let qutex = Qutex::new(0);
+        assert_eq!(qutex.version(), 0);
+        let mut guard = qutex.clone().lock().wait().unwrap();
+        *guard += 1;
+        assert_eq!(Guard::version(&guard), 0);
+        drop(guard);
+        assert_eq!(qutex.version(), 1);
+
+        drop(qutex.lock_ref().wait().unwrap());
+        let token = Guard::handoff(qutex.try_lock().unwrap());
+        drop(token);
+        assert_eq!(qutex.version(), 3);
+
+        // Withdrawn requests write nothing:
+        let guard = qutex.try_lock().unwrap();
+        drop(qutex.clone().lock());
+        drop(guard);
+        assert_eq!(qutex.version(), 4);
+    }
+
+    #[test]
+    fn peek() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(vec![1]);
+        let mut guard = qutex.try_lock().unwrap();
+        let mut peek = qutex.clone().peek();
+        assert!(peek.poll_unpin(cx).is_pending());
+        guard.push(2);
+        drop(guard);
+
+        assert_eq!(peek.wait().unwrap(), [1, 2]);
+        assert!(qutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn raw_guard() {
+        extern "C" fn callback(lock: *const (), data: *mut u32) {
+            unsafe { *data += 1 };
+            drop(unsafe { Guard::from_raw(lock, data) });
+        }
+
+        let qutex = Qutex::new(1u32);
+        let (lock, data) = Guard::into_raw(qutex.try_lock().unwrap());
+        assert!(qutex.try_lock().is_none());
+        callback(lock, data);
+        assert_eq!(*qutex.try_lock().unwrap(), 2);
+        assert_eq!(qutex.version(), 2);
+    }
+
+    #[test]
+    fn as_pin_mut() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, rx) = oneshot::channel();
+        let task = Qutex::new(async move { rx.await.unwrap() * 2 });
+        let mut guard = task.try_lock().unwrap();
+        assert!(unsafe { Guard::as_pin_mut(&mut guard) }.poll(cx).is_pending());
+
+        tx.send(21).unwrap();
+        let res = unsafe { Guard::as_pin_mut(&mut guard) }.poll(cx);
+        assert_eq!(res, Poll::Ready(42));
+    }
+
+    #[test]
+    fn unsized_data() {
+        let text: Qutex<str> = Qutex::from_boxed(Box::from("qutex"));
+        text.try_lock().unwrap().make_ascii_uppercase();
+        assert_eq!(&*text.clone().lock().wait().unwrap(), "QUTEX");
+
+        // Aligned beyond a cache line, and dropped exactly once:
+        #[repr(align(256))]
+        struct Wide(Arc<()>);
+        let count = Arc::new(());
+        let wide: Qutex<[Wide]> = Qutex::from_boxed(
+            (0..3).map(|_| Wide(count.clone())).collect::<Vec<_>>().into_boxed_slice(),
+        );
+        assert!(Arc::ptr_eq(&wide.try_lock().unwrap()[2].0, &count));
+        assert_eq!(wide.as_ptr() as *const Wide as usize % 256, 0);
+        assert_eq!(Arc::strong_count(&count), 4);
+        drop(wide);
+        assert_eq!(Arc::strong_count(&count), 1);
+
+        let counter: Qutex<dyn FnMut() -> usize + Send> = Qutex::from_boxed(Box::new({
+            let mut calls = 0;
+            move || {
+                calls += 1;
+                calls
+            }
+        }));
+        counter.try_lock().unwrap()();
+        assert_eq!((*counter.try_lock().unwrap())(), 2);
+    }
+
+    #[test]
+    fn coerce() {
+        let qutex = QutexBuilder::new().name("shown").queue_bound(1).build(vec![1, 2]);
+        let clone = qutex.clone();
+        let qutex = qutex.coerce(|boxed| boxed as Box<_>).unwrap_err();
+        drop(clone);
+
+        let shown: Qutex<dyn ::core::fmt::Debug + Send> =
+            qutex.coerce(|boxed| boxed as Box<_>).unwrap();
+        assert_eq!(shown.name(), Some("shown"));
+        assert_eq!(shown.queue_bound(), Some(1));
+        let guard = shown.try_lock().unwrap();
+        assert_eq!(format!("{:?}", &*guard), "[1, 2]");
+        let _queued = shown.clone().lock();
+        assert_eq!(shown.clone().try_enqueue().unwrap_err(), Error::QueueFull);
+    }
+
+    #[test]
+    fn coerce_with_finalizer() {
+        let captured = Arc::new(());
+        let qutex = Qutex::new([1]);
+        let captured_c = captured.clone();
+        qutex.set_finalizer(move |guard| {
+            let _ = &captured_c;
+            async move { drop(guard) }
+        });
+
+        // Kept rather than skipped:
+        let qutex = qutex.coerce(|boxed| boxed as Box<[i32]>).unwrap_err();
+        drop(qutex.try_lock().unwrap());
+        qutex.clear_finalizer();
+        assert_eq!(Arc::strong_count(&captured), 1);
+        let qutex: Qutex<[i32]> = qutex.coerce(|boxed| boxed as Box<_>).unwrap();
+        assert_eq!(*qutex.try_lock().unwrap(), [1]);
+    }
+
+    #[test]
+    fn lock_timed() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(0);
+        let (guard, waited) = qutex.clone().lock_timed().wait().unwrap();
+        assert_eq!(waited, Duration::ZERO);
+
+        let start = Instant::now();
+        let mut timed = qutex.clone().lock_timed();
+        assert!(timed.poll_unpin(cx).is_pending());
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        // Not counted, being granted already:
+        thread::sleep(Duration::from_millis(30));
+        let (_, waited) = timed.wait().unwrap();
+        assert!(waited >= Duration::from_millis(20));
+        assert!(waited + Duration::from_millis(30) <= start.elapsed());
+    }
+
+    #[test]
+    fn try_lock_until() {
+        let qutex = Qutex::new(0);
+        let guard = qutex.try_lock().unwrap();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert!(qutex.try_lock_until(deadline).is_none());
+        assert!(Instant::now() >= deadline);
+        drop(guard);
+
+        // Passed, but still tried once:
+        *qutex.try_lock_until(deadline).unwrap() += 1;
+        assert_eq!(*qutex.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn critical_section() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let section = CriticalSection::default();
+        let guard = section.enter_blocking();
+        let log = Qutex::new(Vec::new());
+        let mut guarded = Box::pin(section.guarded(async {
+            log.try_lock().unwrap().push("inside");
+        }));
+        assert!(guarded.poll_unpin(cx).is_pending());
+        assert!(log.try_lock().unwrap().is_empty());
+
+        drop(guard);
+        executor::block_on(guarded).unwrap();
+        assert_eq!(*log.try_lock().unwrap(), ["inside"]);
+        assert!(section.enter().wait().is_ok());
+    }
+
+    #[test]
+    fn lock_and_then() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(0);
+        let (tx, rx) = oneshot::channel::<i32>();
+        let mut future = Box::pin(qutex.clone().lock_and_then(|mut guard| async move {
+            *guard += rx.await.unwrap();
+            *guard
+        }));
+        assert!(future.poll_unpin(cx).is_pending());
+        assert!(qutex.try_lock().is_none());
+
+        tx.send(5).unwrap();
+        assert_eq!(executor::block_on(future).unwrap(), 5);
+        assert_eq!(*qutex.try_lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn with_unlocked() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(vec![1]);
+        let (tx, rx) = oneshot::channel::<u32>();
+        let guard = qutex.clone().lock().wait().unwrap();
+        let mut future = Box::pin(Guard::with_unlocked(guard, || rx));
+        assert!(qutex.try_lock().is_none());
+        assert!(future.poll_unpin(cx).is_pending());
+
+        // Released meanwhile, then queued behind the next holder:
+        let mut other = qutex.try_lock().unwrap();
+        tx.send(7).unwrap();
+        assert!(future.poll_unpin(cx).is_pending());
+        other.push(2);
+        drop(other);
+        let (output, res) = executor::block_on(future);
+        assert_eq!(output, Ok(7));
+        let mut guard = res.unwrap();
+        guard.push(3);
+        drop(guard);
+        assert_eq!(*qutex.try_lock().unwrap(), [1, 2, 3]);
+
+        // Dropped midway without waiting, even with the lock held elsewhere:
+        let guard = qutex.clone().lock().wait().unwrap();
+        let mut future = Box::pin(Guard::with_unlocked(guard, futures::future::pending::<()>));
+        assert!(future.poll_unpin(cx).is_pending());
+        let other = qutex.try_lock().unwrap();
+        drop(future);
+        drop(other);
+        assert!(qutex.try_lock().is_some());
+
+        // Or once re-requesting, withdrawing the request:
+        let (tx, rx) = oneshot::channel::<u32>();
+        let guard = qutex.clone().lock().wait().unwrap();
+        let mut future = Box::pin(Guard::with_unlocked(guard, || rx));
+        assert!(future.poll_unpin(cx).is_pending());
+        let other = qutex.try_lock().unwrap();
+        tx.send(8).unwrap();
+        assert!(future.poll_unpin(cx).is_pending());
+        drop(future);
+        drop(other);
+        assert!(qutex.try_lock().is_some());
+        assert_eq!(qutex.inner.queued.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn finalize_with() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(vec![1]);
+        let (tx, rx) = oneshot::channel::<u32>();
+        let mut guard = qutex.clone().lock().wait().unwrap();
+        Guard::finalize_with(&mut guard, |mut guard| async move {
+            guard.push(rx.await.unwrap());
+        });
+        Guard::finalize_with(&mut guard, |mut guard| async move { guard.push(3) });
+        let mut next = qutex.clone().lock();
+        assert!(next.poll_unpin(cx).is_pending());
+
+        // Held on through the finalizers, run in order:
+        drop(guard);
+        assert!(next.poll_unpin(cx).is_pending());
+        tx.send(2).unwrap();
+        assert_eq!(*next.wait().unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn finalize_with_woken_elsewhere() {
+        let (qutex, other) = (Qutex::new(None), Qutex::new(()));
+        let other_guard = other.clone().lock().wait().unwrap();
+        let mut guard = qutex.clone().lock().wait().unwrap();
+        Guard::finalize_with(&mut guard, move |mut guard| async move {
+            drop(other.lock().await);
+            *guard = thread::current().name().map(String::from);
+        });
+        drop(guard);
+        assert!(qutex.try_lock().is_none());
+
+        // Not polled within the release waking it:
+        drop(other_guard);
+        let name = qutex.lock().wait().unwrap().take();
+        assert_eq!(name.as_deref(), Some("qutex-finalizer"));
+    }
+
+    #[test]
+    fn set_finalizer() {
+        let flushed = Arc::new(AtomicUsize::new(0));
+        let qutex = Qutex::new(0);
+        let counter = flushed.clone();
+        qutex.set_finalizer(move |guard| {
+            counter.fetch_add(*guard, SeqCst);
+            async {}
+        });
+
+        for i in 1..=2 {
+            let mut guard = qutex.clone().lock().wait().unwrap();
+            *guard = i;
+            // After, not instead of, the guard's own:
+            Guard::finalize_with(&mut guard, |mut guard| async move { *guard *= 10 });
+        }
+        assert_eq!(flushed.load(SeqCst), 30);
+
+        qutex.clear_finalizer();
+        *qutex.try_lock().unwrap() = 5;
+        assert_eq!(flushed.load(SeqCst), 30);
+    }
+
+    #[test]
+    fn poll_lock() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(0);
+        let mut pending = PendingLock::new();
+        let guard = qutex.clone().lock().wait().unwrap();
+        assert!(qutex.poll_lock(&mut pending, cx).is_pending());
+        assert!(pending.is_pending());
+        drop(guard);
+        match qutex.poll_lock(&mut pending, cx) {
+            Poll::Ready(Ok(mut guard)) => *guard += 1,
+            _ => panic!("not granted"),
+        }
+        assert!(!pending.is_pending());
+
+        // Withdrawn once canceled:
+        let guard = qutex.clone().lock().wait().unwrap();
+        assert!(qutex.poll_lock(&mut pending, cx).is_pending());
+        pending.cancel();
+        drop(guard);
+        assert_eq!(*qutex.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn lock_with_token() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let qutex = Qutex::new(0);
+        let guard = qutex.clone().lock().wait().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut future = qutex.clone().lock_with_token(rx.map(|_| ()));
+        let mut next = qutex.clone().lock();
+        assert!(future.poll_unpin(cx).is_pending());
+        assert!(next.poll_unpin(cx).is_pending());
+
+        tx.send(()).unwrap();
+        let canceled = future.poll_unpin(cx).map(|res| res.err());
+        assert_eq!(canceled, Poll::Ready(Some(Error::Canceled)));
+        // Skipped, straight to the request behind it:
+        drop(guard);
+        assert!(next.wait().is_ok());
+
+        // The lock wins over a token which has already resolved:
+        assert!(qutex.lock_with_token(futures::future::ready(())).wait().is_ok());
     }
 }
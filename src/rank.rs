@@ -0,0 +1,112 @@
+//! Runtime checks of the order in which tasks take locks given a level
+//! (see `QutexBuilder::level`).
+//!
+//
+// Checked in debug builds, and in release builds with the `lock_ranking`
+// feature. As with deadlock detection, tasks are told apart by their
+// wakers, and only locks granted through a request, not `try_lock` and
+// friends, count as held.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use std::task::Waker;
+
+/// Whether lock levels are checked at all.
+pub(crate) const ENABLED: bool = cfg!(any(debug_assertions, feature = "lock_ranking"));
+
+/// Identifies a task by its waker.
+type TaskId = (usize, usize);
+
+#[inline]
+fn task_id(waker: &Waker) -> TaskId {
+    (waker.data() as usize, waker.vtable() as *const _ as usize)
+}
+
+/// A ranked lock and the task holding it.
+#[derive(Debug)]
+struct Held {
+    task: TaskId,
+    level: u32,
+    name: Option<String>,
+}
+
+// By lock:
+static HELD: Mutex<BTreeMap<usize, Held>> = Mutex::new(BTreeMap::new());
+
+/// Panics if the task `waker` wakes holds a lock other than `lock` of a
+/// level at least `level`.
+pub(crate) fn check(lock: usize, level: u32, name: Option<&str>, waker: &Waker) {
+    let task = task_id(waker);
+    let violation = HELD
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .filter(|&(&id, held)| id != lock && held.task == task && held.level >= level)
+        .max_by_key(|(_, held)| held.level)
+        .map(|(_, held)| (held.name.clone(), held.level));
+
+    if let Some((held_name, held_level)) = violation {
+        panic!(
+            "Qutex::lock: Lock \"{}\" (level {}) requested while holding \"{}\" (level {}).",
+            name.unwrap_or("<unnamed>"),
+            level,
+            held_name.as_deref().unwrap_or("<unnamed>"),
+            held_level,
+        );
+    }
+}
+
+/// Records that the task `waker` wakes holds `lock`.
+pub(crate) fn acquired(lock: usize, level: u32, name: Option<&str>, waker: &Waker) {
+    let held = Held {
+        task: task_id(waker),
+        level,
+        name: name.map(String::from),
+    };
+    HELD.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(lock, held);
+}
+
+/// Records that `lock` is no longer held by a known task.
+pub(crate) fn released(lock: usize) {
+    HELD.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(&lock);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::QutexBuilder;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "lock_ranking"))]
+    fn out_of_order() {
+        let outer = QutexBuilder::new().name("rank-outer").level(1).build(0);
+        let inner = QutexBuilder::new().name("rank-inner").level(2).build(0);
+        let unranked = QutexBuilder::new().build(0);
+
+        // In order, with unranked locks taken at any point:
+        let guards = (
+            outer.clone().lock().wait().unwrap(),
+            unranked.clone().lock().wait().unwrap(),
+            inner.clone().lock().wait().unwrap(),
+        );
+        drop(guards);
+
+        let guard = inner.clone().lock().wait().unwrap();
+        let payload =
+            panic::catch_unwind(AssertUnwindSafe(|| outer.clone().lock().wait())).unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<String>().unwrap(),
+            "Qutex::lock: Lock \"rank-outer\" (level 1) requested while holding \
+             \"rank-inner\" (level 2)."
+        );
+        // Withdrawn as it unwound:
+        assert!(outer.try_lock().is_some());
+
+        drop(guard);
+        assert!(outer.clone().lock().wait().is_ok());
+    }
+}
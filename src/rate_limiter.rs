@@ -0,0 +1,196 @@
+//! A token-bucket rate limiter.
+//!
+//
+
+use crate::semaphore::FuturePermit;
+use crate::timer::{self, Sleep};
+use crate::Semaphore;
+use futures::executor;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Inner {
+    // Holds the tokens available:
+    tokens: Semaphore,
+    capacity: usize,
+    interval: Duration,
+    // The time up to which tokens have been added:
+    refilled: Mutex<Instant>,
+}
+
+impl Inner {
+    /// Adds the tokens due since last refilled, up to the capacity, and
+    /// returns when the next is due.
+    fn refill(&self) -> Instant {
+        let mut refilled = self.refilled.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let due = now.saturating_duration_since(*refilled).as_nanos() / self.interval.as_nanos();
+        if due > 0 {
+            let due = usize::try_from(due).unwrap_or(usize::MAX);
+            // Tokens beyond the capacity are lost, as is the time they took:
+            *refilled = match u32::try_from(due) {
+                Ok(due) => *refilled + self.interval * due,
+                Err(_) => now,
+            };
+            let room = self
+                .capacity
+                .saturating_sub(self.tokens.available_permits());
+            if room > 0 {
+                self.tokens.add_permits(due.min(room));
+            }
+        }
+        *refilled + self.interval
+    }
+}
+
+/// A future which resolves once the tokens requested have been taken from
+/// a `RateLimiter`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureToken {
+    inner: Arc<Inner>,
+    permit: FuturePermit,
+    // Until the next token is due:
+    sleep: Option<Sleep>,
+}
+
+impl FutureToken {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<(), Infallible> {
+        executor::block_on(self)
+    }
+}
+
+impl Future for FutureToken {
+    type Output = Result<(), Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = this.inner.refill();
+            if let Poll::Ready(res) = Pin::new(&mut this.permit).poll(cx) {
+                // Its semaphore is never closed:
+                res.expect("FutureToken::poll: Limiter closed.").forget();
+                return Poll::Ready(Ok(()));
+            }
+
+            let sleep = this.sleep.get_or_insert_with(|| timer::sleep_until(next));
+            match Pin::new(sleep).poll(cx) {
+                // Due, so refill and try again:
+                Poll::Ready(()) => this.sleep = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Limits the rate of some operation by handing out tokens, which refill
+/// over time.
+///
+/// The limiter holds up to `capacity` tokens, starting full, and adds one
+/// every `interval` up to the capacity, so bursts of up to `capacity`
+/// operations are allowed while the average rate is held to one per
+/// `interval`. Callers waiting for tokens are served in FIFO order, as by a
+/// `Semaphore`, each being woken through the crate's timer (see
+/// `set_timer`) as tokens come due.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+impl RateLimiter {
+    /// Creates and returns a new, full `RateLimiter` holding up to
+    /// `capacity` tokens and adding one every `interval`.
+    ///
+    /// Panics if `interval` is zero.
+    pub fn new(capacity: usize, interval: Duration) -> RateLimiter {
+        assert!(
+            !interval.is_zero(),
+            "RateLimiter::new: The interval must be non-zero."
+        );
+        RateLimiter {
+            inner: Arc::new(Inner {
+                tokens: Semaphore::new(capacity),
+                capacity,
+                interval,
+                refilled: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// Returns the number of tokens currently available.
+    #[inline]
+    pub fn available(&self) -> usize {
+        self.inner.refill();
+        self.inner.tokens.available_permits()
+    }
+
+    /// Returns a new `FutureToken` which will resolve once a token has been
+    /// taken.
+    #[inline]
+    pub fn acquire(&self) -> FutureToken {
+        self.acquire_many(1)
+    }
+
+    /// Returns a new `FutureToken` which will resolve once `tokens` tokens
+    /// have been taken at once.
+    ///
+    /// Waiting for more tokens than the capacity never resolves.
+    pub fn acquire_many(&self, tokens: usize) -> FutureToken {
+        FutureToken {
+            inner: self.inner.clone(),
+            permit: self.inner.tokens.acquire_many(tokens),
+            sleep: None,
+        }
+    }
+
+    /// Takes a token if one is available and nobody is waiting, returning
+    /// `false` otherwise.
+    pub fn try_acquire(&self) -> bool {
+        self.inner.refill();
+        match self.inner.tokens.try_acquire() {
+            Some(permit) => {
+                permit.forget();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(2, Duration::from_millis(20));
+        assert!(limiter.try_acquire() && limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+        assert_eq!(limiter.available(), 0);
+
+        // One at a time:
+        limiter.acquire().wait().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        limiter.acquire_many(2).wait().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn capped() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(5));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(limiter.available(), 1);
+        assert!(limiter.try_acquire() && !limiter.try_acquire());
+    }
+}
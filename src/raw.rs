@@ -0,0 +1,213 @@
+//! `lock_api` raw locks backed by `Qutex` and `QrwLock`.
+//!
+//! These use the blocking path (`wait`), so should not be locked from
+//! within an async task.
+//
+
+use crate::{QrwLock, Qutex};
+use futures::task::noop_waker_ref;
+use futures::FutureExt;
+use lock_api::{GuardSend, RawMutex, RawRwLock};
+use std::mem;
+use std::sync::OnceLock;
+use std::task::Context;
+
+/// A `lock_api::RawMutex` backed by a `Qutex`, queueing blocked threads in
+/// FIFO order.
+#[derive(Debug)]
+pub struct RawQutex {
+    // Created on first use, so that `INIT` can be a constant:
+    qutex: OnceLock<Qutex<()>>,
+}
+
+impl RawQutex {
+    #[inline]
+    fn qutex(&self) -> &Qutex<()> {
+        self.qutex.get_or_init(|| Qutex::new(()))
+    }
+}
+
+unsafe impl RawMutex for RawQutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawQutex = RawQutex {
+        qutex: OnceLock::new(),
+    };
+
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        // Requested anew should a `fixed_queue` be full:
+        let guard = self.qutex().lock_ref_blocking();
+        // Released by `unlock`:
+        mem::forget(guard);
+    }
+
+    fn try_lock(&self) -> bool {
+        self.qutex().try_lock_ref().map(mem::forget).is_some()
+    }
+
+    unsafe fn unlock(&self) {
+        self.qutex().direct_unlock()
+    }
+}
+
+/// A `lock_api::RawRwLock` backed by a `QrwLock`.
+#[derive(Debug)]
+pub struct RawQrwLock {
+    // Created on first use, so that `INIT` can be a constant:
+    lock: OnceLock<QrwLock<()>>,
+}
+
+impl RawQrwLock {
+    #[inline]
+    fn lock(&self) -> &QrwLock<()> {
+        self.lock.get_or_init(|| QrwLock::new(()))
+    }
+}
+
+unsafe impl RawRwLock for RawQrwLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawQrwLock = RawQrwLock {
+        lock: OnceLock::new(),
+    };
+
+    type GuardMarker = GuardSend;
+
+    // A `QrwLock` queues in a `SegQueue` whatever the features, so neither
+    // blocking path is ever turned away.
+    fn lock_shared(&self) {
+        let guard = self
+            .lock()
+            .clone()
+            .read()
+            .wait()
+            .expect("RawQrwLock::lock_shared: Request canceled.");
+        mem::forget(guard);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        // Dropping an ungranted request removes it from the queue:
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        match self.lock().clone().read().poll_unpin(cx) {
+            std::task::Poll::Ready(Ok(guard)) => {
+                mem::forget(guard);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.lock().release_read_lock()
+    }
+
+    fn lock_exclusive(&self) {
+        let guard = self
+            .lock()
+            .clone()
+            .write()
+            .wait()
+            .expect("RawQrwLock::lock_exclusive: Request canceled.");
+        mem::forget(guard);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        match self.lock().clone().write().poll_unpin(cx) {
+            std::task::Poll::Ready(Ok(guard)) => {
+                mem::forget(guard);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.lock().release_write_lock()
+    }
+}
+
+/// A `lock_api::Mutex` backed by a `Qutex`.
+pub type QutexMutex<T> = lock_api::Mutex<RawQutex, T>;
+
+/// The guard of a `QutexMutex`.
+pub type QutexMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawQutex, T>;
+
+/// A `lock_api::RwLock` backed by a `QrwLock`.
+pub type QrwRwLock<T> = lock_api::RwLock<RawQrwLock, T>;
+
+/// The shared guard of a `QrwRwLock`.
+pub type QrwRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawQrwLock, T>;
+
+/// The exclusive guard of a `QrwRwLock`.
+pub type QrwRwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawQrwLock, T>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn mutex() {
+        static COUNT: QutexMutex<usize> = QutexMutex::const_new(RawQutex::INIT, 0);
+
+        let threads = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..100 {
+                        *COUNT.lock() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = COUNT.lock();
+        assert_eq!(*guard, 400);
+        assert!(COUNT.try_lock().is_none());
+    }
+
+    #[cfg(feature = "fixed_queue")]
+    #[test]
+    fn mutex_queue_full() {
+        use crate::wait_queue::FIXED_QUEUE_CAPACITY;
+        use std::time::Duration;
+
+        let lock = Arc::new(QutexMutex::new(0));
+        let guard = lock.lock();
+        let threads = (0..FIXED_QUEUE_CAPACITY + 8)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || *lock.lock() += 1)
+            })
+            .collect::<Vec<_>>();
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), FIXED_QUEUE_CAPACITY + 8);
+    }
+
+    #[test]
+    fn rw_lock() {
+        let lock = Arc::new(QrwRwLock::new(vec![1, 2]));
+
+        let read_0 = lock.read();
+        let read_1 = lock.try_read().unwrap();
+        assert!(lock.try_write().is_none());
+        assert_eq!(read_0.len() + read_1.len(), 4);
+        drop((read_0, read_1));
+
+        // Mapped guards come from `lock_api`:
+        let mut first = QrwRwLockWriteGuard::map(lock.write(), |v| &mut v[0]);
+        *first = 10;
+        drop(first);
+        assert_eq!(*lock.read(), [10, 2]);
+    }
+}
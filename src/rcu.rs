@@ -0,0 +1,178 @@
+//! A read-copy-update lock.
+//!
+//
+
+use crate::{Error, FutureGuard, Qutex};
+use arc_swap::ArcSwap;
+use futures::executor;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+#[derive(Debug)]
+struct Inner<T> {
+    current: ArcSwap<T>,
+    // Serializes updates:
+    writer: Qutex<()>,
+}
+
+/// A future which resolves to the new snapshot once an update has been
+/// applied.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureUpdate<T, F> {
+    inner: Arc<Inner<T>>,
+    writer: FutureGuard<()>,
+    f: Option<F>,
+}
+
+impl<T, F> FutureUpdate<T, F>
+where
+    T: Clone,
+    F: FnOnce(&mut T),
+{
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<Arc<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T, F> Future for FutureUpdate<T, F>
+where
+    T: Clone,
+    F: FnOnce(&mut T),
+{
+    type Output = Result<Arc<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.f.is_none() {
+            panic!("FutureUpdate::poll: Task already completed.");
+        }
+
+        // Held until the new snapshot is stored:
+        let _writer = futures::ready!(Pin::new(&mut this.writer).poll(cx))?;
+
+        let mut val = T::clone(&this.inner.current.load());
+        (this.f.take().unwrap())(&mut val);
+
+        let snapshot = Arc::new(val);
+        this.inner.current.store(snapshot.clone());
+        Poll::Ready(Ok(snapshot))
+    }
+}
+
+// Never pinned structurally.
+impl<T, F> Unpin for FutureUpdate<T, F> {}
+
+/// A read-copy-update lock.
+///
+/// `read` returns the current snapshot without ever waiting. `update`
+/// clones the current value, modifies the copy, and atomically replaces the
+/// snapshot with it. Updates are queued, in FIFO order, so that none are
+/// lost, while readers holding older snapshots are unaffected.
+///
+/// Best suited to data which is read constantly and replaced rarely.
+#[derive(Debug)]
+pub struct RcuQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> RcuQutex<T> {
+    /// Creates and returns a new `RcuQutex`.
+    #[inline]
+    pub fn new(val: T) -> RcuQutex<T> {
+        RcuQutex {
+            inner: Arc::new(Inner {
+                current: ArcSwap::from_pointee(val),
+                writer: Qutex::new(()),
+            }),
+        }
+    }
+
+    /// Returns the current snapshot.
+    #[inline]
+    pub fn read(&self) -> Arc<T> {
+        self.inner.current.load_full()
+    }
+
+    /// Returns a new `FutureUpdate` which, once all earlier updates have
+    /// been applied, calls `f` with a copy of the current value and
+    /// publishes the result.
+    #[inline]
+    pub fn update<F>(&self, f: F) -> FutureUpdate<T, F>
+    where
+        T: Clone,
+        F: FnOnce(&mut T),
+    {
+        FutureUpdate {
+            inner: self.inner.clone(),
+            writer: self.inner.writer.clone().lock(),
+            f: Some(f),
+        }
+    }
+}
+
+impl<T> From<T> for RcuQutex<T> {
+    #[inline]
+    fn from(val: T) -> RcuQutex<T> {
+        RcuQutex::new(val)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for RcuQutex<T> {
+    #[inline]
+    fn clone(&self) -> RcuQutex<T> {
+        RcuQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::thread;
+
+    #[test]
+    fn snapshots() {
+        let routes = RcuQutex::new(HashMap::new());
+        let before = routes.read();
+
+        let after = routes
+            .update(|routes| {
+                routes.insert("/", 1);
+            })
+            .wait()
+            .unwrap();
+
+        assert!(before.is_empty());
+        assert_eq!(after.get("/"), Some(&1));
+        assert!(Arc::ptr_eq(&after, &routes.read()));
+    }
+
+    #[test]
+    fn updates_are_not_lost() {
+        let counter = RcuQutex::new(0usize);
+
+        let threads = (0..8)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        counter.update(|val| *val += 1).wait().unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*counter.read(), 400);
+    }
+}
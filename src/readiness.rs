@@ -0,0 +1,230 @@
+//! Lock requests waited on through a file descriptor, for event loops
+//! outside of Rust.
+//!
+//
+// The request is polled with a waker which writes to an eventfd, so that the
+// descriptor becomes readable whenever the request may have been granted.
+// Taking the guard drains the descriptor and polls again, rearming it until
+// the grant.
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+/// Makes an eventfd readable when woken.
+struct FdWaker(OwnedFd);
+
+impl FdWaker {
+    /// Returns a new `FdWaker` over a new, non-blocking eventfd.
+    fn new() -> io::Result<FdWaker> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FdWaker(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Makes the eventfd readable.
+    fn signal(&self) {
+        let val = 1u64;
+        // Only fails once the counter would overflow, when readable anyway:
+        unsafe { libc::write(self.0.as_raw_fd(), &val as *const u64 as *const _, 8) };
+    }
+
+    /// Makes the eventfd unreadable until next signaled.
+    fn drain(&self) {
+        let mut val = 0u64;
+        // Fails with `EAGAIN` if already unreadable:
+        unsafe { libc::read(self.0.as_raw_fd(), &mut val as *mut u64 as *mut _, 8) };
+    }
+}
+
+impl Wake for FdWaker {
+    #[inline]
+    fn wake(self: Arc<FdWaker>) {
+        self.signal()
+    }
+
+    #[inline]
+    fn wake_by_ref(self: &Arc<FdWaker>) {
+        self.signal()
+    }
+}
+
+enum State<T> {
+    Waiting(FutureGuard<T>),
+    Granted(Result<Guard<T>, Error>),
+    Taken,
+}
+
+/// A queued request for a `Qutex` whose file descriptor becomes readable
+/// once the request may have been granted.
+///
+/// Register the descriptor (see `AsRawFd`) for readability with an event
+/// loop such as `epoll`, and call `poll_take` each time it reports it
+/// readable, until the guard is returned. Dropping the request before then
+/// withdraws it from the queue.
+///
+/// ```
+/// use qutex::Qutex;
+/// use std::os::fd::AsRawFd;
+/// use std::task::Poll;
+///
+/// let qutex = Qutex::new(0);
+/// let guard = qutex.clone().lock().wait().unwrap();
+/// let mut request = qutex.readiness_fd().unwrap();
+/// assert!(request.poll_take().is_pending());
+///
+/// // Readable once released, as `poll` or `epoll_wait` would report:
+/// drop(guard);
+/// let mut pollfd = libc::pollfd {
+///     fd: request.as_raw_fd(),
+///     events: libc::POLLIN,
+///     revents: 0,
+/// };
+/// assert_eq!(unsafe { libc::poll(&mut pollfd, 1, -1) }, 1);
+/// let Poll::Ready(Ok(mut guard)) = request.poll_take() else { unreachable!() };
+/// *guard += 1;
+/// ```
+pub struct ReadinessFd<T> {
+    waker: Arc<FdWaker>,
+    state: State<T>,
+}
+
+impl<T> ReadinessFd<T> {
+    /// Returns the guard once the request has been granted, leaving the
+    /// descriptor unreadable until next worth calling otherwise.
+    ///
+    /// Never blocks. Panics if called again after returning the guard.
+    pub fn poll_take(&mut self) -> Poll<Result<Guard<T>, Error>> {
+        self.waker.drain();
+        self.poll_granted();
+        match std::mem::replace(&mut self.state, State::Taken) {
+            State::Granted(res) => Poll::Ready(res),
+            State::Waiting(future_guard) => {
+                self.state = State::Waiting(future_guard);
+                Poll::Pending
+            }
+            State::Taken => panic!("ReadinessFd::poll_take: Guard already taken."),
+        }
+    }
+
+    /// Returns `true` if the guard has been taken.
+    #[inline]
+    pub fn is_taken(&self) -> bool {
+        matches!(self.state, State::Taken)
+    }
+
+    /// Polls the request, leaving the descriptor readable if granted.
+    fn poll_granted(&mut self) {
+        if let State::Waiting(ref mut future_guard) = self.state {
+            let waker = Waker::from(self.waker.clone());
+            let cx = &mut Context::from_waker(&waker);
+            if let Poll::Ready(res) = Pin::new(future_guard).poll(cx) {
+                self.state = State::Granted(res);
+                self.waker.signal();
+            }
+        }
+    }
+}
+
+impl<T> AsRawFd for ReadinessFd<T> {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.waker.0.as_raw_fd()
+    }
+}
+
+impl<T> AsFd for ReadinessFd<T> {
+    #[inline]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.waker.0.as_fd()
+    }
+}
+
+impl<T> fmt::Debug for ReadinessFd<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state {
+            State::Waiting(_) => "Waiting",
+            State::Granted(_) => "Granted",
+            State::Taken => "Taken",
+        };
+        f.debug_struct("ReadinessFd")
+            .field("fd", &self.as_raw_fd())
+            .field("state", &state)
+            .finish()
+    }
+}
+
+impl<T> Qutex<T> {
+    /// Queues a request for the lock and returns it as a `ReadinessFd`,
+    /// whose descriptor becomes readable once the request may have been
+    /// granted, for waiting on the lock from a C event loop or another
+    /// reactor alongside its other descriptors.
+    ///
+    /// Fails only if no eventfd can be created.
+    pub fn readiness_fd(self) -> io::Result<ReadinessFd<T>> {
+        let mut request = ReadinessFd {
+            waker: Arc::new(FdWaker::new()?),
+            state: State::Waiting(self.lock()),
+        };
+        request.poll_granted();
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Returns `true` if `fd` becomes readable within `timeout_ms`.
+    fn readable(fd: &impl AsRawFd, timeout_ms: i32) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, timeout_ms) == 1 }
+    }
+
+    #[test]
+    fn readable_once_granted() {
+        let qutex = Qutex::new(0u32);
+
+        // Granted straight away:
+        let mut request = qutex.clone().readiness_fd().unwrap();
+        assert!(readable(&request, 0));
+        let guard = match request.poll_take() {
+            Poll::Ready(res) => res.unwrap(),
+            Poll::Pending => panic!("not granted"),
+        };
+        assert!(request.is_taken());
+
+        let mut request = qutex.clone().readiness_fd().unwrap();
+        assert!(!readable(&request, 0));
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+        });
+        assert!(readable(&request, 5000));
+        match request.poll_take() {
+            Poll::Ready(res) => *res.unwrap() += 1,
+            Poll::Pending => panic!("not granted"),
+        }
+        releaser.join().unwrap();
+        assert_eq!(*qutex.try_lock().unwrap(), 1);
+
+        // Withdrawn once dropped:
+        let guard = qutex.clone().try_lock().unwrap();
+        drop(qutex.clone().readiness_fd().unwrap());
+        drop(guard);
+        assert!(qutex.try_lock().is_some());
+    }
+}
@@ -0,0 +1,341 @@
+//! A queue-backed lock which its current holder may re-acquire.
+//!
+//
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Identifies the holder of a `ReentrantQutex`, or of a `Qutex` locked
+/// through `Qutex::lock_as`.
+///
+/// An owner stands for whatever may hold the lock at most once at a time,
+/// usually a task, and is created with `Owner::new` and passed along to
+/// each `ReentrantQutex::lock` it makes. There is deliberately no owner per
+/// thread: several tasks on one thread sharing an owner would re-enter each
+/// other's hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Owner(u32);
+
+impl Owner {
+    /// Returns a new, unique, owner token.
+    pub fn new() -> Owner {
+        static NEXT: AtomicU32 = AtomicU32::new(1);
+
+        loop {
+            // Zero designates 'no owner':
+            let id = NEXT.fetch_add(1, SeqCst);
+            if id != 0 {
+                return Owner(id);
+            }
+        }
+    }
+
+    /// Returns the id of this owner, which is never zero.
+    #[inline]
+    pub(crate) fn id(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Owner {
+    #[inline]
+    fn default() -> Owner {
+        Owner::new()
+    }
+}
+
+/// Packs an owner and a hold depth into a single state word so that both can
+/// be compared and updated together.
+#[inline]
+fn pack(owner: Owner, depth: u32) -> u64 {
+    ((owner.0 as u64) << 32) | depth as u64
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    qutex: Qutex<T>,
+    // The current owner (high bits) and depth (low bits):
+    state: AtomicU64,
+    // The guard of the outermost acquisition:
+    guard: UnsafeCell<Option<Guard<T>>>,
+}
+
+impl<T> Inner<T> {
+    /// Increments the depth if `owner` currently holds the lock.
+    fn try_reenter(&self, owner: Owner) -> bool {
+        self.state
+            .fetch_update(SeqCst, SeqCst, |state| {
+                if state >> 32 == owner.0 as u64 && state as u32 != 0 {
+                    Some(state + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for Inner<T> {}
+unsafe impl<T: Send + Sync> Sync for Inner<T> {}
+
+/// Allows shared access to the data contained within a `ReentrantQutex`.
+///
+/// The lock is released once the outermost guard, and every nested guard,
+/// has been dropped.
+#[derive(Debug)]
+pub struct ReentrantGuard<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Deref for ReentrantGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.qutex.as_ptr() }
+    }
+}
+
+impl<T> Drop for ReentrantGuard<T> {
+    fn drop(&mut self) {
+        let prev = self
+            .inner
+            .state
+            .fetch_update(SeqCst, SeqCst, |state| {
+                Some(if state as u32 == 1 { 0 } else { state - 1 })
+            })
+            .unwrap();
+
+        if prev as u32 == 1 {
+            // We were the last. Nobody can re-enter now that the state is
+            // cleared and nobody else can lock until the guard is dropped.
+            unsafe { (*self.inner.guard.get()).take() };
+        }
+    }
+}
+
+/// A future which resolves to a `ReentrantGuard`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureReentrantGuard<T> {
+    inner: Option<Arc<Inner<T>>>,
+    owner: Owner,
+    // `None` if re-entered:
+    future_guard: Option<FutureGuard<T>>,
+}
+
+impl<T> FutureReentrantGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<ReentrantGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureReentrantGuard<T> {
+    type Output = Result<ReentrantGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.inner.is_none() {
+            panic!("FutureReentrantGuard::poll: Task already completed.");
+        }
+
+        if let Some(ref mut future_guard) = this.future_guard {
+            let guard = futures::ready!(Pin::new(future_guard).poll(cx))?;
+            let inner = this.inner.as_ref().unwrap();
+            unsafe { *inner.guard.get() = Some(guard) };
+            inner.state.store(pack(this.owner, 1), SeqCst);
+        }
+
+        Poll::Ready(Ok(ReentrantGuard {
+            inner: this.inner.take().unwrap(),
+        }))
+    }
+}
+
+/// A queue-backed lock which may be locked again, without deadlocking, by
+/// the owner already holding it.
+///
+/// Because nested guards coexist, guards only allow shared (`&T`) access.
+/// Use interior mutability (e.g. `Cell` or `RefCell`) to mutate the data.
+///
+/// Only re-acquisitions by the current holder bypass the queue. Everyone
+/// else is granted the lock in FIFO order as with a `Qutex`.
+#[derive(Debug)]
+pub struct ReentrantQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> ReentrantQutex<T> {
+    /// Creates and returns a new `ReentrantQutex`.
+    #[inline]
+    pub fn new(val: T) -> ReentrantQutex<T> {
+        ReentrantQutex {
+            inner: Arc::new(Inner {
+                qutex: Qutex::new(val),
+                state: AtomicU64::new(0),
+                guard: UnsafeCell::new(None),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureReentrantGuard` on behalf of `owner`.
+    ///
+    /// Resolves immediately if `owner` already holds the lock.
+    pub fn lock(self, owner: Owner) -> FutureReentrantGuard<T> {
+        let future_guard = if self.inner.try_reenter(owner) {
+            None
+        } else {
+            Some(self.inner.qutex.clone().lock())
+        };
+
+        FutureReentrantGuard {
+            inner: Some(self.inner),
+            owner,
+            future_guard,
+        }
+    }
+
+    /// Returns the owner currently holding the lock, if any.
+    pub fn owner(&self) -> Option<Owner> {
+        match self.inner.state.load(SeqCst) {
+            0 => None,
+            state => Some(Owner((state >> 32) as u32)),
+        }
+    }
+
+    /// Returns a mutable reference to the inner value if there are currently
+    /// no other copies of this `ReentrantQutex`.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(&mut self.inner).and_then(|inner| inner.qutex.get_mut())
+    }
+}
+
+impl<T> From<T> for ReentrantQutex<T> {
+    #[inline]
+    fn from(val: T) -> ReentrantQutex<T> {
+        ReentrantQutex::new(val)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for ReentrantQutex<T> {
+    #[inline]
+    fn clone(&self) -> ReentrantQutex<T> {
+        ReentrantQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn nested() {
+        let lock = ReentrantQutex::new(Cell::new(0));
+        let owner = Owner::new();
+
+        let outer = lock.clone().lock(owner).wait().unwrap();
+        let inner = lock.clone().lock(owner).wait().unwrap();
+        inner.set(inner.get() + 1);
+        assert_eq!(outer.get(), 1);
+        assert_eq!(lock.owner(), Some(owner));
+
+        drop(outer);
+        assert_eq!(lock.owner(), Some(owner));
+        drop(inner);
+        assert_eq!(lock.owner(), None);
+    }
+
+    #[test]
+    fn excludes_other_owners() {
+        let lock = ReentrantQutex::new(AtomicUsize::new(0));
+        let owner = Owner::new();
+        let outer = lock.clone().lock(owner).wait().unwrap();
+        let nested = lock.clone().lock(owner).wait().unwrap();
+
+        let lock_c = lock.clone();
+        let thread = thread::spawn(move || {
+            let guard = lock_c.lock(Owner::new()).wait().unwrap();
+            assert_eq!(guard.load(SeqCst), 2);
+        });
+
+        thread::sleep(::std::time::Duration::from_millis(20));
+        outer.fetch_add(1, SeqCst);
+        drop(outer);
+        thread::sleep(::std::time::Duration::from_millis(20));
+        nested.fetch_add(1, SeqCst);
+        drop(nested);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn tasks_on_one_thread() {
+        let lock = ReentrantQutex::new(Cell::new(0));
+        let entered = Rc::new(Cell::new(false));
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut pool = LocalPool::new();
+
+        let first = lock.clone();
+        let task = async move {
+            let guard = first.lock(Owner::new()).await.unwrap();
+            rx.await.unwrap();
+            guard.set(1);
+        };
+        pool.spawner().spawn_local(task).unwrap();
+
+        let (second, entered_c) = (lock.clone(), entered.clone());
+        let task = async move {
+            let guard = second.lock(Owner::new()).await.unwrap();
+            entered_c.set(true);
+            assert_eq!(guard.get(), 1);
+        };
+        pool.spawner().spawn_local(task).unwrap();
+
+        // The second task waits, despite running on the same thread:
+        pool.run_until_stalled();
+        assert!(!entered.get());
+
+        tx.send(()).unwrap();
+        pool.run();
+        assert!(entered.get());
+        assert_eq!(lock.owner(), None);
+    }
+
+    #[test]
+    fn owner_across_threads() {
+        let lock = ReentrantQutex::new(());
+        let owner = Owner::new();
+
+        let outer = lock.clone().lock(owner).wait().unwrap();
+
+        // Re-entered from another thread on behalf of the same owner:
+        let lock_c = lock.clone();
+        thread::spawn(move || drop(lock_c.lock(owner).wait().unwrap()))
+            .join()
+            .unwrap();
+
+        assert_eq!(lock.owner(), Some(owner));
+        drop(outer);
+        assert_eq!(lock.owner(), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! A global registry of named locks, enabled by the `registry` feature.
+//!
+//! Every `Qutex` built with a name registers itself for as long as it
+//! lives, so that the state of all of them can be dumped at once, such as
+//! from an admin endpoint.
+//
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+
+/// Reads the live state of a registered lock: whether it is held, and the
+/// number of waiting requests.
+pub(crate) type Inspect = unsafe fn(usize) -> (bool, usize);
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    inspect: Inspect,
+}
+
+// By lock address. Locks unregister themselves while dropped, before any of
+// their state is, so every entry is safe to inspect while this is held:
+static REGISTRY: Mutex<BTreeMap<usize, Entry>> = Mutex::new(BTreeMap::new());
+
+/// Registers the lock at `lock`.
+///
+/// # Safety
+///
+/// `lock` must stay valid to be passed to `inspect` until unregistered.
+pub(crate) unsafe fn register(lock: usize, name: &str, inspect: Inspect) {
+    let entry = Entry {
+        name: name.to_owned(),
+        inspect,
+    };
+    REGISTRY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(lock, entry);
+}
+
+/// Unregisters the lock at `lock`.
+pub(crate) fn unregister(lock: usize) {
+    REGISTRY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(&lock);
+}
+
+/// The state of a registered lock at the time `registered_locks` was
+/// called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    name: String,
+    is_locked: bool,
+    waiters: usize,
+}
+
+impl LockInfo {
+    /// Returns the lock's name.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` if the lock was held.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+
+    /// Returns the number of requests waiting for the lock.
+    #[inline]
+    pub fn waiters(&self) -> usize {
+        self.waiters
+    }
+}
+
+/// Returns the state of every live named `Qutex`, ordered by address.
+///
+/// Each lock's state is read separately, so the snapshot as a whole is not
+/// atomic. Names need not be unique.
+pub fn registered_locks() -> impl Iterator<Item = LockInfo> {
+    let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    let locks = registry
+        .iter()
+        .map(|(&lock, entry)| {
+            let (is_locked, waiters) = unsafe { (entry.inspect)(lock) };
+            LockInfo {
+                name: entry.name.clone(),
+                is_locked,
+                waiters,
+            }
+        })
+        .collect::<Vec<_>>();
+    locks.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Qutex, QutexBuilder};
+
+    /// Returns the registered locks named `name`, as other tests share the
+    /// registry.
+    fn named(name: &str) -> Vec<LockInfo> {
+        registered_locks()
+            .filter(|lock| lock.name() == name)
+            .collect()
+    }
+
+    #[test]
+    fn registered() {
+        let lock = QutexBuilder::new().name("registered").build(0);
+        let _unnamed = Qutex::new(0);
+        assert_eq!(named("registered").len(), 1);
+        assert!(!named("registered")[0].is_locked());
+
+        let guard = lock.clone().lock().wait().unwrap();
+        let _waiting = (lock.clone().lock(), lock.clone().lock());
+        let info = &named("registered")[0];
+        assert!(info.is_locked());
+        assert_eq!(info.waiters(), 2);
+
+        // Unregistered once the last handle is gone:
+        drop((guard, _waiting));
+        drop(lock);
+        assert!(named("registered").is_empty());
+    }
+
+    #[test]
+    fn coerced() {
+        let lock = QutexBuilder::new().name("coerced").build(0);
+        let lock: Qutex<dyn Send> = lock.coerce(|boxed| boxed as Box<_>).unwrap();
+        let _guard = lock.try_lock().unwrap();
+        assert_eq!(named("coerced").len(), 1);
+        assert!(named("coerced")[0].is_locked());
+        drop((_guard, lock));
+        assert!(named("coerced").is_empty());
+    }
+}
@@ -0,0 +1,534 @@
+//! A fair, asynchronous counting semaphore.
+//!
+//
+
+use crate::queue::SegQueue;
+use futures::channel::oneshot::{self, Canceled, Receiver, Sender};
+use futures::{executor, Stream};
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// A queued request for some number of permits.
+#[derive(Debug)]
+struct Waiter {
+    permits: usize,
+    tx: Sender<()>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    permits: AtomicUsize,
+    queue: SegQueue<Waiter>,
+    // The first waiter, popped but not yet satisfied. Only touched while
+    // `draining` is held:
+    head: UnsafeCell<Option<Waiter>>,
+    head_blocked: AtomicBool,
+    draining: AtomicBool,
+    // Set by every call to `drain`, so that whoever is draining makes
+    // another pass:
+    requested: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl Inner {
+    /// Returns `true` if any waiters are queued or held at the head.
+    #[inline]
+    fn has_waiters(&self) -> bool {
+        self.head_blocked.load(SeqCst) || !self.queue.is_empty()
+    }
+
+    /// Takes `permits` permits if there are enough, nobody is waiting and
+    /// the semaphore is open.
+    fn try_take(&self, permits: usize) -> bool {
+        !self.has_waiters()
+            && !self.closed.load(SeqCst)
+            && self
+                .permits
+                .fetch_update(SeqCst, SeqCst, |avail| avail.checked_sub(permits))
+                .is_ok()
+    }
+
+    /// Returns `permits` permits, waking as many waiters as they satisfy.
+    #[inline]
+    fn release(&self, permits: usize) {
+        self.permits.fetch_add(permits, SeqCst);
+        self.drain();
+    }
+
+    /// Hands out the available permits to waiters in order, stopping at the
+    /// first which can not yet be satisfied, or turns every waiter away once
+    /// closed.
+    ///
+    /// However many permits were released, every waiter they satisfy is
+    /// woken within a single pass. A call made while another thread is
+    /// draining leaves that thread to make another pass on its behalf.
+    fn drain(&self) {
+        self.requested.store(true, SeqCst);
+
+        while !self.draining.swap(true, SeqCst) {
+            self.requested.store(false, SeqCst);
+            let head = unsafe { &mut *self.head.get() };
+
+            while let Some(waiter) = head.take().or_else(|| self.queue.pop()) {
+                // Abandoned by its requester, or turned away, failing its
+                // request as its sender is dropped:
+                if waiter.tx.is_canceled() || self.closed.load(SeqCst) {
+                    continue;
+                }
+
+                let permits = waiter.permits;
+                let taken = self
+                    .permits
+                    .fetch_update(SeqCst, SeqCst, |avail| avail.checked_sub(permits));
+
+                if taken.is_err() {
+                    *head = Some(waiter);
+                    break;
+                }
+                if waiter.tx.send(()).is_err() {
+                    self.permits.fetch_add(permits, SeqCst);
+                }
+            }
+
+            self.head_blocked.store(head.is_some(), SeqCst);
+            self.draining.store(false, SeqCst);
+
+            if !self.requested.load(SeqCst) {
+                return;
+            }
+        }
+    }
+}
+
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+/// Holds permits acquired from a `Semaphore`, returning them when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    inner: Arc<Inner>,
+    permits: usize,
+}
+
+impl Permit {
+    /// Returns the number of permits held.
+    #[inline]
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Drops this `Permit` without returning its permits to the semaphore.
+    #[inline]
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.permits != 0 {
+            self.inner.release(self.permits);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Unqueued,
+    Queued(Receiver<()>),
+    Done,
+}
+
+/// A future which resolves to a `Permit`.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FuturePermit {
+    inner: Arc<Inner>,
+    permits: usize,
+    state: State,
+}
+
+impl FuturePermit {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<Permit, Canceled> {
+        executor::block_on(self)
+    }
+
+    #[inline]
+    fn permit(&self) -> Permit {
+        Permit {
+            inner: self.inner.clone(),
+            permits: self.permits,
+        }
+    }
+}
+
+impl Future for FuturePermit {
+    type Output = Result<Permit, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match mem::replace(&mut this.state, State::Done) {
+                State::Unqueued => {
+                    if this.inner.closed.load(SeqCst) {
+                        return Poll::Ready(Err(Canceled));
+                    }
+                    if this.permits == 0 || this.inner.try_take(this.permits) {
+                        return Poll::Ready(Ok(this.permit()));
+                    }
+
+                    let (tx, rx) = oneshot::channel();
+                    this.inner.queue.push(Waiter {
+                        permits: this.permits,
+                        tx,
+                    });
+                    // Permits may have been returned before we were queued:
+                    this.inner.drain();
+                    this.state = State::Queued(rx);
+                }
+                State::Queued(mut rx) => {
+                    return match Pin::new(&mut rx).poll(cx) {
+                        // The sender is only dropped unsent once closed:
+                        Poll::Ready(res) => Poll::Ready(res.map(|()| this.permit())),
+                        Poll::Pending => {
+                            this.state = State::Queued(rx);
+                            Poll::Pending
+                        }
+                    };
+                }
+                State::Done => panic!("FuturePermit::poll: Task already completed."),
+            }
+        }
+    }
+}
+
+impl Drop for FuturePermit {
+    /// Returns the permits if granted but not yet received, and otherwise
+    /// lets any waiters held up behind this one proceed.
+    fn drop(&mut self) {
+        if let State::Queued(mut rx) = mem::replace(&mut self.state, State::Done) {
+            rx.close();
+
+            match rx.try_recv() {
+                Ok(Some(())) => self.inner.release(self.permits),
+                _ => self.inner.drain(),
+            }
+        }
+    }
+}
+
+/// A `Stream` of `Permit`s, yielding one each time a permit becomes
+/// available, as returned by `Semaphore::permits_stream`.
+///
+/// Each permit is requested only once the stream is polled after yielding
+/// the previous one, queueing behind any other waiters. The stream ends
+/// once the semaphore is closed.
+#[must_use = "streams do nothing unless polled"]
+#[derive(Debug)]
+pub struct PermitStream {
+    inner: Arc<Inner>,
+    pending: Option<FuturePermit>,
+}
+
+impl Stream for PermitStream {
+    type Item = Permit;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Permit>> {
+        let this = self.get_mut();
+        let inner = &this.inner;
+        let pending = this.pending.get_or_insert_with(|| FuturePermit {
+            inner: inner.clone(),
+            permits: 1,
+            state: State::Unqueued,
+        });
+        let res = futures::ready!(Pin::new(pending).poll(cx));
+        this.pending = None;
+        Poll::Ready(res.ok())
+    }
+}
+
+/// An asynchronous counting semaphore.
+///
+/// Waiters are served in FIFO order: one waiting for more permits than are
+/// available holds up those queued behind it, even if they would fit.
+/// Returning permits wakes every waiter they satisfy in a single pass over
+/// the queue, so fanning out to many waiters at once stays cheap.
+///
+/// Waiting for more permits than will ever be available never resolves.
+#[derive(Debug, Clone)]
+pub struct Semaphore {
+    inner: Arc<Inner>,
+}
+
+impl Semaphore {
+    /// Creates and returns a new `Semaphore` with `permits` permits.
+    #[inline]
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            inner: Arc::new(Inner {
+                permits: AtomicUsize::new(permits),
+                queue: SegQueue::new(),
+                head: UnsafeCell::new(None),
+                head_blocked: AtomicBool::new(false),
+                draining: AtomicBool::new(false),
+                requested: AtomicBool::new(false),
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Returns the number of permits currently available.
+    #[inline]
+    pub fn available_permits(&self) -> usize {
+        self.inner.permits.load(SeqCst)
+    }
+
+    /// Returns the number of waiters queued for permits, including any which
+    /// have given up but are yet to be discarded.
+    #[inline]
+    pub fn queued_waiters(&self) -> usize {
+        self.inner.queue.len() + self.inner.head_blocked.load(SeqCst) as usize
+    }
+
+    /// Closes the semaphore, failing every queued and future request for
+    /// permits with `Canceled`, and every `try_acquire` with `None`.
+    ///
+    /// Permits already held remain valid, and are returned as usual.
+    pub fn close(&self) {
+        self.inner.closed.store(true, SeqCst);
+        self.inner.drain();
+    }
+
+    /// Returns `true` if the semaphore has been closed.
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(SeqCst)
+    }
+
+    /// Adds `permits` permits, waking every waiter they satisfy.
+    #[inline]
+    pub fn add_permits(&self, permits: usize) {
+        self.inner.release(permits);
+    }
+
+    /// Returns a new `FuturePermit` which will resolve to a single permit.
+    #[inline]
+    pub fn acquire(&self) -> FuturePermit {
+        self.acquire_many(1)
+    }
+
+    /// Returns a new `FuturePermit` which will resolve to `permits` permits.
+    #[inline]
+    pub fn acquire_many(&self, permits: usize) -> FuturePermit {
+        FuturePermit {
+            inner: self.inner.clone(),
+            permits,
+            state: State::Unqueued,
+        }
+    }
+
+    /// Returns a new `PermitStream` which yields a single permit each time
+    /// one becomes available, for dispatching work as capacity allows.
+    ///
+    /// ```
+    /// use futures::{executor, StreamExt};
+    /// use qutex::Semaphore;
+    ///
+    /// let sem = Semaphore::new(2);
+    /// let mut permits = sem.permits_stream();
+    /// executor::block_on(async {
+    ///     let first = permits.next().await.unwrap();
+    ///     let _second = permits.next().await.unwrap();
+    ///     // Yielded again once the first is returned:
+    ///     drop(first);
+    ///     assert_eq!(permits.next().await.unwrap().permits(), 1);
+    /// });
+    /// ```
+    #[inline]
+    pub fn permits_stream(&self) -> PermitStream {
+        PermitStream {
+            inner: self.inner.clone(),
+            pending: None,
+        }
+    }
+
+    /// Returns a single permit if one is available and nobody is waiting.
+    ///
+    /// Never waits or queues, so that failing leaves the semaphore as it
+    /// was, for paths which would rather skip the work than wait for it.
+    #[inline]
+    pub fn try_acquire(&self) -> Option<Permit> {
+        self.try_acquire_many(1)
+    }
+
+    /// Returns `permits` permits if enough are available and nobody is
+    /// waiting.
+    ///
+    /// Never waits or queues, and takes none of the permits on failure.
+    pub fn try_acquire_many(&self, permits: usize) -> Option<Permit> {
+        if self.inner.try_take(permits) {
+            Some(Permit {
+                inner: self.inner.clone(),
+                permits,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::thread;
+
+    #[test]
+    fn batch_release() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let sem = Semaphore::new(0);
+        let mut waiters = (0..4).map(|_| sem.acquire()).collect::<Vec<_>>();
+        for waiter in waiters.iter_mut() {
+            assert!(waiter.poll_unpin(cx).is_pending());
+        }
+
+        // All three are granted by the one call:
+        sem.add_permits(3);
+        let ready = waiters
+            .iter_mut()
+            .map(|waiter| waiter.poll_unpin(cx))
+            .collect::<Vec<_>>();
+        assert!(ready[..3].iter().all(Poll::is_ready));
+        assert!(ready[3].is_pending());
+        assert_eq!(sem.available_permits(), 0);
+
+        // Passed on to the last:
+        drop(ready);
+        assert_eq!(sem.available_permits(), 2);
+        waiters.pop().unwrap().wait().unwrap();
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn fifo() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let sem = Semaphore::new(1);
+        let permit = sem.try_acquire().unwrap();
+
+        let mut many = sem.acquire_many(2);
+        let mut one = sem.acquire();
+        assert!(many.poll_unpin(cx).is_pending());
+        assert!(one.poll_unpin(cx).is_pending());
+
+        // Held up behind the head, although it would fit:
+        sem.add_permits(1);
+        assert!(one.poll_unpin(cx).is_pending());
+        assert!(sem.try_acquire().is_none());
+
+        // Until the head gives up:
+        drop(many);
+        assert_eq!(one.wait().unwrap().permits(), 1);
+        drop(permit);
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn try_acquire_many() {
+        let sem = Semaphore::new(3);
+        assert!(sem.try_acquire_many(4).is_none());
+        // Failing takes nothing, and holds nobody up:
+        assert_eq!(sem.available_permits(), 3);
+        assert!(!sem.inner.has_waiters());
+
+        let permit = sem.try_acquire_many(2).unwrap();
+        assert_eq!(permit.permits(), 2);
+        assert!(sem.try_acquire_many(2).is_none());
+        assert_eq!(sem.try_acquire_many(0).unwrap().permits(), 0);
+        drop(permit);
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn permits_stream() {
+        use futures::StreamExt;
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let sem = Semaphore::new(1);
+        let mut permits = sem.permits_stream();
+        let first = match permits.poll_next_unpin(cx) {
+            Poll::Ready(Some(permit)) => permit,
+            _ => panic!("permit not yielded"),
+        };
+        assert!(permits.poll_next_unpin(cx).is_pending());
+
+        // Queued, so ahead of later requests:
+        let mut later = sem.acquire();
+        drop(first);
+        assert!(later.poll_unpin(cx).is_pending());
+        assert!(matches!(permits.poll_next_unpin(cx), Poll::Ready(Some(_))));
+        assert!(later.poll_unpin(cx).is_ready());
+    }
+
+    #[test]
+    fn close() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let sem = Semaphore::new(1);
+        let permit = sem.acquire().wait().unwrap();
+        let mut waiters = (0..2).map(|_| sem.acquire()).collect::<Vec<_>>();
+        for waiter in &mut waiters {
+            assert!(waiter.poll_unpin(cx).is_pending());
+        }
+        assert_eq!(sem.queued_waiters(), 2);
+        assert!(!sem.is_closed());
+
+        sem.close();
+        assert!(sem.is_closed());
+        for waiter in waiters {
+            assert_eq!(waiter.wait().unwrap_err(), Canceled);
+        }
+        assert_eq!(sem.queued_waiters(), 0);
+        assert!(sem.acquire().wait().is_err() && sem.try_acquire().is_none());
+
+        drop(permit);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn contended() {
+        let sem = Semaphore::new(3);
+        let active = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..8)
+            .map(|i| {
+                let sem = sem.clone();
+                let active = active.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let permit = sem.acquire_many(1 + i % 2).wait().unwrap();
+                        let now = active.fetch_add(permit.permits(), SeqCst);
+                        assert!(now + permit.permits() <= 3);
+                        active.fetch_sub(permit.permits(), SeqCst);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(sem.acquire_many(3).wait().unwrap().permits(), 3);
+        assert_eq!(sem.available_permits(), 3);
+    }
+}
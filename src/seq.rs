@@ -0,0 +1,241 @@
+//! A sequence lock for small, copyable, read-mostly data.
+//!
+//
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicUsize};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::{hint, ptr};
+
+#[derive(Debug)]
+struct Inner<T> {
+    // Odd while a write is in progress:
+    seq: AtomicUsize,
+    cell: UnsafeCell<T>,
+    // Serializes writers:
+    writer: Qutex<()>,
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// Allows exclusive write access to the data within a `SeqQutex`.
+///
+/// Readers retry until this guard is dropped.
+#[derive(Debug)]
+pub struct SeqWriteGuard<T> {
+    inner: Arc<Inner<T>>,
+    _writer: Guard<()>,
+}
+
+impl<T> Deref for SeqWriteGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.cell.get() }
+    }
+}
+
+impl<T> DerefMut for SeqWriteGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.inner.cell.get() }
+    }
+}
+
+impl<T> Drop for SeqWriteGuard<T> {
+    fn drop(&mut self) {
+        // Back to even, before the writer lock is released:
+        self.inner.seq.fetch_add(1, Release);
+    }
+}
+
+/// A future which resolves to a `SeqWriteGuard`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureSeqWriteGuard<T> {
+    inner: Arc<Inner<T>>,
+    writer: FutureGuard<()>,
+}
+
+impl<T> FutureSeqWriteGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<SeqWriteGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureSeqWriteGuard<T> {
+    type Output = Result<SeqWriteGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let writer = futures::ready!(Pin::new(&mut this.writer).poll(cx))?;
+
+        // Odd for the duration of the write:
+        this.inner.seq.fetch_add(1, Relaxed);
+        fence(Release);
+
+        Poll::Ready(Ok(SeqWriteGuard {
+            inner: this.inner.clone(),
+            _writer: writer,
+        }))
+    }
+}
+
+/// A sequence lock: reads are optimistic, copying the data without any
+/// atomic read-modify-write and retrying if a write overlapped, while writes
+/// are queued exclusively as with a `Qutex`.
+///
+/// Best suited to small `Copy` data which is read far more often than it is
+/// written. Readers never block writers but may retry repeatedly while
+/// writes are frequent.
+#[derive(Debug)]
+pub struct SeqQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> SeqQutex<T> {
+    /// Creates and returns a new `SeqQutex`.
+    #[inline]
+    pub fn new(val: T) -> SeqQutex<T> {
+        SeqQutex {
+            inner: Arc::new(Inner {
+                seq: AtomicUsize::new(0),
+                cell: UnsafeCell::new(val),
+                writer: Qutex::new(()),
+            }),
+        }
+    }
+
+    /// Returns a copy of the data, retrying until no write overlaps the read.
+    pub fn read(&self) -> T
+    where
+        T: Copy,
+    {
+        loop {
+            if let Some(val) = self.try_read() {
+                return val;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Returns a copy of the data, or `None` if a write overlapped the read.
+    pub fn try_read(&self) -> Option<T>
+    where
+        T: Copy,
+    {
+        let seq = self.inner.seq.load(Acquire);
+        if seq & 1 == 1 {
+            return None;
+        }
+
+        // A torn read is possible here, and may not be a valid `T`, so is
+        // only assumed to be one once no write is found to have overlapped:
+        let val = unsafe { ptr::read_volatile(self.inner.cell.get() as *const MaybeUninit<T>) };
+        fence(Acquire);
+
+        if self.inner.seq.load(Relaxed) == seq {
+            Some(unsafe { val.assume_init() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a new `FutureSeqWriteGuard` which will resolve into a
+    /// `SeqWriteGuard` once all earlier writers have finished.
+    #[inline]
+    pub fn write(&self) -> FutureSeqWriteGuard<T> {
+        FutureSeqWriteGuard {
+            inner: self.inner.clone(),
+            writer: self.inner.writer.clone().lock(),
+        }
+    }
+
+    /// Returns the number of writes completed so far.
+    #[inline]
+    pub fn version(&self) -> usize {
+        self.inner.seq.load(SeqCst) / 2
+    }
+
+    /// Returns a mutable reference to the inner value if there are currently
+    /// no other copies of this `SeqQutex`.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(&mut self.inner).map(|inner| inner.cell.get_mut())
+    }
+}
+
+impl<T> From<T> for SeqQutex<T> {
+    #[inline]
+    fn from(val: T) -> SeqQutex<T> {
+        SeqQutex::new(val)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for SeqQutex<T> {
+    #[inline]
+    fn clone(&self) -> SeqQutex<T> {
+        SeqQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn read_write() {
+        let seq = SeqQutex::new((1u64, 2u64));
+        assert_eq!(seq.read(), (1, 2));
+
+        let mut guard = seq.write().wait().unwrap();
+        guard.0 = 10;
+        assert_eq!(seq.try_read(), None);
+        drop(guard);
+
+        assert_eq!(seq.read(), (10, 2));
+        assert_eq!(seq.version(), 1);
+    }
+
+    #[test]
+    fn reads_are_never_torn() {
+        let seq = SeqQutex::new([0u64; 8]);
+
+        let writers = (0..2)
+            .map(|_| {
+                let seq = seq.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let mut guard = seq.write().wait().unwrap();
+                        let next = guard[0] + 1;
+                        *guard = [next; 8];
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        while seq.version() < 1000 {
+            let val = seq.read();
+            assert!(val.iter().all(|&v| v == val[0]));
+        }
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(seq.read(), [1000; 8]);
+    }
+}
@@ -0,0 +1,137 @@
+//! `Serialize` and `Deserialize` for locks, enabled by the `serde` feature.
+//!
+//! Serializing a lock serializes its value, taking the lock without waiting
+//! for the duration. A lock which is taken, or has requests queued, fails to
+//! serialize rather than block the serializer. Deserializing creates a new,
+//! unlocked lock.
+//
+
+use crate::Qutex;
+#[cfg(feature = "std")]
+use crate::{QrwLock, QutexMap};
+#[cfg(feature = "std")]
+use futures::FutureExt;
+use serde::de::{Deserialize, Deserializer};
+#[cfg(feature = "std")]
+use serde::ser::SerializeMap;
+use serde::ser::{Error, Serialize, Serializer};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::hash::Hash;
+
+impl<T: Serialize> Serialize for Qutex<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.try_lock() {
+            Some(guard) => (*guard).serialize(serializer),
+            None => Err(S::Error::custom("Qutex is locked")),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Qutex<T> {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Qutex<T>, D::Error> {
+        T::deserialize(deserializer).map(Qutex::new)
+    }
+}
+
+/// Serializes by read-locking, so alongside other readers.
+#[cfg(feature = "std")]
+impl<T: Serialize> Serialize for QrwLock<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.clone().read().now_or_never() {
+            Some(Ok(guard)) => (*guard).serialize(serializer),
+            _ => Err(S::Error::custom("QrwLock is write locked")),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for QrwLock<T> {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<QrwLock<T>, D::Error> {
+        T::deserialize(deserializer).map(QrwLock::new)
+    }
+}
+
+/// Serializes as a map of each key to its value, locking each value in turn
+/// so that the map as a whole is not a consistent snapshot.
+#[cfg(feature = "std")]
+impl<K, T> Serialize for QutexMap<K, T>
+where
+    K: Serialize + Eq + Hash + Clone,
+    T: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries = self.entries();
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (key, qutex) in &entries {
+            map.serialize_entry(key, qutex)?;
+        }
+        map.end()
+    }
+}
+
+/// Deserializes into a map initializing new values with `T::default()`,
+/// never evicting entries.
+#[cfg(feature = "std")]
+impl<'de, K, T> Deserialize<'de> for QutexMap<K, T>
+where
+    K: Deserialize<'de> + Eq + Hash + Clone,
+    T: Deserialize<'de> + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<QutexMap<K, T>, D::Error> {
+        let map = QutexMap::new();
+        for (key, val) in HashMap::<K, T>::deserialize(deserializer)? {
+            map.insert(key, val);
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{QrwLock, Qutex, QutexMap};
+
+    #[test]
+    fn qutex() {
+        let qutex = Qutex::new(vec![1, 2]);
+        assert_eq!(serde_json::to_string(&qutex).unwrap(), "[1,2]");
+
+        let guard = qutex.try_lock().unwrap();
+        let err = serde_json::to_string(&qutex).unwrap_err();
+        assert_eq!(err.to_string(), "Qutex is locked");
+        drop(guard);
+
+        let qutex: Qutex<Vec<u32>> = serde_json::from_str("[3]").unwrap();
+        assert_eq!(*qutex.try_lock().unwrap(), [3]);
+    }
+
+    #[test]
+    fn qrw_lock() {
+        let lock = QrwLock::new(5);
+        let read = lock.clone().read().wait().unwrap();
+        assert_eq!(serde_json::to_string(&lock).unwrap(), "5");
+        drop(read);
+
+        let write = lock.clone().write().wait().unwrap();
+        let err = serde_json::to_string(&lock).unwrap_err();
+        assert_eq!(err.to_string(), "QrwLock is write locked");
+        drop(write);
+
+        let lock: QrwLock<u8> = serde_json::from_str("7").unwrap();
+        assert_eq!(*lock.read().wait().unwrap(), 7);
+    }
+
+    #[test]
+    fn map() {
+        let map = QutexMap::<String, u32>::new();
+        *map.lock("a".into()).wait().unwrap() = 1;
+        assert_eq!(serde_json::to_string(&map).unwrap(), r#"{"a":1}"#);
+
+        let map: QutexMap<String, u32> = serde_json::from_str(r#"{"b":2}"#).unwrap();
+        assert_eq!(*map.lock("b".into()).wait().unwrap(), 2);
+        assert_eq!(*map.lock("c".into()).wait().unwrap(), 0);
+    }
+}
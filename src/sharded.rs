@@ -0,0 +1,161 @@
+//! A set of independently locked shards selected by key.
+//!
+//
+
+use crate::{FutureGroupGuard, FutureGuard, Qutex, QutexGroup};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+/// A fixed number of independent `Qutex` shards.
+///
+/// Each key is routed, by hash, to one shard so that tasks working with
+/// different keys usually do not contend. Useful for striping counters,
+/// caches, and the like without a map of per-key locks (see `QutexMap`).
+#[derive(Debug)]
+pub struct ShardedQutex<T, S = RandomState> {
+    shards: Arc<[Qutex<T>]>,
+    hasher: S,
+}
+
+impl<T> ShardedQutex<T> {
+    /// Creates and returns a new `ShardedQutex` with `shards` shards, each
+    /// initialized by calling `value_fn` with its index.
+    ///
+    /// Panics if `shards` is zero.
+    #[inline]
+    pub fn new<F>(value_fn: F, shards: usize) -> ShardedQutex<T>
+    where
+        F: FnMut(usize) -> T,
+    {
+        ShardedQutex::with_hasher(value_fn, shards, RandomState::new())
+    }
+}
+
+impl<T, S> ShardedQutex<T, S>
+where
+    S: BuildHasher,
+{
+    /// Creates and returns a new `ShardedQutex` which routes keys using
+    /// `hasher`.
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_hasher<F>(value_fn: F, shards: usize, hasher: S) -> ShardedQutex<T, S>
+    where
+        F: FnMut(usize) -> T,
+    {
+        assert!(
+            shards > 0,
+            "ShardedQutex::with_hasher: At least one shard is required."
+        );
+
+        ShardedQutex {
+            shards: (0..shards).map(value_fn).map(Qutex::new).collect(),
+            hasher,
+        }
+    }
+
+    /// Returns the index of the shard `key` is routed to.
+    #[inline]
+    pub fn shard_for<K>(&self, key: &K) -> usize
+    where
+        K: Hash + ?Sized,
+    {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Returns a new `FutureGuard` for the shard `key` is routed to.
+    #[inline]
+    pub fn lock_for<K>(&self, key: &K) -> FutureGuard<T>
+    where
+        K: Hash + ?Sized,
+    {
+        self.lock_shard(self.shard_for(key))
+    }
+
+    /// Returns a new `FutureGuard` for the shard at `idx`.
+    ///
+    /// Panics if `idx` is out of bounds.
+    #[inline]
+    pub fn lock_shard(&self, idx: usize) -> FutureGuard<T> {
+        self.shards[idx].clone().lock()
+    }
+
+    /// Returns a new `FutureGroupGuard` which will resolve once every shard
+    /// is locked, with guards in shard order.
+    #[inline]
+    pub fn lock_all(&self) -> FutureGroupGuard<T> {
+        QutexGroup::lock_all(self.shards.to_vec())
+    }
+
+    /// Returns the number of shards.
+    #[inline]
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shards.
+    #[inline]
+    pub fn shards(&self) -> &[Qutex<T>] {
+        &self.shards
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T, S> Clone for ShardedQutex<T, S>
+where
+    S: Clone,
+{
+    #[inline]
+    fn clone(&self) -> ShardedQutex<T, S> {
+        ShardedQutex {
+            shards: self.shards.clone(),
+            hasher: self.hasher.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn striped_counter() {
+        let counter = ShardedQutex::new(|_| 0usize, 4);
+
+        let threads = (0..8)
+            .map(|t| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        *counter.lock_for(&(t * 100 + i)).wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let total: usize = counter
+            .lock_all()
+            .wait()
+            .unwrap()
+            .iter()
+            .map(|guard| **guard)
+            .sum();
+        assert_eq!(total, 800);
+    }
+
+    #[test]
+    fn routing_is_stable() {
+        let sharded = ShardedQutex::new(|idx| idx, 8);
+        assert_eq!(sharded.shard_count(), 8);
+
+        let idx = sharded.shard_for("key");
+        assert_eq!(sharded.clone().shard_for("key"), idx);
+        assert_eq!(*sharded.lock_for("key").wait().unwrap(), idx);
+    }
+}
@@ -0,0 +1,284 @@
+//! A spinlock sharing the shape of `Qutex`, for critical sections too short
+//! to be worth queueing for.
+//!
+//
+// A single flag, taken by compare-and-swap. Waiters spin on plain loads
+// between attempts, so as not to keep the cache line bouncing while held.
+
+use crate::Backoff;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::convert::Infallible;
+use core::fmt;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::task::{Context, Poll};
+
+struct Inner<T> {
+    locked: AtomicBool,
+    backoff: Backoff,
+    cell: UnsafeCell<T>,
+}
+
+impl<T> Inner<T> {
+    /// Takes the lock if it is free.
+    #[inline]
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Acquire, Relaxed)
+            .is_ok()
+    }
+
+    /// Spins until the lock is taken, backing off as the `Backoff` allows.
+    fn acquire(&self) {
+        let mut attempt = 0u32;
+        while !self.try_acquire() {
+            // Spinning on loads alone until it looks free:
+            loop {
+                self.backoff.snooze(attempt);
+                attempt = attempt.saturating_add(1);
+                if !self.locked.load(Relaxed) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// Allows access to the data contained within a `SpinQutex`.
+pub struct SpinGuard<T> {
+    qutex: SpinQutex<T>,
+}
+
+impl<T> SpinGuard<T> {
+    /// Releases the lock held by a `SpinGuard` and returns the original
+    /// `SpinQutex`.
+    pub fn unlock(guard: SpinGuard<T>) -> SpinQutex<T> {
+        let qutex = guard.qutex.clone();
+        drop(guard);
+        qutex
+    }
+}
+
+impl<T> Deref for SpinGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.qutex.inner.cell.get() }
+    }
+}
+
+impl<T> DerefMut for SpinGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.qutex.inner.cell.get() }
+    }
+}
+
+impl<T> Drop for SpinGuard<T> {
+    fn drop(&mut self) {
+        self.qutex.inner.locked.store(false, Release);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SpinGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SpinGuard").field(&**self).finish()
+    }
+}
+
+/// A future which resolves to a `SpinGuard`, spinning for the lock when
+/// first polled.
+///
+/// Never returns `Poll::Pending`. Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureSpinGuard<T> {
+    qutex: Option<SpinQutex<T>>,
+}
+
+impl<T> FutureSpinGuard<T> {
+    /// Spins until the lock is acquired.
+    ///
+    /// No executor being involved, this is available without `std`.
+    #[inline]
+    pub fn wait(mut self) -> Result<SpinGuard<T>, Infallible> {
+        Ok(self.acquire())
+    }
+
+    fn acquire(&mut self) -> SpinGuard<T> {
+        let qutex = self
+            .qutex
+            .take()
+            .expect("FutureSpinGuard::poll: Task already completed.");
+        qutex.inner.acquire();
+        SpinGuard { qutex }
+    }
+}
+
+impl<T> Future for FutureSpinGuard<T> {
+    type Output = Result<SpinGuard<T>, Infallible>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Ready(Ok(self.get_mut().acquire()))
+    }
+}
+
+/// A spinlock with the API of `Qutex`, which never queues.
+///
+/// Requests spin for the lock, backing off as the lock's `Backoff` allows
+/// (see `with_backoff`), rather than queueing to be woken, so that a free
+/// lock costs one compare-and-swap to take and one store to release, and
+/// nothing is ever allocated per request. Awaiting `lock` blocks the task
+/// until acquired, so that a `SpinQutex` only suits critical sections far
+/// shorter than the cost of queueing, such as within an executor or next
+/// to interrupt handlers. Requests are served in no particular order.
+///
+/// Switching between it and `Qutex` takes no more than a type change: both
+/// are locked through `lock` and `try_lock`, their guards dereferencing to
+/// the data and being released when dropped or through `unlock`.
+pub struct SpinQutex<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> SpinQutex<T> {
+    /// Creates and returns a new `SpinQutex`, backing off as
+    /// `Backoff::new()` does.
+    #[inline]
+    pub fn new(val: T) -> SpinQutex<T> {
+        SpinQutex::with_backoff(val, Backoff::new())
+    }
+
+    /// Creates and returns a new `SpinQutex` backing off according to
+    /// `backoff`.
+    ///
+    /// Requests past its spinning attempts keep yielding (or, without
+    /// `std`, busy-waiting their longest) until they take the lock, never
+    /// parking, so that its yielding attempts go unused.
+    pub fn with_backoff(val: T, backoff: Backoff) -> SpinQutex<T> {
+        SpinQutex {
+            inner: Arc::new(Inner {
+                locked: AtomicBool::new(false),
+                backoff,
+                cell: UnsafeCell::new(val),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureSpinGuard` which can be used as a future and
+    /// will resolve into a `SpinGuard`.
+    #[inline]
+    pub fn lock(self) -> FutureSpinGuard<T> {
+        FutureSpinGuard { qutex: Some(self) }
+    }
+
+    /// Returns a `SpinGuard` if the lock is free.
+    #[inline]
+    pub fn try_lock(&self) -> Option<SpinGuard<T>> {
+        self.inner.try_acquire().then(|| SpinGuard {
+            qutex: self.clone(),
+        })
+    }
+
+    /// Returns `true` if the lock is held.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.inner.locked.load(Relaxed)
+    }
+
+    /// Returns how requests back off between attempts to take the lock.
+    #[inline]
+    pub fn backoff(&self) -> Backoff {
+        self.inner.backoff
+    }
+
+    /// Returns a mutable reference to the inner value if there are currently
+    /// no other copies of this `SpinQutex`.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        Arc::get_mut(&mut self.inner).map(|inner| inner.cell.get_mut())
+    }
+
+    /// Returns a reference to the inner value.
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        self.inner.cell.get()
+    }
+
+    /// Returns a mutable reference to the inner value.
+    #[inline]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.inner.cell.get()
+    }
+}
+
+impl<T> From<T> for SpinQutex<T> {
+    #[inline]
+    fn from(val: T) -> SpinQutex<T> {
+        SpinQutex::new(val)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for SpinQutex<T> {
+    #[inline]
+    fn clone(&self) -> SpinQutex<T> {
+        SpinQutex {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for SpinQutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpinQutex")
+            .field("locked", &self.is_locked())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn contended() {
+        let total = SpinQutex::with_backoff(0usize, Backoff::new().spins(2));
+
+        let threads = (0..8)
+            .map(|_| {
+                let total = total.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *total.clone().lock().wait().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*total.try_lock().unwrap(), 8000);
+    }
+
+    #[test]
+    fn try_lock() {
+        let mut qutex = SpinQutex::new(vec![1]);
+        let mut guard = futures::executor::block_on(qutex.clone().lock()).unwrap();
+        assert!(qutex.is_locked() && qutex.try_lock().is_none());
+        guard.push(2);
+
+        let mut qutex_c = SpinGuard::unlock(guard);
+        assert!(qutex_c.get_mut().is_none());
+        drop(qutex_c);
+        assert_eq!(qutex.get_mut().unwrap(), &[1, 2]);
+    }
+}
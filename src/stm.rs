@@ -0,0 +1,334 @@
+//! Atomic transactions over several `Qutex`es.
+//!
+//! A transaction is a closure run by `atomically`, reading and writing the
+//! values of whichever locks it likes through a `Txn`. Every lock it
+//! touches is held until the transaction commits, and its writes are made
+//! to copies, applied to all of the locks together only once the closure
+//! returns successfully, so that no other holder of those locks ever sees
+//! the transaction half done.
+//!
+//! ```
+//! use qutex::stm::atomically;
+//! use qutex::Qutex;
+//!
+//! let (checking, savings) = (Qutex::new(100i64), Qutex::new(0i64));
+//!
+//! atomically(|txn| {
+//!     *txn.write(&checking)? -= 30;
+//!     *txn.write(&savings)? += 30;
+//!     Ok(())
+//! })
+//! .wait()
+//! .unwrap();
+//! assert_eq!(*checking.try_lock().unwrap() + *savings.try_lock().unwrap(), 100);
+//! ```
+//
+// The locks a transaction needs are only learnt by running it, so they
+// cannot all be acquired up front in order. Instead, each lock first
+// touched mid-run is only tried (`Qutex::try_lock`), which never blocks,
+// so cannot deadlock. Should it be taken, the run is abandoned, its writes
+// discarded and its locks released, and the transaction is run again once
+// every lock it has touched so far has been acquired, waiting for each in
+// order of address (as `lock_all!` does). The set only grows, so each
+// transaction is retried at most once per lock it touches.
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::executor;
+use std::any::Any;
+use std::collections::{btree_map, BTreeMap};
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A lock held by a transaction, along with its pending write.
+struct Slot<T> {
+    guard: Guard<T>,
+    write: Option<T>,
+}
+
+/// A `Slot` of any type.
+trait Entry: Send {
+    fn as_any(&mut self) -> &mut dyn Any;
+
+    /// Applies the pending write, if any.
+    fn apply(&mut self);
+}
+
+impl<T: Send + 'static> Entry for Slot<T> {
+    #[inline]
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    #[inline]
+    fn apply(&mut self) {
+        if let Some(val) = self.write.take() {
+            *self.guard = val;
+        }
+    }
+}
+
+/// A lock a transaction has touched, to be acquired before it is retried.
+trait Known: Send {
+    fn request(&self) -> Box<dyn PendingEntry>;
+}
+
+impl<T: Send + 'static> Known for Qutex<T> {
+    #[inline]
+    fn request(&self) -> Box<dyn PendingEntry> {
+        Box::new(self.clone().lock())
+    }
+}
+
+/// A pending request for a `Known` lock.
+trait PendingEntry: Send {
+    fn poll_entry(&mut self, cx: &mut Context<'_>) -> Poll<Result<Box<dyn Entry>, Error>>;
+}
+
+impl<T: Send + 'static> PendingEntry for FutureGuard<T> {
+    fn poll_entry(&mut self, cx: &mut Context<'_>) -> Poll<Result<Box<dyn Entry>, Error>> {
+        let guard = futures::ready!(Pin::new(self).poll(cx))?;
+        Poll::Ready(Ok(Box::new(Slot { guard, write: None })))
+    }
+}
+
+/// Returned by `Txn` operations which find a lock taken, to be propagated
+/// out of the transaction (with `?`), which is then run again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conflict(());
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("transaction conflicted with another holder")
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// A run of a transaction, through which it reaches the values of the
+/// locks it touches.
+pub struct Txn {
+    // Keyed by lock address:
+    held: BTreeMap<usize, Box<dyn Entry>>,
+    // The lock found taken, if any:
+    missed: Option<(usize, Box<dyn Known>)>,
+}
+
+impl Txn {
+    /// Returns the slot for `qutex`, taking the lock if not yet held.
+    fn slot<T: Send + 'static>(&mut self, qutex: &Qutex<T>) -> Result<&mut Slot<T>, Conflict> {
+        if self.missed.is_some() {
+            return Err(Conflict(()));
+        }
+        let addr = qutex.as_ptr() as usize;
+        let entry = match self.held.entry(addr) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(vacant) => match qutex.try_lock() {
+                Some(guard) => vacant.insert(Box::new(Slot { guard, write: None })),
+                None => {
+                    self.missed = Some((addr, Box::new(qutex.clone())));
+                    return Err(Conflict(()));
+                }
+            },
+        };
+        Ok(entry.as_any().downcast_mut().unwrap())
+    }
+
+    /// Returns the value of `qutex`, as written so far by this transaction.
+    pub fn read<T: Send + 'static>(&mut self, qutex: &Qutex<T>) -> Result<&T, Conflict> {
+        let slot = self.slot(qutex)?;
+        Ok(slot.write.as_ref().unwrap_or(&*slot.guard))
+    }
+
+    /// Returns the value of `qutex` to be written, which takes effect only
+    /// once the transaction commits.
+    ///
+    /// The value is cloned on first being written.
+    pub fn write<T>(&mut self, qutex: &Qutex<T>) -> Result<&mut T, Conflict>
+    where
+        T: Clone + Send + 'static,
+    {
+        let slot = self.slot(qutex)?;
+        let guard = &slot.guard;
+        Ok(slot.write.get_or_insert_with(|| (**guard).clone()))
+    }
+}
+
+impl fmt::Debug for Txn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Txn")
+            .field("held", &self.held.len())
+            .field("conflicted", &self.missed.is_some())
+            .finish()
+    }
+}
+
+/// A future which resolves to the result of a transaction once it has
+/// committed.
+///
+/// Panics if polled again after resolving.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureAtomically<F, R> {
+    f: Option<F>,
+    // Every lock touched so far, keyed by address:
+    known: BTreeMap<usize, Box<dyn Known>>,
+    held: BTreeMap<usize, Box<dyn Entry>>,
+    pending: Option<(usize, Box<dyn PendingEntry>)>,
+    _result: PhantomData<fn() -> R>,
+}
+
+impl<F, R> FutureAtomically<F, R>
+where
+    F: FnMut(&mut Txn) -> Result<R, Conflict>,
+{
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<R, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<F, R> Future for FutureAtomically<F, R>
+where
+    F: FnMut(&mut Txn) -> Result<R, Conflict>,
+{
+    type Output = Result<R, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            // Every lock touched so far, in order:
+            if let Some((addr, ref mut request)) = this.pending {
+                let entry = futures::ready!(request.poll_entry(cx))?;
+                this.held.insert(addr, entry);
+                this.pending = None;
+            }
+            let held = &this.held;
+            if let Some((&addr, known)) = this.known.iter().find(|(a, _)| !held.contains_key(a)) {
+                this.pending = Some((addr, known.request()));
+                continue;
+            }
+
+            let f = this
+                .f
+                .as_mut()
+                .expect("FutureAtomically::poll: Task already completed.");
+            let mut txn = Txn {
+                held: std::mem::take(&mut this.held),
+                missed: None,
+            };
+            match (f(&mut txn), txn.missed) {
+                (Ok(res), None) => {
+                    // Applied to every lock before any is released:
+                    txn.held.values_mut().for_each(|entry| entry.apply());
+                    drop(txn.held);
+                    this.f = None;
+                    return Poll::Ready(Ok(res));
+                }
+                // Released, and retried once everything is held:
+                (_, Some((addr, known))) => {
+                    this.known.insert(addr, known);
+                }
+                (Err(_), None) => {
+                    panic!("FutureAtomically::poll: Conflict not raised by this transaction.")
+                }
+            }
+        }
+    }
+}
+
+// Never pinned structurally.
+impl<F, R> Unpin for FutureAtomically<F, R> {}
+
+impl<F, R> fmt::Debug for FutureAtomically<F, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FutureAtomically")
+            .field("known", &self.known.len())
+            .field("held", &self.held.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns a new `FutureAtomically` which runs the transaction `f` until
+/// it commits, resolving to its result.
+///
+/// `f` may be run several times, so should do nothing but read and write
+/// through the `Txn`. Any `Conflict` must be propagated out of it; one
+/// swallowed still has the run abandoned.
+///
+/// Panics if `f` returns a `Conflict` raised by some other transaction.
+pub fn atomically<F, R>(f: F) -> FutureAtomically<F, R>
+where
+    F: FnMut(&mut Txn) -> Result<R, Conflict>,
+{
+    FutureAtomically {
+        f: Some(f),
+        known: BTreeMap::new(),
+        held: BTreeMap::new(),
+        pending: None,
+        _result: PhantomData,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+    use std::thread;
+
+    #[test]
+    fn retried_once_held() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (a, b) = (Qutex::new(1), Qutex::new(String::from("b")));
+        let guard = b.try_lock().unwrap();
+
+        let mut runs = 0;
+        let mut txn = atomically(|txn| {
+            runs += 1;
+            *txn.write(&a)? += 1;
+            txn.write(&b)?.push('!');
+            Ok(*txn.read(&a)?)
+        });
+        assert!(txn.poll_unpin(cx).is_pending());
+        // Discarded along with the run:
+        assert_eq!(*a.try_lock().unwrap(), 1);
+
+        drop(guard);
+        assert_eq!(txn.wait().unwrap(), 2);
+        assert_eq!(runs, 2);
+        assert_eq!(*b.try_lock().unwrap(), "b!");
+    }
+
+    #[test]
+    fn transfers_stay_balanced() {
+        let accounts = [Qutex::new(1000i64), Qutex::new(1000i64)];
+        let threads = (0..4)
+            .map(|i| {
+                let (from, to) = (accounts[i % 2].clone(), accounts[(i + 1) % 2].clone());
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        atomically(|txn| {
+                            *txn.write(&from)? -= 1;
+                            *txn.write(&to)? += 1;
+                            Ok(())
+                        })
+                        .wait()
+                        .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let total = atomically(|txn| {
+            let first = *txn.read(&accounts[0])?;
+            Ok(first + *txn.read(&accounts[1])?)
+        });
+        assert_eq!(total.wait().unwrap(), 2000);
+    }
+}
@@ -0,0 +1,645 @@
+//! Serializing the items of a `Stream` or `Sink`, or the operations on an
+//! I/O object, against a `Qutex`.
+//!
+//
+
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::io::{AsyncRead, AsyncWrite, IoSlice, IoSliceMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Requests the lock anew whenever it is not held.
+struct Relock<T> {
+    qutex: Qutex<T>,
+    future_guard: Option<FutureGuard<T>>,
+    guard: Option<Guard<T>>,
+}
+
+impl<T> Relock<T> {
+    #[inline]
+    fn new(qutex: Qutex<T>) -> Relock<T> {
+        Relock {
+            qutex,
+            future_guard: None,
+            guard: None,
+        }
+    }
+
+    /// Resolves once the lock is held.
+    fn poll_lock(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.guard.is_none() {
+            let qutex = &self.qutex;
+            let future_guard = self
+                .future_guard
+                .get_or_insert_with(|| qutex.clone().lock());
+            let res = futures::ready!(Pin::new(future_guard).poll(cx));
+            self.future_guard = None;
+            self.guard = Some(res?);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns the held guard, if any.
+    #[inline]
+    fn take(&mut self) -> Option<Guard<T>> {
+        self.guard.take()
+    }
+}
+
+/// A `Stream` yielding each item of an inner stream along with a `Guard`
+/// acquired before that item was polled.
+///
+/// The lock is requested anew for every item, so other tasks may take it
+/// between items, and is held while waiting for the inner stream to produce
+/// the next one. A rejected request is yielded as its `Error`.
+#[must_use = "streams do nothing unless polled"]
+pub struct GuardedStream<S, T> {
+    stream: S,
+    relock: Relock<T>,
+}
+
+impl<S, T> GuardedStream<S, T> {
+    /// Returns a reference to the inner stream.
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns the inner stream, releasing or withdrawing any pending lock.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, T> Stream for GuardedStream<S, T>
+where
+    S: Stream + Unpin,
+{
+    type Item = Result<(Guard<T>, S::Item), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Err(err) = futures::ready!(this.relock.poll_lock(cx)) {
+            return Poll::Ready(Some(Err(err)));
+        }
+
+        match futures::ready!(this.stream.poll_next_unpin(cx)) {
+            Some(item) => Poll::Ready(Some(Ok((this.relock.take().unwrap(), item)))),
+            None => {
+                this.relock.take();
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+// Never pinned structurally.
+impl<S: Unpin, T> Unpin for GuardedStream<S, T> {}
+
+impl<S: fmt::Debug, T> fmt::Debug for GuardedStream<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuardedStream")
+            .field("stream", &self.stream)
+            .field("locked", &self.relock.guard.is_some())
+            .finish()
+    }
+}
+
+/// An error from a `GuardedSink`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkError<E> {
+    /// The lock request was rejected.
+    Lock(Error),
+    /// The inner sink failed.
+    Sink(E),
+}
+
+impl<E: fmt::Display> fmt::Display for SinkError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SinkError::Lock(ref err) => err.fmt(f),
+            SinkError::Sink(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E: StdError + 'static> StdError for SinkError<E> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            SinkError::Lock(ref err) => Some(err),
+            SinkError::Sink(ref err) => Some(err),
+        }
+    }
+}
+
+impl<E> From<Error> for SinkError<E> {
+    #[inline]
+    fn from(err: Error) -> SinkError<E> {
+        SinkError::Lock(err)
+    }
+}
+
+/// A `Sink` which holds a `Qutex` for every send, flush and close of an
+/// inner sink.
+///
+/// The lock is acquired by `poll_ready` and held until the following
+/// `start_send`, and by `poll_flush` and `poll_close` until they complete,
+/// being released in between so that other writers may interleave.
+#[must_use = "sinks do nothing unless polled"]
+pub struct GuardedSink<K, T> {
+    sink: K,
+    relock: Relock<T>,
+}
+
+impl<K, T> GuardedSink<K, T> {
+    /// Returns a reference to the inner sink.
+    #[inline]
+    pub fn get_ref(&self) -> &K {
+        &self.sink
+    }
+
+    /// Returns the inner sink, releasing or withdrawing any pending lock.
+    #[inline]
+    pub fn into_inner(self) -> K {
+        self.sink
+    }
+
+    /// Polls `f` once the lock is held, releasing it once `f` completes.
+    fn poll_locked<R, E>(
+        &mut self,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(&mut K, &mut Context<'_>) -> Poll<Result<R, E>>,
+    ) -> Poll<Result<R, SinkError<E>>> {
+        futures::ready!(self.relock.poll_lock(cx))?;
+        let res = futures::ready!(f(&mut self.sink, cx));
+        self.relock.take();
+        Poll::Ready(res.map_err(SinkError::Sink))
+    }
+}
+
+impl<K, T, Item> Sink<Item> for GuardedSink<K, T>
+where
+    K: Sink<Item> + Unpin,
+{
+    type Error = SinkError<K::Error>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        futures::ready!(this.relock.poll_lock(cx))?;
+
+        // Held until `start_send`:
+        let res = futures::ready!(this.sink.poll_ready_unpin(cx));
+        if res.is_err() {
+            this.relock.take();
+        }
+        Poll::Ready(res.map_err(SinkError::Sink))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let res = this.sink.start_send_unpin(item);
+        this.relock.take();
+        res.map_err(SinkError::Sink)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut()
+            .poll_locked(cx, |sink, cx| sink.poll_flush_unpin(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut()
+            .poll_locked(cx, |sink, cx| sink.poll_close_unpin(cx))
+    }
+}
+
+// Never pinned structurally.
+impl<K: Unpin, T> Unpin for GuardedSink<K, T> {}
+
+impl<K: fmt::Debug, T> fmt::Debug for GuardedSink<K, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuardedSink")
+            .field("sink", &self.sink)
+            .field("locked", &self.relock.guard.is_some())
+            .finish()
+    }
+}
+
+/// A handle to a `Qutex` holding an I/O object, such as a socket or a
+/// serial port, which implements `AsyncRead` and `AsyncWrite` by locking
+/// it for every operation.
+///
+/// Each read, write, flush and close holds the lock from when it is first
+/// polled until it completes, being released in between so that handles
+/// sharing the object may interleave their operations but never interrupt
+/// one another's. A rejected lock request fails the operation with an
+/// error of kind `ErrorKind::Other`.
+#[must_use = "I/O handles do nothing unless polled"]
+pub struct GuardedIo<T> {
+    relock: Relock<T>,
+}
+
+impl<T> GuardedIo<T> {
+    /// Returns the `Qutex` holding the I/O object.
+    #[inline]
+    pub fn qutex(&self) -> &Qutex<T> {
+        &self.relock.qutex
+    }
+
+    /// Polls `f` on the I/O object once the lock is held, releasing it once
+    /// `f` completes.
+    fn poll_locked<R>(
+        &mut self,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(Pin<&mut T>, &mut Context<'_>) -> Poll<io::Result<R>>,
+    ) -> Poll<io::Result<R>>
+    where
+        T: Unpin,
+    {
+        if let Err(err) = futures::ready!(self.relock.poll_lock(cx)) {
+            return Poll::Ready(Err(io::Error::other(err)));
+        }
+        let guard = self.relock.guard.as_mut().unwrap();
+        let res = futures::ready!(f(Pin::new(&mut **guard), cx));
+        self.relock.take();
+        Poll::Ready(res)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for GuardedIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_locked(cx, |io, cx| io.poll_read(cx, buf))
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_locked(cx, |io, cx| io.poll_read_vectored(cx, bufs))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for GuardedIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_locked(cx, |io, cx| io.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_locked(cx, |io, cx| io.poll_write_vectored(cx, bufs))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_locked(cx, |io, cx| io.poll_flush(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_locked(cx, |io, cx| io.poll_close(cx))
+    }
+}
+
+// Never pinned structurally.
+impl<T> Unpin for GuardedIo<T> {}
+
+impl<T> fmt::Debug for GuardedIo<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuardedIo")
+            .field("locked", &self.relock.guard.is_some())
+            .finish()
+    }
+}
+
+/// A `Stream` of `Guard`s, yielding one each time the lock is acquired, as
+/// returned by `Qutex::lock_stream`.
+///
+/// Each guard is requested only once the stream is polled after yielding
+/// the previous one, queueing behind any other requests, so that a loop
+/// over the stream takes its turn fairly. A next guard polled for while the
+/// previous one is still held is granted only once that is dropped. The
+/// stream ends if a request is rejected, as when the queue is full.
+#[must_use = "streams do nothing unless polled"]
+pub struct LockStream<T> {
+    qutex: Qutex<T>,
+    pending: Option<FutureGuard<T>>,
+}
+
+impl<T> LockStream<T> {
+    /// Returns the `Qutex` locked.
+    #[inline]
+    pub fn qutex(&self) -> &Qutex<T> {
+        &self.qutex
+    }
+}
+
+impl<T> Stream for LockStream<T> {
+    type Item = Guard<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Guard<T>>> {
+        let this = self.get_mut();
+        let qutex = &this.qutex;
+        let pending = this.pending.get_or_insert_with(|| qutex.clone().lock());
+        let res = futures::ready!(Pin::new(pending).poll(cx));
+        this.pending = None;
+        Poll::Ready(res.ok())
+    }
+}
+
+// Never pinned structurally.
+impl<T> Unpin for LockStream<T> {}
+
+impl<T> fmt::Debug for LockStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LockStream")
+            .field("pending", &self.pending.is_some())
+            .finish()
+    }
+}
+
+impl<T> Qutex<T> {
+    /// Returns a `LockStream` which yields a guard each time the lock is
+    /// acquired, for worker loops taking the lock for one unit of work at a
+    /// time.
+    ///
+    /// ```
+    /// use futures::{executor, StreamExt};
+    /// use qutex::Qutex;
+    ///
+    /// let jobs = Qutex::new(vec![1, 2, 3]);
+    /// let mut turns = jobs.clone().lock_stream();
+    /// let mut done = 0;
+    ///
+    /// executor::block_on(async {
+    ///     while let Some(mut jobs) = turns.next().await {
+    ///         match jobs.pop() {
+    ///             Some(job) => done += job,
+    ///             None => break,
+    ///         }
+    ///     }
+    /// });
+    /// assert_eq!(done, 6);
+    /// ```
+    #[inline]
+    pub fn lock_stream(self) -> LockStream<T> {
+        LockStream {
+            qutex: self,
+            pending: None,
+        }
+    }
+
+    /// Returns a `GuardedIo` which locks this `Qutex` for every read,
+    /// write, flush and close of the I/O object it holds.
+    ///
+    /// Each task sharing the object takes a handle of its own:
+    ///
+    /// ```
+    /// use futures::io::{AsyncWriteExt, Cursor};
+    /// use futures::executor;
+    /// use qutex::Qutex;
+    ///
+    /// let port = Qutex::new(Cursor::new(Vec::new()));
+    /// let (mut a, mut b) = (port.guard_io(), port.guard_io());
+    ///
+    /// executor::block_on(async {
+    ///     a.write_all(b"ping ").await.unwrap();
+    ///     b.write_all(b"pong").await.unwrap();
+    /// });
+    /// assert_eq!(port.try_lock().unwrap().get_ref(), b"ping pong");
+    /// ```
+    pub fn guard_io(&self) -> GuardedIo<T> {
+        GuardedIo {
+            relock: Relock::new(self.clone()),
+        }
+    }
+
+    /// Returns a `GuardedStream` which locks this `Qutex` before polling
+    /// each item of `inner`, yielding the guard along with the item.
+    ///
+    /// ```
+    /// use futures::{executor, stream, StreamExt};
+    /// use qutex::Qutex;
+    ///
+    /// let total = Qutex::new(0);
+    /// let mut messages = total.guard_stream(stream::iter(vec![1, 2, 3]));
+    ///
+    /// executor::block_on(async {
+    ///     while let Some(res) = messages.next().await {
+    ///         let (mut total, n) = res.unwrap();
+    ///         *total += n;
+    ///     }
+    /// });
+    /// assert_eq!(*total.lock().wait().unwrap(), 6);
+    /// ```
+    pub fn guard_stream<S: Stream>(&self, inner: S) -> GuardedStream<S, T> {
+        GuardedStream {
+            stream: inner,
+            relock: Relock::new(self.clone()),
+        }
+    }
+
+    /// Returns a `GuardedSink` which locks this `Qutex` for every send,
+    /// flush and close of `inner`.
+    ///
+    /// Sinks guarded by the same `Qutex`, such as several writers sharing a
+    /// connection, never interleave their sends.
+    pub fn guard_sink<K>(&self, inner: K) -> GuardedSink<K, T> {
+        GuardedSink {
+            sink: inner,
+            relock: Relock::new(self.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor;
+    use futures::task::noop_waker_ref;
+    use futures::{stream, FutureExt};
+
+    #[test]
+    fn releases_between_items() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let state = Qutex::new(Vec::new());
+        let mut guarded = state.guard_stream(stream::iter(0..2));
+
+        let guard = state.clone().lock().wait().unwrap();
+        assert!(guarded.poll_next_unpin(cx).is_pending());
+        drop(guard);
+
+        let (mut guard, item) = executor::block_on(guarded.next()).unwrap().unwrap();
+        guard.push(item);
+        // Queued behind the held guard:
+        let mut other = state.clone().lock();
+        assert!(other.poll_unpin(cx).is_pending());
+        drop(guard);
+        other.wait().unwrap().push(10);
+
+        let (mut guard, item) = executor::block_on(guarded.next()).unwrap().unwrap();
+        guard.push(item);
+        drop(guard);
+        assert!(executor::block_on(guarded.next()).is_none());
+        assert_eq!(*state.lock().wait().unwrap(), [0, 10, 1]);
+    }
+
+    #[test]
+    fn lock_stream_requeues() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let state = Qutex::new(Vec::new());
+        let mut turns = state.clone().lock_stream();
+
+        let mut guard = executor::block_on(turns.next()).unwrap();
+        guard.push(0);
+        // Requested after the stream's first turn, so served before its
+        // second:
+        let mut other = state.clone().lock();
+        assert!(other.poll_unpin(cx).is_pending());
+        assert!(turns.poll_next_unpin(cx).is_pending());
+        drop(guard);
+        other.wait().unwrap().push(10);
+
+        executor::block_on(turns.next()).unwrap().push(1);
+        assert_eq!(turns.qutex().try_lock().unwrap()[..], [0, 10, 1]);
+    }
+
+    #[test]
+    fn sink_releases_between_sends() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let state = Qutex::new(());
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut guarded = state.guard_sink(tx);
+
+        let guard = state.clone().lock().wait().unwrap();
+        let mut send = guarded.send(1);
+        assert!(send.poll_unpin(cx).is_pending());
+        drop(guard);
+        executor::block_on(send).unwrap();
+
+        // Released once sent:
+        drop(state.try_lock().unwrap());
+        executor::block_on(guarded.send(2)).unwrap();
+        executor::block_on(guarded.close()).unwrap();
+
+        assert_eq!(executor::block_on(rx.by_ref().collect::<Vec<_>>()), [1, 2]);
+    }
+
+    #[test]
+    fn sink_error() {
+        let state = Qutex::new(());
+        let (tx, rx) = mpsc::channel::<u32>(1);
+        drop(rx);
+
+        let mut guarded = state.guard_sink(tx);
+        match executor::block_on(guarded.send(1)) {
+            Err(SinkError::Sink(err)) => assert!(err.is_disconnected()),
+            res => panic!("unexpected: {:?}", res),
+        }
+        // Released on error:
+        assert!(state.try_lock().is_some());
+    }
+
+    /// Writes of which no more than `limit` bytes are accepted per poll,
+    /// pending in between.
+    struct Trickle {
+        written: Vec<u8>,
+        limit: usize,
+        ready: bool,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(self.limit);
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn io_held_per_operation() {
+        use futures::io::AsyncWriteExt;
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let port = Qutex::new(Trickle {
+            written: Vec::new(),
+            limit: 2,
+            ready: true,
+        });
+        let (mut a, mut b) = (port.guard_io(), port.guard_io());
+
+        // Held while the write is pending:
+        let mut write = a.write(b"abc");
+        assert!(write.poll_unpin(cx).is_pending());
+        assert!(port.try_lock().is_none());
+        let mut other = b.write(b"xyz");
+        assert!(other.poll_unpin(cx).is_pending());
+        assert_eq!(executor::block_on(write).unwrap(), 2);
+
+        // Then handed to the next writer before `a` writes again:
+        let mut write = a.write(b"c");
+        assert!(write.poll_unpin(cx).is_pending());
+        assert_eq!(executor::block_on(other).unwrap(), 2);
+        assert_eq!(executor::block_on(write).unwrap(), 1);
+        executor::block_on(b.close()).unwrap();
+        assert_eq!(port.try_lock().unwrap().written, b"abxyc");
+    }
+
+    #[test]
+    fn held_while_pending() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let state = Qutex::new(0);
+        let (mut tx, rx) = mpsc::channel(1);
+        let mut guarded = state.guard_stream(rx);
+
+        assert!(guarded.poll_next_unpin(cx).is_pending());
+        assert!(state.try_lock().is_none());
+
+        tx.try_send(5).unwrap();
+        drop(tx);
+        let (mut guard, n) = executor::block_on(guarded.next()).unwrap().unwrap();
+        *guard += n;
+        drop(guard);
+
+        assert!(executor::block_on(guarded.next()).is_none());
+        assert_eq!(*state.try_lock().unwrap(), 5);
+    }
+}
@@ -0,0 +1,278 @@
+//! A `Qutex` with the synchronous interface of `std::sync::Mutex`.
+//!
+//
+
+use crate::blocking::back_off;
+use crate::{Qutex, QutexBuilder, RefGuard};
+use std::fmt;
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+/// A mutual exclusion lock with the interface of `std::sync::Mutex`, built
+/// on a `Qutex` which can also be locked asynchronously.
+///
+/// `lock` blocks the calling thread, without involving an executor, so is
+/// just as safe to call from within a thread pool as a `std` mutex is.
+/// Asynchronous call sites lock the very same queue through `as_qutex`.
+/// Either way requests are served in order, and as with a `std` mutex, a
+/// guard dropped while panicking poisons the lock.
+pub struct SyncQutex<T> {
+    qutex: Qutex<T>,
+}
+
+impl<T> SyncQutex<T> {
+    /// Creates and returns a new, unlocked `SyncQutex` holding `val`.
+    #[inline]
+    pub fn new(val: T) -> SyncQutex<T> {
+        SyncQutex {
+            qutex: QutexBuilder::new().poison(true).build(val),
+        }
+    }
+
+    /// Creates and returns a new, unlocked `SyncQutex` holding `val`,
+    /// configured by `builder`, such as for `Admission::Barging`.
+    ///
+    /// Poisoning is always enabled.
+    pub fn from_builder(builder: QutexBuilder, val: T) -> SyncQutex<T> {
+        SyncQutex {
+            qutex: builder.poison(true).build(val),
+        }
+    }
+
+    /// Returns the underlying `Qutex`, for locking asynchronously, as with
+    /// `lock_ref`.
+    ///
+    /// `into_inner` and `get_mut` panic while any clone of it exists.
+    #[inline]
+    pub fn as_qutex(&self) -> &Qutex<T> {
+        &self.qutex
+    }
+
+    /// Blocks the current thread until the lock is acquired.
+    ///
+    /// The lock is retried briefly, as its `Backoff` allows, before the
+    /// thread queues and parks, queueing again should a `queue_bound` turn
+    /// it away. Returns an error holding the guard if the lock is poisoned.
+    #[track_caller]
+    pub fn lock(&self) -> LockResult<RefGuard<'_, T>> {
+        let guard = match back_off(self.qutex.backoff(), || self.qutex.try_lock_ref()) {
+            Some(guard) => guard,
+            None => self.qutex.lock_ref_blocking(),
+        };
+        self.poison_check(guard)
+    }
+
+    /// Acquires the lock if it is free and nobody is waiting for it.
+    ///
+    /// Returns `TryLockError::WouldBlock` otherwise, or
+    /// `TryLockError::Poisoned` holding the guard if the lock is poisoned.
+    #[track_caller]
+    pub fn try_lock(&self) -> TryLockResult<RefGuard<'_, T>> {
+        match self.qutex.try_lock_ref() {
+            Some(guard) => Ok(self.poison_check(guard)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Releases the lock held by `guard`, handing it straight to the next
+    /// thread or task waiting, as `parking_lot`'s `unlock_fair` does.
+    ///
+    /// Dropping a guard under `Admission::Barging` only wakes the next in
+    /// line, which must still race for the lock. Shorthand for
+    /// `RefGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(guard: RefGuard<'_, T>) {
+        RefGuard::unlock_fair(guard)
+    }
+
+    /// Lets anyone waiting for the lock go first, handing it over fairly,
+    /// then blocks the current thread until it comes back round to `guard`,
+    /// as `parking_lot`'s `bump` does.
+    ///
+    /// Does nothing if nobody is waiting, making it cheap to call
+    /// periodically from a long critical section.
+    #[inline]
+    #[track_caller]
+    pub fn bump(guard: &mut RefGuard<'_, T>) {
+        RefGuard::bump_blocking(guard)
+    }
+
+    /// Returns the value, or an error holding it if the lock is poisoned.
+    ///
+    /// Panics if a clone of the `Qutex` returned by `as_qutex` exists.
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let val = match self.qutex.into_inner() {
+            Ok(val) => val,
+            Err(_) => panic!("SyncQutex::into_inner: Qutex shared."),
+        };
+        match poisoned {
+            true => Err(PoisonError::new(val)),
+            false => Ok(val),
+        }
+    }
+
+    /// Returns a mutable reference to the value, or an error holding it if
+    /// the lock is poisoned. No locking is needed, as the mutable borrow
+    /// statically guarantees nobody else holds the lock.
+    ///
+    /// Panics if a clone of the `Qutex` returned by `as_qutex` exists.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        let val = self
+            .qutex
+            .get_mut()
+            .expect("SyncQutex::get_mut: Qutex shared.");
+        match poisoned {
+            true => Err(PoisonError::new(val)),
+            false => Ok(val),
+        }
+    }
+
+    /// Returns `true` if a guard was dropped while its thread was panicking.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.qutex.is_poisoned()
+    }
+
+    /// Clears the poisoned state.
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.qutex.clear_poison()
+    }
+
+    #[inline]
+    fn poison_check<'a>(&self, guard: RefGuard<'a, T>) -> LockResult<RefGuard<'a, T>> {
+        match self.is_poisoned() {
+            true => Err(PoisonError::new(guard)),
+            false => Ok(guard),
+        }
+    }
+}
+
+impl<T> From<T> for SyncQutex<T> {
+    #[inline]
+    fn from(val: T) -> SyncQutex<T> {
+        SyncQutex::new(val)
+    }
+}
+
+impl<T: Default> Default for SyncQutex<T> {
+    #[inline]
+    fn default() -> SyncQutex<T> {
+        SyncQutex::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SyncQutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SyncQutex").field(&self.qutex).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Admission;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn mixed() {
+        let lock = Arc::new(SyncQutex::new(0usize));
+        let threads = (0..4)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        match i % 2 {
+                            0 => *lock.lock().unwrap() += 1,
+                            _ => *lock.as_qutex().lock_ref().wait().unwrap() += 1,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let guard = lock.lock().unwrap();
+        assert!(matches!(lock.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(guard);
+        let lock = Arc::try_unwrap(lock).unwrap();
+        assert_eq!(lock.into_inner().unwrap(), 1000);
+    }
+
+    #[test]
+    fn queue_bound() {
+        let builder = QutexBuilder::new().queue_bound(1);
+        let lock = Arc::new(SyncQutex::from_builder(builder, 0usize));
+        let threads = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        *lock.lock().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.lock().unwrap(), 750);
+    }
+
+    #[test]
+    fn unlock_fair() {
+        let builder = QutexBuilder::new().admission(Admission::Barging);
+        let lock = Arc::new(SyncQutex::from_builder(builder, Vec::new()));
+        let guard = lock.lock().unwrap();
+
+        let lock_c = lock.clone();
+        let waiter = thread::spawn(move || lock_c.lock().unwrap().push("waiter"));
+        thread::sleep(Duration::from_millis(20));
+        SyncQutex::unlock_fair(guard);
+        // Handed over already, so not to be barged past:
+        lock.lock().unwrap().push("main");
+        waiter.join().unwrap();
+        assert_eq!(*lock.lock().unwrap(), ["waiter", "main"]);
+    }
+
+    #[test]
+    fn bump() {
+        let lock = Arc::new(SyncQutex::new(Vec::new()));
+        let mut guard = lock.lock().unwrap();
+        // Nobody waiting, so kept:
+        SyncQutex::bump(&mut guard);
+        guard.push(1);
+
+        let lock_c = lock.clone();
+        let waiter = thread::spawn(move || lock_c.lock().unwrap().push(2));
+        thread::sleep(Duration::from_millis(20));
+        SyncQutex::bump(&mut guard);
+        guard.push(3);
+        drop(guard);
+        waiter.join().unwrap();
+        assert_eq!(*lock.lock().unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn poison() {
+        let mut lock = SyncQutex::new(vec![1]);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = lock.lock().unwrap();
+            panic!();
+        }));
+        assert!(res.is_err());
+        assert!(lock.is_poisoned());
+        lock.lock().unwrap_err().into_inner().push(2);
+        assert!(matches!(lock.try_lock(), Err(TryLockError::Poisoned(_))));
+        assert_eq!(*lock.get_mut().unwrap_err().into_inner(), [1, 2]);
+
+        lock.clear_poison();
+        assert_eq!(lock.into_inner().unwrap(), [1, 2]);
+    }
+}
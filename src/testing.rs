@@ -0,0 +1,557 @@
+//! Deterministic scheduling for testing code which contends on locks.
+//!
+//! A `TestExecutor` polls its tasks on the calling thread, in the order they
+//! were spawned, and only when woken, so that every run of a test sees the
+//! same interleaving without spawning threads or sleeping. Tasks spawned
+//! through `spawn_lock` keep the guard they resolve to until released,
+//! letting a test hand a lock from one waiter to the next, step by step:
+//!
+//! ```
+//! # #[macro_use] extern crate qutex;
+//! # fn main() {
+//! use qutex::testing::TestExecutor;
+//! use qutex::{Priority, Qutex};
+//!
+//! let lock = Qutex::new(0);
+//! let guard = lock.try_lock().unwrap();
+//!
+//! let mut executor = TestExecutor::new();
+//! executor.spawn_lock("first", lock.clone().lock());
+//! executor.spawn_lock("urgent", lock.clone().lock_with_priority(Priority::High));
+//! executor.spawn_lock("second", lock.clone().lock());
+//! executor.run_until_stalled();
+//! assert!(executor.grant_order().is_empty());
+//!
+//! drop(guard);
+//! assert_grant_order!(executor, ["urgent", "first", "second"]);
+//! # }
+//! ```
+//!
+//! `check_interleaving` instead checks the lock itself, against a sequence
+//! of operations such as a fuzzer generates.
+//
+
+use crate::{FutureGuard, FutureTimedGuard, Guard, Priority, Qutex};
+use futures::FutureExt;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+/// A waker which counts its wakeups.
+#[derive(Debug, Default)]
+pub struct TestWaker {
+    wakes: AtomicUsize,
+    woken: AtomicBool,
+}
+
+impl TestWaker {
+    /// Creates and returns a new `TestWaker`, not yet woken.
+    #[inline]
+    pub fn new() -> Arc<TestWaker> {
+        Arc::new(TestWaker::default())
+    }
+
+    /// Returns a `Waker` which wakes `this`.
+    #[inline]
+    pub fn waker(this: &Arc<TestWaker>) -> Waker {
+        Waker::from(this.clone())
+    }
+
+    /// Returns the number of times this has been woken.
+    #[inline]
+    pub fn wake_count(&self) -> usize {
+        self.wakes.load(SeqCst)
+    }
+
+    /// Returns `true` if this has been woken since last called.
+    #[inline]
+    pub fn take_woken(&self) -> bool {
+        self.woken.swap(false, SeqCst)
+    }
+}
+
+impl Wake for TestWaker {
+    #[inline]
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    #[inline]
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, SeqCst);
+        self.woken.store(true, SeqCst);
+    }
+}
+
+/// A task's future, resolving to the guard it holds, if any.
+type TaskFuture = Pin<Box<dyn Future<Output = Option<Box<dyn Any>>>>>;
+
+struct Task {
+    label: &'static str,
+    waker: Arc<TestWaker>,
+    // `None` once complete:
+    future: Option<TaskFuture>,
+    polled: bool,
+}
+
+/// A single-threaded executor which polls its tasks in a deterministic
+/// order.
+///
+/// Each pass polls, in the order spawned, every task which is new or has
+/// been woken since it was last polled.
+#[derive(Default)]
+pub struct TestExecutor {
+    tasks: Vec<Task>,
+    // Guards resolved to by tasks spawned through `spawn_lock`, in the order
+    // granted:
+    held: Vec<(&'static str, Box<dyn Any>)>,
+    granted: Vec<&'static str>,
+    completed: Vec<&'static str>,
+}
+
+impl TestExecutor {
+    /// Creates and returns a new `TestExecutor`, with no tasks.
+    #[inline]
+    pub fn new() -> TestExecutor {
+        TestExecutor::default()
+    }
+
+    /// Spawns `future` as a task labeled `label`. It is first polled by the
+    /// next call to `run_until_stalled`.
+    pub fn spawn<F>(&mut self, label: &'static str, future: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.push(label, Box::pin(future.map(|()| None)));
+    }
+
+    /// Spawns `future`, a lock request, as a task labeled `label`. The guard
+    /// it resolves to is held by the executor until released.
+    ///
+    /// Panics, once polled, if the request fails.
+    pub fn spawn_lock<F, G, E>(&mut self, label: &'static str, future: F)
+    where
+        F: Future<Output = Result<G, E>> + 'static,
+        G: 'static,
+        E: fmt::Debug,
+    {
+        self.push(
+            label,
+            Box::pin(future.map(move |res| match res {
+                Ok(guard) => Some(Box::new(guard) as Box<dyn Any>),
+                Err(err) => panic!("TestExecutor: Lock request {:?} failed: {:?}", label, err),
+            })),
+        );
+    }
+
+    fn push(&mut self, label: &'static str, future: TaskFuture) {
+        self.tasks.push(Task {
+            label,
+            waker: TestWaker::new(),
+            future: Some(future),
+            polled: false,
+        });
+    }
+
+    /// Polls tasks until none are left to poll, returning the number of
+    /// polls made.
+    pub fn run_until_stalled(&mut self) -> usize {
+        let mut total = 0;
+        loop {
+            let mut polls = 0;
+            for task in self.tasks.iter_mut() {
+                let Some(ref mut future) = task.future else {
+                    continue;
+                };
+                if task.polled && !task.waker.take_woken() {
+                    continue;
+                }
+                task.polled = true;
+                polls += 1;
+
+                let waker = TestWaker::waker(&task.waker);
+                if let Poll::Ready(guard) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                    task.future = None;
+                    self.completed.push(task.label);
+                    if let Some(guard) = guard {
+                        self.granted.push(task.label);
+                        self.held.push((task.label, guard));
+                    }
+                }
+            }
+            if polls == 0 {
+                return total;
+            }
+            total += polls;
+        }
+    }
+
+    /// Drops the guard held for the task labeled `label`, returning `false`
+    /// if there was none.
+    pub fn release(&mut self, label: &'static str) -> bool {
+        match self.held.iter().position(|&(held, _)| held == label) {
+            Some(idx) => {
+                drop(self.held.remove(idx));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs every task as far as it goes, releasing each guard held in the
+    /// order granted and running again, until no guards are left.
+    pub fn run_to_completion(&mut self) {
+        loop {
+            self.run_until_stalled();
+            if self.held.is_empty() {
+                return;
+            }
+            drop(self.held.remove(0));
+        }
+    }
+
+    /// Returns the labels of the tasks spawned through `spawn_lock` which
+    /// have been granted their lock, in the order granted.
+    #[inline]
+    pub fn grant_order(&self) -> &[&'static str] {
+        &self.granted
+    }
+
+    /// Returns the labels of the tasks which have completed, in the order
+    /// completed.
+    #[inline]
+    pub fn completed(&self) -> &[&'static str] {
+        &self.completed
+    }
+
+    /// Returns the labels of the tasks yet to complete, in the order spawned.
+    pub fn pending(&self) -> Vec<&'static str> {
+        self.tasks
+            .iter()
+            .filter(|task| task.future.is_some())
+            .map(|task| task.label)
+            .collect()
+    }
+}
+
+impl fmt::Debug for TestExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TestExecutor")
+            .field("pending", &self.pending())
+            .field(
+                "held",
+                &self
+                    .held
+                    .iter()
+                    .map(|&(label, _)| label)
+                    .collect::<Vec<_>>(),
+            )
+            .field("granted", &self.granted)
+            .finish()
+    }
+}
+
+/// Runs a `TestExecutor` to completion (see `TestExecutor::run_to_completion`)
+/// and asserts that its lock requests were granted in the order listed.
+///
+/// See the [`testing`](testing/index.html) module for an example.
+#[macro_export]
+macro_rules! assert_grant_order {
+    ($executor:expr, [$($label:expr),* $(,)?]) => {{
+        let executor: &mut $crate::testing::TestExecutor = &mut $executor;
+        executor.run_to_completion();
+        let expected: &[&str] = &[$($label),*];
+        assert_eq!(executor.grant_order(), expected, "locks granted out of order");
+    }};
+}
+
+/// A request made by `check_interleaving`.
+struct Request {
+    // Requests are numbered in the order made:
+    id: usize,
+    priority: Priority,
+    // Whether its deadline had passed as it was made:
+    expired: bool,
+    future: RequestFuture,
+    waker: Arc<TestWaker>,
+    // Whether woken since last polled:
+    woken: bool,
+}
+
+enum RequestFuture {
+    Plain(FutureGuard<usize>),
+    Timed(FutureTimedGuard<usize>),
+}
+
+/// The requests and guards of a single `check_interleaving` run.
+struct Interleaving {
+    qutex: Qutex<usize>,
+    requests: Vec<Request>,
+    guards: Vec<Guard<usize>>,
+    next_id: usize,
+}
+
+impl Interleaving {
+    /// Makes a request, of `priority` or else timed, with `deadline`, which
+    /// is `Priority::Normal`.
+    fn request(&mut self, priority: Priority, deadline: Option<Instant>) {
+        let (priority, future) = match deadline {
+            Some(deadline) => (
+                Priority::Normal,
+                RequestFuture::Timed(self.qutex.clone().lock_until(deadline)),
+            ),
+            None => (
+                priority,
+                RequestFuture::Plain(self.qutex.clone().lock_with_priority(priority)),
+            ),
+        };
+        self.requests.push(Request {
+            id: self.next_id,
+            priority,
+            expired: deadline.is_some_and(|deadline| deadline <= Instant::now()),
+            future,
+            waker: TestWaker::new(),
+            woken: true,
+        });
+        self.next_id += 1;
+        self.poll(self.requests.len() - 1);
+    }
+
+    /// Polls request `idx`, checking that, if granted, it was granted in
+    /// order.
+    fn poll(&mut self, idx: usize) {
+        let req = &mut self.requests[idx];
+        let waker = TestWaker::waker(&req.waker);
+        let cx = &mut Context::from_waker(&waker);
+        req.woken = false;
+        let res = match req.future {
+            RequestFuture::Plain(ref mut future) => match future.poll_unpin(cx) {
+                Poll::Ready(res) => res.map_err(|_| ()),
+                Poll::Pending => return,
+            },
+            RequestFuture::Timed(ref mut future) => match future.poll_unpin(cx) {
+                Poll::Ready(res) => res.map_err(|_| ()),
+                Poll::Pending => return,
+            },
+        };
+
+        let req = self.requests.remove(idx);
+        match res {
+            Ok(guard) => {
+                // Nobody of at least its priority, and waiting since before
+                // it was made, may be left waiting:
+                let overtaken = self.requests.iter().find(|other| {
+                    !other.expired && other.id < req.id && other.priority >= req.priority
+                });
+                if let Some(other) = overtaken {
+                    panic!(
+                        "check_interleaving: Request #{} ({:?}) granted before #{} ({:?}).",
+                        req.id, req.priority, other.id, other.priority
+                    );
+                }
+                self.guards.push(guard);
+            }
+            // Rejected as the queue was full:
+            Err(()) if cfg!(feature = "fixed_queue") => (),
+            Err(()) => assert!(
+                req.expired,
+                "check_interleaving: Request #{} failed.",
+                req.id
+            ),
+        }
+    }
+
+    /// Checks the lock has at most one holder, and that every request yet
+    /// to be woken, other than those expired, is still waiting.
+    fn check(&mut self) {
+        assert!(
+            self.guards.len() <= 1,
+            "check_interleaving: {} guards held at once.",
+            self.guards.len()
+        );
+        for idx in (0..self.requests.len()).rev() {
+            let req = &mut self.requests[idx];
+            req.woken |= req.waker.take_woken();
+            if req.woken || req.expired {
+                continue;
+            }
+            let (id, held) = (req.id, self.guards.len());
+            self.poll(idx);
+            assert_eq!(
+                self.guards.len(),
+                held,
+                "check_interleaving: Request #{} granted without being woken.",
+                id
+            );
+        }
+    }
+}
+
+/// Runs the operations `ops` encodes against a new `Qutex`, panicking if
+/// the lock misbehaves.
+///
+/// Each byte is one operation on the lock, its guards, or the requests made
+/// so far: making a request (with a priority, or with a deadline which has
+/// already passed or never will), `try_lock`, polling one or every request,
+/// dropping a request, or releasing a guard. Any sequence of bytes is valid.
+/// After each operation, and then while every remaining request is driven to
+/// completion, the lock is checked to:
+///
+/// * have at most one holder,
+/// * never grant a request without waking it,
+/// * grant requests of each priority in the order made, and never before
+///   requests of higher priority made before them,
+/// * never be left held by a dropped request.
+pub fn check_interleaving(ops: &[u8]) {
+    let mut run = Interleaving {
+        qutex: Qutex::new(0),
+        requests: Vec::new(),
+        guards: Vec::new(),
+        next_id: 0,
+    };
+    let priorities = [Priority::Low, Priority::Normal, Priority::High];
+
+    for &op in ops {
+        let arg = usize::from(op >> 3);
+        match op & 7 {
+            0 | 1 => run.request(priorities[arg % 3], None),
+            2 => {
+                if let Some(guard) = run.qutex.try_lock() {
+                    run.guards.push(guard);
+                }
+            }
+            3 if !run.requests.is_empty() => run.poll(arg % run.requests.len()),
+            4 if !run.requests.is_empty() => {
+                run.requests.remove(arg % run.requests.len());
+            }
+            5 => {
+                if let Some(mut guard) = run.guards.pop() {
+                    *guard += 1;
+                }
+            }
+            6 => {
+                let deadline = match arg % 2 {
+                    0 => Instant::now(),
+                    _ => Instant::now() + Duration::from_secs(3600),
+                };
+                run.request(Priority::Normal, Some(deadline));
+            }
+            7 => {
+                for idx in (0..run.requests.len()).rev() {
+                    run.poll(idx);
+                }
+            }
+            _ => (),
+        }
+        run.check();
+    }
+
+    // Every request is eventually granted once the guards are released:
+    for _ in 0..=run.requests.len() {
+        run.guards.clear();
+        for idx in (0..run.requests.len()).rev() {
+            run.poll(idx);
+        }
+        run.check();
+    }
+    assert!(
+        run.requests.is_empty(),
+        "check_interleaving: {} requests never granted.",
+        run.requests.len()
+    );
+    run.guards.clear();
+    assert!(
+        run.qutex.try_lock().is_some(),
+        "check_interleaving: Lock left held."
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Admission, Priority, Qutex, QutexBuilder};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn wakes() {
+        let lock = Qutex::new(0);
+        let guard = lock.try_lock().unwrap();
+        let mut executor = TestExecutor::new();
+        executor.spawn_lock("waiter", lock.clone().lock());
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = count.clone();
+        executor.spawn("counter", async move {
+            counted.fetch_add(1, SeqCst);
+        });
+
+        assert_eq!(executor.run_until_stalled(), 2);
+        assert_eq!(executor.run_until_stalled(), 0);
+        assert_eq!(executor.pending(), ["waiter"]);
+        assert_eq!(executor.completed(), ["counter"]);
+
+        // Polled again only once woken:
+        drop(guard);
+        assert_eq!(executor.run_until_stalled(), 1);
+        assert!(executor.release("waiter") && !executor.release("waiter"));
+        assert_eq!(count.load(SeqCst), 1);
+        assert_eq!(*lock.try_lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn grant_order() {
+        let lock = QutexBuilder::new()
+            .admission(Admission::Fair)
+            .build(Vec::new());
+        let guard = lock.try_lock().unwrap();
+        let mut executor = TestExecutor::new();
+        for (label, priority) in [
+            ("low", Priority::Low),
+            ("normal", Priority::Normal),
+            ("high", Priority::High),
+            ("normal 2", Priority::Normal),
+        ] {
+            let lock = lock.clone();
+            executor.spawn_lock(label, async move {
+                let mut guard = lock.lock_with_priority(priority).await?;
+                guard.push(label);
+                Ok::<_, crate::Error>(guard)
+            });
+        }
+        // Requested only once first polled:
+        executor.run_until_stalled();
+        drop(guard);
+        assert_grant_order!(executor, ["high", "normal", "normal 2", "low"]);
+        assert_eq!(*lock.try_lock().unwrap(), executor.grant_order());
+    }
+
+    #[test]
+    fn test_waker() {
+        let test_waker = TestWaker::new();
+        let waker = TestWaker::waker(&test_waker);
+        waker.wake_by_ref();
+        waker.wake();
+        assert_eq!(test_waker.wake_count(), 2);
+        assert!(test_waker.take_woken() && !test_waker.take_woken());
+    }
+
+    #[test]
+    fn interleaving() {
+        // Queue three requests behind a guard, drop the first, expire one
+        // and release:
+        check_interleaving(&[2, 0, 1 | 8 << 3, 6, 4, 5, 7]);
+    }
+
+    proptest! {
+        #[test]
+        fn interleavings(ops in vec(any::<u8>(), 0..256)) {
+            check_interleaving(&ops);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Timers used by the timed operations within this crate.
+//!
+//! Every timed operation obtains its `Sleep` from the timer installed with
+//! `set_timer`, so that no particular executor or runtime is required. If
+//! none is installed, a `TokioTimer` is used from within a Tokio runtime
+//! (with the `tokio` feature), otherwise a `FuturesTimer` (with the
+//! `futures-timer` feature), otherwise a `ThreadTimer`.
+//
+
+use futures::channel::oneshot::{self, Canceled, Receiver, Sender};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A pending deadline, ordered such that the earliest is popped first.
+struct Entry {
+    deadline: Instant,
+    tx: Sender<()>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// Returns the channel to the timer thread, spawning it if necessary.
+fn timer() -> &'static mpsc::Sender<Entry> {
+    static TIMER: OnceLock<mpsc::Sender<Entry>> = OnceLock::new();
+
+    TIMER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("qutex-timer".to_owned())
+            .spawn(move || run(rx))
+            .expect("qutex::timer: Unable to spawn timer thread");
+        tx
+    })
+}
+
+/// Completes each entry once its deadline has passed.
+fn run(rx: mpsc::Receiver<Entry>) {
+    let mut entries = BinaryHeap::new();
+
+    loop {
+        let now = Instant::now();
+
+        while entries
+            .peek()
+            .is_some_and(|entry: &Entry| entry.deadline <= now)
+        {
+            // A send error means the `Delay` was dropped. Nothing to do.
+            let _ = entries.pop().unwrap().tx.send(());
+        }
+
+        let next = match entries.peek() {
+            Some(entry) => rx.recv_timeout(entry.deadline - now),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match next {
+            Ok(entry) => entries.push(entry),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// A future which resolves once a deadline has passed, serviced by the timer
+/// thread.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+struct Delay {
+    // `None` if the deadline had already passed upon creation:
+    rx: Option<Receiver<()>>,
+}
+
+impl Delay {
+    /// Returns a new `Delay` resolving at `deadline`.
+    fn until(deadline: Instant) -> Delay {
+        if deadline <= Instant::now() {
+            return Delay { rx: None };
+        }
+
+        let (tx, rx) = oneshot::channel();
+        // The timer thread lives for the duration of the process.
+        let _ = timer().send(Entry { deadline, tx });
+        Delay { rx: Some(rx) }
+    }
+}
+
+impl Future for Delay {
+    type Output = Result<(), Canceled>;
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.rx {
+            Some(ref mut rx) => Pin::new(rx).poll(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// A future which resolves once the deadline it was created for has passed.
+#[must_use = "futures do nothing unless polled"]
+pub struct Sleep {
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Sleep {
+    /// Returns a new `Sleep` wrapping the future which resolves at the
+    /// deadline.
+    pub fn new<F>(future: F) -> Sleep
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Sleep {
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for Sleep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sleep").finish_non_exhaustive()
+    }
+}
+
+/// A source of `Sleep`s, used by every timed operation.
+pub trait Timer: Send + Sync {
+    /// Returns a new `Sleep` resolving at `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+/// A `Timer` servicing every deadline from a single, lazily spawned
+/// background thread. Requires no runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadTimer;
+
+impl Timer for ThreadTimer {
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let delay = Delay::until(deadline);
+        // The timer thread never drops an entry without completing it:
+        Sleep::new(async move {
+            let _ = delay.await;
+        })
+    }
+}
+
+/// A `Timer` backed by the `futures-timer` crate.
+#[cfg(feature = "futures-timer")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FuturesTimer;
+
+#[cfg(feature = "futures-timer")]
+impl Timer for FuturesTimer {
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        Sleep::new(futures_timer::Delay::new(timeout))
+    }
+}
+
+static TIMER: OnceLock<Box<dyn Timer>> = OnceLock::new();
+
+/// Installs the `Timer` used by every timed operation.
+///
+/// May only be called once. Returns the rejected timer, boxed, if one is
+/// already installed.
+pub fn set_timer<M>(timer: M) -> Result<(), Box<dyn Timer>>
+where
+    M: Timer + 'static,
+{
+    TIMER.set(Box::new(timer))
+}
+
+/// Returns a new `Sleep` resolving at `deadline` from the installed timer,
+/// or the default.
+pub(crate) fn sleep_until(deadline: Instant) -> Sleep {
+    if let Some(timer) = TIMER.get() {
+        return timer.sleep_until(deadline);
+    }
+
+    #[cfg(feature = "tokio")]
+    {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return crate::tokio_rt::TokioTimer.sleep_until(deadline);
+        }
+    }
+
+    #[cfg(feature = "futures-timer")]
+    {
+        FuturesTimer.sleep_until(deadline)
+    }
+
+    #[cfg(not(feature = "futures-timer"))]
+    {
+        ThreadTimer.sleep_until(deadline)
+    }
+}
+
+/// Returns a new `Sleep` resolving after `duration`.
+#[inline]
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn delays_complete_in_order() {
+        let start = Instant::now();
+        let long = ThreadTimer.sleep_until(start + Duration::from_millis(60));
+        let short = ThreadTimer.sleep_until(start + Duration::from_millis(20));
+
+        block_on(short);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        block_on(long);
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn elapsed_deadline() {
+        let start = Instant::now();
+        block_on(sleep_until(start - Duration::from_millis(1)));
+        block_on(sleep(Duration::ZERO));
+        assert!(start.elapsed() < Duration::from_millis(20));
+    }
+}
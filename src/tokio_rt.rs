@@ -0,0 +1,90 @@
+//! Integration with the Tokio runtime.
+//!
+//
+
+use crate::{Error, Guard, Qutex, Sleep, Timer};
+use std::time::Instant;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// A `Timer` backed by Tokio's timer.
+///
+/// Each `Sleep` must be created from within a Tokio runtime with the timer
+/// enabled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioTimer;
+
+impl Timer for TokioTimer {
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Sleep::new(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+impl<T> Qutex<T> {
+    /// Blocks the current thread until the lock is granted.
+    ///
+    /// May be called from outside of any runtime, or from a task on a
+    /// multi-threaded Tokio runtime, in which case the worker thread is
+    /// handed off via `tokio::task::block_in_place` so that other tasks
+    /// (including the lock's current holder) continue to make progress.
+    ///
+    /// Panics if called from within a current-thread runtime, where waiting
+    /// would stall the very tasks which could release the lock.
+    pub fn blocking_lock(self) -> Result<Guard<T>, Error> {
+        match Handle::try_current() {
+            Ok(handle) => match handle.runtime_flavor() {
+                RuntimeFlavor::CurrentThread => {
+                    panic!("Qutex::blocking_lock: Cannot block within a current-thread runtime.")
+                }
+                _ => tokio::task::block_in_place(|| self.lock().wait()),
+            },
+            Err(_) => self.lock().wait(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn lock_timeout() {
+        let qutex = Qutex::new(0);
+        let guard = qutex.clone().lock().await.unwrap();
+
+        // Woken by Tokio's timer, even though the lock is never released:
+        let timed_out = qutex.clone().lock_timeout(Duration::from_millis(20));
+        assert_eq!(timed_out.await.unwrap_err(), Error::Expired);
+
+        drop(guard);
+        let granted = qutex.lock_timeout(Duration::from_millis(20)).await;
+        assert_eq!(*granted.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn blocking_lock() {
+        let qutex = Qutex::new(0);
+        let guard = qutex.clone().lock().await.unwrap();
+
+        let holder = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+
+        *qutex.clone().blocking_lock().unwrap() += 1;
+        holder.await.unwrap();
+        assert_eq!(*qutex.blocking_lock().unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn blocking_lock_current_thread() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async { Qutex::new(()).blocking_lock().map(drop) })
+            .unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+//! A queue of parked tasks shared by the notification-style primitives.
+//!
+//
+
+use crate::queue::SegQueue;
+use futures::channel::oneshot::{self, Receiver, Sender};
+
+/// A lock-free queue of waiters, each of which is woken by completing its
+/// oneshot channel with a message.
+#[derive(Debug)]
+pub(crate) struct WaitList<M = ()> {
+    queue: SegQueue<Sender<M>>,
+}
+
+impl<M> WaitList<M> {
+    /// Creates and returns a new, empty `WaitList`.
+    #[inline]
+    pub(crate) const fn new() -> WaitList<M> {
+        WaitList {
+            queue: SegQueue::new(),
+        }
+    }
+
+    /// Registers a new waiter, returning the receiver which will be
+    /// completed when it is woken.
+    #[inline]
+    pub(crate) fn push(&self) -> Receiver<M> {
+        let (tx, rx) = oneshot::channel();
+        self.queue.push(tx);
+        rx
+    }
+
+    /// Returns `true` if no waiters are queued (including any which have
+    /// since dropped their receivers).
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Sends `msg` to the first waiter which is still listening, returning
+    /// it if there was none.
+    pub(crate) fn send_one(&self, mut msg: M) -> Result<(), M> {
+        while let Some(tx) = self.queue.pop() {
+            // If there is a send error, a waiter has dropped its receiver so
+            // just go to the next.
+            match tx.send(msg) {
+                Ok(()) => return Ok(()),
+                Err(m) => msg = m,
+            }
+        }
+        Err(msg)
+    }
+
+    /// Sends a copy of `msg` to every waiter currently queued, returning the
+    /// number which were still listening.
+    pub(crate) fn send_all(&self, msg: M) -> usize
+    where
+        M: Clone,
+    {
+        let mut count = 0;
+        while let Some(tx) = self.queue.pop() {
+            if tx.send(msg.clone()).is_ok() {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+impl WaitList<()> {
+    /// Wakes the first waiter which is still listening, returning `false` if
+    /// there was none.
+    #[inline]
+    pub(crate) fn notify_one(&self) -> bool {
+        self.send_one(()).is_ok()
+    }
+
+    /// Wakes every waiter currently queued, returning the number which were
+    /// still listening.
+    #[inline]
+    pub(crate) fn notify_all(&self) -> usize {
+        self.send_all(())
+    }
+
+    /// Wakes up to `n` of the waiters currently queued, oldest first,
+    /// returning the number which were still listening.
+    pub(crate) fn notify_at_most(&self, n: usize) -> usize {
+        (0..n).take_while(|_| self.notify_one()).count()
+    }
+}
@@ -0,0 +1,118 @@
+//! The queues lock requests wait in.
+//!
+//! A `Qutex` keeps one `WaitQueue` per priority level, and touches it only
+//! through this trait, so that the backend can be swapped at build time
+//! without the lock itself changing. `RequestQueue` names the backend in
+//! use: a `SegQueue` by default, or with the `fixed_queue` feature a
+//! `FixedQueue` of `FIXED_QUEUE_CAPACITY` requests per priority level,
+//! which never allocates once created.
+//
+// A backend need not guarantee FIFO order between concurrent pushes, only
+// that each request pushed is popped exactly once, and that a push which
+// happens before a pop is seen by it.
+
+#[cfg(any(test, feature = "fixed_queue"))]
+use crate::queue::FixedQueue;
+use crate::queue::SegQueue;
+
+/// The number of requests of each priority a `Qutex` can queue with the
+/// `fixed_queue` feature. Requests beyond it fail with `Error::QueueFull`.
+/// Abandoned requests keep their place until skipped.
+#[cfg(feature = "fixed_queue")]
+pub(crate) const FIXED_QUEUE_CAPACITY: usize = 32;
+
+/// The backend every `Qutex` queues its requests in.
+#[cfg(not(feature = "fixed_queue"))]
+pub(crate) type RequestQueue<R> = SegQueue<R>;
+#[cfg(feature = "fixed_queue")]
+pub(crate) type RequestQueue<R> = FixedQueue<R, FIXED_QUEUE_CAPACITY>;
+
+/// A multi-producer, multi-consumer queue of waiting requests.
+pub(crate) trait WaitQueue<R>: Default {
+    /// Pushes `req` onto the back of the queue, or returns it if the queue
+    /// is full.
+    fn push(&self, req: R) -> Result<(), R>;
+
+    /// Pops the request at the front of the queue, if any.
+    fn pop(&self) -> Option<R>;
+
+    /// Returns `true` if no requests are queued.
+    fn is_empty(&self) -> bool;
+}
+
+impl<R> WaitQueue<R> for SegQueue<R> {
+    #[inline]
+    fn push(&self, req: R) -> Result<(), R> {
+        SegQueue::push(self, req);
+        Ok(())
+    }
+
+    #[inline]
+    fn pop(&self) -> Option<R> {
+        SegQueue::pop(self)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        SegQueue::is_empty(self)
+    }
+}
+
+#[cfg(any(test, feature = "fixed_queue"))]
+impl<R, const N: usize> WaitQueue<R> for FixedQueue<R, N> {
+    #[inline]
+    fn push(&self, req: R) -> Result<(), R> {
+        FixedQueue::push(self, req)
+    }
+
+    #[inline]
+    fn pop(&self) -> Option<R> {
+        FixedQueue::pop(self)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        FixedQueue::is_empty(self)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Checks `Q` against the `WaitQueue` contract, single threaded.
+    pub(crate) fn check_fifo<Q: WaitQueue<u32>>() {
+        let queue = Q::default();
+        assert!(queue.is_empty() && queue.pop().is_none());
+        for req in 0..100 {
+            assert!(queue.push(req).is_ok());
+        }
+        assert!(!queue.is_empty());
+        for req in 0..50 {
+            assert_eq!(queue.pop(), Some(req));
+        }
+        assert!(queue.push(100).is_ok());
+        for req in 50..=100 {
+            assert_eq!(queue.pop(), Some(req));
+        }
+        assert!(queue.pop().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn seg_queue() {
+        check_fifo::<SegQueue<u32>>();
+    }
+
+    #[test]
+    fn fixed_queue() {
+        check_fifo::<FixedQueue<u32, 128>>();
+
+        // Full at capacity, with room again once popped:
+        let queue = FixedQueue::<u32, 2>::new();
+        assert!(queue.push(0).is_ok() && queue.push(1).is_ok());
+        assert_eq!(WaitQueue::push(&queue, 2), Err(2));
+        assert_eq!(queue.pop(), Some(0));
+        assert!(queue.push(2).is_ok());
+    }
+}
@@ -0,0 +1,469 @@
+//! A lock which can be awaited for changes.
+//!
+//
+
+use crate::wait_list::WaitList;
+use crate::{Error, FutureGuard, Guard, Qutex};
+use futures::channel::oneshot::Receiver;
+use futures::task::AtomicWaker;
+use futures::{executor, Stream};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// The receiving end of a subscription, type-erased so that `Watch` need
+/// not require `T: Clone`.
+trait Subscriber<T>: Send + Sync {
+    /// Buffers a snapshot of `val`.
+    fn send(&self, version: u64, val: &T);
+
+    /// Ends the subscription once its buffer is drained.
+    fn close(&self);
+}
+
+struct Subscribers<T>(Mutex<Vec<Arc<dyn Subscriber<T>>>>);
+
+impl<T> fmt::Debug for Subscribers<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.try_lock() {
+            Ok(subscribers) => write!(f, "Subscribers({})", subscribers.len()),
+            Err(_) => f.write_str("Subscribers(<locked>)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    qutex: Qutex<T>,
+    // Incremented every time a mutated value is unlocked:
+    version: AtomicU64,
+    changed: WaitList,
+    subscribers: Subscribers<T>,
+}
+
+impl<T> Inner<T> {
+    /// Records a change to `val`, which must still be locked, returning the
+    /// new version.
+    fn record(&self, val: &T) -> u64 {
+        let version = self.version.fetch_add(1, SeqCst) + 1;
+
+        let mut subscribers = self.subscribers.0.lock().unwrap();
+        // Those no longer referenced elsewhere have been dropped:
+        subscribers.retain(|subscriber| Arc::strong_count(subscriber) > 1);
+        for subscriber in subscribers.iter() {
+            subscriber.send(version, val);
+        }
+
+        version
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        for subscriber in self.subscribers.0.get_mut().unwrap().drain(..) {
+            subscriber.close();
+        }
+    }
+}
+
+/// Allows access to the data contained within a `Watch`.
+///
+/// If the data was mutably accessed, the version is incremented, subscribers
+/// receive a snapshot, and waiters are notified once this guard is dropped.
+#[derive(Debug)]
+pub struct WatchGuard<T> {
+    inner: Arc<Inner<T>>,
+    guard: Option<Guard<T>>,
+    mutated: bool,
+}
+
+impl<T> WatchGuard<T> {
+    /// Marks the value as unchanged despite having been mutably accessed,
+    /// so that dropping this guard does not notify anyone.
+    #[inline]
+    pub fn discard_change(guard: &mut WatchGuard<T>) {
+        guard.mutated = false;
+    }
+}
+
+impl<T> Deref for WatchGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for WatchGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.mutated = true;
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for WatchGuard<T> {
+    fn drop(&mut self) {
+        if self.mutated {
+            self.inner.record(self.guard.as_ref().unwrap());
+        }
+
+        // Unlock before waking so that those woken can lock immediately:
+        drop(self.guard.take());
+
+        if self.mutated {
+            self.inner.changed.notify_all();
+        }
+    }
+}
+
+/// A future which resolves to a `WatchGuard`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureWatchGuard<T> {
+    inner: Arc<Inner<T>>,
+    future_guard: FutureGuard<T>,
+}
+
+impl<T> FutureWatchGuard<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<WatchGuard<T>, Error> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for FutureWatchGuard<T> {
+    type Output = Result<WatchGuard<T>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let guard = futures::ready!(Pin::new(&mut this.future_guard).poll(cx))?;
+
+        Poll::Ready(Ok(WatchGuard {
+            inner: this.inner.clone(),
+            guard: Some(guard),
+            mutated: false,
+        }))
+    }
+}
+
+/// A future which resolves to the current version once it is newer than
+/// the version it was created with.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct FutureChanged<T> {
+    inner: Arc<Inner<T>>,
+    seen: u64,
+    rx: Option<Receiver<()>>,
+}
+
+impl<T> FutureChanged<T> {
+    /// Blocks the current thread until this future resolves.
+    #[inline]
+    pub fn wait(self) -> Result<u64, Infallible> {
+        executor::block_on(self)
+    }
+
+    /// Returns the current version if it is newer than the one seen.
+    #[inline]
+    fn check(&self) -> Option<u64> {
+        let version = self.inner.version.load(SeqCst);
+        if version > self.seen {
+            Some(version)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Future for FutureChanged<T> {
+    type Output = Result<u64, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if let Some(version) = this.check() {
+                return Poll::Ready(Ok(version));
+            }
+
+            match this.rx {
+                Some(ref mut rx) => match Pin::new(rx).poll(cx) {
+                    Poll::Ready(_) => this.rx = None,
+                    Poll::Pending => return Poll::Pending,
+                },
+                // Register, then re-check in case of a change before we were
+                // queued:
+                None => {
+                    this.rx = Some(this.inner.changed.push());
+                    if let Some(version) = this.check() {
+                        return Poll::Ready(Ok(version));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SubscriptionShared<T> {
+    buffer: Mutex<VecDeque<(u64, T)>>,
+    capacity: usize,
+    lagged: AtomicU64,
+    closed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl<T> Subscriber<T> for SubscriptionShared<T>
+where
+    T: Clone + Send,
+{
+    fn send(&self, version: u64, val: &T) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+            self.lagged.fetch_add(1, SeqCst);
+        }
+        buffer.push_back((version, val.clone()));
+        drop(buffer);
+        self.waker.wake();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, SeqCst);
+        self.waker.wake();
+    }
+}
+
+/// A `Stream` of `(version, snapshot)` pairs, one for every change to the
+/// `Watch` it was created from.
+///
+/// At most `capacity` snapshots are buffered. When a new change arrives at
+/// a full buffer, the oldest snapshot is discarded and counted as lagged.
+/// The stream ends once every copy of the `Watch` has been dropped and the
+/// buffer has been drained.
+#[must_use = "streams do nothing unless polled"]
+#[derive(Debug)]
+pub struct Subscription<T> {
+    shared: Arc<SubscriptionShared<T>>,
+}
+
+impl<T> Subscription<T> {
+    /// Returns the total number of snapshots discarded so far because the
+    /// buffer was full.
+    #[inline]
+    pub fn lagged(&self) -> u64 {
+        self.shared.lagged.load(SeqCst)
+    }
+
+    /// Removes and returns the oldest buffered snapshot, if any.
+    #[inline]
+    fn pop(&self) -> Option<(u64, T)> {
+        self.shared.buffer.lock().unwrap().pop_front()
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = (u64, T);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(item) = self.pop() {
+            return Poll::Ready(Some(item));
+        }
+
+        self.shared.waker.register(cx.waker());
+
+        // Re-check in case of a change, or closure, before registering:
+        if let Some(item) = self.pop() {
+            Poll::Ready(Some(item))
+        } else if self.shared.closed.load(SeqCst) {
+            Poll::Ready(self.pop())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A queue-backed exclusive lock which tasks can wait on for changes.
+///
+/// Every time a guard which mutably accessed the value is dropped, the
+/// version is incremented and waiters are woken. Waiters compare against the
+/// last version they saw, so no change is missed even if several happen
+/// between polls (though intermediate values may be).
+#[derive(Debug)]
+pub struct Watch<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Watch<T> {
+    /// Creates and returns a new `Watch` at version zero.
+    #[inline]
+    pub fn new(val: T) -> Watch<T> {
+        Watch {
+            inner: Arc::new(Inner {
+                qutex: Qutex::new(val),
+                version: AtomicU64::new(0),
+                changed: WaitList::new(),
+                subscribers: Subscribers(Mutex::new(Vec::new())),
+            }),
+        }
+    }
+
+    /// Returns a new `FutureWatchGuard` which will resolve into a
+    /// `WatchGuard`.
+    #[inline]
+    pub fn lock(&self) -> FutureWatchGuard<T> {
+        FutureWatchGuard {
+            inner: self.inner.clone(),
+            future_guard: self.inner.qutex.clone().lock(),
+        }
+    }
+
+    /// Returns the current version.
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.inner.version.load(SeqCst)
+    }
+
+    /// Returns a new `FutureChanged` which will resolve to the new version
+    /// once the version is greater than `seen`.
+    #[inline]
+    pub fn changed(&self, seen: u64) -> FutureChanged<T> {
+        FutureChanged {
+            inner: self.inner.clone(),
+            seen,
+            rx: None,
+        }
+    }
+
+    /// Returns a new `Subscription` which will yield a snapshot of every
+    /// subsequent change, buffering at most `capacity` of them.
+    ///
+    /// Snapshots are cloned while the lock is still held by the writer.
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn subscribe(&self, capacity: usize) -> Subscription<T>
+    where
+        T: Clone + Send + 'static,
+    {
+        assert!(
+            capacity > 0,
+            "Watch::subscribe: Capacity must be greater than zero."
+        );
+
+        let shared = Arc::new(SubscriptionShared {
+            buffer: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            lagged: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+
+        self.inner
+            .subscribers
+            .0
+            .lock()
+            .unwrap()
+            .push(shared.clone());
+        Subscription { shared }
+    }
+}
+
+impl<T> From<T> for Watch<T> {
+    #[inline]
+    fn from(val: T) -> Watch<T> {
+        Watch::new(val)
+    }
+}
+
+// Avoids needing `T: Clone`.
+impl<T> Clone for Watch<T> {
+    #[inline]
+    fn clone(&self) -> Watch<T> {
+        Watch {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::{FutureExt, StreamExt};
+    use std::thread;
+
+    #[test]
+    fn changed() {
+        let watch = Watch::new(0u32);
+        let seen = watch.version();
+
+        let watch_c = watch.clone();
+        let thread = thread::spawn(move || {
+            *watch_c.lock().wait().unwrap() = 5;
+        });
+
+        let version = watch.changed(seen).wait().unwrap();
+        assert_eq!(version, seen + 1);
+        assert_eq!(*watch.lock().wait().unwrap(), 5);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn reads_do_not_notify() {
+        let watch = Watch::new(String::from("a"));
+        assert_eq!(watch.lock().wait().unwrap().len(), 1);
+
+        let mut guard = watch.lock().wait().unwrap();
+        guard.push('b');
+        WatchGuard::discard_change(&mut guard);
+        drop(guard);
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_eq!(watch.changed(0).poll_unpin(cx), Poll::Pending);
+
+        // Already-past versions resolve immediately:
+        watch.lock().wait().unwrap().push('c');
+        assert_eq!(watch.changed(0).wait(), Ok(1));
+    }
+
+    #[test]
+    fn subscribe() {
+        let watch = Watch::new(0u32);
+        let subscription = watch.subscribe(8);
+
+        let watch_c = watch.clone();
+        let thread = thread::spawn(move || {
+            for i in 1..=3 {
+                *watch_c.lock().wait().unwrap() = i * 10;
+            }
+        });
+
+        thread.join().unwrap();
+        drop(watch);
+
+        let snapshots = executor::block_on(subscription.collect::<Vec<_>>());
+        assert_eq!(snapshots, vec![(1, 10), (2, 20), (3, 30)]);
+    }
+
+    #[test]
+    fn subscription_lags() {
+        let watch = Watch::new(0u32);
+        let mut subscription = watch.subscribe(2);
+
+        for i in 1..=5 {
+            *watch.lock().wait().unwrap() = i;
+        }
+
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        assert_eq!(subscription.lagged(), 3);
+        assert_eq!(subscription.poll_next_unpin(cx), Poll::Ready(Some((4, 4))));
+        assert_eq!(subscription.poll_next_unpin(cx), Poll::Ready(Some((5, 5))));
+    }
+}